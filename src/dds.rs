@@ -0,0 +1,795 @@
+// DirectDraw Surface textures, as game HDR assets come: BC6H compressed,
+// or plain half or single float. Taken as linear scRGB, like EXR input.
+// Only the top mip level of the first texture in the file is read.
+// https://learn.microsoft.com/en-us/windows/win32/direct3ddds/dx-graphics-dds-pguide
+// https://learn.microsoft.com/en-us/windows/win32/direct3d11/bc6h-format
+
+use std::convert::TryInto;
+use std::io::Read;
+use std::path::Path;
+
+use glam::f32::Vec3;
+use half::f16;
+use rayon::prelude::*;
+
+use crate::LocalError::*;
+use crate::{
+    open_input, write_scrgb_alpha128float, write_scrgb_rgb128float, PixelBuffer, PixelFormat,
+    Result,
+};
+
+const MAGIC: &[u8] = b"DDS ";
+// Magic number and DDS_HEADER
+const HEADER_SIZE: usize = 128;
+// DDS_HEADER_DXT10, present when the fourCC is "DX10"
+const DX10_HEADER_SIZE: usize = 20;
+const FOURCC_DX10: &[u8] = b"DX10";
+const DDPF_FOURCC: u32 = 0x4;
+
+// Older files give float formats as a D3DFORMAT in the fourCC field
+const D3DFMT_A16B16G16R16F: u32 = 113;
+const D3DFMT_A32B32G32R32F: u32 = 116;
+
+const DXGI_FORMAT_R32G32B32A32_FLOAT: u32 = 2;
+const DXGI_FORMAT_R32G32B32_FLOAT: u32 = 6;
+const DXGI_FORMAT_R16G16B16A16_FLOAT: u32 = 10;
+const DXGI_FORMAT_BC6H_TYPELESS: u32 = 94;
+const DXGI_FORMAT_BC6H_UF16: u32 = 95;
+const DXGI_FORMAT_BC6H_SF16: u32 = 96;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DdsFormat {
+    Rgba32Float,
+    Rgb32Float,
+    Rgba16Float,
+    Bc6h { signed: bool },
+}
+
+struct Header {
+    width: usize,
+    height: usize,
+    format: DdsFormat,
+    data_offset: usize,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Header> {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+        return Err(DDSError("not a DDS file".to_string()));
+    }
+    let height = u32_at(bytes, 12) as usize;
+    let width = u32_at(bytes, 16) as usize;
+    if u32_at(bytes, 80) & DDPF_FOURCC == 0 {
+        return Err(DDSError(
+            "only BC6H and float pixel formats are supported".to_string(),
+        ));
+    }
+
+    let (format, data_offset) = if &bytes[84..88] == FOURCC_DX10 {
+        if bytes.len() < HEADER_SIZE + DX10_HEADER_SIZE {
+            return Err(DDSError("truncated DX10 header".to_string()));
+        }
+        let format = match u32_at(bytes, HEADER_SIZE) {
+            DXGI_FORMAT_R32G32B32A32_FLOAT => DdsFormat::Rgba32Float,
+            DXGI_FORMAT_R32G32B32_FLOAT => DdsFormat::Rgb32Float,
+            DXGI_FORMAT_R16G16B16A16_FLOAT => DdsFormat::Rgba16Float,
+            DXGI_FORMAT_BC6H_TYPELESS | DXGI_FORMAT_BC6H_UF16 => DdsFormat::Bc6h { signed: false },
+            DXGI_FORMAT_BC6H_SF16 => DdsFormat::Bc6h { signed: true },
+            dxgi => return Err(DDSError(format!("unsupported DXGI format {}", dxgi))),
+        };
+        (format, HEADER_SIZE + DX10_HEADER_SIZE)
+    } else {
+        let format = match u32_at(bytes, 84) {
+            D3DFMT_A16B16G16R16F => DdsFormat::Rgba16Float,
+            D3DFMT_A32B32G32R32F => DdsFormat::Rgba32Float,
+            _ => {
+                return Err(DDSError(format!(
+                    "unsupported fourCC '{}'",
+                    String::from_utf8_lossy(&bytes[84..88])
+                )))
+            }
+        };
+        (format, HEADER_SIZE)
+    };
+    Ok(Header {
+        width,
+        height,
+        format,
+        data_offset,
+    })
+}
+
+fn blocks(pixels: usize) -> usize {
+    pixels.div_ceil(4)
+}
+
+pub(crate) fn probe_dds(filename: &Path) -> Result<(usize, usize)> {
+    let mut bytes = Vec::new();
+    open_input(filename)?
+        .take((HEADER_SIZE + DX10_HEADER_SIZE) as u64)
+        .read_to_end(&mut bytes)?;
+    let header = parse_header(&bytes)?;
+    Ok((header.width, header.height))
+}
+
+pub(crate) fn read_dds(filename: &Path) -> Result<PixelBuffer> {
+    let mut bytes = Vec::new();
+    open_input(filename)?.read_to_end(&mut bytes)?;
+    let header = parse_header(&bytes)?;
+    let (width, height) = (header.width, header.height);
+    // The dimensions come straight from the file, so a damaged header
+    // mustn't overflow the size of the data it claims to have.
+    let texels = |bytes_per_texel: usize| width.checked_mul(height)?.checked_mul(bytes_per_texel);
+    let data_end = match header.format {
+        DdsFormat::Rgba32Float => texels(16),
+        DdsFormat::Rgb32Float => texels(12),
+        DdsFormat::Rgba16Float => texels(8),
+        DdsFormat::Bc6h { .. } => blocks(width)
+            .checked_mul(blocks(height))
+            .and_then(|count| count.checked_mul(16)),
+    }
+    .and_then(|data_size| header.data_offset.checked_add(data_size))
+    .ok_or_else(|| DDSError(format!("image size {}x{} too large", width, height)))?;
+    let data = bytes
+        .get(header.data_offset..data_end)
+        .ok_or_else(|| DDSError("truncated image data".to_string()))?;
+
+    let mut buffer = PixelBuffer::new(width, height, PixelFormat::HDRFloat32);
+    let f32_at =
+        |texel: &[u8], i: usize| f32::from_le_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap());
+    let f16_at = |texel: &[u8], i: usize| {
+        f16::from_le_bytes(texel[i * 2..i * 2 + 2].try_into().unwrap()).to_f32()
+    };
+    match header.format {
+        DdsFormat::Rgba32Float => buffer
+            .par_iter_mut()
+            .zip(data.par_chunks_exact(16))
+            .for_each(|(pixel, texel)| {
+                let rgb = Vec3::new(f32_at(texel, 0), f32_at(texel, 1), f32_at(texel, 2));
+                write_scrgb_rgb128float(pixel, rgb);
+                write_scrgb_alpha128float(pixel, f32_at(texel, 3));
+            }),
+        DdsFormat::Rgb32Float => buffer
+            .par_iter_mut()
+            .zip(data.par_chunks_exact(12))
+            .for_each(|(pixel, texel)| {
+                let rgb = Vec3::new(f32_at(texel, 0), f32_at(texel, 1), f32_at(texel, 2));
+                write_scrgb_rgb128float(pixel, rgb);
+                write_scrgb_alpha128float(pixel, 1.0);
+            }),
+        DdsFormat::Rgba16Float => buffer
+            .par_iter_mut()
+            .zip(data.par_chunks_exact(8))
+            .for_each(|(pixel, texel)| {
+                let rgb = Vec3::new(f16_at(texel, 0), f16_at(texel, 1), f16_at(texel, 2));
+                write_scrgb_rgb128float(pixel, rgb);
+                write_scrgb_alpha128float(pixel, f16_at(texel, 3));
+            }),
+        DdsFormat::Bc6h { signed } => {
+            // Each row of blocks fills four rows of pixels
+            let bytes_per_pixel = buffer.bytes_per_pixel;
+            let stride = width * bytes_per_pixel;
+            buffer
+                .data
+                .par_chunks_mut(stride * 4)
+                .zip(data.par_chunks_exact(blocks(width) * 16))
+                .for_each(|(rows, block_row)| {
+                    for (bx, block) in block_row.chunks_exact(16).enumerate() {
+                        for (i, rgb) in decode_bc6h_block(block, signed).iter().enumerate() {
+                            let (x, y) = (bx * 4 + i % 4, i / 4);
+                            let offset = y * stride + x * bytes_per_pixel;
+                            if x < width && offset < rows.len() {
+                                let pixel = &mut rows[offset..offset + bytes_per_pixel];
+                                write_scrgb_rgb128float(pixel, *rgb);
+                                write_scrgb_alpha128float(pixel, 1.0);
+                            }
+                        }
+                    }
+                })
+        }
+    }
+    Ok(buffer)
+}
+
+// Reads a BC6H block's bits, least significant first
+struct BitReader {
+    bits: u128,
+    pos: u32,
+}
+
+impl BitReader {
+    fn read(&mut self, count: u32) -> u32 {
+        let val = (self.bits >> self.pos) & ((1 << count) - 1);
+        self.pos += count;
+        val as u32
+    }
+}
+
+// Endpoint channels as they're named in the BC6H spec: w and x are the
+// first subset's endpoints, y and z the second's. D is the partition.
+const RW: u8 = 0;
+const GW: u8 = 1;
+const BW: u8 = 2;
+const RX: u8 = 3;
+const GX: u8 = 4;
+const BX: u8 = 5;
+const RY: u8 = 6;
+const GY: u8 = 7;
+const BY: u8 = 8;
+const RZ: u8 = 9;
+const GZ: u8 = 10;
+const BZ: u8 = 11;
+const D: u8 = 12;
+
+struct Bc6hMode {
+    // The 2 or 5 mode bits, read least significant first
+    bits: u32,
+    two_subsets: bool,
+    // Whether the endpoints other than w are stored as deltas from it
+    transformed: bool,
+    endpoint_bits: u32,
+    delta_bits: [u32; 3],
+    // Fields in the order they're stored after the mode bits. (field, a, b)
+    // stands for the spec's field[a:b]: its bits from b to a, b first.
+    // The high bits of modes 13 and 14 are written reversed, like rw[10:15].
+    layout: &'static [(u8, u8, u8)],
+}
+
+// Modes 1 to 14 of the spec; the four other 5-bit values are reserved.
+const BC6H_MODES: [Bc6hMode; 14] = [
+    Bc6hMode {
+        bits: 0b00,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 10,
+        delta_bits: [5, 5, 5],
+        layout: &[
+            (GY, 4, 4),
+            (BY, 4, 4),
+            (BZ, 4, 4),
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 4, 0),
+            (GZ, 4, 4),
+            (GY, 3, 0),
+            (GX, 4, 0),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 4, 0),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 4, 0),
+            (BZ, 2, 2),
+            (RZ, 4, 0),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b01,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 7,
+        delta_bits: [6, 6, 6],
+        layout: &[
+            (GY, 5, 5),
+            (GZ, 4, 4),
+            (GZ, 5, 5),
+            (RW, 6, 0),
+            (BZ, 0, 0),
+            (BZ, 1, 1),
+            (BY, 4, 4),
+            (GW, 6, 0),
+            (BY, 5, 5),
+            (BZ, 2, 2),
+            (GY, 4, 4),
+            (BW, 6, 0),
+            (BZ, 3, 3),
+            (BZ, 5, 5),
+            (BZ, 4, 4),
+            (RX, 5, 0),
+            (GY, 3, 0),
+            (GX, 5, 0),
+            (GZ, 3, 0),
+            (BX, 5, 0),
+            (BY, 3, 0),
+            (RY, 5, 0),
+            (RZ, 5, 0),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b00010,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 11,
+        delta_bits: [5, 4, 4],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 4, 0),
+            (RW, 10, 10),
+            (GY, 3, 0),
+            (GX, 3, 0),
+            (GW, 10, 10),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 3, 0),
+            (BW, 10, 10),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 4, 0),
+            (BZ, 2, 2),
+            (RZ, 4, 0),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b00110,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 11,
+        delta_bits: [4, 5, 4],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 3, 0),
+            (RW, 10, 10),
+            (GZ, 4, 4),
+            (GY, 3, 0),
+            (GX, 4, 0),
+            (GW, 10, 10),
+            (GZ, 3, 0),
+            (BX, 3, 0),
+            (BW, 10, 10),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 3, 0),
+            (BZ, 0, 0),
+            (BZ, 2, 2),
+            (RZ, 3, 0),
+            (GY, 4, 4),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b01010,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 11,
+        delta_bits: [4, 4, 5],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 3, 0),
+            (RW, 10, 10),
+            (BY, 4, 4),
+            (GY, 3, 0),
+            (GX, 3, 0),
+            (GW, 10, 10),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 4, 0),
+            (BW, 10, 10),
+            (BY, 3, 0),
+            (RY, 3, 0),
+            (BZ, 1, 1),
+            (BZ, 2, 2),
+            (RZ, 3, 0),
+            (BZ, 4, 4),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b01110,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 9,
+        delta_bits: [5, 5, 5],
+        layout: &[
+            (RW, 8, 0),
+            (BY, 4, 4),
+            (GW, 8, 0),
+            (GY, 4, 4),
+            (BW, 8, 0),
+            (BZ, 4, 4),
+            (RX, 4, 0),
+            (GZ, 4, 4),
+            (GY, 3, 0),
+            (GX, 4, 0),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 4, 0),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 4, 0),
+            (BZ, 2, 2),
+            (RZ, 4, 0),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b10010,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 8,
+        delta_bits: [6, 5, 5],
+        layout: &[
+            (RW, 7, 0),
+            (GZ, 4, 4),
+            (BY, 4, 4),
+            (GW, 7, 0),
+            (BZ, 2, 2),
+            (GY, 4, 4),
+            (BW, 7, 0),
+            (BZ, 3, 3),
+            (BZ, 4, 4),
+            (RX, 5, 0),
+            (GY, 3, 0),
+            (GX, 4, 0),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 4, 0),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 5, 0),
+            (RZ, 5, 0),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b10110,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 8,
+        delta_bits: [5, 6, 5],
+        layout: &[
+            (RW, 7, 0),
+            (BZ, 0, 0),
+            (BY, 4, 4),
+            (GW, 7, 0),
+            (GY, 5, 5),
+            (GY, 4, 4),
+            (BW, 7, 0),
+            (GZ, 5, 5),
+            (BZ, 4, 4),
+            (RX, 4, 0),
+            (GZ, 4, 4),
+            (GY, 3, 0),
+            (GX, 5, 0),
+            (GZ, 3, 0),
+            (BX, 4, 0),
+            (BZ, 1, 1),
+            (BY, 3, 0),
+            (RY, 4, 0),
+            (BZ, 2, 2),
+            (RZ, 4, 0),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b11010,
+        two_subsets: true,
+        transformed: true,
+        endpoint_bits: 8,
+        delta_bits: [5, 5, 6],
+        layout: &[
+            (RW, 7, 0),
+            (BZ, 1, 1),
+            (BY, 4, 4),
+            (GW, 7, 0),
+            (BY, 5, 5),
+            (GY, 4, 4),
+            (BW, 7, 0),
+            (BZ, 5, 5),
+            (BZ, 4, 4),
+            (RX, 4, 0),
+            (GZ, 4, 4),
+            (GY, 3, 0),
+            (GX, 4, 0),
+            (BZ, 0, 0),
+            (GZ, 3, 0),
+            (BX, 5, 0),
+            (BY, 3, 0),
+            (RY, 4, 0),
+            (BZ, 2, 2),
+            (RZ, 4, 0),
+            (BZ, 3, 3),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b11110,
+        two_subsets: true,
+        transformed: false,
+        endpoint_bits: 6,
+        delta_bits: [6, 6, 6],
+        layout: &[
+            (RW, 5, 0),
+            (GZ, 4, 4),
+            (BZ, 0, 0),
+            (BZ, 1, 1),
+            (BY, 4, 4),
+            (GW, 5, 0),
+            (GY, 5, 5),
+            (BY, 5, 5),
+            (BZ, 2, 2),
+            (GY, 4, 4),
+            (BW, 5, 0),
+            (GZ, 5, 5),
+            (BZ, 3, 3),
+            (BZ, 5, 5),
+            (BZ, 4, 4),
+            (RX, 5, 0),
+            (GY, 3, 0),
+            (GX, 5, 0),
+            (GZ, 3, 0),
+            (BX, 5, 0),
+            (BY, 3, 0),
+            (RY, 5, 0),
+            (RZ, 5, 0),
+            (D, 4, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b00011,
+        two_subsets: false,
+        transformed: false,
+        endpoint_bits: 10,
+        delta_bits: [10, 10, 10],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 9, 0),
+            (GX, 9, 0),
+            (BX, 9, 0),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b00111,
+        two_subsets: false,
+        transformed: true,
+        endpoint_bits: 11,
+        delta_bits: [9, 9, 9],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 8, 0),
+            (RW, 10, 10),
+            (GX, 8, 0),
+            (GW, 10, 10),
+            (BX, 8, 0),
+            (BW, 10, 10),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b01011,
+        two_subsets: false,
+        transformed: true,
+        endpoint_bits: 12,
+        delta_bits: [8, 8, 8],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 7, 0),
+            (RW, 10, 11),
+            (GX, 7, 0),
+            (GW, 10, 11),
+            (BX, 7, 0),
+            (BW, 10, 11),
+        ],
+    },
+    Bc6hMode {
+        bits: 0b01111,
+        two_subsets: false,
+        transformed: true,
+        endpoint_bits: 16,
+        delta_bits: [4, 4, 4],
+        layout: &[
+            (RW, 9, 0),
+            (GW, 9, 0),
+            (BW, 9, 0),
+            (RX, 3, 0),
+            (RW, 10, 15),
+            (GX, 3, 0),
+            (GW, 10, 15),
+            (BX, 3, 0),
+            (BW, 10, 15),
+        ],
+    },
+];
+
+// Which subset each texel belongs to, for the 32 two-subset partitions
+const BC6H_PARTITIONS: [[u8; 16]; 32] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1],
+    [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1],
+    [0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1],
+    [0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1, 1],
+    [0, 1, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0],
+    [0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0, 1, 1, 1, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0],
+    [0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0, 1],
+    [0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0],
+    [0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0],
+    [0, 0, 1, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 0],
+    [0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0],
+    [0, 1, 1, 1, 0, 0, 0, 1, 1, 0, 0, 0, 1, 1, 1, 0],
+    [0, 0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 1, 0, 0],
+];
+
+// The second subset's anchor texel in each partition, whose index is a
+// bit shorter like the first texel's, the first subset's anchor.
+const BC6H_ANCHORS: [usize; 32] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 2, 8, 2, 2, 8, 8, 15, 2, 8,
+    2, 2, 8, 8, 2, 2,
+];
+
+const BC6H_WEIGHTS3: [i32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC6H_WEIGHTS4: [i32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+fn sign_extend(val: i32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    (val << shift) >> shift
+}
+
+// Stretch an endpoint to the full 16 bit range the interpolation works in
+fn unquantize(val: i32, bits: u32, signed: bool) -> i32 {
+    if !signed {
+        if bits >= 15 {
+            val
+        } else if val == 0 {
+            0
+        } else if val == (1 << bits) - 1 {
+            0xFFFF
+        } else {
+            ((val << 16) + 0x8000) >> bits
+        }
+    } else if bits >= 16 {
+        val
+    } else {
+        let magnitude = val.abs();
+        let unquantized = if magnitude == 0 {
+            0
+        } else if magnitude >= (1 << (bits - 1)) - 1 {
+            0x7FFF
+        } else {
+            ((magnitude << 15) + 0x4000) >> (bits - 1)
+        };
+        if val < 0 {
+            -unquantized
+        } else {
+            unquantized
+        }
+    }
+}
+
+// Scale an interpolated value back down to the bits of a half float
+fn finish_unquantize(val: i32, signed: bool) -> f32 {
+    let bits = if !signed {
+        (val * 31) >> 6
+    } else if val < 0 {
+        (((-val) * 31) >> 5) | 0x8000
+    } else {
+        (val * 31) >> 5
+    };
+    f16::from_bits(bits as u16).to_f32()
+}
+
+// Decode one 16 byte block into its 4x4 texels, in rows
+fn decode_bc6h_block(block: &[u8], signed: bool) -> [Vec3; 16] {
+    let mut reader = BitReader {
+        bits: u128::from_le_bytes(block.try_into().unwrap()),
+        pos: 0,
+    };
+    let mut mode_bits = reader.read(2);
+    if mode_bits > 1 {
+        mode_bits |= reader.read(3) << 2;
+    }
+    let mode = match BC6H_MODES.iter().find(|mode| mode.bits == mode_bits) {
+        Some(mode) => mode,
+        // Reserved modes decode to black
+        None => return [Vec3::ZERO; 16],
+    };
+
+    // w, x, y and z, each as red, green and blue
+    let mut endpoints = [[0i32; 3]; 4];
+    let mut partition = 0;
+    for &(field, a, b) in mode.layout {
+        let step = if a >= b { 1 } else { -1 };
+        let mut bit = b as i32;
+        loop {
+            let val = (reader.read(1) as i32) << bit;
+            match field {
+                D => partition |= val as usize,
+                _ => endpoints[field as usize / 3][field as usize % 3] |= val,
+            }
+            if bit == a as i32 {
+                break;
+            }
+            bit += step;
+        }
+    }
+
+    let endpoint_count = if mode.two_subsets { 4 } else { 2 };
+    let epb = mode.endpoint_bits;
+    for c in 0..3 {
+        if signed {
+            endpoints[0][c] = sign_extend(endpoints[0][c], epb);
+        }
+        for endpoint in endpoints.iter_mut().take(endpoint_count).skip(1) {
+            if mode.transformed || signed {
+                endpoint[c] = sign_extend(endpoint[c], mode.delta_bits[c]);
+            }
+        }
+    }
+    if mode.transformed {
+        let base = endpoints[0];
+        for endpoint in endpoints.iter_mut().take(endpoint_count).skip(1) {
+            for c in 0..3 {
+                endpoint[c] = (base[c] + endpoint[c]) & ((1 << epb) - 1);
+                if signed {
+                    endpoint[c] = sign_extend(endpoint[c], epb);
+                }
+            }
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(endpoint_count) {
+        for val in endpoint.iter_mut() {
+            *val = unquantize(*val, epb, signed);
+        }
+    }
+
+    let mut texels = [Vec3::ZERO; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        let (subset, weight) = if mode.two_subsets {
+            let anchor = i == 0 || i == BC6H_ANCHORS[partition];
+            let index = reader.read(if anchor { 2 } else { 3 });
+            (
+                BC6H_PARTITIONS[partition][i] as usize,
+                BC6H_WEIGHTS3[index as usize],
+            )
+        } else {
+            let index = reader.read(if i == 0 { 3 } else { 4 });
+            (0, BC6H_WEIGHTS4[index as usize])
+        };
+        let (e0, e1) = (endpoints[subset * 2], endpoints[subset * 2 + 1]);
+        let channel = |c: usize| {
+            finish_unquantize(((64 - weight) * e0[c] + weight * e1[c] + 32) >> 6, signed)
+        };
+        *texel = Vec3::new(channel(0), channel(1), channel(2));
+    }
+    texels
+}
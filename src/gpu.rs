@@ -0,0 +1,197 @@
+// Tone mapping on the GPU with a wgpu compute shader, for --gpu.
+// Only the per-pixel exposure, tone map and color map run here;
+// histograms, levels and output mapping stay on the CPU.
+
+use std::sync::mpsc;
+
+use wgpu::util::DeviceExt;
+
+use crate::LocalError::*;
+use crate::{Options, PixelBuffer, PixelFormat, Primaries, Result, Settings};
+
+// Pixels per invocation of the shader's workgroup
+const WORKGROUP_SIZE: usize = 64;
+
+// wgpu limits each dispatch dimension to 65535 workgroups
+const MAX_WORKGROUPS: usize = 65535;
+
+// HDRFloat32 pixels are four f32s, which the shader reads as vec4<f32>
+const BYTES_PER_PIXEL: usize = 16;
+
+// Tone maps the shader implements; the oklab ones stay on the CPU.
+fn tone_map_index(name: &str) -> Option<u32> {
+    match name {
+        "linear" => Some(0),
+        "reinhard-rgb" => Some(1),
+        "reinhard-luminance" => Some(2),
+        "aces" => Some(3),
+        "uncharted2" => Some(4),
+        "hable" => Some(5),
+        _ => None,
+    }
+}
+
+// The shader's Params uniform. A mat3x3 has its columns padded to vec4.
+fn params_bytes(options: &Options, tone_map: u32) -> Vec<u8> {
+    let mut params = Vec::with_capacity(64);
+    for column in options.channel_mix.to_cols_array_2d().iter() {
+        for val in column.iter().chain(&[0.0]) {
+            params.extend_from_slice(&val.to_ne_bytes());
+        }
+    }
+    for val in &[options.scale, options.hdr_max, options.saturation] {
+        params.extend_from_slice(&val.to_ne_bytes());
+    }
+    params.extend_from_slice(&tone_map.to_ne_bytes());
+    params
+}
+
+/// Run `hdr_to_sdr_pixel()` over a whole HDRFloat32 buffer on the GPU.
+/// Returns false, having done nothing, if the settings need a CPU-only
+/// tone or color map or there's no GPU to use, so the caller falls back.
+pub(crate) fn hdr_to_sdr(
+    source: &PixelBuffer,
+    dest: &mut PixelBuffer,
+    settings: &Settings,
+    options: &Options,
+) -> Result<bool> {
+    assert!(source.format == PixelFormat::HDRFloat32 && dest.format == PixelFormat::HDRFloat32);
+
+    // The chain as run, which --auto-skip-tonemap may have swapped out
+    let tone_map = match options.tone_map[..] {
+        [tone_map] if settings.color_map == "clip" => tone_map_index(tone_map.name),
+        _ => None,
+    };
+    let tone_map = match tone_map {
+        Some(index) => index,
+        None => {
+            eprintln!(
+                "WARNING: tone-map {} with color-map {} isn't supported on the GPU, using the CPU",
+                settings.tone_map, settings.color_map
+            );
+            return Ok(false);
+        }
+    };
+    // The shader has the default constants built in
+    if !options.tone_map_params.is_empty() {
+        eprintln!("WARNING: tone-map-param isn't supported on the GPU, using the CPU");
+        return Ok(false);
+    }
+    if settings.working_space != Primaries::Rec709 {
+        eprintln!("WARNING: working-space isn't supported on the GPU, using the CPU");
+        return Ok(false);
+    }
+    if options.shadows != 0.0 || options.highlights != 0.0 {
+        eprintln!("WARNING: shadows and highlights aren't supported on the GPU, using the CPU");
+        return Ok(false);
+    }
+
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = match pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        compatible_surface: None,
+        force_fallback_adapter: false,
+    })) {
+        Some(adapter) => adapter,
+        None => {
+            eprintln!("WARNING: no GPU adapter found, using the CPU");
+            return Ok(false);
+        }
+    };
+    let limits = adapter.limits();
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: Some("hdrfix"),
+            required_features: wgpu::Features::empty(),
+            required_limits: limits.clone(),
+        },
+        None,
+    ))
+    .map_err(|e| GpuError(e.to_string()))?;
+
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("tonemap.wgsl"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("tonemap.wgsl").into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("tone map"),
+        layout: None,
+        module: &shader,
+        entry_point: "main",
+    });
+    let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("params"),
+        contents: &params_bytes(options, tone_map),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    // Big images go through in chunks that fit the buffer and dispatch limits.
+    let chunk_pixels = (limits.max_storage_buffer_binding_size as usize / BYTES_PER_PIXEL)
+        .min(MAX_WORKGROUPS * WORKGROUP_SIZE);
+    let chunk_bytes = chunk_pixels * BYTES_PER_PIXEL;
+    for (input, output) in source
+        .bytes()
+        .chunks(chunk_bytes)
+        .zip(dest.bytes_mut().chunks_mut(chunk_bytes))
+    {
+        let size = input.len() as wgpu::BufferAddress;
+        let input_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("input"),
+            contents: input,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("output"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: input_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: output_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            let pixels = input.len() / BYTES_PER_PIXEL;
+            let workgroups = pixels.div_ceil(WORKGROUP_SIZE);
+            pass.dispatch_workgroups(workgroups as u32, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&output_buffer, 0, &readback_buffer, 0, size);
+        queue.submit(Some(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()?.map_err(|e| GpuError(e.to_string()))?;
+        output.copy_from_slice(&slice.get_mapped_range());
+        readback_buffer.unmap();
+    }
+    Ok(true)
+}
@@ -0,0 +1,6142 @@
+#![warn(clippy::all)]
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::num;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::RecvError;
+use std::sync::OnceLock;
+
+// Math bits
+use glam::f32::{Mat3, Vec2, Vec3};
+
+// Timing bits
+use time::OffsetDateTime;
+
+// Parallelism bits
+use rayon::prelude::*;
+
+// Error bits
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, LocalError>;
+
+// Color fun
+use oklab::{linear_srgb_to_oklab, oklab_to_linear_srgb, Oklab};
+
+// 16-bit floats
+use half::prelude::*;
+
+mod dds;
+mod lut;
+pub use lut::Lut3d;
+
+#[cfg(feature = "gpu")]
+mod gpu;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Level {
+    Scalar(f32),
+    Percentile(f32),
+    /// Luminance of the original content, like "5nits"
+    Nits(f32),
+    /// Where the bright tail of the image's luma distribution begins
+    Content,
+}
+
+impl Level {
+    /// Parse a level for the named option, like "levels-max", which is
+    /// given in errors. Percentiles must be from 0 to 100%.
+    pub fn with_str(option: &'static str, source: &str) -> Result<Self> {
+        let source = source.trim();
+        let number = |val: &str| match val.trim().parse::<f32>() {
+            Ok(val) if val.is_finite() => Ok(val),
+            _ => Err(InvalidLevel(source.to_string())),
+        };
+        let level = if let Some(val) = source.strip_suffix('%') {
+            Self::Percentile(number(val)?)
+        } else if let Some(val) = source.strip_suffix("nits") {
+            Self::Nits(number(val)?)
+        } else if source == "content" {
+            Self::Content
+        } else {
+            Self::Scalar(number(source)?)
+        };
+        match level {
+            Self::Percentile(val) if !(0.0..=100.0).contains(&val) => Err(LevelOutOfRange(
+                option,
+                source.to_string(),
+                "percentiles must be from 0 to 100%",
+            )),
+            _ => Ok(level),
+        }
+    }
+
+    /// Like `with_str()`, for options where a negative level makes no
+    /// sense, such as a white point.
+    pub fn non_negative(option: &'static str, source: &str) -> Result<Self> {
+        match Self::with_str(option, source)? {
+            Self::Scalar(val) | Self::Nits(val) if val < 0.0 => Err(LevelOutOfRange(
+                option,
+                source.trim().to_string(),
+                "it can't be negative",
+            )),
+            level => Ok(level),
+        }
+    }
+}
+
+/// What to do when an output file already exists.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OverwritePolicy {
+    /// Fail with an `OutputExists` error, so nothing is lost by accident.
+    Error,
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file alone and move on.
+    Skip,
+}
+
+/// Bits per channel of the output image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputDepth {
+    Eight,
+    Sixteen,
+    /// 16 bits if the image has smooth gradients that would band
+    /// at 8 bits, otherwise 8. JPEG output is always 8 bits.
+    Auto,
+}
+
+impl OutputDepth {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "8" => Ok(Self::Eight),
+            "16" => Ok(Self::Sixteen),
+            "auto" => Ok(Self::Auto),
+            _ => Err(InvalidOutputDepth(source.to_string())),
+        }
+    }
+}
+
+/// How SDR output rounds to whole sample values.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Quantize {
+    /// Round down, as versions before --quantize did
+    Truncate,
+    /// Round to the nearest value
+    Round,
+    /// Ordered dithering, trading banding for fine noise
+    Dither,
+}
+
+impl Quantize {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "truncate" => Ok(Self::Truncate),
+            "round" => Ok(Self::Round),
+            "dither" => Ok(Self::Dither),
+            _ => Err(InvalidQuantize(source.to_string())),
+        }
+    }
+
+    // Added to the scaled value before truncating it
+    fn offset(self, x: usize, y: usize) -> f32 {
+        // 4x4 Bayer matrix
+        const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+        match self {
+            Quantize::Truncate => 0.0,
+            Quantize::Round => 0.5,
+            Quantize::Dither => (BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0,
+        }
+    }
+}
+
+/// Automatic white balance, estimating the color of the light from the image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WhiteBalance {
+    Off,
+    /// Take the average color of the image to be gray
+    GrayWorld,
+    /// Take the brightest near-neutral colors to be white
+    WhitePatch,
+}
+
+impl WhiteBalance {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "off" => Ok(Self::Off),
+            "grayworld" => Ok(Self::GrayWorld),
+            "whitepatch" => Ok(Self::WhitePatch),
+            _ => Err(InvalidWhiteBalance(source.to_string())),
+        }
+    }
+}
+
+/// Which part of the frame the input histogram for auto exposure and
+/// detected white points is taken from, like a camera's metering modes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Metering {
+    /// The whole frame, every pixel counting the same
+    Average,
+    /// The whole frame, with pixels counting more toward the middle
+    Center,
+    /// Only a small region in the middle
+    Spot,
+}
+
+impl Metering {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "average" => Ok(Self::Average),
+            "center" => Ok(Self::Center),
+            "spot" => Ok(Self::Spot),
+            _ => Err(InvalidMetering(source.to_string())),
+        }
+    }
+}
+
+/// Input file format, normally picked by the file extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputFormat {
+    Jxr,
+    Png,
+    /// OpenEXR, taken as linear scRGB
+    Exr,
+    /// Radiance RGBE, taken as linear scRGB
+    Hdr,
+    /// DirectDraw Surface texture, BC6H or float, taken as linear scRGB
+    Dds,
+    /// Headerless little-endian f32 scRGB, RGB or RGBA, of this width and height
+    Raw(usize, usize),
+}
+
+impl InputFormat {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "jxr" => Ok(Self::Jxr),
+            "png" => Ok(Self::Png),
+            "exr" => Ok(Self::Exr),
+            "hdr" => Ok(Self::Hdr),
+            "dds" => Ok(Self::Dds),
+            _ => Err(InvalidInputFormat(source.to_string())),
+        }
+    }
+
+    // The forced format if there is one, otherwise the file's extension.
+    // Standard input has no extension, so must have the format forced.
+    fn of(filename: &Path, forced: Option<InputFormat>) -> Result<Self> {
+        match forced {
+            Some(format) => Ok(format),
+            None if is_stdio(filename) => Err(StdinNeedsInputFormat),
+            None => match extension(filename) {
+                Some(ext) => Self::with_str(&ext).map_err(|_| InvalidInputFile),
+                None => Err(InvalidInputFile),
+            },
+        }
+    }
+}
+
+/// Output file format, normally picked by the file extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    /// OpenEXR, keeping HDR as linear scRGB
+    Exr,
+}
+
+impl OutputFormat {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "png" => Ok(Self::Png),
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "exr" => Ok(Self::Exr),
+            _ => Err(InvalidOutputFormat(source.to_string())),
+        }
+    }
+
+    // The forced format if there is one, otherwise the file's extension.
+    // Standard output has no extension to go by, so gets PNG.
+    fn of(filename: &Path, forced: Option<OutputFormat>) -> Result<Self> {
+        match forced {
+            Some(format) => Ok(format),
+            None if is_stdio(filename) => Ok(Self::Png),
+            None => match extension(filename) {
+                Some(ext) => Self::with_str(&ext).map_err(|_| InvalidOutputFile),
+                None => Err(InvalidOutputFile),
+            },
+        }
+    }
+}
+
+/// Filename that stands for standard input as the input, reading the
+/// encoded image from it, or standard output as the output.
+pub const STDIO_FILENAME: &str = "-";
+
+fn is_stdio(filename: &Path) -> bool {
+    filename == Path::new(STDIO_FILENAME)
+}
+
+// Decoders that look at the input more than once, such as for PNG
+// metadata, can't re-read a pipe, so standard input is kept in memory.
+static STDIN_BYTES: OnceLock<Vec<u8>> = OnceLock::new();
+
+// Read and Seek in one trait, so one boxed type covers files and
+// buffered standard input for all the decoders.
+pub(crate) trait InputReader: Read + Seek {}
+
+impl<R: Read + Seek> InputReader for R {}
+
+// Open the input file for reading, or standard input for "-".
+pub(crate) fn open_input(filename: &Path) -> Result<Box<dyn InputReader>> {
+    if !is_stdio(filename) {
+        return Ok(Box::new(File::open(filename)?));
+    }
+    let bytes = match STDIN_BYTES.get() {
+        Some(bytes) => bytes,
+        None => {
+            let mut bytes = Vec::new();
+            io::stdin().lock().read_to_end(&mut bytes)?;
+            STDIN_BYTES.get_or_init(|| bytes)
+        }
+    };
+    Ok(Box::new(io::Cursor::new(bytes.as_slice())))
+}
+
+// Open the output file for writing, or standard output for "-".
+fn create_output(filename: &Path) -> Result<Box<dyn Write>> {
+    if is_stdio(filename) {
+        Ok(Box::new(io::BufWriter::new(io::stdout())))
+    } else {
+        Ok(Box::new(File::create(filename)?))
+    }
+}
+
+/// What to do with the alpha channel of JPEG XR input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AlphaMode {
+    /// Write RGBA PNG output if the image has any transparency.
+    /// A `background` color flattens instead.
+    Keep,
+    /// Ignore alpha, writing RGB output.
+    Strip,
+    /// Composite over the `background` color, black if none is given.
+    Flatten,
+}
+
+impl AlphaMode {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            "flatten" => Ok(Self::Flatten),
+            _ => Err(InvalidAlphaMode(source.to_string())),
+        }
+    }
+}
+
+/// Transfer function SDR output is encoded with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputTransfer {
+    Srgb,
+    /// BT.1886, the pure 2.4 gamma of BT.709 video displays
+    Bt1886,
+    Gamma22,
+}
+
+impl OutputTransfer {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "srgb" => Ok(Self::Srgb),
+            "bt1886" => Ok(Self::Bt1886),
+            "gamma22" => Ok(Self::Gamma22),
+            _ => Err(InvalidOutputTransfer(source.to_string())),
+        }
+    }
+
+    fn encode(self, val: Vec3) -> Vec3 {
+        match self {
+            OutputTransfer::Srgb => linear_to_srgb(val),
+            OutputTransfer::Bt1886 => val.max(Vec3::ZERO).powf(1.0 / 2.4),
+            OutputTransfer::Gamma22 => val.max(Vec3::ZERO).powf(1.0 / 2.2),
+        }
+    }
+}
+
+/// Lossless compression for EXR output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    Zip,
+    Piz,
+}
+
+impl ExrCompression {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "none" => Ok(Self::None),
+            "zip" => Ok(Self::Zip),
+            "piz" => Ok(Self::Piz),
+            _ => Err(InvalidExrCompression(source.to_string())),
+        }
+    }
+}
+
+/// Filter for shrinking images, as for --tui and --sweep.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ResizeFilter {
+    /// Plain average of each block of pixels
+    Box,
+    Bilinear,
+    /// Sharpest, for photographic content, but can ring at hard edges
+    Lanczos3,
+    /// Mitchell-Netravali cubic, between bilinear and Lanczos
+    Mitchell,
+}
+
+impl ResizeFilter {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "box" => Ok(Self::Box),
+            "bilinear" => Ok(Self::Bilinear),
+            "lanczos3" => Ok(Self::Lanczos3),
+            "mitchell" => Ok(Self::Mitchell),
+            _ => Err(InvalidResizeFilter(source.to_string())),
+        }
+    }
+
+    // How far the kernel reaches either side, in source pixels at 1:1
+    fn support(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Bilinear => 1.0,
+            Self::Lanczos3 => 3.0,
+            Self::Mitchell => 2.0,
+        }
+    }
+
+    fn kernel(self, x: f32) -> f32 {
+        let sinc = |x: f32| {
+            if x == 0.0 {
+                1.0
+            } else {
+                (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+            }
+        };
+        let x_abs = x.abs();
+        match self {
+            // Half open so neighboring blocks don't share a pixel
+            Self::Box if (-0.5..0.5).contains(&x) => 1.0,
+            Self::Bilinear if x_abs < 1.0 => 1.0 - x_abs,
+            Self::Lanczos3 if x_abs < 3.0 => sinc(x) * sinc(x / 3.0),
+            // B = C = 1/3
+            Self::Mitchell if x_abs < 1.0 => {
+                (7.0 * x_abs.powi(3) - 12.0 * x_abs.powi(2) + 16.0 / 3.0) / 6.0
+            }
+            Self::Mitchell if x_abs < 2.0 => {
+                (-7.0 / 3.0 * x_abs.powi(3) + 12.0 * x_abs.powi(2) - 20.0 * x_abs + 32.0 / 3.0)
+                    / 6.0
+            }
+            _ => 0.0,
+        }
+    }
+
+    // For each output pixel along one axis, the first source pixel it
+    // takes from and the normalized weights of those it takes.
+    fn weights(self, source_len: usize, dest_len: usize, factor: usize) -> Vec<(usize, Vec<f32>)> {
+        let scale = factor as f32;
+        let reach = self.support() * scale;
+        (0..dest_len)
+            .map(|i| {
+                let center = (i as f32 + 0.5) * scale;
+                let start = (center - reach).floor().max(0.0) as usize;
+                let end = ((center + reach).ceil() as usize).min(source_len);
+                let weights = (start..end)
+                    .map(|j| self.kernel((j as f32 + 0.5 - center) / scale))
+                    .collect::<Vec<f32>>();
+                let sum: f32 = weights.iter().sum();
+                (start, weights.iter().map(|w| w / sum).collect())
+            })
+            .collect()
+    }
+}
+
+/// How --grayscale collapses the output to gray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Grayscale {
+    /// Oklab lightness, rendered as neutral gray
+    Lightness,
+    /// Weighted sum of linear red, green and blue
+    Mix(Vec3),
+}
+
+/// Tints --split-tone adds to the shadows and highlights, as oklab a,b
+/// per unit of lightness, and the lightness where the two weigh the same.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SplitTone {
+    pub shadow: Vec2,
+    pub highlight: Vec2,
+    pub balance: f32,
+}
+
+impl Default for SplitTone {
+    fn default() -> Self {
+        SplitTone {
+            shadow: Vec2::ZERO,
+            highlight: Vec2::ZERO,
+            balance: 0.5,
+        }
+    }
+}
+
+/// How to treat NaN and infinite values in float input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sanitize {
+    /// Leave them alone.
+    Off,
+    /// Replace them with 0.
+    Zero,
+    /// Replace positive infinity with the brightest representable value,
+    /// and NaN or negative infinity with 0.
+    Clamp,
+}
+
+impl Sanitize {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "off" => Ok(Self::Off),
+            "zero" => Ok(Self::Zero),
+            "clamp" => Ok(Self::Clamp),
+            _ => Err(InvalidSanitize(source.to_string())),
+        }
+    }
+}
+
+/// Conversion parameters for `hdrfix()`.
+/// The defaults match the command-line defaults.
+#[derive(Clone)]
+pub struct Settings {
+    pub input_format: Option<InputFormat>,
+    pub output_format: Option<OutputFormat>,
+    pub sanitize: Sanitize,
+    pub background: Option<Vec3>,
+    pub alpha: AlphaMode,
+    pub trim_black: Option<f32>,
+    /// Center-crop the input to this width:height ratio
+    pub aspect: Option<(usize, usize)>,
+    pub input_transfer: Option<Transfer>,
+    pub detect_input_transfer: bool,
+    pub input_primaries: Option<Primaries>,
+    /// Multiplier on the decoded input, for captures stored at another scale
+    pub input_scale: f32,
+    pub auto_wb: WhiteBalance,
+    pub auto_exposure: Level,
+    pub exposure: f32,
+    pub exposure_bracket: Option<Vec<f32>>,
+    /// Split the output into a grid of this many rows and columns of files
+    pub tile_output: Option<(usize, usize)>,
+    pub sweep: Option<(String, Vec<f32>)>,
+    pub channel_mix: Mat3,
+    /// Stops added to `exposure` for red, green and blue
+    pub channel_exposure: Vec3,
+    pub shadows: f32,
+    pub highlights: f32,
+    pub working_space: Primaries,
+    pub tone_map: String,
+    pub tone_map_params: HashMap<String, f32>,
+    pub hdr_max: Level,
+    pub hdr_max_clip: f32,
+    pub roi: Option<(usize, usize, usize, usize)>,
+    pub saturation: f32,
+    pub auto_saturation: bool,
+    pub vibrance: f32,
+    pub grain: f32,
+    pub seed: u64,
+    /// Share of the pixels, above 0 and at most 1, to build the input
+    /// histogram from, picked at random with `seed`. All of them if None.
+    pub histogram_sample: Option<f32>,
+    pub metering: Metering,
+    pub max_chroma: Option<f32>,
+    pub color_map: String,
+    pub lut: Option<Lut3d>,
+    pub grayscale: Option<Grayscale>,
+    pub split_tone: SplitTone,
+    pub pre_gamma: f32,
+    pub pre_levels_min: Level,
+    pub pre_levels_max: Level,
+    pub post_gamma: f32,
+    pub post_levels_min: Level,
+    pub post_levels_max: Level,
+    pub auto_levels: Option<f32>,
+    /// Leave out the tone map when the exposed input never goes past SDR white
+    pub auto_skip_tone_map: bool,
+    /// Gaussian sigma in pixels for blurring the tone mapped oklab chroma,
+    /// 0 for none
+    pub denoise_chroma: f32,
+    pub output_depth: OutputDepth,
+    pub quantize: Quantize,
+    /// Write an indexed PNG with a palette of this many colors, 2 to 256
+    pub palette: Option<usize>,
+    pub output_transfer: OutputTransfer,
+    pub exr_compression: ExrCompression,
+    pub keep_hdr: bool,
+    pub export_curve: Option<PathBuf>,
+    pub linearize_only: bool,
+    pub inverse: bool,
+    pub add_metadata: Vec<(String, String)>,
+    /// Dots per inch to record in a pHYs chunk of PNG output
+    pub dpi: Option<f32>,
+    pub split_compare: bool,
+    pub show_oog: bool,
+    pub oog_mask: Option<PathBuf>,
+    pub color_map_diff: Option<PathBuf>,
+    pub false_color: bool,
+    pub warn_clip_above: Option<f32>,
+    pub clip_report: bool,
+    pub ignore_hue_shift_warning: bool,
+    pub low_memory: bool,
+    pub best_effort: bool,
+    /// Fail on NaN, out of range values and mismatched metadata in the
+    /// input instead of fixing them up with a warning
+    pub strict: bool,
+    pub resize_filter: ResizeFilter,
+    pub fast: bool,
+    pub gpu: bool,
+    pub overwrite: OverwritePolicy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            input_format: None,
+            output_format: None,
+            sanitize: Sanitize::Zero,
+            background: None,
+            alpha: AlphaMode::Keep,
+            trim_black: None,
+            aspect: None,
+            input_transfer: None,
+            detect_input_transfer: false,
+            input_primaries: None,
+            input_scale: 1.0,
+            auto_wb: WhiteBalance::Off,
+            auto_exposure: Level::Scalar(0.5),
+            exposure: 0.0,
+            exposure_bracket: None,
+            tile_output: None,
+            sweep: None,
+            channel_mix: Mat3::IDENTITY,
+            channel_exposure: Vec3::ZERO,
+            shadows: 0.0,
+            highlights: 0.0,
+            working_space: Primaries::Rec709,
+            tone_map: "hable".to_string(),
+            tone_map_params: HashMap::new(),
+            hdr_max: Level::Percentile(100.0),
+            hdr_max_clip: 0.01,
+            roi: None,
+            saturation: 1.0,
+            auto_saturation: false,
+            vibrance: 1.0,
+            grain: 0.0,
+            seed: 0,
+            histogram_sample: None,
+            metering: Metering::Average,
+            max_chroma: None,
+            color_map: "clip".to_string(),
+            lut: None,
+            grayscale: None,
+            split_tone: SplitTone::default(),
+            pre_gamma: 1.0,
+            pre_levels_min: Level::Scalar(0.0),
+            pre_levels_max: Level::Scalar(1.0),
+            post_gamma: 1.0,
+            post_levels_min: Level::Scalar(0.0),
+            post_levels_max: Level::Scalar(1.0),
+            auto_levels: None,
+            auto_skip_tone_map: false,
+            denoise_chroma: 0.0,
+            output_depth: OutputDepth::Eight,
+            quantize: Quantize::Round,
+            palette: None,
+            output_transfer: OutputTransfer::Srgb,
+            exr_compression: ExrCompression::Zip,
+            keep_hdr: false,
+            export_curve: None,
+            linearize_only: false,
+            inverse: false,
+            add_metadata: Vec::new(),
+            dpi: None,
+            split_compare: false,
+            show_oog: false,
+            oog_mask: None,
+            color_map_diff: None,
+            false_color: false,
+            warn_clip_above: None,
+            clip_report: false,
+            ignore_hue_shift_warning: false,
+            low_memory: false,
+            best_effort: false,
+            strict: false,
+            resize_filter: ResizeFilter::Lanczos3,
+            fast: false,
+            gpu: false,
+            overwrite: OverwritePolicy::Error,
+        }
+    }
+}
+
+type ToneMapFn = fn(Vec3, &Options) -> Vec3;
+type ColorMapFn = fn(Vec3) -> Vec3;
+type OklabFn = fn(Vec3) -> Oklab;
+
+struct Options {
+    scale: f32,
+    channel_mix: Mat3,
+    shadows: f32,
+    highlights: f32,
+    hdr_max: f32,
+    saturation: f32,
+    vibrance: f32,
+    max_chroma: Option<f32>,
+    to_working_space: Mat3,
+    from_working_space: Mat3,
+    luma: Vec3,
+    tone_map: Vec<&'static ToneMap>,
+    tone_map_params: HashMap<String, f32>,
+    // The .cube file given as --tone-map lut:FILE
+    tone_map_lut: Option<Lut3d>,
+    // White points across the image for adaptive-tiles on its own
+    tile_whites: Option<TileWhites>,
+    color_map: ColorMapFn,
+    // scrgb_to_oklab(), or the approximation for --fast previews
+    oklab: OklabFn,
+}
+
+impl Options {
+    // A constant given with --tone-map-param, or the tone map's default
+    fn param(&self, key: &str, default: f32) -> f32 {
+        self.tone_map_params.get(key).copied().unwrap_or(default)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    SDR8bit,
+    SDR16bit,
+    HDR8bit,
+    HDR16bit,
+    HLG8bit,
+    HLG16bit,
+    HDRFloat16,
+    HDRFloat32,
+    /// Half float scRGB in a 16-bit PNG, see `FLOAT_PNG_KEYWORD`
+    HDRFloat16PNG,
+}
+use PixelFormat::*;
+
+/// Ancillary PNG metadata, carried through from the input to the output.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// Keyword and text pairs from tEXt, zTXt and iTXt chunks
+    pub text: Vec<(String, String)>,
+    /// Raw contents of an eXIf chunk
+    pub exif: Option<Vec<u8>>,
+    /// Print resolution for a pHYs chunk, only ever set by --dpi
+    pub dpi: Option<f32>,
+}
+
+impl Metadata {
+    fn read_png(filename: &Path, info: &png::Info) -> Result<Self> {
+        let latin1 = info.uncompressed_latin1_text.iter();
+        let compressed = info.compressed_latin1_text.iter();
+        let utf8 = info.utf8_text.iter();
+        let text = latin1
+            .map(|chunk| Some((chunk.keyword.clone(), chunk.text.clone())))
+            .chain(compressed.map(|chunk| Some((chunk.keyword.clone(), chunk.get_text().ok()?))))
+            .chain(utf8.map(|chunk| Some((chunk.keyword.clone(), chunk.get_text().ok()?))))
+            .flatten()
+            .filter(|(keyword, _)| keyword != FLOAT_PNG_KEYWORD)
+            .collect();
+        Ok(Metadata {
+            text,
+            exif: read_png_exif(filename)?,
+            dpi: None,
+        })
+    }
+
+    // Copy for the output, with the --add-metadata fields replacing any
+    // with the same keyword, and the --dpi.
+    fn for_output(&self, settings: &Settings) -> Self {
+        let mut merged = self.clone();
+        for (keyword, text) in &settings.add_metadata {
+            merged.text.retain(|(existing, _)| existing != keyword);
+            merged.text.push((keyword.clone(), text.clone()));
+        }
+        merged.dpi = settings.dpi;
+        merged
+    }
+}
+
+// Note: currently assumes stride == width
+#[derive(Clone)]
+pub struct PixelBuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    bytes_per_pixel: usize,
+    data: Vec<u8>,
+    metadata: Metadata,
+
+    // If we wanted these could be traits
+    // but we don't need that level of complexity
+    read_rgb_func: fn(&[u8]) -> Vec3,
+    write_rgb_func: fn(&mut [u8], Vec3),
+}
+
+impl PixelBuffer {
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        let bytes_per_pixel = match format {
+            SDR8bit | HDR8bit | HLG8bit => 3,
+            SDR16bit | HDR16bit | HLG16bit => 6,
+            HDRFloat16 => 8,
+            HDRFloat16PNG => 6,
+            HDRFloat32 => 16,
+        };
+        let read_rgb_func = match format {
+            SDR8bit => read_srgb_rgb24,
+            SDR16bit => read_srgb_rgb48,
+            HDR8bit => read_rec2100_rgb24,
+            HDR16bit => read_rec2100_rgb48,
+            HLG8bit => read_hlg_rgb24,
+            HLG16bit => read_hlg_rgb48,
+            HDRFloat16 => read_scrgb_rgb64half,
+            HDRFloat16PNG => read_scrgb_rgb48half,
+            HDRFloat32 => read_scrgb_rgb128float,
+        };
+        let write_rgb_func = match format {
+            SDR8bit => write_srgb_rgb24,
+            SDR16bit => write_srgb_rgb48,
+            HDR8bit => write_rec2100_rgb24,
+            HDR16bit => write_rec2100_rgb48,
+            HLG8bit | HLG16bit => write_hlg,
+            HDRFloat16 => write_scrgb_rgb64half,
+            HDRFloat16PNG => write_scrgb_rgb48half,
+            HDRFloat32 => write_scrgb_rgb128float,
+        };
+        let stride = width * bytes_per_pixel;
+        let size = stride * height;
+        let data = vec![0u8; size];
+
+        PixelBuffer {
+            width,
+            height,
+            format,
+            bytes_per_pixel,
+            data,
+            metadata: Metadata::default(),
+            read_rgb_func,
+            write_rgb_func,
+        }
+    }
+
+    // Color primaries the read function assumes the data is in
+    fn native_primaries(&self) -> Primaries {
+        match self.format {
+            HDR8bit | HDR16bit | HLG8bit | HLG16bit => Primaries::Rec2020,
+            SDR8bit | SDR16bit | HDRFloat16 | HDRFloat32 | HDRFloat16PNG => Primaries::Rec709,
+        }
+    }
+
+    // The linear part of the read function, from samples with the
+    // transfer function undone to scRGB
+    fn read_matrix(&self) -> Mat3 {
+        match self.format {
+            HDR8bit | HDR16bit | HLG8bit | HLG16bit => {
+                rec2100_matrix() * Mat3::from_diagonal(Vec3::splat(REC2100_MAX / SDR_WHITE))
+            }
+            SDR8bit | SDR16bit | HDRFloat16 | HDRFloat32 | HDRFloat16PNG => Mat3::IDENTITY,
+        }
+    }
+
+    // Re-interpret the samples as another format of the same size
+    fn reinterpret(&mut self, format: PixelFormat) {
+        let blank = PixelBuffer::new(0, 0, format);
+        assert_eq!(blank.bytes_per_pixel, self.bytes_per_pixel);
+        self.format = format;
+        self.read_rgb_func = blank.read_rgb_func;
+        self.write_rgb_func = blank.write_rgb_func;
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        let offset = (y * self.width + x) * self.bytes_per_pixel;
+        (self.read_rgb_func)(&self.data[offset..offset + self.bytes_per_pixel])
+    }
+
+    // Copy out a rectangle of the image, in the same format
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> PixelBuffer {
+        let mut dest = PixelBuffer::new(width, height, self.format);
+        let stride = self.width * self.bytes_per_pixel;
+        let row_bytes = width * self.bytes_per_pixel;
+        let offset = x * self.bytes_per_pixel;
+        for (dest, src) in dest
+            .data
+            .chunks_exact_mut(row_bytes)
+            .zip(self.data.chunks_exact(stride).skip(y))
+        {
+            dest.copy_from_slice(&src[offset..offset + row_bytes]);
+        }
+        dest.metadata = self.metadata.clone();
+        dest
+    }
+
+    // Replace the left half of the image with another of the same size
+    // and format, for side by side comparisons.
+    fn copy_left_half(&mut self, other: &PixelBuffer) {
+        let stride = self.width * self.bytes_per_pixel;
+        let half = self.width / 2 * self.bytes_per_pixel;
+        self.data
+            .par_chunks_mut(stride)
+            .zip(other.data.par_chunks(stride))
+            .for_each(|(dest, src)| dest[..half].copy_from_slice(&src[..half]));
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn par_iter(&self) -> impl IndexedParallelIterator<Item = &[u8]> {
+        self.data.par_chunks(self.bytes_per_pixel)
+    }
+
+    fn par_iter_mut(&mut self) -> impl IndexedParallelIterator<Item = &mut [u8]> {
+        self.data.par_chunks_mut(self.bytes_per_pixel)
+    }
+
+    fn pixels(&self) -> impl '_ + IndexedParallelIterator<Item = Vec3> {
+        self.par_iter().map(self.read_rgb_func)
+    }
+
+    fn fill<T>(&mut self, source: T)
+    where
+        T: IndexedParallelIterator<Item = Vec3>,
+    {
+        let write_rgb_func = self.write_rgb_func;
+        self.par_iter_mut()
+            .zip(source)
+            .for_each(|(dest, rgb)| write_rgb_func(dest, rgb))
+    }
+
+    // Like fill(), with a choice of transfer function and of how
+    // SDR formats round to whole values
+    fn fill_quantized<T>(&mut self, source: T, transfer: OutputTransfer, quantize: Quantize)
+    where
+        T: IndexedParallelIterator<Item = Vec3>,
+    {
+        let quantize_func = match self.format {
+            SDR8bit => quantize_rgb24,
+            SDR16bit => quantize_rgb48,
+            _ => return self.fill(source),
+        };
+        let width = self.width;
+        self.par_iter_mut()
+            .zip(source)
+            .enumerate()
+            .for_each(|(i, (dest, rgb))| {
+                let offset = quantize.offset(i % width, i / width);
+                quantize_func(dest, transfer.encode(rgb), offset)
+            })
+    }
+}
+
+fn rgb24_samples(data: &[u8]) -> Vec3 {
+    let scale = Vec3::splat(1.0 / 255.0);
+    Vec3::new(data[0] as f32, data[1] as f32, data[2] as f32) * scale
+}
+
+// 16-bit samples are big-endian, as PNG stores them
+fn rgb48_samples(data: &[u8]) -> Vec3 {
+    let scale = Vec3::splat(1.0 / 65535.0);
+    let sample = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]) as f32;
+    Vec3::new(sample(0), sample(2), sample(4)) * scale
+}
+
+fn read_srgb_rgb24(data: &[u8]) -> Vec3 {
+    srgb_to_linear(rgb24_samples(data))
+}
+
+fn write_srgb_rgb24(data: &mut [u8], val: Vec3) {
+    quantize_rgb24(data, linear_to_srgb(val), Quantize::Round.offset(0, 0));
+}
+
+// Gamma-encoded values truncate after adding `offset`, see `Quantize::offset()`
+fn quantize_rgb24(data: &mut [u8], gamma_out: Vec3, offset: f32) {
+    let clipped = clip(gamma_out);
+    let scaled = clipped * 255.0 + Vec3::splat(offset);
+    data[0] = scaled.x as u8;
+    data[1] = scaled.y as u8;
+    data[2] = scaled.z as u8;
+}
+
+fn read_srgb_rgb48(data: &[u8]) -> Vec3 {
+    srgb_to_linear(rgb48_samples(data))
+}
+
+fn write_srgb_rgb48(data: &mut [u8], val: Vec3) {
+    quantize_rgb48(data, linear_to_srgb(val), Quantize::Round.offset(0, 0));
+}
+
+// 16-bit samples are big-endian, as PNG stores them
+fn quantize_rgb48(data: &mut [u8], gamma_out: Vec3, offset: f32) {
+    let clipped = clip(gamma_out);
+    let scaled = clipped * 65535.0 + Vec3::splat(offset);
+    data[0..2].copy_from_slice(&(scaled.x as u16).to_be_bytes());
+    data[2..4].copy_from_slice(&(scaled.y as u16).to_be_bytes());
+    data[4..6].copy_from_slice(&(scaled.z as u16).to_be_bytes());
+}
+
+fn read_rec2100_rgb24(data: &[u8]) -> Vec3 {
+    let rgb_linear = pq_to_linear(rgb24_samples(data));
+    rec2100_to_scrgb(rgb_linear)
+}
+
+fn write_rec2100_rgb24(_data: &mut [u8], _rgb: Vec3) {
+    panic!("not yet implemented");
+}
+
+fn read_rec2100_rgb48(data: &[u8]) -> Vec3 {
+    let rgb_linear = pq_to_linear(rgb48_samples(data));
+    rec2100_to_scrgb(rgb_linear)
+}
+
+fn write_rec2100_rgb48(data: &mut [u8], rgb: Vec3) {
+    let signal = linear_to_pq(scrgb_to_rec2100(rgb));
+    quantize_rgb48(data, signal, Quantize::Round.offset(0, 0));
+}
+
+fn read_hlg_rgb24(data: &[u8]) -> Vec3 {
+    rec2100_to_scrgb(hlg_to_linear(rgb24_samples(data)))
+}
+
+fn read_hlg_rgb48(data: &[u8]) -> Vec3 {
+    rec2100_to_scrgb(hlg_to_linear(rgb48_samples(data)))
+}
+
+fn write_hlg(_data: &mut [u8], _rgb: Vec3) {
+    panic!("not yet implemented");
+}
+
+fn read_scrgb_rgb64half(data: &[u8]) -> Vec3 {
+    let data_ref_f16: &f16 = unsafe { std::mem::transmute(&data[0]) };
+    let data_f16 = unsafe { std::slice::from_raw_parts(data_ref_f16, data.len()) };
+    Vec3::new(
+        data_f16[0].to_f32(),
+        data_f16[1].to_f32(),
+        data_f16[2].to_f32(),
+    )
+}
+
+fn write_scrgb_rgb64half(data: &mut [u8], rgb: Vec3) {
+    let data_ref_f16: &mut f16 = unsafe { std::mem::transmute(&mut data[0]) };
+    let data_f16 = &mut unsafe { std::slice::from_raw_parts_mut(data_ref_f16, data.len()) };
+    data_f16[0] = f16::from_f32(rgb.x);
+    data_f16[1] = f16::from_f32(rgb.y);
+    data_f16[2] = f16::from_f32(rgb.z);
+}
+
+// PNG samples are big-endian
+fn read_scrgb_rgb48half(data: &[u8]) -> Vec3 {
+    let sample = |i: usize| f16::from_be_bytes([data[i], data[i + 1]]).to_f32();
+    Vec3::new(sample(0), sample(2), sample(4))
+}
+
+fn write_scrgb_rgb48half(data: &mut [u8], rgb: Vec3) {
+    data[0..2].copy_from_slice(&f16::from_f32(rgb.x).to_be_bytes());
+    data[2..4].copy_from_slice(&f16::from_f32(rgb.y).to_be_bytes());
+    data[4..6].copy_from_slice(&f16::from_f32(rgb.z).to_be_bytes());
+}
+
+fn read_scrgb_rgb128float(data: &[u8]) -> Vec3 {
+    let data_ref_f32: &f32 = unsafe { std::mem::transmute(&data[0]) };
+    let data_f32 = unsafe { std::slice::from_raw_parts(data_ref_f32, data.len()) };
+    Vec3::new(data_f32[0], data_f32[1], data_f32[2])
+}
+
+fn write_scrgb_rgb128float(data: &mut [u8], rgb: Vec3) {
+    let data_ref_f32: &mut f32 = unsafe { std::mem::transmute(&mut data[0]) };
+    let data_f32 = &mut unsafe { std::slice::from_raw_parts_mut(data_ref_f32, data.len()) };
+    data_f32[0] = rgb.x;
+    data_f32[1] = rgb.y;
+    data_f32[2] = rgb.z;
+}
+
+fn read_scrgb_alpha64half(data: &[u8]) -> f32 {
+    f16::from_ne_bytes([data[6], data[7]]).to_f32()
+}
+
+fn read_scrgb_alpha128float(data: &[u8]) -> f32 {
+    f32::from_ne_bytes([data[12], data[13], data[14], data[15]])
+}
+
+fn write_scrgb_alpha128float(data: &mut [u8], alpha: f32) {
+    data[12..16].copy_from_slice(&alpha.to_ne_bytes());
+}
+
+// Alpha reader for the formats that have one
+fn read_alpha_func(format: PixelFormat) -> Option<fn(&[u8]) -> f32> {
+    match format {
+        HDRFloat16 => Some(read_scrgb_alpha64half),
+        HDRFloat32 => Some(read_scrgb_alpha128float),
+        _ => None,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LocalError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("numeric format error: {0}")]
+    ParseFloatError(#[from] num::ParseFloatError),
+    #[error("PNG decoding error: {0}")]
+    PNGDecodingError(#[from] png::DecodingError),
+    #[error("PNG input must be in 8 or 16 bpc true color or grayscale, found {bit_depth:?} bpc {color_type:?} ({width}x{height})")]
+    PNGFormatError {
+        bit_depth: png::BitDepth,
+        color_type: png::ColorType,
+        width: u32,
+        height: u32,
+    },
+    #[error("JPEG XR decoding error: {0}")]
+    JXRError(#[from] jpegxr::JXRError),
+    #[error("Invalid input file type")]
+    InvalidInputFile,
+    #[error("Invalid output file type")]
+    InvalidOutputFile,
+    #[error("Unsupported JPEG XR pixel format {format:?} ({width}x{height})")]
+    UnsupportedPixelFormat {
+        format: jpegxr::PixelFormat,
+        width: i32,
+        height: i32,
+    },
+    #[error("Folder watch error")]
+    NotifyError(#[from] notify::Error),
+    #[error("Recv error")]
+    RecvError(#[from] RecvError),
+    #[error("Image format error")]
+    ImageError(#[from] image::ImageError),
+    #[error("DDS error: {0}")]
+    DDSError(String),
+    #[error("EXR error: {0}")]
+    ExrError(#[from] exr::error::Error),
+    #[error("JPEG write failure")]
+    JpegWriteFailure,
+    #[error("Unknown tone-map '{0}'")]
+    UnknownToneMap(String),
+    #[error("Unknown tone-map-param '{0}' for tone-map {1}")]
+    UnknownToneMapParam(String, String),
+    #[error("Tone map adaptive-tiles can't be chained with others, in '{0}'")]
+    AdaptiveTilesChain(String),
+    #[error("Invalid tone-map-param '{0}', expected key=value with a value in the key's range")]
+    InvalidToneMapParam(String),
+    #[error("Unknown color-map '{0}'")]
+    UnknownColorMap(String),
+    #[error("Invalid exposure bracket '{0}', expected start:stop:step")]
+    InvalidExposureBracket(String),
+    #[error("Invalid channel mix '{0}', expected nine comma-separated values")]
+    InvalidChannelMix(String),
+    #[error("Invalid grayscale mix '{0}', expected three comma-separated weights for red, green and blue with a positive sum")]
+    InvalidGrayscaleMix(String),
+    #[error("Invalid split tone '{0}', expected shadow=#rrggbb, highlight=#rrggbb and balance between 0 and 1, separated by commas")]
+    InvalidSplitTone(String),
+    #[error("Benchmark iterations must be a positive whole number")]
+    InvalidIterations,
+    #[error("File concurrency must be a positive whole number")]
+    InvalidFileConcurrency,
+    #[error("TUI preview width must be a positive whole number")]
+    InvalidTuiWidth,
+    #[error("Invalid seed '{0}', expected a whole number")]
+    InvalidSeed(String),
+    #[error(
+        "Invalid histogram sample '{0}', expected a fraction of the pixels above 0 and at most 1"
+    )]
+    InvalidHistogramSample(String),
+    #[error("Invalid EXR compression '{0}', expected none, zip or piz")]
+    InvalidExrCompression(String),
+    #[error("Invalid resize filter '{0}', expected box, bilinear, lanczos3 or mitchell")]
+    InvalidResizeFilter(String),
+    #[error("Invalid folder config '{0}': {1}")]
+    InvalidFolderConfig(String, String),
+    #[error("Invalid manifest '{0}': {1}")]
+    InvalidManifest(String, String),
+    #[error("Invalid .cube LUT: {0}")]
+    InvalidLut(String),
+    #[error("Invalid metadata '{0}', expected key=value with a 1-79 character Latin-1 key")]
+    InvalidMetadata(String),
+    #[error("Invalid DPI '{0}', expected a positive number")]
+    InvalidDpi(String),
+    #[error("Invalid sanitize mode '{0}', expected off, zero or clamp")]
+    InvalidSanitize(String),
+    #[error("Invalid level '{0}', expected a number, a percentile like 50%, a luminance like 200nits or content")]
+    InvalidLevel(String),
+    #[error("{0} '{1}' out of range, {2}")]
+    LevelOutOfRange(&'static str, String, &'static str),
+    #[error("Invalid exposure '{0}', expected stops as one value or three comma-separated for red, green and blue")]
+    InvalidExposure(String),
+    #[error("Invalid background '{0}', expected three comma-separated values")]
+    InvalidBackground(String),
+    #[error("Invalid region of interest '{0}', expected x,y,width,height")]
+    InvalidRoi(String),
+    #[error("Invalid aspect ratio '{0}', expected width:height like 16:9")]
+    InvalidAspect(String),
+    #[error("Invalid raw input size '{0}', expected WIDTHxHEIGHT like 1920x1080")]
+    InvalidRawSize(String),
+    #[error("Raw input is {0} bytes, expected {1} for RGB or {2} for RGBA float32")]
+    RawSizeMismatch(usize, usize, usize),
+    #[error("Invalid tile grid '{0}', expected rows,cols")]
+    InvalidTileGrid(String),
+    #[error("Can't split a {2}x{3} image into {0} rows of {1} tiles")]
+    TileGridTooFine(usize, usize, usize, usize),
+    #[error("--tile-output needs an output filename to name the tiles after")]
+    TileOutputToStdout,
+    #[error("Invalid sweep '{0}', expected a parameter and comma-separated values like hdr-max=200,400,800; parameters are exposure, hdr-max, saturation, vibrance, shadows, highlights and grain")]
+    InvalidSweep(String),
+    #[error("Region of interest {0:?} is outside the {1}x{2} image")]
+    RoiOutsideImage((usize, usize, usize, usize), usize, usize),
+    #[error("Invalid output template '{0}', placeholders are {{stem}}, {{ext}}, {{date}} and {{tonemap}}")]
+    InvalidOutputTemplate(String),
+    #[error("Invalid output depth '{0}', expected 8, 16 or auto")]
+    InvalidOutputDepth(String),
+    #[error("--linearize-only writes float data, so needs .exr output")]
+    LinearizeOnlyNeedsExr,
+    #[error("--inverse writes HDR, so needs .png (PQ) or .exr output")]
+    InverseNeedsHdrOutput,
+    #[error("Invalid quantize mode '{0}', expected truncate, round or dither")]
+    InvalidQuantize(String),
+    #[error("Invalid white balance mode '{0}', expected off, grayworld or whitepatch")]
+    InvalidWhiteBalance(String),
+    #[error("Invalid metering mode '{0}', expected average, center or spot")]
+    InvalidMetering(String),
+    #[error("Invalid output transfer '{0}', expected srgb, bt1886 or gamma22")]
+    InvalidOutputTransfer(String),
+    #[error("Invalid input transfer '{0}', expected pq, hlg or srgb")]
+    InvalidTransfer(String),
+    #[error("Invalid primaries '{0}', expected rec709, srgb, rec2020, rec2100 or p3")]
+    InvalidPrimaries(String),
+    #[error("Invalid alpha mode '{0}', expected keep, strip or flatten")]
+    InvalidAlphaMode(String),
+    #[error("Invalid input format '{0}', expected jxr, png, exr, hdr or dds")]
+    InvalidInputFormat(String),
+    #[error("Reading the input from standard input needs --input-format")]
+    StdinNeedsInputFormat,
+    #[error("Invalid output format '{0}', expected png, jpg or exr")]
+    InvalidOutputFormat(String),
+    #[error("{0:?} input can't be read in strips, so can't be used in low-memory mode")]
+    LowMemoryInput(InputFormat),
+    #[error("JPEG output only supports 8-bit depth")]
+    UnsupportedOutputDepth,
+    #[error("--palette takes 2 to 256 colors")]
+    InvalidPaletteSize,
+    #[error("--palette needs 8-bit PNG output without an alpha channel")]
+    PaletteNeedsPng,
+    #[error("--{0} needs the whole image, so can't be used in low-memory mode")]
+    LowMemoryUnsupported(&'static str),
+    #[error("--strict: {0} input values are NaN or infinite, use --sanitize instead of --strict to replace them")]
+    StrictNonFinite(usize),
+    #[error("--strict: {0} input values are beyond the 10000 nits HDR formats can hold")]
+    StrictOutOfRange(usize),
+    #[error("--strict: the PNG is tagged as {0} but would be read as PQ, pass --input-transfer to say which is right")]
+    StrictPngTransfer(&'static str),
+    #[error("--strict: nothing to estimate the white balance from")]
+    StrictNoWhiteBalance,
+    #[error("Output file '{0}' already exists, use --overwrite or --skip-existing")]
+    OutputExists(String),
+    #[error("GPU error: {0}")]
+    GpuError(String),
+    #[error("{0} self-test checks failed")]
+    SelfTestFailed(usize),
+    #[error("{0} of {1} input files failed validation")]
+    DryRunFailure(usize, usize),
+    #[error("{0:.2}% of pixels clipped, over the --warn-clip-above threshold of {1}%")]
+    ClipThresholdExceeded(f32, f32),
+    #[error("{0} of {1} files clipped over the --warn-clip-above threshold")]
+    ClipThresholdFiles(usize, usize),
+}
+use LocalError::*;
+
+thread_local! {
+    // While benchmarking, time_func() collects timings here instead of printing them.
+    static TIMINGS: RefCell<Option<Vec<(String, f64)>>> = const { RefCell::new(None) };
+}
+
+fn time_func<F, G>(msg: &str, func: F) -> Result<G>
+where
+    F: FnOnce() -> Result<G>,
+{
+    let start = OffsetDateTime::now_utc();
+    let result = func()?;
+    let delta = OffsetDateTime::now_utc() - start;
+    let ms = delta.as_seconds_f64() * 1000.0;
+    TIMINGS.with(|timings| match timings.borrow_mut().as_mut() {
+        Some(timings) => timings.push((msg.to_string(), ms)),
+        None => eprintln!("{} in {} ms", msg, ms),
+    });
+    Ok(result)
+}
+
+// PNG has no float samples, so linear HDR can be passed losslessly as
+// 16-bit RGB holding half float scRGB bit patterns instead, marked with
+// this tEXt chunk before the image data.
+pub const FLOAT_PNG_KEYWORD: &str = "hdrfix-sample-format";
+pub const FLOAT_PNG_FORMAT: &str = "scrgb-float16";
+
+// Check the PNG is a format we can read, and pick the buffer format for it
+fn check_png_format(info: &png::Info) -> Result<PixelFormat> {
+    let float = info
+        .uncompressed_latin1_text
+        .iter()
+        .any(|chunk| chunk.keyword == FLOAT_PNG_KEYWORD && chunk.text == FLOAT_PNG_FORMAT);
+    match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) if float => Ok(HDRFloat16PNG),
+        (png::ColorType::Rgb, png::BitDepth::Eight) => Ok(HDR8bit),
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) => Ok(HDR16bit),
+        // Replicated into RGB as it's read, see copy_png_samples()
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => Ok(HDR8bit),
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => Ok(HDR16bit),
+        (color_type, bit_depth) => Err(PNGFormatError {
+            bit_depth,
+            color_type,
+            width: info.width,
+            height: info.height,
+        }),
+    }
+}
+
+type PngLayout = (png::ColorType, png::BitDepth);
+
+fn png_layout(info: &png::Info) -> PngLayout {
+    (info.color_type, info.bit_depth)
+}
+
+// Copy decoded PNG samples into an RGB buffer, replicating the one
+// channel of grayscale PNG into red, green and blue.
+fn copy_png_samples(source: &[u8], dest: &mut [u8], (color_type, bit_depth): PngLayout) {
+    if color_type == png::ColorType::Grayscale {
+        let sample = bit_depth as usize / 8;
+        for (dest, gray) in dest
+            .chunks_exact_mut(sample * 3)
+            .zip(source.chunks_exact(sample))
+        {
+            for channel in dest.chunks_exact_mut(sample) {
+                channel.copy_from_slice(gray);
+            }
+        }
+    } else {
+        dest.copy_from_slice(source);
+    }
+}
+
+// For --strict, refuse 8- and 16-bit PNG tagged as sRGB or with a gamma
+// when nothing says how to read it, since it would be taken to be PQ.
+fn check_png_transfer(filename: &Path, settings: &Settings) -> Result<()> {
+    let by_default = settings.input_transfer.is_none() && !settings.detect_input_transfer;
+    if !settings.strict || settings.inverse || !by_default {
+        return Ok(());
+    }
+    let decoder = png::Decoder::new(open_input(filename)?);
+    let reader = decoder.read_info()?;
+    let info = reader.info();
+    if check_png_format(info)? == HDRFloat16PNG {
+        return Ok(());
+    }
+    if info.srgb.is_some() {
+        Err(StrictPngTransfer("sRGB"))
+    } else if info.source_gamma.is_some() {
+        Err(StrictPngTransfer("gamma encoded"))
+    } else {
+        Ok(())
+    }
+}
+
+// The png crate doesn't decode eXIf chunks, so find one ourselves.
+// It must come before the image data.
+fn read_png_exif(filename: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = io::BufReader::new(open_input(filename)?);
+    // Skip the PNG signature
+    file.seek_relative(8)?;
+    loop {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match &header[4..] {
+            b"eXIf" => {
+                let mut exif = vec![0u8; length as usize];
+                file.read_exact(&mut exif)?;
+                return Ok(Some(exif));
+            }
+            b"IDAT" | b"IEND" => return Ok(None),
+            // Skip the data and CRC
+            _ => file.seek_relative(i64::from(length) + 4)?,
+        }
+    }
+}
+
+// Read an input PNG and return its size and contents
+// It must be a certain format (8 or 16 bpc true color no alpha)
+fn read_png(filename: &Path) -> Result<PixelBuffer> {
+    use png::Decoder;
+    use png::Transformations;
+
+    let mut decoder = Decoder::new(open_input(filename)?);
+    decoder.set_transformations(Transformations::IDENTITY);
+
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    let format = check_png_format(info)?;
+
+    let mut buffer = PixelBuffer::new(info.width as usize, info.height as usize, format);
+    if info.color_type == png::ColorType::Grayscale {
+        let mut gray = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut gray)?;
+        copy_png_samples(&gray, buffer.bytes_mut(), png_layout(reader.info()));
+    } else {
+        reader.next_frame(buffer.bytes_mut())?;
+    }
+
+    // Text chunks may also come after the image data.
+    reader.finish()?;
+    buffer.metadata = Metadata::read_png(filename, reader.info())?;
+
+    Ok(buffer)
+}
+
+// Pick the buffer format for a JPEG XR's pixel format
+fn jxr_buffer_format<R>(decoder: &jpegxr::ImageDecode<R>) -> Result<(usize, PixelFormat)>
+where
+    R: io::Read + io::Seek,
+{
+    use jpegxr::PixelFormat::*;
+
+    match decoder.get_pixel_format()? {
+        PixelFormat128bppRGBAFloat => Ok((16, HDRFloat32)),
+        PixelFormat64bppRGBAHalf => Ok((8, HDRFloat16)),
+        format => {
+            let (width, height) = decoder.get_size()?;
+            Err(UnsupportedPixelFormat {
+                format,
+                width,
+                height,
+            })
+        }
+    }
+}
+
+fn read_jxr(filename: &Path) -> Result<PixelBuffer> {
+    use jpegxr::ImageDecode;
+    use jpegxr::Rect;
+
+    let input = open_input(filename)?;
+    let mut decoder = ImageDecode::with_reader(input)?;
+
+    let (width, height) = decoder.get_size()?;
+    let (bytes_per_pixel, buf_fmt) = jxr_buffer_format(&decoder)?;
+
+    let stride = width as usize * bytes_per_pixel;
+    let mut buffer = PixelBuffer::new(width as usize, height as usize, buf_fmt);
+
+    let rect = Rect::new(0, 0, width, height);
+    decoder.copy(&rect, buffer.bytes_mut(), stride)?;
+
+    Ok(buffer)
+}
+
+fn read_exr(filename: &Path) -> Result<PixelBuffer> {
+    use exr::prelude::*;
+
+    let image = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .rgba_channels(
+            |resolution, _| PixelBuffer::new(resolution.width(), resolution.height(), HDRFloat32),
+            // Alpha is 1 when the file has none
+            |buffer: &mut PixelBuffer, position, (r, g, b, a): (f32, f32, f32, f32)| {
+                let offset = (position.y() * buffer.width + position.x()) * buffer.bytes_per_pixel;
+                let pixel = &mut buffer.data[offset..offset + buffer.bytes_per_pixel];
+                write_scrgb_rgb128float(pixel, Vec3::new(r, g, b));
+                write_scrgb_alpha128float(pixel, a);
+            },
+        )
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(io::BufReader::new(open_input(filename)?))?;
+    Ok(image.layer_data.channel_data.pixels)
+}
+
+fn read_hdr(filename: &Path) -> Result<PixelBuffer> {
+    let input = io::BufReader::new(open_input(filename)?);
+    let decoder = image::codecs::hdr::HdrDecoder::new(input)?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+
+    let mut buffer = PixelBuffer::new(meta.width as usize, meta.height as usize, HDRFloat32);
+    buffer
+        .par_iter_mut()
+        .zip(pixels.par_iter())
+        .for_each(|(pixel, rgb)| {
+            write_scrgb_rgb128float(pixel, Vec3::from(rgb.0));
+            write_scrgb_alpha128float(pixel, 1.0);
+        });
+    Ok(buffer)
+}
+
+// Lower-cased file extension, used to pick a decoder or encoder.
+// Returns None rather than panicking when there is no extension, or when
+// it isn't valid Unicode, so callers can report an invalid file instead.
+fn extension(filename: &Path) -> Option<String> {
+    filename
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+/// Lowercase extensions of the input files `hdrfix()` reads, for file
+/// type filters. Matches the arms of `read_input()`.
+pub fn supported_input_extensions() -> &'static [&'static str] {
+    &["jxr", "png", "exr", "hdr", "dds"]
+}
+
+/// Lowercase extensions of the output files `hdrfix()` writes.
+/// Matches the arms of `tone_map_and_write()`.
+pub fn supported_output_extensions() -> &'static [&'static str] {
+    &["png", "jpg", "jpeg", "exr"]
+}
+
+// Parse just the headers of an input file, without decoding pixel data,
+// and return its size if it's in a format we can convert.
+pub fn probe_input(filename: &Path) -> Result<(usize, usize)> {
+    probe_input_as(filename, None)
+}
+
+/// Like `probe_input()`, but with the format forced as by `Settings::input_format`.
+pub fn probe_input_as(filename: &Path, format: Option<InputFormat>) -> Result<(usize, usize)> {
+    match InputFormat::of(filename, format)? {
+        InputFormat::Png => probe_png(filename),
+        InputFormat::Jxr => probe_jxr(filename),
+        InputFormat::Exr => probe_exr(filename),
+        InputFormat::Hdr => probe_hdr(filename),
+        InputFormat::Dds => dds::probe_dds(filename),
+        InputFormat::Raw(width, height) => Ok((width, height)),
+    }
+}
+
+// Raw input carries no header, so whether there's alpha is told by its length
+fn read_raw(filename: &Path, width: usize, height: usize) -> Result<PixelBuffer> {
+    let mut bytes = Vec::new();
+    open_input(filename)?.read_to_end(&mut bytes)?;
+    let channels = match bytes.len() {
+        len if len == width * height * 12 => 3,
+        len if len == width * height * 16 => 4,
+        len => {
+            return Err(RawSizeMismatch(
+                len,
+                width * height * 12,
+                width * height * 16,
+            ))
+        }
+    };
+    let mut buffer = PixelBuffer::new(width, height, HDRFloat32);
+    buffer
+        .par_iter_mut()
+        .zip(bytes.par_chunks_exact(channels * 4))
+        .for_each(|(pixel, samples)| {
+            let sample = |i: usize| {
+                f32::from_le_bytes([
+                    samples[i * 4],
+                    samples[i * 4 + 1],
+                    samples[i * 4 + 2],
+                    samples[i * 4 + 3],
+                ])
+            };
+            write_scrgb_rgb128float(pixel, Vec3::new(sample(0), sample(1), sample(2)));
+            write_scrgb_alpha128float(pixel, if channels == 4 { sample(3) } else { 1.0 });
+        });
+    Ok(buffer)
+}
+
+fn probe_png(filename: &Path) -> Result<(usize, usize)> {
+    let decoder = png::Decoder::new(open_input(filename)?);
+    let reader = decoder.read_info()?;
+    let info = reader.info();
+    check_png_format(info)?;
+    Ok((info.width as usize, info.height as usize))
+}
+
+fn probe_jxr(filename: &Path) -> Result<(usize, usize)> {
+    let decoder = jpegxr::ImageDecode::with_reader(open_input(filename)?)?;
+    jxr_buffer_format(&decoder)?;
+    let (width, height) = decoder.get_size()?;
+    Ok((width as usize, height as usize))
+}
+
+fn probe_exr(filename: &Path) -> Result<(usize, usize)> {
+    let input = io::BufReader::new(open_input(filename)?);
+    let meta = exr::meta::MetaData::read_from_buffered(input, false)?;
+    let size = meta.headers[0].layer_size;
+    Ok((size.width(), size.height()))
+}
+
+fn probe_hdr(filename: &Path) -> Result<(usize, usize)> {
+    let input = io::BufReader::new(open_input(filename)?);
+    let meta = image::codecs::hdr::HdrDecoder::new(input)?.metadata();
+    Ok((meta.width as usize, meta.height as usize))
+}
+
+fn pq_to_linear(val: Vec3) -> Vec3 {
+    // fixme make sure all the splats are efficient constants
+    let inv_m1: f32 = 1.0 / 0.15930176;
+    let inv_m2: f32 = 1.0 / 78.84375;
+    let c1 = Vec3::splat(0.8359375);
+    let c2 = Vec3::splat(18.851563);
+    let c3 = Vec3::splat(18.6875);
+    let val_powered = val.powf(inv_m2);
+    (Vec3::max(val_powered - c1, Vec3::ZERO) / (c2 - c3 * val_powered)).powf(inv_m1)
+}
+
+// Inverse of pq_to_linear(), for HDR output
+fn linear_to_pq(val: Vec3) -> Vec3 {
+    let m1 = 0.15930176;
+    let m2 = 78.84375;
+    let c1 = Vec3::splat(0.8359375);
+    let c2 = Vec3::splat(18.851563);
+    let c3 = Vec3::splat(18.6875);
+    let val_powered = val.max(Vec3::ZERO).powf(m1);
+    ((c1 + c2 * val_powered) / (Vec3::ONE + c3 * val_powered)).powf(m2)
+}
+
+// BT.2100 HLG as shown on its 1000 nit reference display
+fn hlg_to_linear(val: Vec3) -> Vec3 {
+    let a = 0.17883277;
+    let b = 0.28466892;
+    let c = 0.5599107;
+    let inverse_oetf = |e: f32| {
+        if e <= 0.5 {
+            e * e / 3.0
+        } else {
+            (((e - c) / a).exp() + b) / 12.0
+        }
+    };
+    let scene = Vec3::new(
+        inverse_oetf(val.x),
+        inverse_oetf(val.y),
+        inverse_oetf(val.z),
+    );
+    // The OOTF applies the system gamma to the scene luminance.
+    let luma = scene.dot(Vec3::new(0.2627, 0.6780, 0.0593));
+    scene * luma.powf(HLG_GAMMA - 1.0) * (HLG_PEAK / REC2100_MAX)
+}
+
+fn rec2100_matrix() -> Mat3 {
+    Mat3::from_cols_array(&[
+        1.6605, -0.1246, -0.0182, -0.5876, 1.1329, -0.1006, -0.0728, -0.0083, 1.1187,
+    ])
+}
+
+fn rec2100_to_scrgb(val: Vec3) -> Vec3 {
+    let scale = REC2100_MAX / SDR_WHITE;
+    rec2100_matrix().mul_vec3(val * scale)
+}
+
+fn scrgb_to_rec2100(val: Vec3) -> Vec3 {
+    let scale = SDR_WHITE / REC2100_MAX;
+    rec2100_matrix().inverse().mul_vec3(val) * scale
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primaries {
+    Rec709,
+    Rec2020,
+    DisplayP3,
+}
+
+impl Primaries {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "rec709" | "srgb" => Ok(Primaries::Rec709),
+            // BT.2100 uses the BT.2020 primaries
+            "rec2020" | "rec2100" => Ok(Primaries::Rec2020),
+            "p3" => Ok(Primaries::DisplayP3),
+            _ => Err(InvalidPrimaries(source.to_string())),
+        }
+    }
+
+    // CIE xy chromaticities of red, green, blue, and white
+    fn chromaticities(self) -> [(f32, f32); 4] {
+        const D65: (f32, f32) = (0.3127, 0.3290);
+        match self {
+            Primaries::Rec709 => [(0.640, 0.330), (0.300, 0.600), (0.150, 0.060), D65],
+            Primaries::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65],
+            Primaries::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060), D65],
+        }
+    }
+
+    // http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html
+    fn to_xyz(self) -> Mat3 {
+        let xy_to_xyz = |(x, y): (f32, f32)| Vec3::new(x / y, 1.0, (1.0 - x - y) / y);
+        let [red, green, blue, white] = self.chromaticities().map(xy_to_xyz);
+        let scale = Mat3::from_cols(red, green, blue).inverse().mul_vec3(white);
+        Mat3::from_cols(red * scale.x, green * scale.y, blue * scale.z)
+    }
+
+    // Convert linear RGB in these primaries to linear scRGB
+    fn to_scrgb(self) -> Mat3 {
+        Primaries::Rec709.to_xyz().inverse() * self.to_xyz()
+    }
+
+    // Weights of R, G and B in the luminance of a color in these primaries
+    fn luma_coefficients(self) -> Vec3 {
+        match self {
+            // The rounded values BT.709 and BT.2020 publish
+            Primaries::Rec709 => Vec3::new(0.2126, 0.7152, 0.0722),
+            Primaries::Rec2020 => Vec3::new(0.2627, 0.6780, 0.0593),
+            Primaries::DisplayP3 => {
+                let xyz = self.to_xyz();
+                Vec3::new(xyz.x_axis.y, xyz.y_axis.y, xyz.z_axis.y)
+            }
+        }
+    }
+}
+
+// Matrix to re-interpret linear scRGB data, decoded assuming `native` primaries,
+// as having actually been in the `actual` primaries.
+fn primaries_correction(native: Primaries, actual: Primaries) -> Mat3 {
+    if native == actual {
+        Mat3::IDENTITY
+    } else {
+        actual.to_scrgb() * native.to_scrgb().inverse()
+    }
+}
+
+// Bradford cone response, from XYZ to LMS
+fn bradford_matrix() -> Mat3 {
+    Mat3::from_cols_array(&[
+        0.8951, -0.7502, 0.0389, 0.2664, 1.7135, -0.0685, -0.1614, 0.0367, 1.0296,
+    ])
+}
+
+// Bradford chromatic adaptation of scRGB from a light of the given color
+// to D65, the white of scRGB. Luminance of the light is kept.
+fn bradford_adaptation(illuminant: Vec3) -> Mat3 {
+    let to_xyz = Primaries::Rec709.to_xyz();
+    let source = to_xyz.mul_vec3(illuminant);
+    let source = bradford_matrix().mul_vec3(source / source.y);
+    let white = bradford_matrix().mul_vec3(to_xyz.mul_vec3(Vec3::ONE));
+    to_xyz.inverse()
+        * bradford_matrix().inverse()
+        * Mat3::from_diagonal(white / source)
+        * bradford_matrix()
+        * to_xyz
+}
+
+// Share of the brightest near-neutral pixels --auto-wb whitepatch averages,
+// so a few hot pixels can't decide the white point
+const WHITE_PATCH_SHARE: f32 = 0.01;
+
+// Largest oklab chroma, relative to lightness, of a near-neutral color.
+// Generous, since a color cast moves the whites off neutral too.
+const NEUTRAL_CHROMA: f32 = 0.15;
+
+// Estimate the color of the light from the scRGB pixels, if there's
+// anything to go on.
+fn estimate_illuminant<T>(pixels: T, mode: WhiteBalance) -> Option<Vec3>
+where
+    T: IndexedParallelIterator<Item = Vec3>,
+{
+    let pixels = pixels.filter(|rgb| rgb.is_finite() && rgb.min_element() >= 0.0);
+    let illuminant = match mode {
+        WhiteBalance::Off => return None,
+        WhiteBalance::GrayWorld => pixels.reduce(|| Vec3::ZERO, |a, b| a + b),
+        WhiteBalance::WhitePatch => {
+            let mut neutral: Vec<(f32, Vec3)> = pixels
+                .filter_map(|rgb| {
+                    let lab = scrgb_to_oklab(rgb);
+                    let chroma = (lab.a * lab.a + lab.b * lab.b).sqrt();
+                    (lab.l > 0.0 && chroma <= NEUTRAL_CHROMA * lab.l).then_some((lab.l, rgb))
+                })
+                .collect();
+            neutral.par_sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+            let count = (neutral.len() as f32 * WHITE_PATCH_SHARE).ceil() as usize;
+            neutral[..count]
+                .iter()
+                .fold(Vec3::ZERO, |sum, &(_, rgb)| sum + rgb)
+        }
+    };
+    (illuminant.min_element() > 0.0).then_some(illuminant)
+}
+
+// Matrix neutralizing the cast --auto-wb finds, applied to the source
+// after the given primaries correction.
+fn auto_white_balance(
+    source: &PixelBuffer,
+    primaries_matrix: Mat3,
+    settings: &Settings,
+) -> Result<Mat3> {
+    if settings.auto_wb == WhiteBalance::Off {
+        return Ok(Mat3::IDENTITY);
+    }
+    let pixels = source.pixels().map(|rgb| primaries_matrix.mul_vec3(rgb));
+    Ok(match estimate_illuminant(pixels, settings.auto_wb) {
+        Some(illuminant) => {
+            let light = illuminant / illuminant.max_element();
+            eprintln!(
+                "Auto white balance: neutralizing light of color {:.3},{:.3},{:.3}",
+                light.x, light.y, light.z
+            );
+            bradford_adaptation(illuminant)
+        }
+        None if settings.strict => return Err(StrictNoWhiteBalance),
+        None => {
+            eprintln!("WARNING: nothing to estimate the white balance from, leaving it alone");
+            Mat3::IDENTITY
+        }
+    })
+}
+
+/// Transfer function of 8- and 16-bit PNG input, which is otherwise
+/// taken to be PQ as written by HDR screenshot tools.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transfer {
+    Pq,
+    Hlg,
+    Srgb,
+}
+
+impl Transfer {
+    pub fn with_str(source: &str) -> Result<Self> {
+        match source {
+            "pq" => Ok(Transfer::Pq),
+            "hlg" => Ok(Transfer::Hlg),
+            "srgb" => Ok(Transfer::Srgb),
+            _ => Err(InvalidTransfer(source.to_string())),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Transfer::Pq => "PQ",
+            Transfer::Hlg => "HLG",
+            Transfer::Srgb => "sRGB",
+        }
+    }
+
+    // Format reading the samples of an integer format with this transfer,
+    // or None for float formats, which have no transfer function.
+    fn integer_format(self, format: PixelFormat) -> Option<PixelFormat> {
+        let eight_bit = match format {
+            SDR8bit | HDR8bit | HLG8bit => true,
+            SDR16bit | HDR16bit | HLG16bit => false,
+            HDRFloat16 | HDRFloat32 | HDRFloat16PNG => return None,
+        };
+        Some(match (self, eight_bit) {
+            (Transfer::Pq, true) => HDR8bit,
+            (Transfer::Pq, false) => HDR16bit,
+            (Transfer::Hlg, true) => HLG8bit,
+            (Transfer::Hlg, false) => HLG16bit,
+            (Transfer::Srgb, true) => SDR8bit,
+            (Transfer::Srgb, false) => SDR16bit,
+        })
+    }
+}
+
+// Brightest the 99th percentile of PQ content plausibly gets, in nits.
+// Full range sRGB or HLG signals read as PQ put it near 10000.
+const PQ_PLAUSIBLE_MAX: f32 = 4000.0;
+
+// Signal level counted as the top of the range, and the share of pixels
+// up there that marks SDR. SDR clips a visible part of most images to
+// full code, where HLG keeps all but specular highlights below the 75%
+// signal of its reference white.
+const TOP_SIGNAL: f32 = 0.95;
+const SDR_TOP_SHARE: f32 = 0.02;
+
+/// Guess the transfer function of integer PNG input from its pixels,
+/// logging the guess. Float input has none, and gives None.
+fn detect_transfer(source: &PixelBuffer) -> Option<Transfer> {
+    let pq_format = Transfer::Pq.integer_format(source.format)?;
+    let read_pq = PixelBuffer::new(0, 0, pq_format).read_rgb_func;
+    let samples = match source.bytes_per_pixel {
+        3 => rgb24_samples,
+        _ => rgb48_samples,
+    };
+
+    let pq_nits = Histogram::with_values(source.par_iter().map(|data| luma_scrgb(read_pq(data))))
+        .percentile(99.0)
+        * SDR_WHITE;
+    let top = source
+        .par_iter()
+        .filter(|data| samples(data).max_element() >= TOP_SIGNAL)
+        .count();
+    let top_share = top as f32 / (source.width * source.height) as f32;
+
+    let transfer = if pq_nits <= PQ_PLAUSIBLE_MAX {
+        Transfer::Pq
+    } else if top_share >= SDR_TOP_SHARE {
+        Transfer::Srgb
+    } else {
+        Transfer::Hlg
+    };
+    eprintln!(
+        "Detected input transfer: {} (99th percentile {:.0} nits as PQ, {:.1}% of pixels at the top of the signal range)",
+        transfer.name(),
+        pq_nits,
+        top_share * 100.0
+    );
+    Some(transfer)
+}
+
+// Re-read integer input with the transfer function from --input-transfer,
+// or the one --gamma-input-detect guesses.
+fn apply_input_transfer(source: &mut PixelBuffer, settings: &Settings) {
+    let transfer = match settings.input_transfer {
+        Some(transfer) => Some(transfer),
+        None if settings.detect_input_transfer => detect_transfer(source),
+        None => None,
+    };
+    if let Some(format) = transfer.and_then(|transfer| transfer.integer_format(source.format)) {
+        source.reinterpret(format);
+    }
+}
+
+fn luma_scrgb(val: Vec3) -> f32 {
+    luma_oklab(scrgb_to_oklab(val))
+}
+
+fn luma_oklab(val: Oklab) -> f32 {
+    // oklab's l is not linear
+    // so translate it back to linear srgb desaturated
+    // and take one of its rgb values
+    let oklab_gray = Oklab {
+        l: val.l,
+        a: 0.0,
+        b: 0.0,
+    };
+    let rgb_gray = oklab_to_scrgb(oklab_gray);
+    rgb_gray.x
+}
+
+// Hard clip at the linear_white level, which defaults to SDR white.
+// tone_map_options() has checked that it's positive.
+fn tonemap_linear(c_in: Vec3, options: &Options) -> Vec3 {
+    c_in / options.param("linear_white", 1.0)
+}
+
+// The whole transform from a 3D LUT. Input is shaped the way HDR PNG
+// captures are stored, BT.2100 PQ, and output is read as sRGB.
+fn tonemap_lut(c_in: Vec3, options: &Options) -> Vec3 {
+    match &options.tone_map_lut {
+        Some(lut) => srgb_to_linear(clip(lut.sample(linear_to_pq(scrgb_to_rec2100(c_in))))),
+        None => c_in,
+    }
+}
+
+fn tonemap_reinhard_rgb(c_in: Vec3, options: &Options) -> Vec3 {
+    // Variant that maps R, G, and B channels separately.
+    // This should desaturate very bright colors gradually, but will
+    // possible cause some color shift.
+    let white = options.hdr_max;
+    let white2 = white * white;
+    c_in * (Vec3::ONE + c_in / white2) / (Vec3::ONE + c_in)
+}
+
+fn tonemap_reinhard_oklab(c_in: Vec3, options: &Options) -> Vec3 {
+    reinhard_oklab(c_in, options.hdr_max, options)
+}
+
+fn reinhard_oklab(c_in: Vec3, white: f32, options: &Options) -> Vec3 {
+    // Map luminance from HDR to SDR domain, and scale the input color
+    // in oklab perceptual color space.
+    //
+    // oklab color space: https://bottosson.github.io/posts/oklab/
+    //
+    let white2 = white * white;
+
+    // use Oklab's L coordinate as luminance
+    let oklab_in = (options.oklab)(c_in);
+    let luma_in = luma_oklab(oklab_in);
+
+    // Reinhard tone-mapping algo.
+    //
+    // Original:
+    // http://www.cmap.polytechnique.fr/%7Epeyre/cours/x2005signal/hdr_photographic.pdf
+    //
+    // Extended:
+    // https://64.github.io/tonemapping/#reinhard
+    // TMO_reinhardext​(C) = C(1 + C/C_white^2​) / (1 + C)
+    //
+    let luma_out = luma_in * (1.0 + luma_in / white2) / (1.0 + luma_in);
+    let oklab_out = scale_oklab_desat(oklab_in, luma_out, options.saturation, options.oklab);
+    oklab_to_scrgb(oklab_out)
+}
+
+fn tonemap_reinhard_luminance(c_in: Vec3, options: &Options) -> Vec3 {
+    // Extended Reinhard on photometric luminance, as in the original
+    // paper, for matching other tools' reference Reinhard output.
+    let white = options.hdr_max;
+    let white2 = white * white;
+
+    let luma_in = options.luma.dot(c_in);
+    if luma_in <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let luma_out = luma_in * (1.0 + luma_in / white2) / (1.0 + luma_in);
+
+    // Rescale by the luminance ratio, with the saturation control:
+    // C_out = (C_in / L_in)^s * L_out
+    // https://www.cs.ubc.ca/~heidrich/Papers/EG.09_1.pdf
+    (c_in / luma_in).max(Vec3::ZERO).powf(options.saturation) * luma_out
+}
+
+fn oklab_l_for_luma(luma: f32, oklab: OklabFn) -> f32 {
+    oklab(Vec3::splat(luma)).l
+}
+
+fn scale_oklab_desat(oklab_in: Oklab, luma_out: f32, saturation: f32, oklab: OklabFn) -> Oklab {
+    let l_in = oklab_in.l;
+    if l_in == 0.0 {
+        oklab_in
+    } else {
+        let l_out = oklab_l_for_luma(luma_out, oklab);
+        // oklab coords scale cubically
+        // 1.0 -> desaturate linearly according to luma compression ratio
+        // 0.5 -> desaturate more aggressively
+        // 2.0 -> saturate more aggressively
+        // a and b share the one positive ratio, so this only scales oklch
+        // chroma; the hue angle atan2(b, a) comes through exactly.
+        let ratio = (l_out / l_in).powf(3.0 / saturation);
+        Oklab {
+            l: l_out,
+            a: oklab_in.a * ratio,
+            b: oklab_in.b * ratio,
+        }
+    }
+}
+
+fn scale_oklab(oklab_in: Oklab, luma_out: f32, oklab: OklabFn) -> Oklab {
+    if oklab_in.l == 0.0 {
+        oklab_in
+    } else {
+        let gray_l = oklab_l_for_luma(luma_out, oklab);
+        let ratio = gray_l / oklab_in.l;
+        Oklab {
+            l: gray_l,
+            a: oklab_in.a * ratio,
+            b: oklab_in.b * ratio,
+        }
+    }
+}
+
+fn clip(input: Vec3) -> Vec3 {
+    input.max(Vec3::ZERO).min(Vec3::ONE)
+}
+
+// Whether a linear color can't be shown in SDR without a color map
+fn out_of_gamut(c_in: Vec3) -> bool {
+    gamut_excess(c_in) > 0.0
+}
+
+// How far past SDR white the brightest channel went, or 0 if in gamut
+fn gamut_excess(c_in: Vec3) -> f32 {
+    (c_in.max_element() - 1.0).max(0.0)
+}
+
+// Amplification for --color-map-diff, making an oklab distance of 0.1
+// full white; the color maps' changes are small next to the whole range.
+const COLOR_MAP_DIFF_GAIN: f32 = 10.0;
+
+// How far the color map moved a tone mapped color from plain clipping,
+// as the distance between the two in oklab.
+fn color_map_difference(unmapped: Vec3, options: &Options) -> f32 {
+    let mapped = scrgb_to_oklab(clip((options.color_map)(unmapped)));
+    let clipped = scrgb_to_oklab(clip(unmapped));
+    let (dl, da, db) = (
+        mapped.l - clipped.l,
+        mapped.a - clipped.a,
+        mapped.b - clipped.b,
+    );
+    (dl * dl + da * da + db * db).sqrt()
+}
+
+// Pixels with each channel below black or above SDR white before
+// color mapping, for --clip-report. Counted from parallel fills.
+struct ClipCounts {
+    below: [AtomicUsize; 3],
+    above: [AtomicUsize; 3],
+}
+
+impl ClipCounts {
+    fn new() -> Self {
+        ClipCounts {
+            below: Default::default(),
+            above: Default::default(),
+        }
+    }
+
+    fn add(&self, c_in: Vec3) {
+        for channel in 0..3 {
+            if c_in[channel] < 0.0 {
+                self.below[channel].fetch_add(1, AtomicOrdering::Relaxed);
+            } else if c_in[channel] > 1.0 {
+                self.above[channel].fetch_add(1, AtomicOrdering::Relaxed);
+            }
+        }
+    }
+
+    fn report(&self, pixels: usize) {
+        let percent = |count: &AtomicUsize| {
+            100.0 * count.load(AtomicOrdering::Relaxed) as f32 / pixels as f32
+        };
+        eprintln!("Clipping per channel, before color mapping:");
+        for (channel, name) in ["red", "green", "blue"].iter().enumerate() {
+            eprintln!(
+                "  {:<6}{:.2}% above white, {:.2}% below black",
+                name,
+                percent(&self.above[channel]),
+                percent(&self.below[channel])
+            );
+        }
+    }
+}
+
+// Mark a pixel that was out of gamut before color mapping, for --show-oog
+fn tint_out_of_gamut(c_in: Vec3) -> Vec3 {
+    let magenta = Vec3::new(1.0, 0.0, 1.0);
+    c_in.lerp(magenta, 0.5)
+}
+
+// Luminance bands for --false-color, as the upper bound in nits, a name
+// for the legend and the linear color they're shown in. 203 nits is the
+// BT.2408 reference white for HDR graphics.
+const FALSE_COLOR_BANDS: [(f32, &str, [f32; 3]); 7] = [
+    (0.1, "blue", [0.0, 0.0, 1.0]),
+    (1.0, "cyan", [0.0, 1.0, 1.0]),
+    (10.0, "green", [0.0, 1.0, 0.0]),
+    (SDR_WHITE, "gray", [0.2, 0.2, 0.2]),
+    (203.0, "yellow", [1.0, 1.0, 0.0]),
+    (1000.0, "orange", [1.0, 0.25, 0.0]),
+    (f32::INFINITY, "red", [1.0, 0.0, 0.0]),
+];
+
+// Replace an input pixel with the color of its luminance band
+fn false_color_pixel(c_in: Vec3) -> Vec3 {
+    let nits = luma_scrgb(c_in) * SDR_WHITE;
+    let (_, _, color) = FALSE_COLOR_BANDS
+        .iter()
+        .find(|(max, _, _)| nits < *max)
+        .unwrap_or(&FALSE_COLOR_BANDS[FALSE_COLOR_BANDS.len() - 1]);
+    Vec3::from(*color)
+}
+
+fn print_false_color_legend() {
+    eprintln!("False color legend:");
+    let mut min = 0.0;
+    for (max, name, _) in FALSE_COLOR_BANDS.iter() {
+        if min == 0.0 {
+            eprintln!("  below {} nits: {}", max, name);
+        } else if max.is_infinite() {
+            eprintln!("  above {} nits: {}", min, name);
+        } else {
+            eprintln!("  {}-{} nits: {}", min, max, name);
+        }
+        min = *max;
+    }
+}
+
+fn color_clip(input: Vec3) -> Vec3 {
+    clip(input)
+}
+
+fn darken_oklab(c_in: Oklab, brightness: f32) -> Vec3 {
+    let c_out = Oklab {
+        l: c_in.l * brightness,
+        a: c_in.a * brightness,
+        b: c_in.b * brightness,
+    };
+    oklab_to_scrgb(c_out)
+}
+
+fn desat_oklab(c_in: Oklab, saturation: f32) -> Vec3 {
+    let c_out = Oklab {
+        l: c_in.l,
+        a: c_in.a * saturation,
+        b: c_in.b * saturation,
+    };
+    oklab_to_scrgb(c_out)
+}
+
+// Roughly the highest oklab chroma inside the sRGB gamut
+const MAX_SRGB_CHROMA: f32 = 0.32;
+
+// Where the shadow and highlight zones of --shadows and --highlights
+// center, in stops from middle gray, which is 18% of SDR white.
+const ZONE_STOPS: f32 = 6.0;
+const MIDDLE_GRAY: f32 = 0.18;
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Dodge and burn by luminance zone before tone mapping: shadows and
+// highlights are exposure changes in stops, fading in smoothly from none
+// at middle gray to all of it ZONE_STOPS below or above.
+fn zone_exposure(c_in: Vec3, shadows: f32, highlights: f32, oklab: OklabFn) -> Vec3 {
+    if shadows == 0.0 && highlights == 0.0 {
+        return c_in;
+    }
+    let c_in_oklab = oklab(c_in);
+    let luma_in = luma_oklab(c_in_oklab);
+    if luma_in <= 0.0 {
+        return c_in;
+    }
+    let stops = (luma_in / MIDDLE_GRAY).log2();
+    let shadow_weight = 1.0 - smoothstep(-ZONE_STOPS, 0.0, stops);
+    let highlight_weight = smoothstep(0.0, ZONE_STOPS, stops);
+    let luma_out =
+        luma_in * exposure_scale(shadows * shadow_weight + highlights * highlight_weight);
+    oklab_to_scrgb(scale_oklab(c_in_oklab, luma_out, oklab))
+}
+
+// Creative saturation boost (or cut) on the output. Like vibrance in
+// photo editors, colors that are already saturated change less.
+fn vibrance(c_in: Vec3, amount: f32) -> Vec3 {
+    if amount == 1.0 {
+        return c_in;
+    }
+    let c_in_oklab = scrgb_to_oklab(c_in);
+    let chroma = (c_in_oklab.a * c_in_oklab.a + c_in_oklab.b * c_in_oklab.b).sqrt();
+    let headroom = 1.0 - (chroma / MAX_SRGB_CHROMA).min(1.0);
+    desat_oklab(c_in_oklab, 1.0 + (amount - 1.0) * headroom)
+}
+
+// Creative tint for --split-tone: shift oklab a,b towards the shadow tint
+// in dark colors and the highlight tint in bright ones, in proportion to
+// lightness so black stays black.
+fn split_tone(c_in: Vec3, split_tone: SplitTone) -> Vec3 {
+    if split_tone.shadow == Vec2::ZERO && split_tone.highlight == Vec2::ZERO {
+        return c_in;
+    }
+    let oklab = scrgb_to_oklab(c_in);
+    // Highlight weight, curved to be one half at the balance lightness
+    let weight = oklab
+        .l
+        .clamp(0.0, 1.0)
+        .powf(0.5f32.ln() / split_tone.balance.ln());
+    let tint = (split_tone.shadow * (1.0 - weight) + split_tone.highlight * weight) * oklab.l;
+    oklab_to_scrgb(Oklab {
+        l: oklab.l,
+        a: oklab.a + tint.x,
+        b: oklab.b + tint.y,
+    })
+}
+
+// Lightness change in oklab L for --grain 1 in the deepest shadows
+const GRAIN_STRENGTH: f32 = 0.05;
+
+// Normalized weights of a gaussian with this sigma, out to three sigmas
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as i32;
+    let weights = (-radius..=radius)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<f32>>();
+    let sum: f32 = weights.iter().sum();
+    weights.iter().map(|weight| weight / sum).collect()
+}
+
+// One pass of a separable blur along the rows or down the columns,
+// repeating the edge pixels past the ends.
+fn blur_pass(
+    values: &[(f32, f32)],
+    width: usize,
+    vertical: bool,
+    kernel: &[f32],
+) -> Vec<(f32, f32)> {
+    let radius = (kernel.len() / 2) as isize;
+    values
+        .par_iter()
+        .enumerate()
+        .map(|(index, _)| {
+            // Position along the line being blurred, its length, and the
+            // distance between neighbors on it
+            let (pos, len, step) = match vertical {
+                false => (index % width, width, 1),
+                true => (index / width, values.len() / width, width),
+            };
+            kernel
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(a, b), (k, weight)| {
+                    let offset = (pos as isize + k as isize - radius).clamp(0, len as isize - 1)
+                        - pos as isize;
+                    let (va, vb) = values[(index as isize + offset * step as isize) as usize];
+                    (a + va * weight, b + vb * weight)
+                })
+        })
+        .collect()
+}
+
+// Blur the oklab a and b channels of the image for --denoise-chroma,
+// keeping the original lightness so edges and fine detail stay sharp
+// while color blotches in noisy shadows are smoothed out.
+fn denoise_chroma(source: &PixelBuffer, sigma: f32) -> PixelBuffer {
+    let oklab = source.pixels().map(scrgb_to_oklab).collect::<Vec<Oklab>>();
+    let chroma = oklab.iter().map(|c| (c.a, c.b)).collect::<Vec<_>>();
+    let kernel = gaussian_kernel(sigma);
+    let chroma = blur_pass(&chroma, source.width, false, &kernel);
+    let chroma = blur_pass(&chroma, source.width, true, &kernel);
+    let mut dest = source.clone();
+    dest.fill(
+        oklab
+            .into_par_iter()
+            .zip(chroma)
+            .map(|(c, (a, b))| oklab_to_scrgb(Oklab { l: c.l, a, b })),
+    );
+    dest
+}
+
+// Deterministic random bits for each index and seed, from the splitmix64 hash
+fn splitmix64(seed: u64, index: usize) -> u64 {
+    let mut z = seed.wrapping_add((index as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// A deterministic noise value in -1..1 for each pixel index and seed.
+// Summing two uniform values gives a softer triangular distribution,
+// closer to the look of real grain.
+fn grain_noise(seed: u64, index: usize) -> f32 {
+    let z = splitmix64(seed, index);
+    let uniform = |bits: u64| bits as f32 / u32::MAX as f32;
+    uniform(z >> 32) + uniform(z & 0xffff_ffff) - 1.0
+}
+
+// Monochrome film grain on the output lightness, stronger in the
+// shadows as with real film. Unlike dithering it's meant to be seen.
+fn add_grain(c_in: Vec3, amount: f32, seed: u64, index: usize) -> Vec3 {
+    if amount == 0.0 {
+        return c_in;
+    }
+    let c_in_oklab = scrgb_to_oklab(c_in);
+    let darkness = 1.0 - 0.5 * c_in_oklab.l.clamp(0.0, 1.0);
+    let noise = grain_noise(seed, index) * amount * GRAIN_STRENGTH * darkness;
+    oklab_to_scrgb(Oklab {
+        l: c_in_oklab.l + noise,
+        ..c_in_oklab
+    })
+}
+
+// Pull oklab chroma down to a ceiling for --max-chroma,
+// keeping hue and lightness.
+fn clamp_chroma(c_in: Vec3, max_chroma: Option<f32>) -> Vec3 {
+    let max_chroma = match max_chroma {
+        Some(max_chroma) => max_chroma,
+        None => return c_in,
+    };
+    let c_in_oklab = scrgb_to_oklab(c_in);
+    let chroma = (c_in_oklab.a * c_in_oklab.a + c_in_oklab.b * c_in_oklab.b).sqrt();
+    if chroma <= max_chroma {
+        return c_in;
+    }
+    desat_oklab(c_in_oklab, max_chroma / chroma)
+}
+
+const EPSILON: f32 = 0.001; // good enough for us for now
+
+fn close_enough(a: f32, b: f32) -> Ordering {
+    let delta = a - b;
+    if delta.abs() < EPSILON {
+        Ordering::Equal
+    } else if delta < 0.0 {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+fn binary_search<I, O, F, G>(input: I, min: f32, max: f32, func: F, comparator: G) -> O
+where
+    I: Copy + Clone,
+    O: Copy + Clone,
+    F: Fn(I, f32) -> O,
+    G: Fn(O) -> Ordering,
+{
+    let mid = (min + max) / 2.0;
+    let result = func(input, mid);
+    match close_enough(min, max) {
+        Ordering::Equal => result,
+        _ => match comparator(result) {
+            Ordering::Less => binary_search(input, mid, max, func, comparator),
+            Ordering::Greater => binary_search(input, min, mid, func, comparator),
+            Ordering::Equal => result,
+        },
+    }
+}
+
+fn color_darken_oklab(c_in: Vec3) -> Vec3 {
+    if out_of_gamut(c_in) {
+        let c_in_oklab = scrgb_to_oklab(c_in);
+        let c_out = binary_search(c_in_oklab, 0.0, 1.0, darken_oklab, |rgb| {
+            close_enough(rgb.max_element(), 1.0)
+        });
+        clip(c_out)
+    } else {
+        c_in
+    }
+}
+
+fn color_desat_oklab(c_in: Vec3) -> Vec3 {
+    if out_of_gamut(c_in) {
+        let c_in_oklab = scrgb_to_oklab(c_in);
+        let c_out = binary_search(c_in_oklab, 0.0, 1.0, desat_oklab, |rgb| {
+            close_enough(rgb.max_element(), 1.0)
+        });
+        clip(c_out)
+    } else {
+        c_in
+    }
+}
+
+// Oklab a and b into nonlinear LMS, and cubed LMS into linear sRGB, as in
+// oklab_to_linear_srgb()
+const OKLAB_AB_TO_LMS: [[f32; 2]; 3] = [
+    [0.396_337_78, 0.215_803_76],
+    [-0.105_561_346, -0.063_854_17],
+    [-0.089_484_18, -1.291_485_5],
+];
+const LMS_TO_LINEAR_SRGB: [[f32; 3]; 3] = [
+    [4.076_741_7, -3.307_711_6, 0.230_969_94],
+    [-1.268_438, 2.609_757_4, -0.341_319_38],
+    [-0.004_196_086_3, -0.703_418_6, 1.707_614_7],
+];
+
+// Halley steps to where a channel leaves the gamut for clip-hue
+const CLIP_HUE_STEPS: usize = 2;
+
+// Pull out of gamut colors in along a straight line in oklab to the gray
+// of the same lightness (or black or white if the lightness itself is out
+// of range), which keeps the hue exactly. Along the line each of red,
+// green and blue is a cubic in the distance, so a couple of Halley steps
+// on those, from a linear first guess, find the gamut boundary instead
+// of a binary search.
+fn color_clip_hue_oklab(c_in: Vec3) -> Vec3 {
+    if !out_of_gamut(c_in) && c_in.min_element() >= 0.0 {
+        return c_in;
+    }
+    let c_in_oklab = scrgb_to_oklab(c_in);
+    let gray = c_in_oklab.l.clamp(0.0, 1.0);
+    // How fast each of the nonlinear LMS values changes along the line
+    let slopes =
+        OKLAB_AB_TO_LMS.map(|[ka, kb]| c_in_oklab.l - gray + ka * c_in_oklab.a + kb * c_in_oklab.b);
+    // A channel's value and first two derivatives at t along the line,
+    // from the gray at 0 to the input color at 1
+    let channel = |row: [f32; 3], t: f32| {
+        row.iter()
+            .zip(slopes)
+            .fold((0.0, 0.0, 0.0), |(f, f1, f2), (weight, slope)| {
+                let x = gray + slope * t;
+                (
+                    f + weight * x * x * x,
+                    f1 + weight * 3.0 * x * x * slope,
+                    f2 + weight * 6.0 * x * slope * slope,
+                )
+            })
+    };
+    let mut t_out = 1.0f32;
+    for row in LMS_TO_LINEAR_SRGB {
+        let (value, _, _) = channel(row, 1.0);
+        let bound = match value {
+            _ if value > 1.0 => 1.0,
+            _ if value < 0.0 => 0.0,
+            _ => continue,
+        };
+        // Starting from where a straight line between the ends crosses
+        let (start, _, _) = channel(row, 0.0);
+        let mut t = (bound - start) / (value - start);
+        for _ in 0..CLIP_HUE_STEPS {
+            let (f, f1, f2) = channel(row, t);
+            let f = f - bound;
+            let denominator = 2.0 * f1 * f1 - f * f2;
+            if denominator == 0.0 {
+                break;
+            }
+            t -= 2.0 * f * f1 / denominator;
+        }
+        t_out = t_out.min(t.clamp(0.0, 1.0));
+    }
+    let c_out = Oklab {
+        l: gray + (c_in_oklab.l - gray) * t_out,
+        a: c_in_oklab.a * t_out,
+        b: c_in_oklab.b * t_out,
+    };
+    // Catch what the Halley steps left just outside
+    clip(oklab_to_scrgb(c_out))
+}
+
+// Darken and desaturate at the same time; blend 0 is pure darkening,
+// blend 1 is pure desaturation.
+fn darken_desat_oklab(input: (Oklab, f32), amount: f32) -> Vec3 {
+    let (c_in, blend) = input;
+    let c_out = Oklab {
+        l: c_in.l * (amount + (1.0 - amount) * blend),
+        a: c_in.a * amount,
+        b: c_in.b * amount,
+    };
+    oklab_to_scrgb(c_out)
+}
+
+fn oklab_distance(a: Oklab, b: Oklab) -> f32 {
+    let dl = a.l - b.l;
+    let da = a.a - b.a;
+    let db = a.b - b.b;
+    (dl * dl + da * da + db * db).sqrt()
+}
+
+fn color_adaptive_oklab(c_in: Vec3) -> Vec3 {
+    if out_of_gamut(c_in) {
+        // Try a few blends between darkening and desaturating, and keep
+        // whichever lands in gamut closest to the original color.
+        // Bright near-neutral colors can only be fixed by darkening,
+        // while saturated colors just over the edge do better desaturated.
+        const BLENDS: [f32; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+        let c_in_oklab = scrgb_to_oklab(c_in);
+        let mut best = Vec3::ZERO;
+        let mut best_error = f32::INFINITY;
+        for blend in BLENDS {
+            let c_out = clip(binary_search(
+                (c_in_oklab, blend),
+                0.0,
+                1.0,
+                darken_desat_oklab,
+                |rgb| close_enough(rgb.max_element(), 1.0),
+            ));
+            let error = oklab_distance(c_in_oklab, scrgb_to_oklab(c_out));
+            if error < best_error {
+                best = c_out;
+                best_error = error;
+            }
+        }
+        best
+    } else {
+        c_in
+    }
+}
+
+fn luma_rgb(val: Vec3) -> f32 {
+    val.x * 0.2126 + val.y * 0.7152 + val.z * 0.0722
+}
+
+fn scale_rgb(val: Vec3, luma_in: f32, luma_out: f32) -> Vec3 {
+    let scale = luma_out / luma_in;
+    val * scale
+}
+
+// https://64.github.io/tonemapping/#uncharted-2
+// Uncharted 2 / Hable Filmic
+fn uncharted2_tonemap_partial(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    ((x * (A * x + (C * B)) + (D * E)) / (x * (A * x + (B)) + (D * F))) - (E / F)
+}
+
+fn tonemap_uncharted2(v: Vec3, options: &Options) -> Vec3 {
+    let exposure_bias = options.param("exposure_bias", 2.0);
+    let luma = options.luma.dot(v);
+    let curr = uncharted2_tonemap_partial(luma * exposure_bias);
+
+    let w = options.param("linear_white", 11.2);
+    let white_scale = 1.0f32 / uncharted2_tonemap_partial(w);
+    let luma_out = curr * white_scale;
+
+    scale_rgb(v, luma, luma_out)
+}
+
+fn tonemap_hable(val: Vec3, options: &Options) -> Vec3 {
+    // stolen from ffmpeg's vf_tonemap
+
+    // desat
+    let luma = options.luma.dot(val);
+    let desaturation = options.param("desaturation", 2.0);
+    let epsilon: f32 = 1e-6;
+    let overbright = f32::max(luma - desaturation, epsilon) / f32::max(luma, epsilon);
+    let rgb_out = val * (1.0 - overbright) + luma * overbright;
+    let sig_orig = f32::max(rgb_out.max_element(), epsilon);
+
+    // hable/uncharted2
+    let exposure_bias = options.param("exposure_bias", 2.0);
+    let luma = sig_orig;
+    let curr = uncharted2_tonemap_partial(luma * exposure_bias);
+    let w = options.param("linear_white", 11.2);
+    let white_scale = 1.0f32 / uncharted2_tonemap_partial(w);
+    let sig = curr * white_scale;
+
+    rgb_out * (sig / sig_orig)
+}
+
+// can't use glam's Mat3 as a constant literal?
+type Matrix3x3 = [[f32; 3]; 3];
+
+// https://64.github.io/tonemapping/#aces
+// ACES (Academy Color Encoding System)
+const ACES_INPUT_MATRIX: Matrix3x3 = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+const ACES_OUTPUT_MATRIX: Matrix3x3 = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+#[allow(clippy::many_single_char_names)]
+fn aces_mul(m: &Matrix3x3, v: Vec3) -> Vec3 {
+    let x = m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2];
+    let y = m[1][0] * v[1] + m[1][1] * v[1] + m[1][2] * v[2];
+    let z = m[2][0] * v[1] + m[2][1] * v[1] + m[2][2] * v[2];
+    Vec3::new(x, y, z)
+}
+
+fn aces_rtt_and_odt_fit(v: Vec3) -> Vec3 {
+    let a = v * (v + Vec3::splat(0.0245786)) - Vec3::splat(0.000090537);
+    let b = v * (Vec3::splat(0.983729) * v + Vec3::splat(0.432951)) + Vec3::splat(0.238081);
+    a / b
+}
+
+fn tonemap_aces(c_in: Vec3, options: &Options) -> Vec3 {
+    let v = c_in * options.param("exposure_bias", 1.0);
+    let v = aces_mul(&ACES_INPUT_MATRIX, v);
+    let v = aces_rtt_and_odt_fit(v);
+    aces_mul(&ACES_OUTPUT_MATRIX, v)
+}
+
+// Inverse of linear_to_srgb(), so output can round-trip
+// through display-referred processing like LUTs.
+fn srgb_to_linear(val: Vec3) -> Vec3 {
+    let min = Vec3::splat(0.0031308 * 12.92);
+    let linear = val / Vec3::splat(12.92);
+    let gamma = (val + Vec3::splat(0.055)).powf(2.4) / Vec3::splat(1.055);
+    Vec3::select(val.cmple(min), linear, gamma)
+}
+
+// Collapse a final SDR color to neutral gray for --grayscale
+fn grayscale(rgb: Vec3, mode: Option<Grayscale>) -> Vec3 {
+    match mode {
+        None => rgb,
+        Some(Grayscale::Lightness) => Vec3::splat(luma_scrgb(rgb)),
+        Some(Grayscale::Mix(weights)) => Vec3::splat(weights.dot(rgb)),
+    }
+}
+
+// LUTs work on display-referred values, so apply them in sRGB gamma.
+fn apply_lut(rgb: Vec3, lut: Option<&Lut3d>) -> Vec3 {
+    match lut {
+        Some(lut) => srgb_to_linear(clip(lut.sample(linear_to_srgb(rgb)))),
+        None => rgb,
+    }
+}
+
+fn linear_to_srgb(val: Vec3) -> Vec3 {
+    // fixme make sure all the splats are efficient constants
+    let min = Vec3::splat(0.0031308);
+    let linear = val * Vec3::splat(12.92);
+    let gamma = (val * Vec3::splat(1.055)).powf(1.0 / 2.4) - Vec3::splat(0.055);
+    Vec3::select(val.cmple(min), linear, gamma)
+}
+
+const REC2100_MAX: f32 = 10000.0; // the 1.0 value for BT.2100 linear
+/// Nits of scRGB 1.0, the SDR reference white
+pub const SDR_WHITE: f32 = 80.0;
+const HLG_PEAK: f32 = 1000.0;
+const HLG_GAMMA: f32 = 1.2;
+
+fn nits_to_scrgb(nits: f32) -> f32 {
+    nits / SDR_WHITE
+}
+
+// Where content of the given luminance ends up after exposure and
+// tone mapping, for levels given in nits of the original content.
+fn nits_to_sdr(nits: f32, options: &Options) -> f32 {
+    luma_scrgb(hdr_to_sdr_pixel(Vec3::splat(nits_to_scrgb(nits)), options))
+}
+
+fn exposure_scale(stops: f32) -> f32 {
+    2.0_f32.powf(stops)
+}
+
+// Exposed and mixed, ready for the tone map
+fn exposed(rgb_scrgb: Vec3, options: &Options) -> Vec3 {
+    let val = rgb_scrgb * options.scale;
+    let val = options.channel_mix.mul_vec3(val);
+    zone_exposure(val, options.shadows, options.highlights, options.oklab)
+}
+
+// Tone mapped, but possibly still out of gamut
+fn hdr_to_sdr_unmapped(rgb_scrgb: Vec3, options: &Options) -> Vec3 {
+    let val = exposed(rgb_scrgb, options);
+    // Operators on RGB channels or luminance run in the --working-space;
+    // the rest expect scRGB. Either way the result comes back as scRGB.
+    let (val, in_working_space) =
+        options
+            .tone_map
+            .iter()
+            .fold((val, false), |(val, in_working_space), tone_map| {
+                let val = match (tone_map.working_space, in_working_space) {
+                    (true, false) => options.to_working_space.mul_vec3(val),
+                    (false, true) => options.from_working_space.mul_vec3(val),
+                    _ => val,
+                };
+                ((tone_map.func)(val, options), tone_map.working_space)
+            });
+    if in_working_space {
+        options.from_working_space.mul_vec3(val)
+    } else {
+        val
+    }
+}
+
+fn hdr_to_sdr_pixel(rgb_scrgb: Vec3, options: &Options) -> Vec3 {
+    (options.color_map)(hdr_to_sdr_unmapped(rgb_scrgb, options))
+}
+
+// hdr_to_sdr_unmapped() for the source pixel at this index, which only
+// makes a difference to adaptive-tiles with its white point that varies.
+fn hdr_to_sdr_unmapped_at(rgb_scrgb: Vec3, index: usize, options: &Options) -> Vec3 {
+    match &options.tile_whites {
+        Some(whites) => reinhard_oklab(exposed(rgb_scrgb, options), whites.at(index), options),
+        None => hdr_to_sdr_unmapped(rgb_scrgb, options),
+    }
+}
+
+// Tiles across the longer side for adaptive-tiles
+const ADAPTIVE_TILES: f32 = 4.0;
+
+// A white point for each tile of the image, measured like --hdr-max from
+// the tile's own histogram, for the adaptive-tiles tone map. Never below
+// SDR white, so tiles that are already SDR aren't stretched.
+struct TileWhites {
+    width: usize,
+    columns: usize,
+    rows: usize,
+    tile_width: usize,
+    tile_height: usize,
+    // In log2, so blending between a dim and a bright tile is even
+    log_whites: Vec<f32>,
+}
+
+impl TileWhites {
+    fn new(source: &PixelBuffer, settings: &Settings, scale: f32, tiles: f32) -> Self {
+        let (width, height) = (source.width, source.height);
+        let tiles = tiles.max(1.0);
+        let (columns, rows) = if width >= height {
+            let rows = tiles * height as f32 / width as f32;
+            (tiles as usize, (rows.round() as usize).max(1))
+        } else {
+            let columns = tiles * width as f32 / height as f32;
+            ((columns.round() as usize).max(1), tiles as usize)
+        };
+        // No more tiles than pixels, and none left empty past the edge
+        // once their size is rounded up
+        let tile_width = width.div_ceil(columns.min(width));
+        let tile_height = height.div_ceil(rows.min(height));
+        let (columns, rows) = (width.div_ceil(tile_width), height.div_ceil(tile_height));
+        let log_whites = (0..rows * columns)
+            .into_par_iter()
+            .map(|tile| {
+                let (x, y) = (
+                    (tile % columns) * tile_width,
+                    (tile / columns) * tile_height,
+                );
+                let histogram = || {
+                    Histogram::new(&source.crop(
+                        x,
+                        y,
+                        (x + tile_width).min(width) - x,
+                        (y + tile_height).min(height) - y,
+                    ))
+                };
+                let white = match settings.hdr_max {
+                    Level::Scalar(nits) | Level::Nits(nits) => nits / SDR_WHITE,
+                    Level::Content => histogram().highlight_knee(),
+                    Level::Percentile(val) => {
+                        histogram().percentile_clipped(val, settings.hdr_max_clip)
+                    }
+                };
+                (white * scale).max(1.0).log2()
+            })
+            .collect();
+        TileWhites {
+            width,
+            columns,
+            rows,
+            tile_width,
+            tile_height,
+            log_whites,
+        }
+    }
+
+    // Bilinear between the four nearest tile centers, held flat past
+    // the outermost centers.
+    fn at(&self, index: usize) -> f32 {
+        let position = |pixel: usize, size: usize, count: usize| {
+            let pos = ((pixel as f32 + 0.5) / size as f32 - 0.5).clamp(0.0, (count - 1) as f32);
+            let low = (pos.floor() as usize).min(count.saturating_sub(2));
+            (low, (low + 1).min(count - 1), pos - low as f32)
+        };
+        let (x0, x1, fx) = position(index % self.width, self.tile_width, self.columns);
+        let (y0, y1, fy) = position(index / self.width, self.tile_height, self.rows);
+        let white = |x: usize, y: usize| self.log_whites[y * self.columns + x];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let top = lerp(white(x0, y0), white(x1, y0), fx);
+        let bottom = lerp(white(x0, y1), white(x1, y1), fx);
+        lerp(top, bottom, fy).exp2()
+    }
+}
+
+// Saturation coefficients --saturation auto picks from, in increasing order
+const AUTO_SATURATION_CANDIDATES: [f32; 6] = [0.5, 0.75, 1.0, 1.25, 1.5, 2.0];
+
+// Share of pixels the picked saturation may push out of gamut
+const AUTO_SATURATION_CLIP_BUDGET: f32 = 0.01;
+
+// Roughly how many pixels to try each candidate on
+const AUTO_SATURATION_SAMPLES: usize = 65536;
+
+// Oklab chroma relative to lightness, so tone compression alone doesn't change it
+fn relative_chroma(rgb: Vec3) -> f32 {
+    let oklab = scrgb_to_oklab(rgb);
+    if oklab.l > EPSILON {
+        (oklab.a * oklab.a + oklab.b * oklab.b).sqrt() / oklab.l
+    } else {
+        0.0
+    }
+}
+
+/// Pick a per-image saturation for --saturation auto: the most vivid
+/// candidate that keeps at most the source's relative chroma and pushes
+/// few pixels out of gamut, or the least saturated if none do.
+fn auto_saturation(source: &PixelBuffer, options: &mut Options) -> f32 {
+    let step = (source.width * source.height / AUTO_SATURATION_SAMPLES).max(1);
+    let scale = options.scale;
+    let channel_mix = options.channel_mix;
+    let (source_chroma, samples) = source
+        .pixels()
+        .step_by(step)
+        .map(|rgb| (relative_chroma(channel_mix.mul_vec3(rgb * scale)), 1))
+        .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+    let mut best = None;
+    for &saturation in AUTO_SATURATION_CANDIDATES.iter() {
+        options.saturation = saturation;
+        let options = &*options;
+        let (chroma, out_of_gamut_count) = source
+            .pixels()
+            .step_by(step)
+            .map(|rgb| {
+                let mapped = hdr_to_sdr_unmapped(rgb, options);
+                (relative_chroma(clip(mapped)), out_of_gamut(mapped) as usize)
+            })
+            .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        let kept = chroma / source_chroma.max(EPSILON);
+        let clipped = out_of_gamut_count as f32 / samples as f32;
+        if best.is_none() || (kept <= 1.0 && clipped <= AUTO_SATURATION_CLIP_BUDGET) {
+            best = Some((saturation, kept, clipped));
+        }
+    }
+
+    let (saturation, kept, clipped) = best.expect("saturation candidates");
+    eprintln!(
+        "Auto saturation: {} ({:.0}% of the source's chroma, {:.1}% out of gamut)",
+        saturation,
+        kept * 100.0,
+        clipped * 100.0
+    );
+    saturation
+}
+
+// Roughly how many pixels to measure the hue shift on
+const HUE_SHIFT_SAMPLES: usize = 65536;
+
+// Oklab chroma below which a color is too near gray for its hue to count
+const HUE_SHIFT_MIN_CHROMA: f32 = 0.02;
+
+fn oklab_hue(c: Oklab) -> Option<f32> {
+    if (c.a * c.a + c.b * c.b).sqrt() < HUE_SHIFT_MIN_CHROMA {
+        None
+    } else {
+        Some(c.b.atan2(c.a).to_degrees())
+    }
+}
+
+// Print how far the tone map turned the oklab hue of colored pixels,
+// so the color cost of per-channel operators is visible.
+fn report_hue_shift(source: &PixelBuffer, options: &Options) {
+    let step = (source.width * source.height / HUE_SHIFT_SAMPLES).max(1);
+    let scale = options.scale;
+    let channel_mix = options.channel_mix;
+    let mut shifts = source
+        .pixels()
+        .step_by(step)
+        .filter_map(|rgb| {
+            let hue_in = oklab_hue(scrgb_to_oklab(channel_mix.mul_vec3(rgb * scale)))?;
+            let hue_out = oklab_hue(scrgb_to_oklab(hdr_to_sdr_unmapped(rgb, options)))?;
+            let shift = (hue_out - hue_in).abs() % 360.0;
+            // NaN input, or a tone map overflowing, has no hue to compare
+            Some(shift.min(360.0 - shift)).filter(|shift| shift.is_finite())
+        })
+        .collect::<Vec<f32>>();
+    if shifts.is_empty() {
+        return;
+    }
+    shifts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mean = shifts.iter().sum::<f32>() / shifts.len() as f32;
+    let p95 = shifts[(shifts.len() - 1) * 95 / 100];
+    eprintln!(
+        "Hue shift from the tone map: mean {:.1}°, 95th percentile {:.1}°; a luminance tone map like reinhard or hable keeps hue",
+        mean, p95
+    );
+}
+
+// Start a PNG file and write its header, ready for image rows.
+fn png_encoder(
+    filename: &Path,
+    width: usize,
+    height: usize,
+    color_type: mtpng::ColorType,
+    depth: u8,
+) -> Result<mtpng::encoder::Encoder<Box<dyn Write>>> {
+    use mtpng::encoder::{Encoder, Options};
+    use mtpng::{CompressionLevel, Header};
+
+    let writer = create_output(filename)?;
+
+    let mut options = Options::new();
+    options.set_compression_level(CompressionLevel::High)?;
+
+    let mut header = Header::new();
+    header.set_size(width as u32, height as u32)?;
+    header.set_color(color_type, depth)?;
+
+    let mut encoder = Encoder::new(writer, &options);
+    encoder.write_header(&header)?;
+    Ok(encoder)
+}
+
+fn latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars()
+        .map(|c| u8::try_from(u32::from(c)).ok())
+        .collect()
+}
+
+/// Parse a `key=value` tone map constant for `Settings::tone_map_params`.
+/// Keys no tone map takes are left for `convert` to report.
+pub fn parse_tone_map_param(source: &str) -> Result<(String, f32)> {
+    match source.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+            let value = value.parse()?;
+            if !tone_map_param_in_range(key, value) {
+                return Err(InvalidToneMapParam(source.to_string()));
+            }
+            Ok((key.to_string(), value))
+        }
+        _ => Err(InvalidToneMapParam(source.to_string())),
+    }
+}
+
+// White points and tile counts are divided by, so they can't be 0
+fn tone_map_param_in_range(key: &str, value: f32) -> bool {
+    match key {
+        "white" | "linear_white" | "tiles" => value.is_finite() && value > 0.0,
+        "exposure_bias" | "desaturation" => value.is_finite() && value >= 0.0,
+        _ => value.is_finite(),
+    }
+}
+
+/// Parse a --dpi print resolution, which must be positive.
+pub fn parse_dpi(source: &str) -> Result<f32> {
+    match source.trim().parse::<f32>() {
+        Ok(dpi) if dpi > 0.0 && dpi.is_finite() => Ok(dpi),
+        _ => Err(InvalidDpi(source.to_string())),
+    }
+}
+
+/// Parse a `key=value` metadata field for `Settings::add_metadata`.
+/// PNG keywords must be 1-79 Latin-1 characters.
+pub fn parse_metadata_field(source: &str) -> Result<(String, String)> {
+    match source.split_once('=') {
+        Some((key, value)) if (1..80).contains(&key.chars().count()) && latin1(key).is_some() => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(InvalidMetadata(source.to_string())),
+    }
+}
+
+const METERS_PER_INCH: f32 = 0.0254;
+
+// Write metadata chunks; must come between the header and the image rows.
+// Text that fits in Latin-1 goes in tEXt chunks, anything else in iTXt.
+fn write_png_metadata(
+    encoder: &mut mtpng::encoder::Encoder<Box<dyn Write>>,
+    metadata: &Metadata,
+) -> Result<()> {
+    if let Some(dpi) = metadata.dpi {
+        // The same pixels per meter both ways, with the unit byte 1 for meters
+        let pixels_per_meter = ((dpi / METERS_PER_INCH).round() as u32).to_be_bytes();
+        let mut data = pixels_per_meter.to_vec();
+        data.extend_from_slice(&pixels_per_meter);
+        data.push(1);
+        encoder.write_chunk(b"pHYs", &data)?;
+    }
+    if let Some(exif) = &metadata.exif {
+        encoder.write_chunk(b"eXIf", exif)?;
+    }
+    for (keyword, text) in &metadata.text {
+        let mut data = latin1(keyword).ok_or_else(|| InvalidMetadata(keyword.clone()))?;
+        data.push(0);
+        match latin1(text) {
+            Some(latin1_text) => {
+                data.extend_from_slice(&latin1_text);
+                encoder.write_chunk(b"tEXt", &data)?;
+            }
+            None => {
+                // Uncompressed, with empty language tag and translated keyword
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(text.as_bytes());
+                encoder.write_chunk(b"iTXt", &data)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_png(filename: &Path, data: &PixelBuffer, alpha: Option<&[f32]>) -> Result<()> {
+    let depth = match data.format {
+        SDR16bit | HDR16bit => 16,
+        _ => 8,
+    };
+    let color_type = match alpha {
+        Some(_) => mtpng::ColorType::TruecolorAlpha,
+        None => mtpng::ColorType::Truecolor,
+    };
+    let mut encoder = png_encoder(filename, data.width, data.height, color_type, depth)?;
+    if data.format == HDR16bit {
+        // BT.2020 primaries, PQ transfer, RGB, full range
+        encoder.write_chunk(b"cICP", &[9, 16, 0, 1])?;
+    }
+    write_png_metadata(&mut encoder, &data.metadata)?;
+    match alpha {
+        Some(alpha) => {
+            // Interleave the alpha after each pixel's RGB samples
+            let mut rgba = Vec::with_capacity(data.bytes().len() / 3 * 4);
+            for (rgb, &alpha) in data.bytes().chunks(data.bytes_per_pixel).zip(alpha) {
+                rgba.extend_from_slice(rgb);
+                match depth {
+                    16 => rgba.extend_from_slice(&((alpha * 65535.0).round() as u16).to_be_bytes()),
+                    _ => rgba.push((alpha * 255.0).round() as u8),
+                }
+            }
+            encoder.write_image_rows(&rgba)?;
+        }
+        None => encoder.write_image_rows(data.bytes())?,
+    }
+    encoder.finish()?.flush()?;
+
+    Ok(())
+}
+
+// Write 8-bit grayscale samples, as for --oog-mask
+fn write_gray_png(filename: &Path, width: usize, height: usize, data: &[u8]) -> Result<()> {
+    let mut encoder = png_encoder(filename, width, height, mtpng::ColorType::Greyscale, 8)?;
+    encoder.write_image_rows(data)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+// K-means passes refining the median cut colors for --palette
+const PALETTE_ITERATIONS: usize = 8;
+
+// Palette matching works in oklab, taking the output samples as sRGB
+fn srgb8_to_oklab(rgb: [u8; 3]) -> Vec3 {
+    let gamma = Vec3::new(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32) / 255.0;
+    let lab = scrgb_to_oklab(srgb_to_linear(gamma));
+    Vec3::new(lab.l, lab.a, lab.b)
+}
+
+fn oklab_to_srgb8(lab: Vec3) -> [u8; 3] {
+    let linear = oklab_to_scrgb(Oklab {
+        l: lab.x,
+        a: lab.y,
+        b: lab.z,
+    });
+    let gamma = (linear_to_srgb(linear.max(Vec3::ZERO)) * 255.0)
+        .round()
+        .clamp(Vec3::ZERO, Vec3::splat(255.0));
+    [gamma.x as u8, gamma.y as u8, gamma.z as u8]
+}
+
+fn nearest_color(palette: &[Vec3], lab: Vec3) -> usize {
+    palette
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            a.distance_squared(lab)
+                .partial_cmp(&b.distance_squared(lab))
+                .unwrap()
+        })
+        .map_or(0, |(index, _)| index)
+}
+
+fn weighted_mean(colors: &[(Vec3, usize)]) -> Vec3 {
+    let (sum, count) = colors
+        .iter()
+        .fold((Vec3::ZERO, 0), |(sum, total), &(lab, count)| {
+            (sum + lab * count as f32, total + count)
+        });
+    sum / count as f32
+}
+
+// Median cut in oklab: split the box of colors spreading furthest along
+// any axis at its pixel-weighted median, until there are enough boxes.
+// Their means then get a few rounds of k-means.
+fn build_palette(colors: &[(Vec3, usize)], size: usize) -> Vec<Vec3> {
+    let mut boxes = vec![colors.to_vec()];
+    while boxes.len() < size {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| {
+                let (min, max) = colors.iter().fold(
+                    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                    |(min, max), &(lab, _)| (min.min(lab), max.max(lab)),
+                );
+                let extent = max - min;
+                let axis = (0..3)
+                    .max_by(|&a, &b| extent[a].partial_cmp(&extent[b]).unwrap())
+                    .unwrap();
+                (index, axis, extent[axis])
+            })
+            .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+        let (index, axis) = match widest {
+            Some((index, axis, _)) => (index, axis),
+            None => break,
+        };
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_by(|a, b| a.0[axis].partial_cmp(&b.0[axis]).unwrap());
+        let total: usize = colors.iter().map(|&(_, count)| count).sum();
+        let mut seen = 0;
+        let split = colors
+            .iter()
+            .position(|&(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .map_or(1, |median| (median + 1).min(colors.len() - 1));
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    let mut palette: Vec<Vec3> = boxes.iter().map(|colors| weighted_mean(colors)).collect();
+    for _ in 0..PALETTE_ITERATIONS {
+        let nearest: Vec<usize> = colors
+            .par_iter()
+            .map(|&(lab, _)| nearest_color(&palette, lab))
+            .collect();
+        let mut sums = vec![(Vec3::ZERO, 0); palette.len()];
+        for (&(lab, count), index) in colors.iter().zip(nearest) {
+            sums[index].0 += lab * count as f32;
+            sums[index].1 += count;
+        }
+        for (color, (sum, count)) in palette.iter_mut().zip(sums) {
+            if count > 0 {
+                *color = sum / count as f32;
+            }
+        }
+    }
+    palette
+}
+
+// Map each pixel to the nearest palette color, spreading the difference
+// onto the pixels still to come with Floyd-Steinberg weights.
+fn dither_to_palette(data: &PixelBuffer, palette: &[Vec3]) -> Vec<u8> {
+    let width = data.width;
+    let mut indices = Vec::with_capacity(width * data.height);
+    // Errors carried to this row and the next, offset by one pixel
+    let mut errors = vec![Vec3::ZERO; width + 2];
+    let mut next_errors = vec![Vec3::ZERO; width + 2];
+    for row in data.bytes().chunks_exact(width * 3) {
+        for (x, rgb) in row.chunks_exact(3).enumerate() {
+            let lab = srgb8_to_oklab([rgb[0], rgb[1], rgb[2]]) + errors[x + 1];
+            let index = nearest_color(palette, lab);
+            let error = lab - palette[index];
+            errors[x + 2] += error * (7.0 / 16.0);
+            next_errors[x] += error * (3.0 / 16.0);
+            next_errors[x + 1] += error * (5.0 / 16.0);
+            next_errors[x + 2] += error * (1.0 / 16.0);
+            indices.push(index as u8);
+        }
+        errors = std::mem::replace(&mut next_errors, vec![Vec3::ZERO; width + 2]);
+    }
+    indices
+}
+
+// Write 8-bit SDR output as an indexed PNG for --palette. Images with
+// few enough colors keep them exactly; others get a palette picked in
+// oklab and are dithered to it.
+fn write_indexed_png(filename: &Path, data: &PixelBuffer, size: usize) -> Result<()> {
+    if !(2..=256).contains(&size) {
+        return Err(InvalidPaletteSize);
+    }
+    if data.format != SDR8bit {
+        return Err(PaletteNeedsPng);
+    }
+    let mut counts = HashMap::new();
+    for rgb in data.bytes().chunks_exact(3) {
+        *counts.entry([rgb[0], rgb[1], rgb[2]]).or_insert(0) += 1;
+    }
+    // Sorted so the palette doesn't depend on hash order
+    let mut counts: Vec<([u8; 3], usize)> = counts.into_iter().collect();
+    counts.sort_unstable();
+    let palette: Vec<[u8; 3]> = if counts.len() <= size {
+        counts.iter().map(|&(rgb, _)| rgb).collect()
+    } else {
+        let colors: Vec<(Vec3, usize)> = counts
+            .iter()
+            .map(|&(rgb, count)| (srgb8_to_oklab(rgb), count))
+            .collect();
+        build_palette(&colors, size)
+            .into_iter()
+            .map(oklab_to_srgb8)
+            .collect()
+    };
+    // Dither against the colors as written, after rounding
+    let palette_oklab: Vec<Vec3> = palette.iter().copied().map(srgb8_to_oklab).collect();
+    let indices = dither_to_palette(data, &palette_oklab);
+
+    let mut encoder = png_encoder(
+        filename,
+        data.width,
+        data.height,
+        mtpng::ColorType::IndexedColor,
+        8,
+    )?;
+    encoder.write_palette(&palette.concat())?;
+    write_png_metadata(&mut encoder, &data.metadata)?;
+    encoder.write_image_rows(&indices)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+fn write_exr(filename: &Path, data: &PixelBuffer, compression: ExrCompression) -> Result<()> {
+    use exr::prelude::*;
+
+    let channels = SpecificChannels::rgb(|pos: Vec2<usize>| {
+        let rgb = data.pixel(pos.x(), pos.y());
+        (rgb.x, rgb.y, rgb.z)
+    });
+    let encoding = Encoding {
+        compression: match compression {
+            ExrCompression::None => Compression::Uncompressed,
+            ExrCompression::Zip => Compression::ZIP16,
+            ExrCompression::Piz => Compression::PIZ,
+        },
+        ..Encoding::FAST_LOSSLESS
+    };
+    let layer = Layer::new(
+        (data.width, data.height),
+        LayerAttributes::default(),
+        encoding,
+        channels,
+    );
+    let image = Image::from_layer(layer);
+    if is_stdio(filename) {
+        // The EXR writer seeks back to fill in offsets, which a pipe can't
+        let mut buffer = io::Cursor::new(Vec::new());
+        image.write().to_buffered(&mut buffer)?;
+        io::stdout().write_all(buffer.get_ref())?;
+    } else {
+        image.write().to_file(filename)?;
+    }
+    Ok(())
+}
+
+// Start compressing a JPEG into memory, ready for scanlines.
+// mozjpeg reports errors by panicking, so callers must catch them.
+fn jpeg_compressor(width: usize, height: usize) -> mozjpeg::Compress {
+    use mozjpeg::{ColorSpace, Compress};
+    // @todo allow setting jpeg quality
+    let mut c = Compress::new(ColorSpace::JCS_EXT_RGB);
+    c.set_size(width, height);
+    c.set_quality(95.0);
+    c.set_mem_dest(); // can't write direct to file?
+    c.start_compress();
+    c
+}
+
+fn write_jpeg(filename: &Path, data: &PixelBuffer) -> Result<()> {
+    if data.format != SDR8bit {
+        return Err(UnsupportedOutputDepth);
+    }
+    // mozjpeg is much faster than image crate's encoder
+    let jpeg = std::panic::catch_unwind(|| {
+        let mut c = jpeg_compressor(data.width, data.height);
+        if !c.write_scanlines(data.bytes()) {
+            panic!("error writing scanlines");
+        }
+        c.finish_compress();
+        c.data_as_mut_slice()
+            .expect("error accessing JPEG output buffer")
+            .to_vec()
+    })
+    .map_err(|_| JpegWriteFailure)?;
+    let mut writer = create_output(filename)?;
+    writer.write_all(&jpeg)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Extra weight of the middle of the frame for center-weighted metering,
+// on top of the weight of 1 every pixel has
+const CENTER_WEIGHT: f32 = 3.0;
+
+// How fast the extra weight falls off, as the sigma of a gaussian in
+// fractions of the frame's width and height
+const CENTER_WEIGHT_SIGMA: f32 = 0.2;
+
+// Share of the frame's width and height spot metering reads
+const SPOT_SIZE: f32 = 0.1;
+
+// Weight of the pixel at this index for center-weighted metering
+fn center_weight(index: usize, width: usize, height: usize) -> f32 {
+    let dx = ((index % width) as f32 + 0.5) / width as f32 - 0.5;
+    let dy = ((index / width) as f32 + 0.5) / height as f32 - 0.5;
+    let sigma2 = CENTER_WEIGHT_SIGMA * CENTER_WEIGHT_SIGMA;
+    1.0 + CENTER_WEIGHT * (-(dx * dx + dy * dy) / (2.0 * sigma2)).exp()
+}
+
+// The middle of the frame spot metering reads, at least a pixel
+fn spot_region(source: &PixelBuffer) -> PixelBuffer {
+    let spot_width = ((source.width as f32 * SPOT_SIZE).round() as usize).max(1);
+    let spot_height = ((source.height as f32 * SPOT_SIZE).round() as usize).max(1);
+    source.crop(
+        (source.width - spot_width) / 2,
+        (source.height - spot_height) / 2,
+        spot_width,
+        spot_height,
+    )
+}
+
+struct Histogram {
+    values: Vec<f32>,
+    // Running total of the weights of the sorted values, for center-weighted
+    // metering; every value counts the same if None.
+    cumulative_weights: Option<Vec<f64>>,
+}
+
+impl Histogram {
+    fn new(source: &PixelBuffer) -> Self {
+        Self::with_values(source.pixels().map(luma_scrgb))
+    }
+
+    // Histogram of about `fraction` of the pixels for --histogram-sample:
+    // one picked at random from each run of 1/fraction pixels, so the
+    // sample is spread evenly over the image.
+    fn sampled(source: &PixelBuffer, fraction: f32, seed: u64, center_weighted: bool) -> Self {
+        let count = source.width * source.height;
+        let stride = ((1.0 / fraction).round() as usize).max(1);
+        let indices = (0..count.div_ceil(stride)).into_par_iter().map(|run| {
+            let offset = splitmix64(seed, run) as usize % stride;
+            (run * stride + offset).min(count - 1)
+        });
+        Self::of_pixels(source, indices, center_weighted)
+    }
+
+    // Luma histogram of the pixels at the given indices, weighted toward
+    // the middle of the frame for center-weighted metering.
+    fn of_pixels<T>(source: &PixelBuffer, indices: T, center_weighted: bool) -> Self
+    where
+        T: IndexedParallelIterator<Item = usize>,
+    {
+        let read_rgb_func = source.read_rgb_func;
+        let bytes_per_pixel = source.bytes_per_pixel;
+        let luma =
+            |index: usize| luma_scrgb(read_rgb_func(&source.data[index * bytes_per_pixel..]));
+        if !center_weighted {
+            return Self::with_values(indices.map(luma));
+        }
+        let mut weighted = Vec::new();
+        indices
+            .map(|index| {
+                let weight = center_weight(index, source.width, source.height);
+                (luma(index), weight)
+            })
+            .collect_into_vec(&mut weighted);
+        weighted.par_sort_unstable_by(|a, b| match a.0.partial_cmp(&b.0) {
+            Some(ordering) => ordering,
+            None => Ordering::Equal,
+        });
+        let cumulative_weights = weighted
+            .iter()
+            .scan(0.0f64, |total, (_, weight)| {
+                *total += f64::from(*weight);
+                Some(*total)
+            })
+            .collect();
+        Self {
+            values: weighted.into_iter().map(|(luma, _)| luma).collect(),
+            cumulative_weights: Some(cumulative_weights),
+        }
+    }
+
+    // Histogram of a single R, G, or B channel rather than luma
+    fn channel(source: &PixelBuffer, index: usize) -> Self {
+        Self::with_values(source.pixels().map(|rgb| rgb[index]))
+    }
+
+    fn with_values<T>(source: T) -> Self
+    where
+        T: IndexedParallelIterator<Item = f32>,
+    {
+        // @todo maybe do a proper histogram with buckets
+        // instead of sorting every pixel value
+        let mut values = Vec::<f32>::new();
+        source.collect_into_vec(&mut values);
+        values.par_sort_unstable_by(|a, b| match a.partial_cmp(b) {
+            Some(ordering) => ordering,
+            None => Ordering::Equal,
+        });
+        Self {
+            values,
+            cumulative_weights: None,
+        }
+    }
+
+    fn weight(&self, index: usize) -> f64 {
+        match &self.cumulative_weights {
+            None => 1.0,
+            Some(cumulative) if index == 0 => cumulative[0],
+            Some(cumulative) => cumulative[index] - cumulative[index - 1],
+        }
+    }
+
+    // Index of the value `percent` of the way through the weight of
+    // the values from start to end
+    fn weighted_index(cumulative: &[f64], start: usize, end: usize, percent: f32) -> usize {
+        let before = match start {
+            0 => 0.0,
+            _ => cumulative[start - 1],
+        };
+        let target = before + (cumulative[end - 1] - before) * f64::from(percent) / 100.0;
+        (start + cumulative[start..end].partition_point(|&total| total < target)).min(end - 1)
+    }
+
+    fn percentile(&self, target: f32) -> f32 {
+        let max_index = self.values.len() - 1;
+        let target_index = match &self.cumulative_weights {
+            None => (max_index as f32 * target / 100.0) as usize,
+            Some(cumulative) => Self::weighted_index(cumulative, 0, max_index + 1, target),
+        };
+        self.values[target_index]
+    }
+
+    // Percentile of the values left after dropping the brightest
+    // clip percent of them as outliers.
+    fn percentile_clipped(&self, target: f32, clip: f32) -> f32 {
+        let target_index = match &self.cumulative_weights {
+            None => {
+                let clipped = (self.values.len() as f32 * clip / 100.0) as usize;
+                let max_index = self.values.len().saturating_sub(clipped + 1);
+                (max_index as f32 * target / 100.0) as usize
+            }
+            Some(cumulative) => {
+                let kept = Self::weighted_index(cumulative, 0, self.values.len(), 100.0 - clip);
+                Self::weighted_index(cumulative, 0, kept + 1, target)
+            }
+        };
+        self.values[target_index]
+    }
+
+    fn average_below_percentile(&self, percent: f32) -> f32 {
+        self.average_below(self.percentile(percent))
+    }
+
+    fn average_below(&self, max: f32) -> f32 {
+        let (sum, count) =
+            self.values
+                .iter()
+                .enumerate()
+                .fold((0.0f32, 0.0f64), |(sum, count), (index, luma)| {
+                    if *luma > max {
+                        (sum, count)
+                    } else {
+                        let weight = self.weight(index);
+                        (sum + luma * weight as f32, count + weight)
+                    }
+                });
+        sum / count as f32
+    }
+}
+
+// Log-luminance histogram resolution for highlight_knee()
+const KNEE_BUCKETS_PER_STOP: f32 = 4.0;
+
+impl Histogram {
+    // Find the "knee" where the bright tail of the distribution begins.
+    // In a histogram of log luminance, that's where the CDF bends over
+    // most sharply above the median: its most negative second derivative,
+    // or the steepest fall-off of the (lightly smoothed) density.
+    fn highlight_knee(&self) -> f32 {
+        let positive = &self.values[self.values.partition_point(|val| *val <= 0.0)..];
+        let (lowest, highest) = match (positive.first(), positive.last()) {
+            (Some(lowest), Some(highest)) => (lowest.log2(), *highest),
+            _ => return 0.0,
+        };
+        let bucket = |val: f32| ((val.log2() - lowest) * KNEE_BUCKETS_PER_STOP) as usize;
+
+        let start = self.values.len() - positive.len();
+        let mut counts = vec![0.0f64; bucket(highest) + 2];
+        for (i, &val) in positive.iter().enumerate() {
+            counts[bucket(val)] += self.weight(start + i);
+        }
+        let density = (0..counts.len())
+            .map(|i| {
+                let window = &counts[i.saturating_sub(1)..(i + 2).min(counts.len())];
+                window.iter().sum::<f64>() as f32 / window.len() as f32
+            })
+            .collect::<Vec<f32>>();
+
+        let median = bucket(match &self.cumulative_weights {
+            None => positive[positive.len() / 2],
+            Some(cumulative) => {
+                self.values[Self::weighted_index(cumulative, start, self.values.len(), 50.0)]
+            }
+        });
+        let knee = (median..density.len() - 1)
+            .min_by(|&a, &b| {
+                let slope_a = density[a + 1] - density[a];
+                let slope_b = density[b + 1] - density[b];
+                slope_a.partial_cmp(&slope_b).unwrap_or(Ordering::Equal)
+            })
+            .unwrap_or(median);
+        // The top of the bucket the density falls off into
+        2.0f32
+            .powf(lowest + (knee + 2) as f32 / KNEE_BUCKETS_PER_STOP)
+            .min(highest)
+    }
+}
+
+// Stretch each channel separately so the given percent of its
+// darkest and brightest values are clipped, like auto-levels in
+// photo editors. This corrects color casts as well as contrast.
+fn auto_levels(source: &PixelBuffer, clip_percent: f32) -> PixelBuffer {
+    let mut low = Vec3::ZERO;
+    let mut high = Vec3::ONE;
+    for index in 0..3 {
+        let histogram = Histogram::channel(source, index);
+        low[index] = histogram.percentile(clip_percent);
+        high[index] = histogram.percentile(100.0 - clip_percent);
+    }
+    let range = (high - low).max(Vec3::splat(EPSILON));
+
+    let mut dest = PixelBuffer::new(source.width, source.height, HDRFloat32);
+    dest.fill(source.pixels().map(|rgb| (rgb - low) / range));
+    dest
+}
+
+fn scrgb_to_linear_srgb(c: Vec3) -> oklab::RGB<f32> {
+    oklab::RGB::new(c.x, c.y, c.z)
+}
+
+fn linear_srgb_to_scrgb(c: oklab::RGB<f32>) -> Vec3 {
+    Vec3::new(c.r, c.g, c.b)
+}
+
+fn scrgb_to_oklab(c: Vec3) -> Oklab {
+    linear_srgb_to_oklab(scrgb_to_linear_srgb(c))
+}
+
+// Cube root from a first guess made by dividing the float's exponent
+// in its bit pattern, refined by one Newton step. Good to about 0.1%.
+fn fast_cbrt(x: f32) -> f32 {
+    let a = x.abs();
+    if a < f32::MIN_POSITIVE {
+        return 0.0;
+    }
+    let y = f32::from_bits(a.to_bits() / 3 + 0x2a51_4067);
+    let y = (2.0 * y + a / (y * y)) / 3.0;
+    y.copysign(x)
+}
+
+// scrgb_to_oklab() with fast_cbrt(), for --fast previews only.
+// The matrices are oklab's own.
+fn scrgb_to_oklab_fast(c: Vec3) -> Oklab {
+    let l = fast_cbrt(0.41222147 * c.x + 0.53633254 * c.y + 0.051445993 * c.z);
+    let m = fast_cbrt(0.2119035 * c.x + 0.6806995 * c.y + 0.10739696 * c.z);
+    let s = fast_cbrt(0.08830246 * c.x + 0.28171884 * c.y + 0.6299787 * c.z);
+    Oklab {
+        l: 0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        a: 1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        b: 0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    }
+}
+
+fn oklab_to_scrgb(c: Oklab) -> Vec3 {
+    linear_srgb_to_scrgb(oklab_to_linear_srgb(c))
+}
+
+fn apply_levels(c_in: Vec3, level_min: f32, level_max: f32, gamma: f32, oklab: OklabFn) -> Vec3 {
+    let offset = level_min;
+    let scale = level_max - level_min;
+    let oklab_in = oklab(c_in);
+    let luma_in = luma_oklab(oklab_in);
+    let luma_out = ((luma_in - offset) / scale).powf(gamma);
+    let oklab_out = scale_oklab(oklab_in, luma_out, oklab);
+    oklab_to_scrgb(oklab_out)
+}
+
+struct Lazy<T, F>
+where
+    F: (FnOnce() -> T),
+{
+    value: Option<T>,
+    func: Option<F>,
+}
+
+impl<T, F> Lazy<T, F>
+where
+    F: (FnOnce() -> T),
+{
+    fn new(func: F) -> Self {
+        Lazy {
+            value: None,
+            func: Some(func),
+        }
+    }
+
+    fn force(&mut self) -> &T {
+        if self.value.is_none() {
+            let func = self.func.take().unwrap();
+            self.value = Some(func());
+        }
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<F> Lazy<Histogram, F>
+where
+    F: (FnOnce() -> Histogram),
+{
+    // Resolve a level, using the given function to map
+    // nits into the histogram's domain.
+    fn level<N>(&mut self, level: Level, nits: N) -> f32
+    where
+        N: Fn(f32) -> f32,
+    {
+        match level {
+            Level::Scalar(val) => val,
+            Level::Percentile(val) => self.force().percentile(val),
+            Level::Nits(val) => nits(val),
+            Level::Content => self.force().highlight_knee(),
+        }
+    }
+}
+
+// Apply the overwrite policy to an output file,
+// returning true if it should be skipped.
+fn skip_existing(output_filename: &Path, settings: &Settings) -> Result<bool> {
+    if is_stdio(output_filename) || !output_filename.exists() {
+        return Ok(false);
+    }
+    match settings.overwrite {
+        OverwritePolicy::Error => Err(OutputExists(output_filename.display().to_string())),
+        OverwritePolicy::Overwrite => Ok(false),
+        OverwritePolicy::Skip => {
+            eprintln!(
+                "INFO: Skipping existing file '{}'",
+                output_filename.display()
+            );
+            Ok(true)
+        }
+    }
+}
+
+// Parse a start:stop:step exposure range, inclusive of both ends.
+pub fn parse_exposure_bracket(source: &str) -> Result<Vec<f32>> {
+    let parts = source
+        .split(':')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()?;
+    match parts[..] {
+        [start, stop, step] if step > 0.0 && start <= stop => {
+            let count = ((stop - start) / step + EPSILON).floor() as usize + 1;
+            Ok((0..count).map(|i| start + step * i as f32).collect())
+        }
+        _ => Err(InvalidExposureBracket(source.to_string())),
+    }
+}
+
+// Parse a 3x3 matrix given as nine comma-separated values, row by row,
+// so the first three values make up the output red channel.
+pub fn parse_channel_mix(source: &str) -> Result<Mat3> {
+    let values = source
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()?;
+    match values[..] {
+        [a, b, c, d, e, f, g, h, i] => Ok(Mat3::from_cols(
+            Vec3::new(a, d, g),
+            Vec3::new(b, e, h),
+            Vec3::new(c, f, i),
+        )),
+        _ => Err(InvalidChannelMix(source.to_string())),
+    }
+}
+
+/// Parse the fraction of pixels for --histogram-sample.
+pub fn parse_histogram_sample(source: &str) -> Result<f32> {
+    match source.trim().parse::<f32>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(InvalidHistogramSample(source.to_string())),
+    }
+}
+
+/// Parse --grayscale weights for red, green and blue, scaled to sum to one
+/// so white stays white.
+pub fn parse_grayscale_mix(source: &str) -> Result<Vec3> {
+    let values = source
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()
+        .map_err(|_| InvalidGrayscaleMix(source.to_string()))?;
+    match values[..] {
+        [r, g, b] if r + g + b > 0.0 => Ok(Vec3::new(r, g, b) / (r + g + b)),
+        _ => Err(InvalidGrayscaleMix(source.to_string())),
+    }
+}
+
+// An sRGB "#rrggbb" color as linear, the # being optional
+fn parse_hex_color(source: &str) -> Option<Vec3> {
+    let hex = source.strip_prefix('#').unwrap_or(source);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok();
+    let srgb = Vec3::new(channel(0)? as f32, channel(1)? as f32, channel(2)? as f32) / 255.0;
+    Some(srgb_to_linear(srgb))
+}
+
+/// Parse --split-tone as comma-separated shadow=#rrggbb, highlight=#rrggbb
+/// and balance=N, any of which may be left out. Each color gives its hue
+/// and its chroma relative to its lightness, so a muted color tints gently.
+pub fn parse_split_tone(source: &str) -> Result<SplitTone> {
+    let invalid = || InvalidSplitTone(source.to_string());
+    let tint = |hex: &str| -> Result<Vec2> {
+        let oklab = scrgb_to_oklab(parse_hex_color(hex).ok_or_else(invalid)?);
+        if oklab.l > EPSILON {
+            Ok(Vec2::new(oklab.a, oklab.b) / oklab.l)
+        } else {
+            Ok(Vec2::ZERO)
+        }
+    };
+    let mut split_tone = SplitTone::default();
+    for part in source.split(',') {
+        match part
+            .split_once('=')
+            .map(|(key, val)| (key.trim(), val.trim()))
+        {
+            Some(("shadow", hex)) => split_tone.shadow = tint(hex)?,
+            Some(("highlight", hex)) => split_tone.highlight = tint(hex)?,
+            Some(("balance", val)) => match val.parse::<f32>() {
+                Ok(balance) if balance > 0.0 && balance < 1.0 => split_tone.balance = balance,
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(split_tone)
+}
+
+/// Parse --exposure as the stops for all channels, or three comma-separated
+/// values for red, green and blue, giving the uniform and per-channel parts.
+pub fn parse_exposure(source: &str) -> Result<(f32, Vec3)> {
+    let values = source
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()
+        .map_err(|_| InvalidExposure(source.to_string()))?;
+    match values[..] {
+        [stops] => Ok((stops, Vec3::ZERO)),
+        [r, g, b] => Ok((0.0, Vec3::new(r, g, b))),
+        _ => Err(InvalidExposure(source.to_string())),
+    }
+}
+
+// Parse a linear scRGB color given as three comma-separated values.
+pub fn parse_background(source: &str) -> Result<Vec3> {
+    let values = source
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()?;
+    match values[..] {
+        [r, g, b] => Ok(Vec3::new(r, g, b)),
+        _ => Err(InvalidBackground(source.to_string())),
+    }
+}
+
+/// Parse a --roi rectangle given as "x,y,width,height" in pixels.
+pub fn parse_roi(source: &str) -> Result<(usize, usize, usize, usize)> {
+    let values = source
+        .split(',')
+        .map(|part| part.trim().parse::<usize>())
+        .collect::<std::result::Result<Vec<usize>, _>>()
+        .map_err(|_| InvalidRoi(source.to_string()))?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok((x, y, width, height)),
+        _ => Err(InvalidRoi(source.to_string())),
+    }
+}
+
+/// Parse an --aspect ratio given as "width:height".
+pub fn parse_aspect(source: &str) -> Result<(usize, usize)> {
+    match source.split_once(':') {
+        Some((width, height)) => match (width.trim().parse(), height.trim().parse()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+            _ => Err(InvalidAspect(source.to_string())),
+        },
+        None => Err(InvalidAspect(source.to_string())),
+    }
+}
+
+/// Parse a --raw input size given as "widthxheight".
+pub fn parse_raw_size(source: &str) -> Result<(usize, usize)> {
+    match source.split_once('x') {
+        Some((width, height)) => match (width.trim().parse(), height.trim().parse()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+            _ => Err(InvalidRawSize(source.to_string())),
+        },
+        None => Err(InvalidRawSize(source.to_string())),
+    }
+}
+
+/// Parse a --tile-output grid given as "rows,cols".
+pub fn parse_tile_grid(source: &str) -> Result<(usize, usize)> {
+    match source.split_once(',') {
+        Some((rows, cols)) => match (rows.trim().parse(), cols.trim().parse()) {
+            (Ok(rows), Ok(cols)) if rows > 0 && cols > 0 => Ok((rows, cols)),
+            _ => Err(InvalidTileGrid(source.to_string())),
+        },
+        None => Err(InvalidTileGrid(source.to_string())),
+    }
+}
+
+// Settings --sweep can vary, all plain numbers
+const SWEEP_PARAMS: &[&str] = &[
+    "exposure",
+    "hdr-max",
+    "saturation",
+    "vibrance",
+    "shadows",
+    "highlights",
+    "grain",
+];
+
+/// Parse a --sweep of one setting over several values, like "hdr-max=200,400,800".
+pub fn parse_sweep(source: &str) -> Result<(String, Vec<f32>)> {
+    let invalid = || InvalidSweep(source.to_string());
+    let (param, values) = source.split_once('=').ok_or_else(invalid)?;
+    let param = param.trim();
+    if !SWEEP_PARAMS.contains(&param) {
+        return Err(invalid());
+    }
+    let values = values
+        .split(',')
+        .map(|part| match part.trim().parse::<f32>() {
+            Ok(val) if val.is_finite() => Ok(val),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<f32>>>()?;
+    for &value in &values {
+        check_sweep_value(param, value)?;
+    }
+    Ok((param.to_string(), values))
+}
+
+// Like Level::non_negative() for --hdr-max
+fn check_sweep_value(param: &str, value: f32) -> Result<()> {
+    if param == "hdr-max" && value < 0.0 {
+        return Err(LevelOutOfRange(
+            "hdr-max",
+            value.to_string(),
+            "it can't be negative",
+        ));
+    }
+    Ok(())
+}
+
+// The settings for one tile of a --sweep
+fn swept_settings(settings: &Settings, param: &str, value: f32) -> Result<Settings> {
+    check_sweep_value(param, value)?;
+    let mut swept = settings.clone();
+    match param {
+        "exposure" => swept.exposure = value,
+        "hdr-max" => swept.hdr_max = Level::Nits(value),
+        "saturation" => swept.saturation = value,
+        "vibrance" => swept.vibrance = value,
+        "shadows" => swept.shadows = value,
+        "highlights" => swept.highlights = value,
+        "grain" => swept.grain = value,
+        _ => return Err(InvalidSweep(param.to_string())),
+    }
+    swept.sweep = None;
+    Ok(swept)
+}
+
+/// Fill in an output file name template for the given input file.
+/// Placeholders are `{stem}` and `{ext}` of the input file name,
+/// `{date}` of the conversion as YYYY-MM-DD (UTC), and `{tonemap}`.
+pub fn expand_output_template(template: &str, input: &Path, settings: &Settings) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| InvalidOutputTemplate(template.to_string()))?;
+        match &rest[start + 1..start + end] {
+            "stem" => expanded.push_str(&input.file_stem().unwrap_or_default().to_string_lossy()),
+            "ext" => expanded.push_str(&input.extension().unwrap_or_default().to_string_lossy()),
+            "date" => expanded.push_str(&OffsetDateTime::now_utc().date().to_string()),
+            "tonemap" => expanded.push_str(&settings.tone_map),
+            _ => return Err(InvalidOutputTemplate(template.to_string())),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// out.png -> out-2.0.png, out+1.5.png etc
+fn exposure_bracket_filename(output_filename: &Path, exposure: f32) -> PathBuf {
+    let mut filename = output_filename
+        .file_stem()
+        .unwrap_or_default()
+        .to_os_string();
+    filename.push(format!("{:+.1}", exposure));
+    if let Some(ext) = output_filename.extension() {
+        filename.push(".");
+        filename.push(ext);
+    }
+    output_filename.with_file_name(filename)
+}
+
+// out.png -> out-r0-c0.png, out-r0-c1.png etc, for --tile-output
+fn tile_filename(output_filename: &Path, row: usize, col: usize) -> PathBuf {
+    let mut filename = output_filename
+        .file_stem()
+        .unwrap_or_default()
+        .to_os_string();
+    filename.push(format!("-r{}-c{}", row, col));
+    if let Some(ext) = output_filename.extension() {
+        filename.push(".");
+        filename.push(ext);
+    }
+    output_filename.with_file_name(filename)
+}
+
+// out.png -> out-hdr.exr, for --keep-hdr
+fn hdr_sidecar_filename(output_filename: &Path) -> PathBuf {
+    let mut filename = output_filename
+        .file_stem()
+        .unwrap_or_default()
+        .to_os_string();
+    filename.push("-hdr.exr");
+    output_filename.with_file_name(filename)
+}
+
+// Write the decoded input as it was read, before any processing.
+fn write_hdr_sidecar(
+    source: &PixelBuffer,
+    output_filename: &Path,
+    settings: &Settings,
+) -> Result<()> {
+    let sidecar_filename = hdr_sidecar_filename(output_filename);
+    if skip_existing(&sidecar_filename, settings)? {
+        return Ok(());
+    }
+    eprintln!("Keeping HDR -> {}", sidecar_filename.display());
+    time_func("write hdr sidecar", || {
+        write_exr(&sidecar_filename, source, settings.exr_compression)
+    })
+}
+
+// Keep in sync with supported_input_extensions()
+fn read_input(input_filename: &Path, settings: &Settings) -> Result<PixelBuffer> {
+    let format = InputFormat::of(input_filename, settings.input_format)?;
+    if format == InputFormat::Png {
+        check_png_transfer(input_filename, settings)?;
+    }
+    match format {
+        InputFormat::Png | InputFormat::Jxr if settings.best_effort && !settings.strict => {
+            read_best_effort(input_filename, settings.input_format)
+        }
+        InputFormat::Png => read_png(input_filename),
+        InputFormat::Jxr => read_jxr(input_filename),
+        InputFormat::Exr => read_exr(input_filename),
+        InputFormat::Hdr => read_hdr(input_filename),
+        InputFormat::Dds => dds::read_dds(input_filename),
+        InputFormat::Raw(width, height) => read_raw(input_filename, width, height),
+    }
+}
+
+/// Convert an HDR input file to SDR output.
+pub fn hdrfix(input_filename: &Path, output_filename: &Path, settings: &Settings) -> Result<()> {
+    if settings.exposure_bracket.is_none() && skip_existing(output_filename, settings)? {
+        return Ok(());
+    }
+
+    eprintln!(
+        "{} -> {}",
+        input_filename.display(),
+        output_filename.display()
+    );
+
+    if settings.linearize_only {
+        return linearize(input_filename, output_filename, settings);
+    }
+    if settings.inverse {
+        return inverse_tone_map(input_filename, output_filename, settings);
+    }
+    if settings.low_memory {
+        return hdrfix_low_memory(input_filename, output_filename, settings);
+    }
+
+    let source = time_func("read_input", || read_input(input_filename, settings))?;
+    convert(source, output_filename, settings)
+}
+
+/// Measure the input's auto exposure and white point as fixed levels,
+/// so other inputs can be converted with the same ones for --exposure-match.
+/// Scalar and nits levels are returned as they were.
+pub fn measure_levels(input_filename: &Path, settings: &Settings) -> Result<(Level, Level)> {
+    if settings.low_memory {
+        return Err(LowMemoryUnsupported("exposure-match"));
+    }
+    let source = read_input(input_filename, settings)?;
+    let source = prepare_source(source, settings)?;
+    let mut histogram = Lazy::new(|| input_histogram(&source, settings));
+    let auto_exposure = match settings.auto_exposure {
+        Level::Percentile(percent) => {
+            Level::Scalar(histogram.force().average_below_percentile(percent))
+        }
+        Level::Content => {
+            let histogram = histogram.force();
+            Level::Scalar(histogram.average_below(histogram.highlight_knee()))
+        }
+        level => level,
+    };
+    // Detected from just the region of interest, as tone_map() does
+    let detected = matches!(settings.hdr_max, Level::Content | Level::Percentile(_));
+    let roi_histogram = match settings.roi {
+        Some((x, y, roi_width, roi_height))
+            if detected && x < source.width && y < source.height =>
+        {
+            Some(Histogram::new(&source.crop(
+                x,
+                y,
+                roi_width.min(source.width - x),
+                roi_height.min(source.height - y),
+            )))
+        }
+        _ => None,
+    };
+    let hdr_max = match settings.hdr_max {
+        Level::Percentile(val) => Level::Nits(
+            match &roi_histogram {
+                Some(histogram) => histogram,
+                None => histogram.force(),
+            }
+            .percentile_clipped(val, settings.hdr_max_clip)
+                * SDR_WHITE,
+        ),
+        Level::Content => Level::Nits(
+            match &roi_histogram {
+                Some(histogram) => histogram,
+                None => histogram.force(),
+            }
+            .highlight_knee()
+                * SDR_WHITE,
+        ),
+        level => level,
+    };
+    Ok((auto_exposure, hdr_max))
+}
+
+/// Measure the input's peak and average luminance in nits for --suggest.
+/// The peak drops the brightest --hdr-max-clip percent as outliers.
+pub fn measure_nits(input_filename: &Path, settings: &Settings) -> Result<(f32, f32)> {
+    if settings.low_memory {
+        return Err(LowMemoryUnsupported("suggest"));
+    }
+    let source = read_input(input_filename, settings)?;
+    let source = prepare_source(source, settings)?;
+    let histogram = input_histogram(&source, settings);
+    Ok((
+        histogram.percentile_clipped(100.0, settings.hdr_max_clip) * SDR_WHITE,
+        histogram.average_below_percentile(100.0) * SDR_WHITE,
+    ))
+}
+
+// Write exactly what the decoder produced as linear scRGB, for --linearize-only.
+fn linearize(input_filename: &Path, output_filename: &Path, settings: &Settings) -> Result<()> {
+    if OutputFormat::of(output_filename, settings.output_format).ok() != Some(OutputFormat::Exr) {
+        return Err(LinearizeOnlyNeedsExr);
+    }
+    let source = time_func("read_input", || read_input(input_filename, settings))?;
+    let mut dest = PixelBuffer::new(source.width, source.height, HDRFloat32);
+    dest.fill(source.pixels());
+    time_func("write output", || {
+        write_exr(output_filename, &dest, settings.exr_compression)
+    })
+}
+
+// Peak in nits --inverse expands SDR white to when --hdr-max isn't in nits
+const INVERSE_DEFAULT_PEAK: f32 = 1000.0;
+
+// Solve the extended Reinhard curve y = x (1 + x / w²) / (1 + x) for x,
+// with whichever form of the quadratic formula doesn't cancel out.
+fn inverse_reinhard(y: f32, white: f32) -> f32 {
+    let b = 1.0 - y;
+    let root = (b * b + 4.0 * y / (white * white)).sqrt();
+    if b > 0.0 {
+        2.0 * y / (b + root)
+    } else {
+        white * white / 2.0 * (root - b)
+    }
+}
+
+// Expand SDR input into HDR for --inverse, undoing a Reinhard tone map
+// on luminance so SDR white reaches the peak and the shadows and mid
+// tones stay about where they were.
+fn inverse_tone_map(
+    input_filename: &Path,
+    output_filename: &Path,
+    settings: &Settings,
+) -> Result<()> {
+    let format = match OutputFormat::of(output_filename, settings.output_format) {
+        Ok(OutputFormat::Png) => HDR16bit,
+        Ok(OutputFormat::Exr) => HDRFloat32,
+        _ => return Err(InverseNeedsHdrOutput),
+    };
+    let mut source = time_func("read_input", || read_input(input_filename, settings))?;
+    // Integer input is SDR here, unless --input-transfer says otherwise
+    let transfer = settings.input_transfer.unwrap_or(Transfer::Srgb);
+    if let Some(format) = transfer.integer_format(source.format) {
+        source.reinterpret(format);
+    }
+
+    let white = match settings.hdr_max {
+        Level::Scalar(nits) | Level::Nits(nits) => nits,
+        _ => INVERSE_DEFAULT_PEAK,
+    } / SDR_WHITE;
+    let scale = exposure_scale(settings.exposure);
+    let luma = Primaries::Rec709.luma_coefficients();
+    let mut dest = PixelBuffer::new(source.width, source.height, format);
+    time_func("inverse tone map", || {
+        dest.fill(source.pixels().map(|rgb| {
+            let rgb = rgb * scale;
+            let luma_in = luma.dot(rgb);
+            if luma_in <= 0.0 {
+                return Vec3::ZERO;
+            }
+            // SDR white is as far as the curve goes
+            rgb * (inverse_reinhard(luma_in.min(1.0), white) / luma_in)
+        }));
+        Ok(())
+    })?;
+    dest.metadata = source.metadata.for_output(settings);
+    time_func("write output", || match format {
+        HDR16bit => write_png(output_filename, &dest, None),
+        _ => write_exr(output_filename, &dest, settings.exr_compression),
+    })
+}
+
+// Largest scRGB value the HDR formats can represent, 10000 nits
+const SCRGB_MAX: f32 = REC2100_MAX / SDR_WHITE;
+
+fn sanitize_value(val: f32, mode: Sanitize) -> f32 {
+    match mode {
+        _ if val.is_finite() => val,
+        Sanitize::Off => val,
+        Sanitize::Zero => 0.0,
+        Sanitize::Clamp if val == f32::INFINITY => SCRGB_MAX,
+        Sanitize::Clamp => 0.0,
+    }
+}
+
+// Replace NaN and infinite channel values in float input, which would
+// otherwise spread through the color math and histograms.
+// Returns how many channel values were replaced.
+fn sanitize(source: &mut PixelBuffer, mode: Sanitize) -> usize {
+    if mode == Sanitize::Off || !matches!(source.format, HDRFloat16 | HDRFloat32 | HDRFloat16PNG) {
+        return 0;
+    }
+    let read_rgb_func = source.read_rgb_func;
+    let write_rgb_func = source.write_rgb_func;
+    source
+        .par_iter_mut()
+        .map(|pixel| {
+            let rgb = read_rgb_func(pixel);
+            let bad = rgb.to_array().iter().filter(|val| !val.is_finite()).count();
+            if bad > 0 {
+                let fixed = Vec3::new(
+                    sanitize_value(rgb.x, mode),
+                    sanitize_value(rgb.y, mode),
+                    sanitize_value(rgb.z, mode),
+                );
+                write_rgb_func(pixel, fixed);
+            }
+            bad
+        })
+        .sum()
+}
+
+// For --strict, refuse float input holding NaN or infinite values, or
+// values past what the HDR formats can hold, rather than let sanitize()
+// and the tone map quietly fix them up.
+fn check_strict_values(source: &PixelBuffer, settings: &Settings) -> Result<()> {
+    if !settings.strict || !matches!(source.format, HDRFloat16 | HDRFloat32 | HDRFloat16PNG) {
+        return Ok(());
+    }
+    let (non_finite, out_of_range) = source
+        .pixels()
+        .map(|rgb| {
+            let vals = rgb.to_array();
+            let non_finite = vals.iter().filter(|val| !val.is_finite()).count();
+            let out_of_range = vals
+                .iter()
+                .filter(|val| val.is_finite() && val.abs() > SCRGB_MAX)
+                .count();
+            (non_finite, out_of_range)
+        })
+        .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    if non_finite > 0 {
+        Err(StrictNonFinite(non_finite))
+    } else if out_of_range > 0 {
+        Err(StrictOutOfRange(out_of_range))
+    } else {
+        Ok(())
+    }
+}
+
+fn report_sanitized(count: usize, mode: Sanitize) {
+    if count > 0 {
+        eprintln!(
+            "WARNING: Replaced {} NaN or infinite input values ({:?})",
+            count, mode
+        );
+    }
+}
+
+// The color to composite over, if the alpha mode flattens.
+// Giving a background implies flattening.
+fn flatten_background(settings: &Settings) -> Option<Vec3> {
+    match settings.alpha {
+        AlphaMode::Keep => settings.background,
+        AlphaMode::Strip => None,
+        AlphaMode::Flatten => Some(settings.background.unwrap_or(Vec3::ZERO)),
+    }
+}
+
+// Alpha of the prepared source, if --alpha keep left any transparency
+// to write to PNG output.
+fn output_alpha(source: &PixelBuffer) -> Option<Vec<f32>> {
+    let alpha = source
+        .par_iter()
+        .map(read_scrgb_alpha128float)
+        .collect::<Vec<f32>>();
+    if alpha.par_iter().all(|&alpha| alpha >= 1.0) {
+        None
+    } else {
+        Some(alpha)
+    }
+}
+
+// Composite float input over a solid background using its alpha channel,
+// with the linear "over" operator. Opaque pixels are left as they are.
+fn composite_over(source: &mut PixelBuffer, background: Option<Vec3>) {
+    let background = match background {
+        Some(background) => background,
+        None => return,
+    };
+    let read_alpha_func = match read_alpha_func(source.format) {
+        Some(func) => func,
+        // The other formats have no alpha
+        None => return,
+    };
+    let read_rgb_func = source.read_rgb_func;
+    let write_rgb_func = source.write_rgb_func;
+    source.par_iter_mut().for_each(|pixel| {
+        let alpha = read_alpha_func(pixel).max(0.0);
+        if alpha < 1.0 {
+            let rgb = read_rgb_func(pixel) * alpha + background * (1.0 - alpha);
+            write_rgb_func(pixel, rgb);
+        }
+    });
+}
+
+// Find the rectangle left after trimming border rows and columns with
+// no channel brighter than the threshold, as (x, y, width, height).
+// Returns None if there's nothing to trim, or nothing but black.
+fn black_border_trim(source: &PixelBuffer, threshold: f32) -> Option<(usize, usize, usize, usize)> {
+    let (width, height) = (source.width, source.height);
+    let black = (0..width * height)
+        .into_par_iter()
+        .map(|i| source.pixel(i % width, i / width).max_element() <= threshold)
+        .collect::<Vec<bool>>();
+    let row_black = |y: usize| black[y * width..(y + 1) * width].iter().all(|&b| b);
+    let column_black = |x: usize| (0..height).all(|y| black[y * width + x]);
+
+    let top = (0..height).find(|&y| !row_black(y))?;
+    let bottom = (0..height).rev().find(|&y| !row_black(y))?;
+    let left = (0..width).find(|&x| !column_black(x))?;
+    let right = (0..width).rev().find(|&x| !column_black(x))?;
+    let trim = (left, top, right - left + 1, bottom - top + 1);
+    if trim == (0, 0, width, height) {
+        None
+    } else {
+        Some(trim)
+    }
+}
+
+// Crop off letterbox bars, reporting what was found.
+fn trim_black(source: PixelBuffer, threshold_nits: Option<f32>) -> PixelBuffer {
+    let threshold = match threshold_nits {
+        Some(nits) => nits_to_scrgb(nits),
+        None => return source,
+    };
+    match black_border_trim(&source, threshold) {
+        Some((x, y, width, height)) => {
+            eprintln!(
+                "Trimming black borders: {}x{} at {},{} of {}x{}",
+                width, height, x, y, source.width, source.height
+            );
+            source.crop(x, y, width, height)
+        }
+        None => source,
+    }
+}
+
+// The largest centered rectangle of the image with the given aspect ratio
+fn aspect_crop(
+    width: usize,
+    height: usize,
+    aspect: (usize, usize),
+) -> (usize, usize, usize, usize) {
+    let (aspect_width, aspect_height) = aspect;
+    if width * aspect_height > height * aspect_width {
+        let cropped = ((height * aspect_width + aspect_height / 2) / aspect_height).max(1);
+        ((width - cropped) / 2, 0, cropped, height)
+    } else {
+        let cropped = ((width * aspect_height + aspect_width / 2) / aspect_width).max(1);
+        (0, (height - cropped) / 2, width, cropped)
+    }
+}
+
+// Cut the sides or the top and bottom off to the --aspect ratio,
+// reporting the size that's left.
+fn crop_to_aspect(source: PixelBuffer, aspect: Option<(usize, usize)>) -> PixelBuffer {
+    let aspect = match aspect {
+        Some(aspect) => aspect,
+        None => return source,
+    };
+    let (x, y, width, height) = aspect_crop(source.width, source.height, aspect);
+    eprintln!(
+        "Cropping to {}:{}: {}x{} at {},{} of {}x{}",
+        aspect.0, aspect.1, width, height, x, y, source.width, source.height
+    );
+    if (width, height) == (source.width, source.height) {
+        source
+    } else {
+        source.crop(x, y, width, height)
+    }
+}
+
+// Multiply the decoded values by --input-scale. Integer formats are
+// decoded to float first, so their encoding can't clip the result;
+// only the float formats have alpha to keep.
+fn scale_input(mut source: PixelBuffer, scale: f32) -> PixelBuffer {
+    if scale == 1.0 {
+        return source;
+    }
+    match source.format {
+        HDRFloat16 | HDRFloat32 | HDRFloat16PNG => {
+            let read_rgb_func = source.read_rgb_func;
+            let write_rgb_func = source.write_rgb_func;
+            source
+                .par_iter_mut()
+                .for_each(|pixel| write_rgb_func(pixel, read_rgb_func(pixel) * scale));
+            source
+        }
+        _ => {
+            let mut dest = PixelBuffer::new(source.width, source.height, HDRFloat32);
+            dest.fill(source.pixels().map(|rgb| rgb * scale));
+            dest.metadata = source.metadata;
+            dest
+        }
+    }
+}
+
+// Correct the input primaries and apply the pre-levels,
+// giving the float buffer the rest of the pipeline works on.
+fn prepare_source(mut source: PixelBuffer, settings: &Settings) -> Result<PixelBuffer> {
+    apply_input_transfer(&mut source, settings);
+    // Before scale_input() can turn integer input into float
+    let native_primaries = source.native_primaries();
+    check_strict_values(&source, settings)?;
+    report_sanitized(sanitize(&mut source, settings.sanitize), settings.sanitize);
+    let mut source = scale_input(source, settings.input_scale);
+    composite_over(&mut source, flatten_background(settings));
+    let source = trim_black(source, settings.trim_black);
+    let source = crop_to_aspect(source, settings.aspect);
+
+    let primaries_matrix = match settings.input_primaries {
+        Some(primaries) => primaries_correction(native_primaries, primaries),
+        None => Mat3::IDENTITY,
+    };
+    let primaries_matrix =
+        auto_white_balance(&source, primaries_matrix, settings)? * primaries_matrix;
+
+    let pre_gamma = settings.pre_gamma;
+    let mut pre_histogram = Lazy::new(|| Histogram::new(&source));
+    let pre_levels_min = pre_histogram.level(settings.pre_levels_min, nits_to_scrgb);
+    let pre_levels_max = pre_histogram.level(settings.pre_levels_max, nits_to_scrgb);
+    let mut dest = PixelBuffer::new(source.width, source.height, PixelFormat::HDRFloat32);
+    dest.fill(source.pixels().map(|rgb| {
+        apply_levels(
+            primaries_matrix.mul_vec3(rgb),
+            pre_levels_min,
+            pre_levels_max,
+            pre_gamma,
+            scrgb_to_oklab,
+        )
+    }));
+    // Carry alpha through for --alpha keep, otherwise the image is opaque now.
+    match read_alpha_func(source.format) {
+        Some(read_alpha) if settings.alpha == AlphaMode::Keep && settings.background.is_none() => {
+            dest.par_iter_mut()
+                .zip(source.par_iter())
+                .for_each(|(dest, src)| {
+                    write_scrgb_alpha128float(dest, read_alpha(src).clamp(0.0, 1.0))
+                })
+        }
+        _ => dest
+            .par_iter_mut()
+            .for_each(|dest| write_scrgb_alpha128float(dest, 1.0)),
+    }
+    dest.metadata = source.metadata;
+    Ok(dest)
+}
+
+// The histogram auto exposure and detected white points come from
+fn input_histogram(source: &PixelBuffer, settings: &Settings) -> Histogram {
+    let spot;
+    let source = match settings.metering {
+        Metering::Spot => {
+            spot = spot_region(source);
+            &spot
+        }
+        _ => source,
+    };
+    let center_weighted = settings.metering == Metering::Center;
+    match settings.histogram_sample {
+        Some(fraction) => Histogram::sampled(source, fraction, settings.seed, center_weighted),
+        None if center_weighted => {
+            let count = source.width * source.height;
+            Histogram::of_pixels(source, (0..count).into_par_iter(), true)
+        }
+        None => Histogram::new(source),
+    }
+}
+
+/// Convert an already-decoded HDR buffer to SDR output.
+pub fn convert(source: PixelBuffer, output_filename: &Path, settings: &Settings) -> Result<()> {
+    if settings.keep_hdr {
+        write_hdr_sidecar(&source, output_filename, settings)?;
+    }
+    let source = prepare_source(source, settings)?;
+    let mut input_histogram = Lazy::new(|| {
+        time_func("input histogram", || Ok(input_histogram(&source, settings))).unwrap()
+    });
+
+    if let Some((param, values)) = &settings.sweep {
+        if skip_existing(output_filename, settings)? {
+            return Ok(());
+        }
+        return write_sweep(
+            &source,
+            &mut input_histogram,
+            param,
+            values,
+            output_filename,
+            settings,
+        );
+    }
+
+    match &settings.exposure_bracket {
+        Some(exposures) => {
+            // Decode once, then run the rest of the pipeline at each exposure.
+            for &exposure in exposures {
+                let bracket_filename = exposure_bracket_filename(output_filename, exposure);
+                if skip_existing(&bracket_filename, settings)? {
+                    continue;
+                }
+                eprintln!("{:+.1} -> {}", exposure, bracket_filename.display());
+                tone_map_and_write(
+                    &source,
+                    &mut input_histogram,
+                    exposure,
+                    &bracket_filename,
+                    settings,
+                )?;
+            }
+            Ok(())
+        }
+        None => {
+            if skip_existing(output_filename, settings)? {
+                return Ok(());
+            }
+            tone_map_and_write(
+                &source,
+                &mut input_histogram,
+                settings.exposure,
+                output_filename,
+                settings,
+            )
+        }
+    }
+}
+
+/// Read the input once, then run the conversion stages after it the given
+/// number of times without writing any output, and print timing statistics
+/// for each stage. The output filename only picks the output format.
+pub fn benchmark(
+    input_filename: &Path,
+    output_filename: &Path,
+    settings: &Settings,
+    iterations: usize,
+) -> Result<()> {
+    let source = time_func("read_input", || read_input(input_filename, settings))?;
+    let source = prepare_source(source, settings)?;
+
+    TIMINGS.with(|timings| *timings.borrow_mut() = Some(Vec::new()));
+    let mut result = Ok(());
+    for _ in 0..iterations {
+        let mut input_histogram = Lazy::new(|| {
+            time_func("input histogram", || Ok(input_histogram(&source, settings))).unwrap()
+        });
+        result = time_func("total", || {
+            tone_map(
+                &source,
+                &mut input_histogram,
+                settings.exposure,
+                output_filename,
+                settings,
+                false,
+            )
+        })
+        .map(|_| ());
+        if result.is_err() {
+            break;
+        }
+    }
+    let timings = TIMINGS.with(|timings| timings.borrow_mut().take().unwrap_or_default());
+    result?;
+
+    // Report the stages in the order they first ran.
+    let mut stages: Vec<(String, Vec<f64>)> = Vec::new();
+    for (stage, ms) in timings {
+        match stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, samples)) => samples.push(ms),
+            None => stages.push((stage, vec![ms])),
+        }
+    }
+    println!("{} iterations, times in ms:", iterations);
+    for (stage, mut samples) in stages {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = samples
+            .iter()
+            .map(|ms| (ms - mean) * (ms - mean))
+            .sum::<f64>()
+            / count;
+        println!(
+            "{}: min {:.3} median {:.3} mean {:.3} stddev {:.3}",
+            stage,
+            samples[0],
+            samples[samples.len() / 2],
+            mean,
+            variance.sqrt()
+        );
+    }
+    Ok(())
+}
+
+/// The color matrix from the input's linear samples to the RGB the tone
+/// map works on: the read function's primaries conversion and scaling,
+/// --input-scale, --input-primaries, --channel-mix and --working-space
+/// in that order.
+/// Exposure, levels and the other non-linear steps aren't part of it.
+/// For HLG input it applies after the OOTF.
+pub fn color_matrix(input_filename: &Path, settings: &Settings) -> Result<Mat3> {
+    let mut source = read_input(input_filename, settings)?;
+    apply_input_transfer(&mut source, settings);
+    let primaries_matrix = match settings.input_primaries {
+        Some(primaries) => primaries_correction(source.native_primaries(), primaries),
+        None => Mat3::IDENTITY,
+    };
+    let to_working_space = working_space_to_scrgb(settings.working_space).inverse();
+    Ok(to_working_space
+        * settings.channel_mix
+        * primaries_matrix
+        * Mat3::from_diagonal(Vec3::splat(settings.input_scale))
+        * source.read_matrix())
+}
+
+/// Print the `color_matrix()` of the input, and the matrix back to the
+/// sRGB output primaries after tone mapping, a row per line.
+pub fn print_matrix(input_filename: &Path, settings: &Settings) -> Result<()> {
+    let print_rows = |matrix: Mat3| {
+        for i in 0..3 {
+            let row = matrix.row(i);
+            println!("{:12.6} {:12.6} {:12.6}", row.x, row.y, row.z);
+        }
+    };
+    println!("Input to tone map working space:");
+    print_rows(color_matrix(input_filename, settings)?);
+    println!("Working space to output, after tone mapping:");
+    print_rows(working_space_to_scrgb(settings.working_space));
+    Ok(())
+}
+
+// Largest difference between the matching values of two lists
+fn max_error<I>(pairs: I) -> f32
+where
+    I: Iterator<Item = (Vec3, Vec3)>,
+{
+    pairs.fold(0.0, |max, (a, b)| {
+        let error = (a - b).abs().max_element();
+        // NaN counts as a failure rather than being skipped by max()
+        if error.is_nan() || max.is_nan() {
+            f32::NAN
+        } else {
+            max.max(error)
+        }
+    })
+}
+
+fn matrix_error(a: Mat3, b: Mat3) -> f32 {
+    max_error((0..3).map(|i| (a.col(i), b.col(i))))
+}
+
+// The checks --self-test runs: a name, the largest error seen and the
+// most that's allowed.
+fn self_test_checks() -> Vec<(&'static str, f32, f32)> {
+    let ramp = |steps: usize| (0..=steps).map(move |i| i as f32 / steps as f32);
+    // Spaced evenly in log from 1e-6 to 1e3
+    let log_ramp = || ramp(900).map(|t| 10f32.powf(t * 9.0 - 6.0));
+    let all_primaries = [Primaries::Rec709, Primaries::Rec2020, Primaries::DisplayP3];
+    let reinhard = |x: f32, white: f32| x * (1.0 + x / (white * white)) / (1.0 + x);
+
+    vec![
+        (
+            "PQ round trip",
+            max_error(ramp(1024).map(|signal| {
+                let val = Vec3::splat(signal);
+                (linear_to_pq(pq_to_linear(val)), val)
+            })),
+            1e-4,
+        ),
+        (
+            "PQ reference levels",
+            // 10000 nits at full signal, 100 nits at 0.50807842
+            max_error(
+                [(1.0, 1.0), (0.508_078_4, 0.01)]
+                    .iter()
+                    .map(|&(signal, linear)| {
+                        let decoded = pq_to_linear(Vec3::splat(signal)) / linear;
+                        (decoded, Vec3::ONE)
+                    }),
+            ),
+            1e-4,
+        ),
+        (
+            "sRGB round trip",
+            max_error(ramp(1024).map(|linear| {
+                let val = Vec3::splat(linear);
+                (srgb_to_linear(linear_to_srgb(val)), val)
+            })),
+            1e-4,
+        ),
+        (
+            "oklab round trip",
+            max_error(ramp(8).flat_map(|r| {
+                ramp(8).flat_map(move |g| {
+                    ramp(8).map(move |b| {
+                        let val = Vec3::new(r, g, b);
+                        (oklab_to_scrgb(scrgb_to_oklab(val)), val)
+                    })
+                })
+            })),
+            1e-4,
+        ),
+        (
+            "oklab white",
+            max_error(std::iter::once({
+                let white = scrgb_to_oklab(Vec3::ONE);
+                (Vec3::new(white.l, white.a, white.b), Vec3::X)
+            })),
+            1e-4,
+        ),
+        (
+            "fast oklab cube root",
+            max_error(log_ramp().map(|val| {
+                let relative = fast_cbrt(val) / val.cbrt();
+                (Vec3::splat(relative), Vec3::ONE)
+            })),
+            2e-3,
+        ),
+        (
+            "BT.2100 matrix",
+            matrix_error(Primaries::Rec2020.to_scrgb(), rec2100_matrix()),
+            1e-4,
+        ),
+        (
+            "primaries keep white",
+            max_error(
+                all_primaries
+                    .iter()
+                    .map(|primaries| (primaries.to_scrgb().mul_vec3(Vec3::ONE), Vec3::ONE)),
+            ),
+            1e-4,
+        ),
+        (
+            "primaries round trip",
+            all_primaries
+                .iter()
+                .map(|primaries| {
+                    let matrix = primaries.to_scrgb();
+                    matrix_error(matrix * matrix.inverse(), Mat3::IDENTITY)
+                })
+                .fold(0.0, f32::max),
+            1e-5,
+        ),
+        (
+            "inverse Reinhard round trip",
+            max_error(log_ramp().map(|val| {
+                let white = INVERSE_DEFAULT_PEAK / SDR_WHITE;
+                let relative = inverse_reinhard(reinhard(val, white), white) / val;
+                (Vec3::splat(relative), Vec3::ONE)
+            })),
+            1e-4,
+        ),
+    ]
+}
+
+/// Check the color math on known values and round trips, printing the
+/// largest error of each check, for confirming a build behaves on an
+/// unusual platform. Fails if any check is out of tolerance.
+pub fn self_test() -> Result<()> {
+    let mut failed = 0;
+    for (name, error, tolerance) in self_test_checks() {
+        // NaN fails too
+        let pass = error <= tolerance;
+        if !pass {
+            failed += 1;
+        }
+        println!(
+            "{:<28} max error {:.1e}, allowed {:.0e}: {}",
+            name,
+            error,
+            tolerance,
+            if pass { "pass" } else { "FAIL" }
+        );
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(SelfTestFailed(failed)),
+    }
+}
+
+/// An input decoded once and shrunk to terminal size, for trying out
+/// tone mapping settings interactively as --tui does. The input settings
+/// (transfer, primaries, pre-levels and so on) are fixed when it's made.
+pub struct Preview {
+    source: PixelBuffer,
+    small: PixelBuffer,
+}
+
+impl Preview {
+    pub fn new(input_filename: &Path, max_width: usize, settings: &Settings) -> Result<Self> {
+        let source = time_func("read_input", || read_input(input_filename, settings))?;
+        let small = downscale(
+            &prepare_source(source.clone(), settings)?,
+            max_width,
+            settings.resize_filter,
+        );
+        Ok(Preview { source, small })
+    }
+
+    pub fn width(&self) -> usize {
+        self.small.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.small.height
+    }
+
+    /// Tone map the shrunken image, giving its 8-bit sRGB pixels in rows
+    /// and the percentage clipped if --warn-clip-above asked for it.
+    /// Auto exposure and hdr-max are detected from the shrunken image,
+    /// so may differ a little from the full size conversion.
+    pub fn render(&self, settings: &Settings) -> Result<(Vec<[u8; 3]>, Option<f32>)> {
+        let mut input_histogram = Lazy::new(|| input_histogram(&self.small, settings));
+        let (dest, clipped_percent) = tone_map(
+            &self.small,
+            &mut input_histogram,
+            settings.exposure,
+            Path::new("preview.png"),
+            settings,
+            settings.fast,
+        )?;
+        // 16-bit samples are big-endian, so the first byte is the 8-bit value.
+        let sample_bytes = dest.bytes_per_pixel / 3;
+        let pixels = dest
+            .bytes()
+            .chunks_exact(dest.bytes_per_pixel)
+            .map(|pixel| [pixel[0], pixel[sample_bytes], pixel[2 * sample_bytes]])
+            .collect();
+        Ok((pixels, clipped_percent))
+    }
+
+    /// Convert the full size input to the output file.
+    pub fn write(self, output_filename: &Path, settings: &Settings) -> Result<()> {
+        eprintln!("-> {}", output_filename.display());
+        convert(self.source, output_filename, settings)
+    }
+}
+
+// Shrink a buffer to at most the given width as linear HDRFloat32,
+// by a whole factor to keep the aspect ratio. Filtering happens in
+// linear light, one axis at a time.
+fn downscale(source: &PixelBuffer, max_width: usize, filter: ResizeFilter) -> PixelBuffer {
+    let factor = source.width.div_ceil(max_width.max(1)).max(1);
+    let width = source.width.div_ceil(factor);
+    let height = source.height.div_ceil(factor);
+    let mut dest = PixelBuffer::new(width, height, HDRFloat32);
+    if factor == 1 {
+        dest.fill(source.pixels());
+        return dest;
+    }
+
+    let x_weights = filter.weights(source.width, width, factor);
+    let y_weights = filter.weights(source.height, height, factor);
+    let columns = (0..width * source.height)
+        .into_par_iter()
+        .map(|i| {
+            let (start, weights) = &x_weights[i % width];
+            let y = i / width;
+            weights.iter().enumerate().fold(Vec3::ZERO, |sum, (j, w)| {
+                sum + source.pixel(start + j, y) * *w
+            })
+        })
+        .collect::<Vec<Vec3>>();
+    dest.fill((0..width * height).into_par_iter().map(|i| {
+        let (start, weights) = &y_weights[i / width];
+        let x = i % width;
+        weights.iter().enumerate().fold(Vec3::ZERO, |sum, (j, w)| {
+            sum + columns[(start + j) * width + x] * *w
+        })
+    }));
+    dest
+}
+
+// The operators of a --tone-map chain like "reinhard,aces", in the order
+// they're applied. A single tone map is a chain of one.
+fn tone_map_chain(tone_map: &str) -> impl Iterator<Item = &str> {
+    tone_map.split(',').map(str::trim)
+}
+
+// A tone map --tone-map can pick by name, with the constants
+// it takes through --tone-map-param.
+struct ToneMap {
+    name: &'static str,
+    func: ToneMapFn,
+    params: &'static [&'static str],
+    // Works on RGB channels or luminance, so runs in the --working-space.
+    // The others do their own color handling on scRGB.
+    working_space: bool,
+    // Compresses each channel on its own, shifting the hue of saturated colors
+    per_channel: bool,
+}
+
+// Registering a tone map or color map here is all it takes to make
+// it available on the command line and listed in --help.
+const TONE_MAPS: &[ToneMap] = &[
+    ToneMap {
+        name: "linear",
+        func: tonemap_linear,
+        params: &["linear_white"],
+        working_space: false,
+        per_channel: false,
+    },
+    ToneMap {
+        name: "reinhard",
+        func: tonemap_reinhard_oklab,
+        params: &["white"],
+        working_space: false,
+        per_channel: false,
+    },
+    ToneMap {
+        name: "reinhard-rgb",
+        func: tonemap_reinhard_rgb,
+        params: &["white"],
+        working_space: true,
+        per_channel: true,
+    },
+    ToneMap {
+        name: "adaptive-tiles",
+        func: tonemap_reinhard_oklab,
+        params: &["tiles"],
+        working_space: false,
+        per_channel: false,
+    },
+    ToneMap {
+        name: "reinhard-luminance",
+        func: tonemap_reinhard_luminance,
+        params: &["white"],
+        working_space: true,
+        per_channel: false,
+    },
+    ToneMap {
+        name: "aces",
+        func: tonemap_aces,
+        params: &["exposure_bias"],
+        working_space: false,
+        per_channel: true,
+    },
+    ToneMap {
+        name: "uncharted2",
+        func: tonemap_uncharted2,
+        params: &["exposure_bias", "linear_white"],
+        working_space: true,
+        per_channel: false,
+    },
+    ToneMap {
+        name: "hable",
+        func: tonemap_hable,
+        params: &["exposure_bias", "linear_white", "desaturation"],
+        working_space: true,
+        per_channel: false,
+    },
+];
+
+const COLOR_MAPS: &[(&str, ColorMapFn)] = &[
+    ("clip", color_clip),
+    ("clip-hue", color_clip_hue_oklab),
+    ("darken", color_darken_oklab),
+    ("desaturate", color_desat_oklab),
+    ("adaptive", color_adaptive_oklab),
+];
+
+/// Names of the tone maps --tone-map accepts.
+pub fn tone_map_names() -> Vec<&'static str> {
+    TONE_MAPS.iter().map(|tone_map| tone_map.name).collect()
+}
+
+/// Names of the color maps --color-map accepts.
+pub fn color_map_names() -> Vec<&'static str> {
+    COLOR_MAPS.iter().map(|&(name, _)| name).collect()
+}
+
+// Stands in for the chain when --tone-map is lut:FILE
+const TONE_MAP_LUT: ToneMap = ToneMap {
+    name: "lut",
+    func: tonemap_lut,
+    params: &[],
+    working_space: false,
+    per_channel: false,
+};
+
+fn find_tone_map(name: &str) -> Result<&'static ToneMap> {
+    TONE_MAPS
+        .iter()
+        .find(|tone_map| tone_map.name == name)
+        .ok_or_else(|| UnknownToneMap(name.to_string()))
+}
+
+fn find_color_map(name: &str) -> Result<ColorMapFn> {
+    COLOR_MAPS
+        .iter()
+        .find(|&&(color_map, _)| color_map == name)
+        .map(|&(_, func)| func)
+        .ok_or_else(|| UnknownColorMap(name.to_string()))
+}
+
+// The --channel-mix matrix after the per-channel exposure gains, so
+// the gains ride along wherever the mix is applied, GPU included.
+fn channel_matrix(settings: &Settings) -> Mat3 {
+    let gains = Vec3::new(
+        exposure_scale(settings.channel_exposure.x),
+        exposure_scale(settings.channel_exposure.y),
+        exposure_scale(settings.channel_exposure.z),
+    );
+    settings.channel_mix * Mat3::from_diagonal(gains)
+}
+
+// Skip the round trip through the matrices when they'd do nothing
+fn working_space_to_scrgb(working_space: Primaries) -> Mat3 {
+    match working_space {
+        Primaries::Rec709 => Mat3::IDENTITY,
+        primaries => primaries.to_scrgb(),
+    }
+}
+
+fn tone_map_options(settings: &Settings, scale: f32, hdr_max: f32) -> Result<Options> {
+    // A LUT replaces the built-in operators rather than joining a chain,
+    // so its path may contain commas.
+    let (tone_maps, tone_map_lut) = match settings.tone_map.strip_prefix("lut:") {
+        Some(filename) => (vec![&TONE_MAP_LUT], Some(Lut3d::load(Path::new(filename))?)),
+        None => (
+            tone_map_chain(&settings.tone_map)
+                .map(find_tone_map)
+                .collect::<Result<Vec<_>>>()?,
+            None,
+        ),
+    };
+    // Its white point varies over the image, which a chain can't follow
+    if tone_maps.len() > 1 && tone_maps.iter().any(|op| op.name == "adaptive-tiles") {
+        return Err(AdaptiveTilesChain(settings.tone_map.to_string()));
+    }
+    // Parameters go to every operator in the chain that takes them
+    if let Some(key) = settings.tone_map_params.keys().find(|key| {
+        !tone_maps
+            .iter()
+            .any(|tone_map| tone_map.params.contains(&key.as_str()))
+    }) {
+        return Err(UnknownToneMapParam(
+            key.to_string(),
+            settings.tone_map.to_string(),
+        ));
+    }
+    if let Some((key, value)) = settings
+        .tone_map_params
+        .iter()
+        .find(|(key, value)| !tone_map_param_in_range(key, **value))
+    {
+        return Err(InvalidToneMapParam(format!("{}={}", key, value)));
+    }
+    let from_working_space = working_space_to_scrgb(settings.working_space);
+    Ok(Options {
+        scale,
+        channel_mix: channel_matrix(settings),
+        shadows: settings.shadows,
+        highlights: settings.highlights,
+        // The Reinhard white point may be given in nits as a parameter
+        hdr_max: match settings.tone_map_params.get("white") {
+            Some(nits) => nits / SDR_WHITE * scale,
+            None => hdr_max,
+        },
+        saturation: settings.saturation,
+        vibrance: settings.vibrance,
+        max_chroma: settings.max_chroma,
+        to_working_space: from_working_space.inverse(),
+        from_working_space,
+        luma: settings.working_space.luma_coefficients(),
+        tone_map: tone_maps,
+        tone_map_params: settings.tone_map_params.clone(),
+        tone_map_lut,
+        tile_whites: None,
+        color_map: find_color_map(&settings.color_map)?,
+        oklab: scrgb_to_oklab,
+    })
+}
+
+// Run hdr_to_sdr_pixel() over the buffer on the GPU if possible,
+// returning false if the caller has to do it on the CPU instead.
+#[cfg(feature = "gpu")]
+fn gpu_hdr_to_sdr(
+    source: &PixelBuffer,
+    dest: &mut PixelBuffer,
+    settings: &Settings,
+    options: &Options,
+) -> Result<bool> {
+    gpu::hdr_to_sdr(source, dest, settings, options)
+}
+
+#[cfg(not(feature = "gpu"))]
+fn gpu_hdr_to_sdr(
+    _source: &PixelBuffer,
+    _dest: &mut PixelBuffer,
+    _settings: &Settings,
+    _options: &Options,
+) -> Result<bool> {
+    eprintln!("WARNING: hdrfix was built without the gpu feature, using the CPU");
+    Ok(false)
+}
+
+// Brightest channel of the input once exposed and mixed, as the tone map sees it
+fn exposed_peak(source: &PixelBuffer, options: &Options) -> f32 {
+    source
+        .pixels()
+        .map(|rgb| {
+            options
+                .channel_mix
+                .mul_vec3(rgb * options.scale)
+                .max_element()
+        })
+        .reduce(|| 0.0, f32::max)
+}
+
+fn is_jpeg(filename: &Path, settings: &Settings) -> bool {
+    OutputFormat::of(filename, settings.output_format).ok() == Some(OutputFormat::Jpeg)
+}
+
+// Share of neighboring pixels that differ by less than an 8-bit step,
+// above which the image is mostly smooth gradients that would band.
+const BANDING_THRESHOLD: f32 = 0.25;
+
+// Check gamma-encoded output luma for gradients too subtle for 8 bits.
+// Identical neighbors don't count, so flat areas don't call for 16 bits.
+fn banding_risk(luma: &[f32], width: usize) -> bool {
+    let step = 1.0 / 255.0;
+    let subtle: usize = luma
+        .par_chunks(width)
+        .map(|row| {
+            row.windows(2)
+                .filter(|pair| {
+                    let delta = (pair[1] - pair[0]).abs();
+                    delta > 0.0 && delta < step
+                })
+                .count()
+        })
+        .sum();
+    let pairs = luma.len() - luma.len() / width;
+    pairs > 0 && subtle as f32 / pairs as f32 > BANDING_THRESHOLD
+}
+
+fn tone_map_and_write<F>(
+    source: &PixelBuffer,
+    input_histogram: &mut Lazy<Histogram, F>,
+    exposure: f32,
+    output_filename: &Path,
+    settings: &Settings,
+) -> Result<()>
+where
+    F: (FnOnce() -> Histogram),
+{
+    let format = OutputFormat::of(output_filename, settings.output_format).ok();
+    if format == Some(OutputFormat::Exr) && !settings.false_color {
+        // Keep the HDR float data, only applying exposure and channel mixing.
+        let scale = input_scale(input_histogram, exposure, settings);
+        let channel_matrix = channel_matrix(settings);
+        let mut dest = PixelBuffer::new(source.width, source.height, HDRFloat32);
+        time_func("exposure", || {
+            dest.fill(
+                source
+                    .pixels()
+                    .map(|rgb| channel_matrix.mul_vec3(rgb * scale)),
+            );
+            Ok(())
+        })?;
+        return time_func("write output", || {
+            write_exr(output_filename, &dest, settings.exr_compression)
+        });
+    }
+
+    let (mut dest, clipped_percent) = if settings.false_color {
+        // Shows the input's luminance, so exposure and tone mapping don't apply.
+        print_false_color_legend();
+        let mut dest = PixelBuffer::new(source.width, source.height, SDR8bit);
+        dest.fill_quantized(
+            source.pixels().map(false_color_pixel),
+            settings.output_transfer,
+            settings.quantize,
+        );
+        (dest, None)
+    } else {
+        tone_map(
+            source,
+            input_histogram,
+            exposure,
+            output_filename,
+            settings,
+            false,
+        )?
+    };
+    dest.metadata = source.metadata.for_output(settings);
+    if settings.split_compare {
+        // Same exposure, but just clipped to SDR without any
+        // tone mapping, levels or color fixes.
+        let naive_settings = Settings {
+            auto_exposure: settings.auto_exposure,
+            channel_mix: settings.channel_mix,
+            channel_exposure: settings.channel_exposure,
+            tone_map: "linear".to_string(),
+            color_map: "clip".to_string(),
+            output_depth: match dest.format {
+                SDR16bit => OutputDepth::Sixteen,
+                _ => OutputDepth::Eight,
+            },
+            quantize: settings.quantize,
+            output_transfer: settings.output_transfer,
+            ..Settings::default()
+        };
+        let (naive, _) = tone_map(
+            source,
+            input_histogram,
+            exposure,
+            output_filename,
+            &naive_settings,
+            false,
+        )?;
+        dest.copy_left_half(&naive);
+    }
+    time_func("write output", || {
+        let alpha = output_alpha(source);
+        match settings.tile_output {
+            Some(grid) => write_tiles(output_filename, &dest, alpha.as_deref(), grid, settings),
+            None => write_output(output_filename, &dest, alpha.as_deref(), settings),
+        }
+    })?;
+    check_clipping(clipped_percent, settings)
+}
+
+// Write the output as a grid of separate files for --tile-output, with
+// the rows and columns spread as evenly as whole pixels allow.
+fn write_tiles(
+    output_filename: &Path,
+    dest: &PixelBuffer,
+    alpha: Option<&[f32]>,
+    (rows, cols): (usize, usize),
+    settings: &Settings,
+) -> Result<()> {
+    if is_stdio(output_filename) {
+        return Err(TileOutputToStdout);
+    }
+    if rows > dest.height || cols > dest.width {
+        return Err(TileGridTooFine(rows, cols, dest.width, dest.height));
+    }
+    for row in 0..rows {
+        let (y, y_end) = (row * dest.height / rows, (row + 1) * dest.height / rows);
+        for col in 0..cols {
+            let (x, x_end) = (col * dest.width / cols, (col + 1) * dest.width / cols);
+            let tile_filename = tile_filename(output_filename, row, col);
+            if skip_existing(&tile_filename, settings)? {
+                continue;
+            }
+            let tile = dest.crop(x, y, x_end - x, y_end - y);
+            let tile_alpha = alpha.map(|alpha| {
+                (y..y_end)
+                    .flat_map(|y| &alpha[y * dest.width + x..y * dest.width + x_end])
+                    .copied()
+                    .collect::<Vec<f32>>()
+            });
+            eprintln!("{},{} -> {}", row, col, tile_filename.display());
+            write_output(&tile_filename, &tile, tile_alpha.as_deref(), settings)?;
+        }
+    }
+    Ok(())
+}
+
+// Combined auto exposure and exposure adjustment for the input
+fn input_scale<F>(
+    input_histogram: &mut Lazy<Histogram, F>,
+    exposure: f32,
+    settings: &Settings,
+) -> f32
+where
+    F: (FnOnce() -> Histogram),
+{
+    exposure_scale(exposure) * 0.5
+        / match settings.auto_exposure {
+            Level::Scalar(level) => level,
+            Level::Percentile(percent) => input_histogram.force().average_below_percentile(percent),
+            Level::Nits(nits) => nits_to_scrgb(nits),
+            Level::Content => {
+                let histogram = input_histogram.force();
+                histogram.average_below(histogram.highlight_knee())
+            }
+        }
+}
+
+// Write an SDR buffer in the output file's format
+fn write_output(
+    output_filename: &Path,
+    dest: &PixelBuffer,
+    alpha: Option<&[f32]>,
+    settings: &Settings,
+) -> Result<()> {
+    // Keep in sync with supported_output_extensions()
+    match OutputFormat::of(output_filename, settings.output_format)? {
+        OutputFormat::Png => match (settings.palette, alpha) {
+            (Some(size), None) => write_indexed_png(output_filename, dest, size),
+            (Some(_), Some(_)) => Err(PaletteNeedsPng),
+            (None, _) => write_png(output_filename, dest, alpha),
+        },
+        _ if settings.palette.is_some() => Err(PaletteNeedsPng),
+        OutputFormat::Jpeg => write_jpeg(output_filename, dest),
+        // Only false color and sweeps reach here with float output
+        OutputFormat::Exr => write_exr(output_filename, dest, settings.exr_compression),
+    }
+}
+
+// Gray levels sampled for --export-curve, spaced evenly in log
+// luminance from CURVE_MIN_NITS to the 10000 nits PQ can encode
+const CURVE_SAMPLES: usize = 256;
+const CURVE_MIN_NITS: f32 = 0.001;
+
+// Write the luminance transfer of the conversion as CSV, running a ramp
+// of grays from the source through the given output mapping.
+fn write_curve<F>(filename: &Path, transfer: OutputTransfer, output_map: F) -> Result<()>
+where
+    F: Fn(Vec3) -> Vec3,
+{
+    let mut csv = String::from("input_nits,output_linear,output_signal\n");
+    let decades = (REC2100_MAX / CURVE_MIN_NITS).log10();
+    for i in 0..CURVE_SAMPLES {
+        let nits = CURVE_MIN_NITS * 10f32.powf(decades * i as f32 / (CURVE_SAMPLES - 1) as f32);
+        let luma = luma_rgb(output_map(Vec3::splat(nits_to_scrgb(nits))));
+        let signal = transfer.encode(Vec3::splat(luma)).x;
+        csv.push_str(&format!("{},{:.6},{:.6}\n", nits, luma, signal));
+    }
+    eprintln!("Tone curve -> {}", filename.display());
+    fs::write(filename, csv)?;
+    Ok(())
+}
+
+// Width in pixels of each tile of a --sweep grid
+const SWEEP_TILE_WIDTH: usize = 320;
+
+// Label glyphs for digits, '.' and '-', three pixels wide and five
+// tall, one row of three bits per octal digit from the top.
+fn label_glyph(c: char) -> Option<u16> {
+    Some(match c {
+        '0' => 0o75557,
+        '1' => 0o26227,
+        '2' => 0o71747,
+        '3' => 0o71717,
+        '4' => 0o55711,
+        '5' => 0o74717,
+        '6' => 0o74757,
+        '7' => 0o71111,
+        '8' => 0o75757,
+        '9' => 0o75711,
+        '.' => 0o00002,
+        '-' => 0o00700,
+        _ => return None,
+    })
+}
+
+// Label glyphs are drawn at this many output pixels per font pixel
+const LABEL_SCALE: usize = 2;
+const LABEL_MARGIN: usize = 2;
+const LABEL_HEIGHT: usize = 5 * LABEL_SCALE + 2 * LABEL_MARGIN;
+
+// Whether the pixel at x, y of a label band showing the text is lit
+fn label_pixel(text: &str, x: usize, y: usize) -> bool {
+    let (x, y) = match (x.checked_sub(LABEL_MARGIN), y.checked_sub(LABEL_MARGIN)) {
+        (Some(x), Some(y)) => (x / LABEL_SCALE, y / LABEL_SCALE),
+        _ => return false,
+    };
+    // One blank column between glyphs
+    match (text.chars().nth(x / 4).and_then(label_glyph), x % 4) {
+        (Some(glyph), column) if column < 3 && y < 5 => {
+            (glyph >> ((4 - y) * 3 + (2 - column))) & 1 == 1
+        }
+        _ => false,
+    }
+}
+
+// Tone map the source once for each --sweep value and tile shrunken
+// copies into a grid, left to right and top to bottom, each labeled
+// with its value.
+fn write_sweep<F>(
+    source: &PixelBuffer,
+    input_histogram: &mut Lazy<Histogram, F>,
+    param: &str,
+    values: &[f32],
+    output_filename: &Path,
+    settings: &Settings,
+) -> Result<()>
+where
+    F: (FnOnce() -> Histogram),
+{
+    if values.is_empty() {
+        return Err(InvalidSweep(param.to_string()));
+    }
+    let mut tiles = Vec::with_capacity(values.len());
+    for &value in values {
+        eprintln!("{}={}", param, value);
+        let swept = swept_settings(settings, param, value)?;
+        let (dest, _) = tone_map(
+            source,
+            input_histogram,
+            swept.exposure,
+            output_filename,
+            &swept,
+            settings.fast,
+        )?;
+        tiles.push(downscale(&dest, SWEEP_TILE_WIDTH, settings.resize_filter));
+    }
+
+    let columns = (values.len() as f32).sqrt().ceil() as usize;
+    let rows = values.len().div_ceil(columns);
+    let (tile_width, tile_height) = (tiles[0].width, tiles[0].height + LABEL_HEIGHT);
+    let (width, height) = (columns * tile_width, rows * tile_height);
+    let labels = values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>();
+    let mut grid = PixelBuffer::new(width, height, SDR8bit);
+    grid.fill((0..width * height).into_par_iter().map(|i| {
+        let (x, y) = (i % width, i / width);
+        let tile = y / tile_height * columns + x / tile_width;
+        let (x, y) = (x % tile_width, y % tile_height);
+        match tiles.get(tile) {
+            Some(_) if y < LABEL_HEIGHT => {
+                if label_pixel(&labels[tile], x, y) {
+                    Vec3::ONE
+                } else {
+                    Vec3::ZERO
+                }
+            }
+            Some(tile) => tile.pixel(x, y - LABEL_HEIGHT),
+            None => Vec3::ZERO,
+        }
+    }));
+    grid.metadata = source.metadata.for_output(settings);
+    time_func("write output", || {
+        write_output(output_filename, &grid, None, settings)
+    })
+}
+
+// Tone map, level and color map the source into an SDR buffer
+// ready to be written in the output file's format. Also gives the
+// percentage of pixels clipped, if --warn-clip-above asked for it.
+// fast_oklab swaps in the approximate oklab conversion, for previews.
+fn tone_map<F>(
+    source: &PixelBuffer,
+    input_histogram: &mut Lazy<Histogram, F>,
+    exposure: f32,
+    output_filename: &Path,
+    settings: &Settings,
+    fast_oklab: bool,
+) -> Result<(PixelBuffer, Option<f32>)>
+where
+    F: (FnOnce() -> Histogram),
+{
+    let width = source.width;
+    let height = source.height;
+
+    let scale = input_scale(input_histogram, exposure, settings);
+
+    // Detected white points come from just the region of interest if one was given,
+    // cut down to fit the image.
+    let roi = match settings.roi {
+        Some(roi @ (x, y, roi_width, roi_height)) => {
+            if x >= width || y >= height {
+                return Err(RoiOutsideImage(roi, width, height));
+            }
+            Some((x, y, roi_width.min(width - x), roi_height.min(height - y)))
+        }
+        None => None,
+    };
+    let roi_histogram = match roi {
+        Some((x, y, roi_width, roi_height))
+            if matches!(settings.hdr_max, Level::Content | Level::Percentile(_)) =>
+        {
+            Some(time_func("roi histogram", || {
+                Ok(Histogram::new(&source.crop(x, y, roi_width, roi_height)))
+            })?)
+        }
+        _ => None,
+    };
+
+    let hdr_max = match settings.hdr_max {
+        // hdr_max input is in nits if scalar, so scale it to scrgb
+        Level::Scalar(nits) | Level::Nits(nits) => nits / SDR_WHITE,
+
+        // Adapt to where the image's highlights actually begin
+        Level::Content => match &roi_histogram {
+            Some(histogram) => histogram,
+            None => input_histogram.force(),
+        }
+        .highlight_knee(),
+
+        // If given a percentile for hdr_max, detect from input histogram.
+        // Outliers are dropped first so a lone hot pixel can't set the white point.
+        Level::Percentile(val) => match &roi_histogram {
+            Some(histogram) => histogram,
+            None => input_histogram.force(),
+        }
+        .percentile_clipped(val, settings.hdr_max_clip),
+    } * scale;
+
+    let mut options = tone_map_options(settings, scale, hdr_max)?;
+    if fast_oklab {
+        options.oklab = scrgb_to_oklab_fast;
+    }
+    if settings.auto_skip_tone_map {
+        let peak = time_func("sdr check", || Ok(exposed_peak(source, &options)))?;
+        // Already SDR, where a curve could only darken it. Allow for
+        // rounding in the color conversions before this.
+        if peak <= 1.0 + EPSILON {
+            eprintln!(
+                "Input peaks at {:.3} of SDR white, skipping the tone map",
+                peak
+            );
+            options.tone_map = vec![find_tone_map("linear")?];
+            options.tone_map_params.clear();
+        }
+    }
+    // Only the Reinhard luminance modes have a saturation control
+    if settings.auto_saturation
+        && tone_map_chain(&settings.tone_map)
+            .any(|name| ["reinhard", "reinhard-luminance"].contains(&name))
+    {
+        options.saturation = time_func("auto saturation", || {
+            Ok(auto_saturation(source, &mut options))
+        })?;
+    }
+    if let [tone_map] = options.tone_map[..] {
+        if tone_map.name == "adaptive-tiles" {
+            let tiles = options.param("tiles", ADAPTIVE_TILES);
+            options.tile_whites = Some(time_func("tile white points", || {
+                Ok(TileWhites::new(source, settings, scale, tiles))
+            })?);
+        }
+    }
+    if !settings.ignore_hue_shift_warning && options.tone_map.iter().any(|op| op.per_channel) {
+        time_func("hue shift", || {
+            report_hue_shift(source, &options);
+            Ok(())
+        })?;
+    }
+
+    let mut tone_mapped = PixelBuffer::new(width, height, HDRFloat32);
+    time_func("hdr_to_sdr", || {
+        if !(settings.gpu && gpu_hdr_to_sdr(source, &mut tone_mapped, settings, &options)?) {
+            tone_mapped.fill(source.pixels().enumerate().map(|(index, rgb)| {
+                (options.color_map)(hdr_to_sdr_unmapped_at(rgb, index, &options))
+            }));
+        }
+        Ok(())
+    })?;
+
+    let tone_mapped = match settings.auto_levels {
+        Some(clip_percent) => time_func("auto levels", || {
+            Ok(auto_levels(&tone_mapped, clip_percent))
+        })?,
+        None => tone_mapped,
+    };
+    let tone_mapped = match settings.denoise_chroma {
+        sigma if sigma > 0.0 => {
+            time_func("denoise chroma", || Ok(denoise_chroma(&tone_mapped, sigma)))?
+        }
+        _ => tone_mapped,
+    };
+
+    // apply histogram expansion and color gamut correction to output
+    let mut lazy_histogram =
+        Lazy::new(|| time_func("levels histogram", || Ok(Histogram::new(&tone_mapped))).unwrap());
+    let post_nits = |nits| nits_to_sdr(nits, &options);
+    let post_levels_min = lazy_histogram.level(settings.post_levels_min, post_nits);
+    let post_levels_max = lazy_histogram.level(settings.post_levels_max, post_nits);
+    let post_gamma = settings.post_gamma;
+
+    if let Some(curve_filename) = &settings.export_curve {
+        time_func("export curve", || {
+            // Grain is left out, it isn't part of the curve.
+            write_curve(curve_filename, settings.output_transfer, |rgb| {
+                let rgb = hdr_to_sdr_pixel(rgb, &options);
+                let rgb = apply_levels(
+                    rgb,
+                    post_levels_min,
+                    post_levels_max,
+                    post_gamma,
+                    options.oklab,
+                );
+                let rgb = vibrance(rgb, options.vibrance);
+                let rgb = split_tone(rgb, settings.split_tone);
+                let rgb = clip((options.color_map)(clamp_chroma(rgb, options.max_chroma)));
+                grayscale(apply_lut(rgb, settings.lut.as_ref()), settings.grayscale)
+            })
+        })?;
+    }
+
+    // Takes the pixel index along with its color, to seed the grain.
+    let output_map = |(index, rgb)| {
+        // We have to color map again
+        // in case the histogram pushed things back out of gamut.
+        let rgb = apply_levels(
+            rgb,
+            post_levels_min,
+            post_levels_max,
+            post_gamma,
+            options.oklab,
+        );
+        let rgb = add_grain(rgb, settings.grain, settings.seed, index);
+        let rgb = vibrance(rgb, options.vibrance);
+        let rgb = split_tone(rgb, settings.split_tone);
+        let rgb = clip((options.color_map)(clamp_chroma(rgb, options.max_chroma)));
+        grayscale(apply_lut(rgb, settings.lut.as_ref()), settings.grayscale)
+    };
+
+    let output_format = match (settings.output_depth, is_jpeg(output_filename, settings)) {
+        (OutputDepth::Eight, _) | (OutputDepth::Auto, true) => SDR8bit,
+        (OutputDepth::Sixteen, true) => return Err(UnsupportedOutputDepth),
+        (OutputDepth::Sixteen, false) => SDR16bit,
+        // Palette entries are 8-bit
+        (OutputDepth::Auto, false) if settings.palette.is_some() => SDR8bit,
+        (OutputDepth::Auto, false) => time_func("banding check", || {
+            let luma = tone_mapped
+                .pixels()
+                .enumerate()
+                .map(output_map)
+                .map(|rgb| luma_rgb(linear_to_srgb(rgb)))
+                .collect::<Vec<f32>>();
+            Ok(if banding_risk(&luma, width) {
+                SDR16bit
+            } else {
+                SDR8bit
+            })
+        })?,
+    };
+
+    if let Some(mask_filename) = &settings.oog_mask {
+        time_func("write oog mask", || {
+            // Full white is twice SDR white or more
+            let mask = source
+                .pixels()
+                .enumerate()
+                .map(|(index, rgb)| {
+                    let excess = gamut_excess(hdr_to_sdr_unmapped_at(rgb, index, &options));
+                    (excess.min(1.0) * 255.0).round() as u8
+                })
+                .collect::<Vec<u8>>();
+            eprintln!("Out of gamut mask -> {}", mask_filename.display());
+            write_gray_png(mask_filename, width, height, &mask)
+        })?;
+    }
+
+    if let Some(diff_filename) = &settings.color_map_diff {
+        time_func("write color map diff", || {
+            let diff = source
+                .pixels()
+                .enumerate()
+                .map(|(index, rgb)| {
+                    let distance = color_map_difference(
+                        hdr_to_sdr_unmapped_at(rgb, index, &options),
+                        &options,
+                    );
+                    ((distance * COLOR_MAP_DIFF_GAIN).min(1.0) * 255.0).round() as u8
+                })
+                .collect::<Vec<u8>>();
+            eprintln!("Color map difference -> {}", diff_filename.display());
+            write_gray_png(diff_filename, width, height, &diff)
+        })?;
+    }
+
+    // Which pixels the tone map left out of gamut, before color mapping
+    let clip_counts = settings.clip_report.then(ClipCounts::new);
+    let oog_mask =
+        if settings.show_oog || settings.warn_clip_above.is_some() || settings.clip_report {
+            time_func("gamut check", || {
+                Ok(Some(
+                    source
+                        .pixels()
+                        .enumerate()
+                        .map(|(index, rgb)| {
+                            let unmapped = hdr_to_sdr_unmapped_at(rgb, index, &options);
+                            if let Some(counts) = &clip_counts {
+                                counts.add(unmapped);
+                            }
+                            out_of_gamut(unmapped)
+                        })
+                        .collect::<Vec<bool>>(),
+                ))
+            })?
+        } else {
+            None
+        };
+    if let Some(counts) = &clip_counts {
+        counts.report(width * height);
+    }
+
+    let clipped_percent = oog_mask.as_ref().map(|mask| {
+        100.0 * mask.par_iter().filter(|&&oog| oog).count() as f32 / (width * height) as f32
+    });
+
+    let mut dest = PixelBuffer::new(width, height, output_format);
+    time_func("output mapping", || {
+        match &oog_mask {
+            Some(mask) if settings.show_oog => dest.fill_quantized(
+                tone_mapped
+                    .pixels()
+                    .enumerate()
+                    .zip(mask.par_iter())
+                    .map(|(pixel, &oog)| {
+                        if oog {
+                            tint_out_of_gamut(output_map(pixel))
+                        } else {
+                            output_map(pixel)
+                        }
+                    }),
+                settings.output_transfer,
+                settings.quantize,
+            ),
+            _ => dest.fill_quantized(
+                tone_mapped.pixels().enumerate().map(output_map),
+                settings.output_transfer,
+                settings.quantize,
+            ),
+        }
+        Ok(())
+    })?;
+
+    Ok((dest, clipped_percent))
+}
+
+// Report how much of the image the tone map clipped, failing once the
+// output is written if it's over the --warn-clip-above threshold.
+fn check_clipping(clipped_percent: Option<f32>, settings: &Settings) -> Result<()> {
+    match (clipped_percent, settings.warn_clip_above) {
+        (Some(clipped), Some(threshold)) => {
+            eprintln!("Clipped {:.2}% of pixels", clipped);
+            if clipped > threshold {
+                eprintln!(
+                    "WARNING: clipped more than {}% of pixels, try a lower exposure or another tone or color map",
+                    threshold
+                );
+                return Err(ClipThresholdExceeded(clipped, threshold));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Rows decoded and converted at a time in low-memory mode.
+// JPEG XR decodes in 16-row macroblocks, so keep this a multiple of 16.
+const STRIP_ROWS: usize = 64;
+
+// Input decoders that can produce an image a strip of rows at a time.
+enum StripReader {
+    Png(Box<png::Reader<Box<dyn InputReader>>>),
+    Jxr(jpegxr::ImageDecode<Box<dyn InputReader>>),
+}
+
+impl StripReader {
+    fn open(
+        filename: &Path,
+        format: Option<InputFormat>,
+    ) -> Result<(Self, usize, usize, PixelFormat)> {
+        match InputFormat::of(filename, format)? {
+            InputFormat::Png => {
+                let mut decoder = png::Decoder::new(open_input(filename)?);
+                decoder.set_transformations(png::Transformations::IDENTITY);
+                let reader = decoder.read_info()?;
+                let info = reader.info();
+                let format = check_png_format(info)?;
+                let (width, height) = (info.width as usize, info.height as usize);
+                Ok((StripReader::Png(Box::new(reader)), width, height, format))
+            }
+            InputFormat::Jxr => {
+                let decoder = jpegxr::ImageDecode::with_reader(open_input(filename)?)?;
+                let (_, format) = jxr_buffer_format(&decoder)?;
+                let (width, height) = decoder.get_size()?;
+                Ok((
+                    StripReader::Jxr(decoder),
+                    width as usize,
+                    height as usize,
+                    format,
+                ))
+            }
+            format => Err(LowMemoryInput(format)),
+        }
+    }
+
+    // Metadata from the chunks before the image data; any after it
+    // can't be reached without decoding the whole image first.
+    fn metadata(&self, filename: &Path) -> Result<Metadata> {
+        match self {
+            StripReader::Png(reader) => Metadata::read_png(filename, reader.info()),
+            StripReader::Jxr(_) => Ok(Metadata::default()),
+        }
+    }
+
+    // Decode the rows starting at `y` into the strip buffer.
+    fn read_strip(&mut self, y: usize, strip: &mut PixelBuffer) -> Result<()> {
+        let stride = strip.width * strip.bytes_per_pixel;
+        match self {
+            StripReader::Png(reader) => {
+                let layout = png_layout(reader.info());
+                for dest in strip.bytes_mut().chunks_exact_mut(stride) {
+                    match reader.next_row()? {
+                        Some(row) => copy_png_samples(row.data(), dest, layout),
+                        None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                    }
+                }
+            }
+            StripReader::Jxr(decoder) => {
+                let rect = jpegxr::Rect::new(0, y as i32, strip.width as i32, strip.height as i32);
+                decoder.copy(&rect, strip.bytes_mut(), stride)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Decode a PNG or JPEG XR a strip at a time for --best-effort, so if the
+// file is cut short the rows before the damage can still be converted.
+// The rest of the image is left black. Metadata after the image data is
+// lost, as with --low-memory.
+fn read_best_effort(filename: &Path, format: Option<InputFormat>) -> Result<PixelBuffer> {
+    let (mut reader, width, height, format) = StripReader::open(filename, format)?;
+    // PNG can be read a row at a time, JPEG XR in 16-row macroblocks
+    let strip_rows = match reader {
+        StripReader::Png(_) => 1,
+        StripReader::Jxr(_) => 16,
+    };
+    let mut buffer = PixelBuffer::new(width, height, format);
+    buffer.metadata = reader.metadata(filename)?;
+    let stride = width * buffer.bytes_per_pixel;
+    let mut y = 0;
+    while y < height {
+        let rows = strip_rows.min(height - y);
+        let mut strip = PixelBuffer::new(width, rows, format);
+        if let Err(e) = reader.read_strip(y, &mut strip) {
+            eprintln!(
+                "WARNING: decoding failed after {} of {} rows, the rest is black: {}",
+                y, height, e
+            );
+            break;
+        }
+        buffer.data[y * stride..(y + rows) * stride].copy_from_slice(strip.bytes());
+        y += rows;
+    }
+    Ok(buffer)
+}
+
+// Output encoders that can take an image a strip of rows at a time.
+enum StripWriter {
+    Png(mtpng::encoder::Encoder<Box<dyn Write>>),
+    Jpeg(PathBuf, mozjpeg::Compress),
+}
+
+fn catch_jpeg<T, F: FnOnce() -> T>(func: F) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(func)).map_err(|_| JpegWriteFailure)
+}
+
+impl StripWriter {
+    fn create(
+        filename: &Path,
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+        metadata: &Metadata,
+        settings: &Settings,
+    ) -> Result<Self> {
+        let depth = match format {
+            SDR16bit => 16,
+            _ => 8,
+        };
+        match OutputFormat::of(filename, settings.output_format)? {
+            OutputFormat::Png => {
+                let mut encoder =
+                    png_encoder(filename, width, height, mtpng::ColorType::Truecolor, depth)?;
+                write_png_metadata(&mut encoder, metadata)?;
+                Ok(StripWriter::Png(encoder))
+            }
+            OutputFormat::Jpeg if depth != 8 => Err(UnsupportedOutputDepth),
+            OutputFormat::Jpeg => Ok(StripWriter::Jpeg(
+                filename.to_path_buf(),
+                catch_jpeg(|| jpeg_compressor(width, height))?,
+            )),
+            OutputFormat::Exr => Err(InvalidOutputFile),
+        }
+    }
+
+    fn write_strip(&mut self, strip: &PixelBuffer) -> Result<()> {
+        match self {
+            StripWriter::Png(encoder) => encoder.write_image_rows(strip.bytes())?,
+            StripWriter::Jpeg(_, c) => {
+                if !catch_jpeg(|| c.write_scanlines(strip.bytes()))? {
+                    return Err(JpegWriteFailure);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            StripWriter::Png(encoder) => {
+                encoder.finish()?.flush()?;
+            }
+            StripWriter::Jpeg(filename, mut c) => {
+                let data = catch_jpeg(|| {
+                    c.finish_compress();
+                    c.data_as_mut_slice().map(|data| data.to_vec())
+                })?
+                .map_err(|_| JpegWriteFailure)?;
+                let mut writer = create_output(&filename)?;
+                writer.write_all(&data)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Low-memory mode works on one strip of the image at a time,
+// so it can't use anything that needs statistics of the whole image.
+fn low_memory_level<N>(level: Level, name: &'static str, nits: N) -> Result<f32>
+where
+    N: Fn(f32) -> f32,
+{
+    match level {
+        Level::Scalar(val) => Ok(val),
+        Level::Percentile(_) | Level::Content => Err(LowMemoryUnsupported(name)),
+        Level::Nits(val) => Ok(nits(val)),
+    }
+}
+
+fn hdrfix_low_memory(
+    input_filename: &Path,
+    output_filename: &Path,
+    settings: &Settings,
+) -> Result<()> {
+    if settings.exposure_bracket.is_some() {
+        return Err(LowMemoryUnsupported("exposure-bracket"));
+    }
+    if settings.auto_levels.is_some() {
+        return Err(LowMemoryUnsupported("auto-levels"));
+    }
+    if settings.denoise_chroma > 0.0 {
+        return Err(LowMemoryUnsupported("denoise-chroma"));
+    }
+    if settings.split_compare {
+        return Err(LowMemoryUnsupported("split-compare"));
+    }
+    if settings.sweep.is_some() {
+        return Err(LowMemoryUnsupported("sweep"));
+    }
+    if settings.export_curve.is_some() {
+        return Err(LowMemoryUnsupported("export-curve"));
+    }
+    if settings.best_effort {
+        return Err(LowMemoryUnsupported("best-effort"));
+    }
+    if settings.oog_mask.is_some() {
+        return Err(LowMemoryUnsupported("oog-mask"));
+    }
+    if settings.color_map_diff.is_some() {
+        return Err(LowMemoryUnsupported("color-map-diff"));
+    }
+    if settings.trim_black.is_some() {
+        return Err(LowMemoryUnsupported("trim-black"));
+    }
+    if settings.aspect.is_some() {
+        return Err(LowMemoryUnsupported("aspect"));
+    }
+    if settings.keep_hdr {
+        return Err(LowMemoryUnsupported("keep-hdr"));
+    }
+    if settings.palette.is_some() {
+        return Err(LowMemoryUnsupported("palette"));
+    }
+    if settings.auto_wb != WhiteBalance::Off {
+        return Err(LowMemoryUnsupported("auto-wb"));
+    }
+    if settings.tile_output.is_some() {
+        return Err(LowMemoryUnsupported("tile-output"));
+    }
+    if settings.auto_saturation {
+        return Err(LowMemoryUnsupported("saturation auto"));
+    }
+    if settings.auto_skip_tone_map {
+        return Err(LowMemoryUnsupported("auto-skip-tonemap"));
+    }
+    if tone_map_chain(&settings.tone_map).any(|name| name == "adaptive-tiles") {
+        return Err(LowMemoryUnsupported("tone-map adaptive-tiles"));
+    }
+    if settings.detect_input_transfer && settings.input_transfer.is_none() {
+        return Err(LowMemoryUnsupported("gamma-input-detect"));
+    }
+    if InputFormat::of(input_filename, settings.input_format)? == InputFormat::Png {
+        check_png_transfer(input_filename, settings)?;
+    }
+
+    let (mut reader, width, height, format) =
+        StripReader::open(input_filename, settings.input_format)?;
+    let format = settings
+        .input_transfer
+        .and_then(|transfer| transfer.integer_format(format))
+        .unwrap_or(format);
+    let native_primaries = PixelBuffer::new(0, 0, format).native_primaries();
+    // Strips are written as they're converted, so there's no going back to
+    // make the PNG RGBA once some transparency turns up.
+    let keep_alpha = settings.alpha == AlphaMode::Keep
+        && settings.background.is_none()
+        && OutputFormat::of(output_filename, settings.output_format)? == OutputFormat::Png;
+    let read_alpha = read_alpha_func(format).filter(|_| keep_alpha);
+    let primaries_matrix = match settings.input_primaries {
+        Some(primaries) => primaries_correction(native_primaries, primaries),
+        None => Mat3::IDENTITY,
+    };
+
+    let pre_levels_min =
+        low_memory_level(settings.pre_levels_min, "pre-levels-min", nits_to_scrgb)?;
+    let pre_levels_max =
+        low_memory_level(settings.pre_levels_max, "pre-levels-max", nits_to_scrgb)?;
+    let scale = exposure_scale(settings.exposure) * 0.5
+        / low_memory_level(settings.auto_exposure, "auto-exposure", nits_to_scrgb)?;
+    let uses_white = tone_map_chain(&settings.tone_map)
+        .any(|name| matches!(name, "reinhard" | "reinhard-rgb" | "reinhard-luminance"));
+    let hdr_max = if uses_white && !settings.tone_map_params.contains_key("white") {
+        low_memory_level(settings.hdr_max, "hdr-max", |nits| nits)? / SDR_WHITE * scale
+    } else {
+        // The other tone maps don't use the white point.
+        1.0
+    };
+    let options = tone_map_options(settings, scale, hdr_max)?;
+    let post_nits = |nits| nits_to_sdr(nits, &options);
+    let post_levels_min = low_memory_level(settings.post_levels_min, "post-levels-min", post_nits)?;
+    let post_levels_max = low_memory_level(settings.post_levels_max, "post-levels-max", post_nits)?;
+    let output_format = match settings.output_depth {
+        OutputDepth::Eight => SDR8bit,
+        OutputDepth::Sixteen => SDR16bit,
+        OutputDepth::Auto => return Err(LowMemoryUnsupported("output-depth auto")),
+    };
+
+    let metadata = reader.metadata(input_filename)?.for_output(settings);
+    let mut writer = StripWriter::create(
+        output_filename,
+        width,
+        height,
+        output_format,
+        &metadata,
+        settings,
+    )?;
+    if settings.false_color {
+        print_false_color_legend();
+    }
+    let mut sanitized = 0;
+    let clipped = AtomicUsize::new(0);
+    let clip_counts = settings.clip_report.then(ClipCounts::new);
+    time_func("low memory conversion", || {
+        let mut y = 0;
+        while y < height {
+            let rows = STRIP_ROWS.min(height - y);
+            let mut strip = PixelBuffer::new(width, rows, format);
+            reader.read_strip(y, &mut strip)?;
+            if let Some(read_alpha) = read_alpha {
+                if strip.par_iter().any(|pixel| read_alpha(pixel) < 1.0) {
+                    return Err(LowMemoryUnsupported("alpha keep"));
+                }
+            }
+            check_strict_values(&strip, settings)?;
+            sanitized += sanitize(&mut strip, settings.sanitize);
+            composite_over(&mut strip, flatten_background(settings));
+
+            let mut dest = PixelBuffer::new(width, rows, output_format);
+            // Strips are a multiple of 4 rows, so dithering lines up across them.
+            dest.fill_quantized(
+                strip.pixels().enumerate().map(|(i, rgb)| {
+                    let rgb = apply_levels(
+                        primaries_matrix.mul_vec3(rgb * settings.input_scale),
+                        pre_levels_min,
+                        pre_levels_max,
+                        settings.pre_gamma,
+                        scrgb_to_oklab,
+                    );
+                    if settings.false_color {
+                        return false_color_pixel(rgb);
+                    }
+                    let unmapped = hdr_to_sdr_unmapped(rgb, &options);
+                    if let Some(counts) = &clip_counts {
+                        counts.add(unmapped);
+                    }
+                    let rgb = (options.color_map)(unmapped);
+                    let rgb = apply_levels(
+                        rgb,
+                        post_levels_min,
+                        post_levels_max,
+                        settings.post_gamma,
+                        scrgb_to_oklab,
+                    );
+                    let rgb = add_grain(rgb, settings.grain, settings.seed, y * width + i);
+                    let rgb = vibrance(rgb, options.vibrance);
+                    let rgb = split_tone(rgb, settings.split_tone);
+                    let rgb = clip((options.color_map)(clamp_chroma(rgb, options.max_chroma)));
+                    let rgb = grayscale(apply_lut(rgb, settings.lut.as_ref()), settings.grayscale);
+                    let oog = out_of_gamut(unmapped);
+                    if oog {
+                        clipped.fetch_add(1, AtomicOrdering::Relaxed);
+                    }
+                    if settings.show_oog && oog {
+                        tint_out_of_gamut(rgb)
+                    } else {
+                        rgb
+                    }
+                }),
+                settings.output_transfer,
+                settings.quantize,
+            );
+            writer.write_strip(&dest)?;
+            y += rows;
+        }
+        Ok(())
+    })?;
+    report_sanitized(sanitized, settings.sanitize);
+    writer.finish()?;
+    if let Some(counts) = &clip_counts {
+        if !settings.false_color {
+            counts.report(width * height);
+        }
+    }
+    let clipped_percent = if settings.false_color {
+        None
+    } else {
+        Some(100.0 * clipped.into_inner() as f32 / (width * height) as f32)
+    };
+    check_clipping(clipped_percent, settings)
+}
@@ -0,0 +1,102 @@
+// 3D color lookup tables in the .cube format used by Resolve and friends
+// https://resolve.cafe/developers/luts/
+
+use std::fs;
+use std::path::Path;
+
+use glam::f32::Vec3;
+
+use crate::LocalError::*;
+use crate::Result;
+
+#[derive(Clone)]
+pub struct Lut3d {
+    size: usize,
+    domain_min: Vec3,
+    domain_max: Vec3,
+    // Red varies fastest, then green, then blue
+    table: Vec<Vec3>,
+}
+
+fn parse_vec3(line: &str, values: &[&str]) -> Result<Vec3> {
+    match values {
+        [r, g, b] => Ok(Vec3::new(r.parse()?, g.parse()?, b.parse()?)),
+        _ => Err(InvalidLut(format!("expected three values in '{}'", line))),
+    }
+}
+
+impl Lut3d {
+    pub fn load(filename: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut size = None;
+        let mut domain_min = Vec3::ZERO;
+        let mut domain_max = Vec3::ONE;
+        let mut table = Vec::new();
+
+        for line in source.lines() {
+            let line = line.trim();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words[..] {
+                [] => {}
+                [word, ..] if word.starts_with('#') => {}
+                ["TITLE", ..] => {}
+                ["LUT_3D_SIZE", n] => {
+                    size = Some(n.parse().map_err(|_| InvalidLut(line.to_string()))?)
+                }
+                ["LUT_1D_SIZE", ..] => {
+                    return Err(InvalidLut("1D LUTs are not supported".to_string()))
+                }
+                ["DOMAIN_MIN", ref values @ ..] => domain_min = parse_vec3(line, values)?,
+                ["DOMAIN_MAX", ref values @ ..] => domain_max = parse_vec3(line, values)?,
+                ref values => table.push(parse_vec3(line, values)?),
+            }
+        }
+
+        let size: usize = size.ok_or_else(|| InvalidLut("missing LUT_3D_SIZE".to_string()))?;
+        if size < 2 || table.len() != size * size * size {
+            return Err(InvalidLut(format!(
+                "expected {} entries for size {}, found {}",
+                size * size * size,
+                size,
+                table.len()
+            )));
+        }
+        Ok(Self {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> Vec3 {
+        self.table[(b * self.size + g) * self.size + r]
+    }
+
+    /// Look up a color, trilinearly interpolating between table entries.
+    /// Input outside the LUT's domain is clamped to its edges.
+    pub fn sample(&self, rgb: Vec3) -> Vec3 {
+        let max_index = (self.size - 1) as f32;
+        let pos = ((rgb - self.domain_min) / (self.domain_max - self.domain_min))
+            .max(Vec3::ZERO)
+            .min(Vec3::ONE)
+            * max_index;
+        let low = pos.floor().min(Vec3::splat(max_index - 1.0));
+        let frac = pos - low;
+        let (r, g, b) = (low.x as usize, low.y as usize, low.z as usize);
+
+        let lerp = |a: Vec3, b: Vec3, t: f32| a + (b - a) * t;
+        let c00 = lerp(self.entry(r, g, b), self.entry(r + 1, g, b), frac.x);
+        let c10 = lerp(self.entry(r, g + 1, b), self.entry(r + 1, g + 1, b), frac.x);
+        let c01 = lerp(self.entry(r, g, b + 1), self.entry(r + 1, g, b + 1), frac.x);
+        let c11 = lerp(
+            self.entry(r, g + 1, b + 1),
+            self.entry(r + 1, g + 1, b + 1),
+            frac.x,
+        );
+        lerp(lerp(c00, c10, frac.y), lerp(c01, c11, frac.y), frac.z)
+    }
+}
@@ -1,978 +1,1034 @@
 #![warn(clippy::all)]
 
-use std::cmp::Ordering;
-use std::fs::File;
-use std::io::{self, Write};
-use std::num;
-use std::path::Path;
-use std::sync::mpsc::{channel, RecvError};
-use std::time::Duration;
-
-// Math bits
-use glam::f32::{Mat3, Vec3};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 // CLI bits
-use clap::{crate_version, App, Arg, ArgMatches};
-use time::OffsetDateTime;
-
-// Parallelism bits
-use rayon::prelude::*;
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches};
 
 // Directory watch bits
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
-// Error bits
-use thiserror::Error;
-
-type Result<T> = std::result::Result<T, LocalError>;
-
-// Color fun
-use oklab::{linear_srgb_to_oklab, oklab_to_linear_srgb, Oklab};
-
-// 16-bit floats
-use half::prelude::*;
-
-#[derive(Copy, Clone, Debug)]
-enum Level {
-    Scalar(f32),
-    Percentile(f32),
-}
-
-impl Level {
-    fn with_str(source: &str) -> Result<Self> {
-        match source.strip_suffix('%') {
-            Some(val) => Ok(Self::Percentile(val.parse()?)),
-            None => Ok(Self::Scalar(source.parse::<f32>()?)),
-        }
-    }
-}
-
-struct Options {
-    scale: f32,
-    hdr_max: f32,
-    saturation: f32,
-    tone_map: fn(Vec3, &Options) -> Vec3,
-    color_map: fn(Vec3) -> Vec3,
+// Math bits
+use glam::Mat3;
+
+// The actual conversion
+use hdrfix::LocalError::{self, *};
+use hdrfix::{
+    benchmark, color_map_names, expand_output_template, hdrfix, measure_levels, measure_nits,
+    parse_aspect, parse_background, parse_channel_mix, parse_dpi, parse_exposure,
+    parse_exposure_bracket, parse_grayscale_mix, parse_histogram_sample, parse_metadata_field,
+    parse_raw_size, parse_roi, parse_split_tone, parse_sweep, parse_tile_grid,
+    parse_tone_map_param, print_matrix, probe_input_as, self_test, tone_map_names, AlphaMode,
+    ExrCompression, Grayscale, InputFormat, Level, Lut3d, Metering, OutputDepth, OutputFormat,
+    OutputTransfer, OverwritePolicy, Primaries, Quantize, ResizeFilter, Result, Sanitize, Settings,
+    SplitTone, Transfer, WhiteBalance, SDR_WHITE,
+};
+
+mod tui;
+
+// Threshold in nits for --trim-black without a value
+const DEFAULT_TRIM_BLACK: f32 = 0.5;
+
+fn settings(args: &ArgMatches) -> Result<Settings> {
+    let (exposure, channel_exposure) = parse_exposure(args.value_of("exposure").unwrap())?;
+    Ok(Settings {
+        input_format: match (args.value_of("raw"), args.value_of("input-format")) {
+            (Some(size), _) => {
+                let (width, height) = parse_raw_size(size)?;
+                Some(InputFormat::Raw(width, height))
+            }
+            (None, Some(format)) => Some(InputFormat::with_str(format)?),
+            (None, None) => None,
+        },
+        output_format: match args.value_of("output-format") {
+            Some(format) => Some(OutputFormat::with_str(format)?),
+            None => None,
+        },
+        sanitize: Sanitize::with_str(args.value_of("sanitize").expect("sanitize arg"))?,
+        background: match args.value_of("background") {
+            Some(source) => Some(parse_background(source)?),
+            None => None,
+        },
+        alpha: AlphaMode::with_str(args.value_of("alpha").expect("alpha arg"))?,
+        trim_black: match args.value_of("trim-black") {
+            Some(nits) => Some(nits.parse()?),
+            None if args.is_present("trim-black") => Some(DEFAULT_TRIM_BLACK),
+            None => None,
+        },
+        aspect: match args.value_of("aspect") {
+            Some(source) => Some(parse_aspect(source)?),
+            None => None,
+        },
+        input_transfer: match args.value_of("input-transfer") {
+            Some(source) => Some(Transfer::with_str(source)?),
+            None => None,
+        },
+        detect_input_transfer: args.is_present("gamma-input-detect"),
+        input_primaries: match args.value_of("input-primaries") {
+            Some(source) => Some(Primaries::with_str(source)?),
+            None => None,
+        },
+        input_scale: args
+            .value_of("input-scale")
+            .expect("input-scale arg")
+            .parse()?,
+        auto_wb: WhiteBalance::with_str(args.value_of("auto-wb").expect("auto-wb arg"))?,
+        auto_exposure: Level::non_negative(
+            "auto-exposure",
+            args.value_of("auto-exposure").unwrap(),
+        )?,
+        exposure,
+        channel_exposure,
+        exposure_bracket: match args.value_of("exposure-bracket") {
+            Some(source) => Some(parse_exposure_bracket(source)?),
+            None => None,
+        },
+        tile_output: match args.value_of("tile-output") {
+            Some(source) => Some(parse_tile_grid(source)?),
+            None => None,
+        },
+        sweep: match args.value_of("sweep") {
+            Some(source) => Some(parse_sweep(source)?),
+            None => None,
+        },
+        channel_mix: match args.value_of("channel-mix") {
+            Some(source) => parse_channel_mix(source)?,
+            None => Mat3::IDENTITY,
+        },
+        shadows: args.value_of("shadows").expect("shadows arg").parse()?,
+        highlights: args
+            .value_of("highlights")
+            .expect("highlights arg")
+            .parse()?,
+        working_space: Primaries::with_str(
+            args.value_of("working-space").expect("working-space arg"),
+        )?,
+        tone_map: args.value_of("tone-map").expect("tone-map arg").to_string(),
+        tone_map_params: match args.values_of("tone-map-param") {
+            Some(params) => params.map(parse_tone_map_param).collect::<Result<_>>()?,
+            None => HashMap::new(),
+        },
+        hdr_max: Level::non_negative("hdr-max", args.value_of("hdr-max").unwrap())?,
+        hdr_max_clip: args
+            .value_of("hdr-max-clip")
+            .expect("hdr-max-clip arg")
+            .trim_end_matches('%')
+            .parse()?,
+        roi: match args.value_of("roi") {
+            Some(source) => Some(parse_roi(source)?),
+            None => None,
+        },
+        saturation: match args.value_of("saturation").expect("saturation arg") {
+            "auto" => 1.0,
+            saturation => saturation.parse()?,
+        },
+        auto_saturation: args.value_of("saturation") == Some("auto"),
+        vibrance: args.value_of("vibrance").expect("vibrance arg").parse()?,
+        split_tone: match args.value_of("split-tone") {
+            Some(source) => parse_split_tone(source)?,
+            None => SplitTone::default(),
+        },
+        grain: args.value_of("grain").expect("grain arg").parse()?,
+        seed: {
+            let seed = args.value_of("seed").expect("seed arg");
+            seed.parse().map_err(|_| InvalidSeed(seed.to_string()))?
+        },
+        metering: Metering::with_str(args.value_of("metering").expect("metering arg"))?,
+        histogram_sample: match args.value_of("histogram-sample") {
+            Some(fraction) => Some(parse_histogram_sample(fraction)?),
+            None => None,
+        },
+        max_chroma: match args.value_of("max-chroma") {
+            Some(chroma) => Some(chroma.parse()?),
+            None => None,
+        },
+        color_map: args
+            .value_of("color-map")
+            .expect("color-map arg")
+            .to_string(),
+        lut: match args.value_of_os("lut-apply") {
+            Some(filename) => Some(Lut3d::load(Path::new(filename))?),
+            None => None,
+        },
+        grayscale: match args.value_of("grayscale") {
+            Some(mix) => Some(Grayscale::Mix(parse_grayscale_mix(mix)?)),
+            None if args.is_present("grayscale") => Some(Grayscale::Lightness),
+            None => None,
+        },
+        pre_gamma: args.value_of("pre-gamma").expect("pre-gamma arg").parse()?,
+        pre_levels_min: Level::with_str(
+            "pre-levels-min",
+            args.value_of("pre-levels-min").expect("pre-levels-min arg"),
+        )?,
+        pre_levels_max: Level::with_str(
+            "pre-levels-max",
+            args.value_of("pre-levels-max").expect("pre-levels-max arg"),
+        )?,
+        post_gamma: args
+            .value_of("post-gamma")
+            .expect("post-gamma arg")
+            .parse()?,
+        post_levels_min: Level::with_str(
+            "post-levels-min",
+            args.value_of("post-levels-min")
+                .expect("post-levels-min arg"),
+        )?,
+        post_levels_max: Level::with_str(
+            "post-levels-max",
+            args.value_of("post-levels-max")
+                .expect("post-levels-max arg"),
+        )?,
+        auto_levels: match args.value_of("auto-levels") {
+            Some(percent) => Some(percent.trim_end_matches('%').parse()?),
+            None => None,
+        },
+        auto_skip_tone_map: args.is_present("auto-skip-tonemap"),
+        denoise_chroma: args
+            .value_of("denoise-chroma")
+            .expect("denoise-chroma arg")
+            .parse()?,
+        output_depth: OutputDepth::with_str(
+            args.value_of("output-depth").expect("output-depth arg"),
+        )?,
+        quantize: Quantize::with_str(args.value_of("quantize").expect("quantize arg"))?,
+        palette: match args.value_of("palette") {
+            Some(size) => Some(size.parse().map_err(|_| InvalidPaletteSize)?),
+            None => None,
+        },
+        output_transfer: OutputTransfer::with_str(
+            args.value_of("output-transfer")
+                .expect("output-transfer arg"),
+        )?,
+        exr_compression: ExrCompression::with_str(
+            args.value_of("exr-compression")
+                .expect("exr-compression arg"),
+        )?,
+        keep_hdr: args.is_present("keep-hdr"),
+        export_curve: args.value_of_os("export-curve").map(PathBuf::from),
+        linearize_only: args.is_present("linearize-only"),
+        inverse: args.is_present("inverse"),
+        add_metadata: match args.values_of("add-metadata") {
+            Some(fields) => fields.map(parse_metadata_field).collect::<Result<_>>()?,
+            None => Vec::new(),
+        },
+        dpi: match args.value_of("dpi") {
+            Some(source) => Some(parse_dpi(source)?),
+            None => None,
+        },
+        split_compare: args.is_present("split-compare"),
+        show_oog: args.is_present("show-oog"),
+        oog_mask: args.value_of_os("oog-mask").map(PathBuf::from),
+        color_map_diff: args.value_of_os("color-map-diff").map(PathBuf::from),
+        false_color: args.is_present("false-color"),
+        ignore_hue_shift_warning: args.is_present("ignore-hue-shift-warning"),
+        clip_report: args.is_present("clip-report"),
+        warn_clip_above: match args.value_of("warn-clip-above") {
+            Some(percent) => Some(percent.trim_end_matches('%').parse()?),
+            None => None,
+        },
+        low_memory: args.is_present("low-memory"),
+        best_effort: args.is_present("best-effort"),
+        strict: args.is_present("strict"),
+        resize_filter: ResizeFilter::with_str(
+            args.value_of("resize-filter").expect("resize-filter arg"),
+        )?,
+        fast: args.is_present("fast"),
+        gpu: args.is_present("gpu"),
+        overwrite: if args.is_present("overwrite") {
+            OverwritePolicy::Overwrite
+        } else if args.is_present("skip-existing") {
+            OverwritePolicy::Skip
+        } else {
+            OverwritePolicy::Error
+        },
+    })
 }
 
-enum PixelFormat {
-    SDR8bit,
-    HDR8bit,
-    HDRFloat16,
-    HDRFloat32,
-}
-use PixelFormat::*;
-
-// Note: currently assumes stride == width
-struct PixelBuffer {
-    width: usize,
-    height: usize,
-    bytes_per_pixel: usize,
-    data: Vec<u8>,
-
-    // If we wanted these could be traits
-    // but we don't need that level of complexity
-    read_rgb_func: fn(&[u8]) -> Vec3,
-    write_rgb_func: fn(&mut [u8], Vec3),
+// How output files are named when converting more than one file,
+// or when no output file name is given.
+struct OutputNaming<'a> {
+    template: Option<&'a str>,
+    suffix: &'a OsStr,
 }
 
-impl PixelBuffer {
-    fn new(width: usize, height: usize, format: PixelFormat) -> Self {
-        let bytes_per_pixel = match format {
-            SDR8bit | HDR8bit => 3,
-            HDRFloat16 => 8,
-            HDRFloat32 => 16,
-        };
-        let read_rgb_func = match format {
-            SDR8bit => read_srgb_rgb24,
-            HDR8bit => read_rec2100_rgb24,
-            HDRFloat16 => read_scrgb_rgb64half,
-            HDRFloat32 => read_scrgb_rgb128float,
-        };
-        let write_rgb_func = match format {
-            SDR8bit => write_srgb_rgb24,
-            HDR8bit => write_rec2100_rgb24,
-            HDRFloat16 => write_scrgb_rgb64half,
-            HDRFloat32 => write_scrgb_rgb128float,
-        };
-        let stride = width * bytes_per_pixel;
-        let size = stride * height;
-        let data = vec![0u8; size];
-
-        PixelBuffer {
-            width,
-            height,
-            bytes_per_pixel,
-            data,
-            read_rgb_func,
-            write_rgb_func,
+impl<'a> OutputNaming<'a> {
+    fn new(args: &'a ArgMatches) -> Self {
+        OutputNaming {
+            template: args.value_of("output-template"),
+            suffix: args
+                .value_of_os("output-suffix")
+                .expect("Output suffix must be set"),
         }
     }
 
-    fn bytes(&self) -> &[u8] {
-        &self.data
-    }
-
-    fn bytes_mut(&mut self) -> &mut [u8] {
-        &mut self.data
-    }
-
-    fn par_iter(&self) -> impl IndexedParallelIterator<Item = &[u8]> {
-        self.data.par_chunks(self.bytes_per_pixel)
-    }
-
-    fn par_iter_mut(&mut self) -> impl IndexedParallelIterator<Item = &mut [u8]> {
-        self.data.par_chunks_mut(self.bytes_per_pixel)
+    fn file_name(&self, input_path: &Path, settings: &Settings) -> Result<OsString> {
+        match self.template {
+            Some(template) => Ok(expand_output_template(template, input_path, settings)?.into()),
+            None => {
+                let mut file_name = input_path
+                    .file_stem()
+                    .ok_or(InvalidInputFile)?
+                    .to_os_string();
+                file_name.push(self.suffix);
+                Ok(file_name)
+            }
+        }
     }
+}
 
-    fn pixels(&self) -> impl '_ + IndexedParallelIterator<Item = Vec3> {
-        self.par_iter().map(self.read_rgb_func)
+fn dry_run(inputs: &[PathBuf], format: Option<InputFormat>) -> Result<()> {
+    let mut failures = 0;
+    for input_filename in inputs {
+        match probe_input_as(input_filename, format) {
+            Ok((width, height)) => {
+                println!("OK: {} ({}x{})", input_filename.display(), width, height)
+            }
+            Err(e) => {
+                println!("FAIL: {}: {}", input_filename.display(), e);
+                failures += 1;
+            }
+        }
     }
-
-    fn fill<T>(&mut self, source: T)
-    where
-        T: IndexedParallelIterator<Item = Vec3>,
-    {
-        let write_rgb_func = self.write_rgb_func;
-        self.par_iter_mut()
-            .zip(source)
-            .for_each(|(dest, rgb)| write_rgb_func(dest, rgb))
+    println!(
+        "{} of {} input files can be converted",
+        inputs.len() - failures,
+        inputs.len()
+    );
+    if failures > 0 {
+        Err(DryRunFailure(failures, inputs.len()))
+    } else {
+        Ok(())
     }
 }
 
-fn read_srgb_rgb24(_data: &[u8]) -> Vec3 {
-    panic!("not yet implemented");
-}
-
-fn write_srgb_rgb24(data: &mut [u8], val: Vec3) {
-    let gamma_out = linear_to_srgb(val);
-    let clipped = clip(gamma_out);
-    let scaled = clipped * 255.0;
-    data[0] = scaled.x as u8;
-    data[1] = scaled.y as u8;
-    data[2] = scaled.z as u8;
-}
-
-fn read_rec2100_rgb24(data: &[u8]) -> Vec3 {
-    let scale = Vec3::splat(1.0 / 255.0);
-    let rgb_rec2100 = Vec3::new(data[0] as f32, data[1] as f32, data[2] as f32) * scale;
-    let rgb_linear = pq_to_linear(rgb_rec2100);
-    rec2100_to_scrgb(rgb_linear)
+// How often to check a new file's size while waiting for it to stop growing.
+const SIZE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// The capture tool may still be writing when the debounced event arrives,
+// so wait until the file size stops changing before trying to decode it.
+fn wait_for_stable_size(path: &Path) -> Result<()> {
+    let mut size = fs::metadata(path)?.len();
+    loop {
+        thread::sleep(SIZE_POLL_INTERVAL);
+        let new_size = fs::metadata(path)?.len();
+        if new_size == size {
+            return Ok(());
+        }
+        size = new_size;
+    }
 }
 
-fn write_rec2100_rgb24(_data: &mut [u8], _rgb: Vec3) {
-    panic!("not yet implemented");
-}
+// Retries for a watched file that can't be read yet, e.g. because the
+// capture tool still holds it open. The wait doubles after each attempt.
+const WATCH_RETRIES: u32 = 4;
+const WATCH_RETRY_DELAY: Duration = Duration::from_millis(500);
 
-fn read_scrgb_rgb64half(data: &[u8]) -> Vec3 {
-    let data_ref_f16: &f16 = unsafe { std::mem::transmute(&data[0]) };
-    let data_f16 = unsafe { std::slice::from_raw_parts(data_ref_f16, data.len()) };
-    Vec3::new(
-        data_f16[0].to_f32(),
-        data_f16[1].to_f32(),
-        data_f16[2].to_f32(),
-    )
+// Errors that may go away if the file is given a bit more time.
+fn is_transient(e: &LocalError) -> bool {
+    matches!(e, IoError(_) | JXRError(_) | PNGDecodingError(_))
 }
 
-fn write_scrgb_rgb64half(data: &mut [u8], rgb: Vec3) {
-    let data_ref_f16: &mut f16 = unsafe { std::mem::transmute(&mut data[0]) };
-    let data_f16 = &mut unsafe { std::slice::from_raw_parts_mut(data_ref_f16, data.len()) };
-    data_f16[0] = f16::from_f32(rgb.x);
-    data_f16[1] = f16::from_f32(rgb.y);
-    data_f16[2] = f16::from_f32(rgb.z);
+fn convert_with_retry(input_path: &Path, output_path: &Path, settings: &Settings) -> Result<()> {
+    let mut delay = WATCH_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match hdrfix(input_path, output_path, settings) {
+            Err(e) if attempt < WATCH_RETRIES && is_transient(&e) => {
+                attempt += 1;
+                eprintln!(
+                    "Retrying {} in {} ms: {}",
+                    input_path.display(),
+                    delay.as_millis(),
+                    e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
 }
 
-fn read_scrgb_rgb128float(data: &[u8]) -> Vec3 {
-    let data_ref_f32: &f32 = unsafe { std::mem::transmute(&data[0]) };
-    let data_f32 = unsafe { std::slice::from_raw_parts(data_ref_f32, data.len()) };
-    Vec3::new(data_f32[0], data_f32[1], data_f32[2])
-}
+// Remembers the inputs of a watched folder that were converted, so a file
+// that's touched without being changed isn't converted again. Kept in a
+// dotfile in the folder so it lasts across runs of the watcher.
+const WATCH_CACHE_NAME: &str = ".hdrfix-cache";
 
-fn write_scrgb_rgb128float(data: &mut [u8], rgb: Vec3) {
-    let data_ref_f32: &mut f32 = unsafe { std::mem::transmute(&mut data[0]) };
-    let data_f32 = &mut unsafe { std::slice::from_raw_parts_mut(data_ref_f32, data.len()) };
-    data_f32[0] = rgb.x;
-    data_f32[1] = rgb.y;
-    data_f32[2] = rgb.z;
+// What a file looked like when it was converted
+#[derive(Copy, Clone, PartialEq)]
+struct FileStamp {
+    modified: u128,
+    size: u64,
 }
 
-#[derive(Error, Debug)]
-enum LocalError {
-    #[error("I/O error: {0}")]
-    IoError(#[from] io::Error),
-    #[error("numeric format error: {0}")]
-    ParseFloatError(#[from] num::ParseFloatError),
-    #[error("PNG decoding error: {0}")]
-    PNGDecodingError(#[from] png::DecodingError),
-    #[error("PNG input must be in 8bpp true color")]
-    PNGFormatError,
-    #[error("JPEG XR decoding error: {0}")]
-    JXRError(#[from] jpegxr::JXRError),
-    #[error("Invalid input file type")]
-    InvalidInputFile,
-    #[error("Invalid output file type")]
-    InvalidOutputFile,
-    #[error("Unsupported pixel format")]
-    UnsupportedPixelFormat,
-    #[error("Folder watch error")]
-    NotifyError(#[from] notify::Error),
-    #[error("Recv error")]
-    RecvError(#[from] RecvError),
-    #[error("Image format error")]
-    ImageError(#[from] image::ImageError),
-    #[error("JPEG write failure")]
-    JpegWriteFailure,
+impl FileStamp {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or(0);
+        Ok(FileStamp {
+            modified,
+            size: metadata.len(),
+        })
+    }
 }
-use LocalError::*;
 
-fn time_func<F, G>(msg: &str, func: F) -> Result<G>
-where
-    F: FnOnce() -> Result<G>,
-{
-    let start = OffsetDateTime::now_utc();
-    let result = func()?;
-    let delta = OffsetDateTime::now_utc() - start;
-    println!("{} in {} ms", msg, delta.as_seconds_f64() * 1000.0);
-    Ok(result)
+// Input paths relative to the watched folder, with the stamp of the file
+// that was converted. The dotfile has a line per input, tab separated.
+struct WatchCache {
+    folder: PathBuf,
+    entries: HashMap<String, FileStamp>,
 }
 
-// Read an input PNG and return its size and contents
-// It must be a certain format (8bpp true color no alpha)
-fn read_png(filename: &Path) -> Result<PixelBuffer> {
-    use png::Decoder;
-    use png::Transformations;
-
-    let mut decoder = Decoder::new(File::open(filename)?);
-    decoder.set_transformations(Transformations::IDENTITY);
-
-    let mut reader = decoder.read_info()?;
-    let info = reader.info();
-
-    if info.bit_depth != png::BitDepth::Eight {
-        return Err(PNGFormatError);
-    }
-    if info.color_type != png::ColorType::Rgb {
-        return Err(PNGFormatError);
+impl WatchCache {
+    // An unreadable or damaged cache just means converting again.
+    fn load(folder: &Path) -> Self {
+        let contents = fs::read_to_string(folder.join(WATCH_CACHE_NAME)).unwrap_or_default();
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let modified = fields.next()?.parse().ok()?;
+                let size = fields.next()?.parse().ok()?;
+                let key = fields.next()?.to_string();
+                Some((key, FileStamp { modified, size }))
+            })
+            .collect();
+        WatchCache {
+            folder: folder.to_path_buf(),
+            entries,
+        }
     }
 
-    let mut buffer = PixelBuffer::new(info.width as usize, info.height as usize, HDR8bit);
-    reader.next_frame(buffer.bytes_mut())?;
+    fn key(&self, input_path: &Path) -> String {
+        input_path
+            .strip_prefix(&self.folder)
+            .unwrap_or(input_path)
+            .to_string_lossy()
+            .into_owned()
+    }
 
-    Ok(buffer)
-}
+    // Whether the input is unchanged since it was converted to an output that's still there
+    fn is_current(&self, input_path: &Path, output_path: &Path, stamp: FileStamp) -> bool {
+        output_path.exists() && self.entries.get(&self.key(input_path)) == Some(&stamp)
+    }
 
-fn read_jxr(filename: &Path) -> Result<PixelBuffer> {
-    use jpegxr::ImageDecode;
-    use jpegxr::PixelFormat::*;
-    use jpegxr::Rect;
-
-    let input = File::open(filename)?;
-    let mut decoder = ImageDecode::with_reader(input)?;
-
-    let (width, height) = decoder.get_size()?;
-    let format = decoder.get_pixel_format()?;
-    let (bytes_per_pixel, buf_fmt) = match format {
-        PixelFormat128bppRGBAFloat => (16, HDRFloat32),
-        PixelFormat64bppRGBAHalf => (8, HDRFloat16),
-        _ => {
-            println!("Pixel format: {:?}", format);
-            return Err(UnsupportedPixelFormat);
+    fn insert(&mut self, input_path: &Path, stamp: FileStamp) -> Result<()> {
+        self.entries.insert(self.key(input_path), stamp);
+        let mut contents = String::new();
+        for (key, stamp) in &self.entries {
+            contents.push_str(&format!("{}\t{}\t{}\n", stamp.modified, stamp.size, key));
         }
-    };
-
-    let stride = width as usize * bytes_per_pixel;
-    let mut buffer = PixelBuffer::new(width as usize, height as usize, buf_fmt);
-
-    let rect = Rect::new(0, 0, width, height);
-    decoder.copy(&rect, buffer.bytes_mut(), stride)?;
-
-    Ok(buffer)
-}
-
-fn pq_to_linear(val: Vec3) -> Vec3 {
-    // fixme make sure all the splats are efficient constants
-    let inv_m1: f32 = 1.0 / 0.15930176;
-    let inv_m2: f32 = 1.0 / 78.84375;
-    let c1 = Vec3::splat(0.8359375);
-    let c2 = Vec3::splat(18.851563);
-    let c3 = Vec3::splat(18.6875);
-    let val_powered = val.powf(inv_m2);
-    (Vec3::max(val_powered - c1, Vec3::ZERO) / (c2 - c3 * val_powered)).powf(inv_m1)
-}
-
-fn rec2100_to_scrgb(val: Vec3) -> Vec3 {
-    let matrix = Mat3::from_cols_array(&[
-        1.6605, -0.1246, -0.0182, -0.5876, 1.1329, -0.1006, -0.0728, -0.0083, 1.1187,
-    ]);
-    let scale = REC2100_MAX / SDR_WHITE;
-    matrix.mul_vec3(val * scale)
-}
-
-fn luma_scrgb(val: Vec3) -> f32 {
-    luma_oklab(scrgb_to_oklab(val))
-}
-
-fn luma_oklab(val: Oklab) -> f32 {
-    // oklab's l is not linear
-    // so translate it back to linear srgb desaturated
-    // and take one of its rgb values
-    let oklab_gray = Oklab {
-        l: val.l,
-        a: 0.0,
-        b: 0.0,
-    };
-    let rgb_gray = oklab_to_scrgb(oklab_gray);
-    rgb_gray.x
-}
-
-fn tonemap_linear(c_in: Vec3, _options: &Options) -> Vec3 {
-    c_in
-}
-
-fn tonemap_reinhard_rgb(c_in: Vec3, options: &Options) -> Vec3 {
-    // Variant that maps R, G, and B channels separately.
-    // This should desaturate very bright colors gradually, but will
-    // possible cause some color shift.
-    let white = options.hdr_max;
-    let white2 = white * white;
-    c_in * (Vec3::ONE + c_in / white2) / (Vec3::ONE + c_in)
-}
-
-fn tonemap_reinhard_oklab(c_in: Vec3, options: &Options) -> Vec3 {
-    // Map luminance from HDR to SDR domain, and scale the input color
-    // in oklab perceptual color space.
-    //
-    // oklab color space: https://bottosson.github.io/posts/oklab/
-    //
-    let white = options.hdr_max;
-    let white2 = white * white;
-
-    // use Oklab's L coordinate as luminance
-    let oklab_in = scrgb_to_oklab(c_in);
-    let luma_in = luma_oklab(oklab_in);
-
-    // Reinhard tone-mapping algo.
-    //
-    // Original:
-    // http://www.cmap.polytechnique.fr/%7Epeyre/cours/x2005signal/hdr_photographic.pdf
-    //
-    // Extended:
-    // https://64.github.io/tonemapping/#reinhard
-    // TMO_reinhardext​(C) = C(1 + C/C_white^2​) / (1 + C)
-    //
-    let luma_out = luma_in * (1.0 + luma_in / white2) / (1.0 + luma_in);
-    let oklab_out = scale_oklab_desat(oklab_in, luma_out, options.saturation);
-    oklab_to_scrgb(oklab_out)
-}
-
-fn oklab_l_for_luma(luma: f32) -> f32 {
-    let gray_rgb = oklab::RGB::new(luma, luma, luma);
-    let gray_oklab = linear_srgb_to_oklab(gray_rgb);
-    gray_oklab.l
+        fs::write(self.folder.join(WATCH_CACHE_NAME), contents)?;
+        Ok(())
+    }
 }
 
-fn scale_oklab_desat(oklab_in: Oklab, luma_out: f32, saturation: f32) -> Oklab {
-    let l_in = oklab_in.l;
-    if l_in == 0.0 {
-        oklab_in
-    } else {
-        let l_out = oklab_l_for_luma(luma_out);
-        // oklab coords scale cubically
-        // 1.0 -> desaturate linearly according to luma compression ratio
-        // 0.5 -> desaturate more aggressively
-        // 2.0 -> saturate more aggressively
-        let ratio = (l_out / l_in).powf(3.0 / saturation);
-        Oklab {
-            l: l_out,
-            a: oklab_in.a * ratio,
-            b: oklab_in.b * ratio,
+// Options for the inputs of a watched folder and its subfolders,
+// overriding those given on the command line.
+const FOLDER_CONFIG_NAME: &str = ".hdrfix.toml";
+
+// Turn the `key = value` lines of a folder config into command line
+// options. This is only the flat subset of TOML that options need:
+// strings, numbers and booleans, with # comments. `true` turns a flag
+// on; `false` leaves it as the command line had it.
+fn folder_config_args(contents: &str) -> std::result::Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", number + 1))?;
+        // TOML style underscores are accepted for the dashes
+        let key = key.trim().replace('_', "-");
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("line {}: invalid key '{}'", number + 1, key));
+        }
+        match value.trim() {
+            "true" => args.push(OsString::from(format!("--{}", key))),
+            "false" => {}
+            value => {
+                let value = match value.strip_prefix('"') {
+                    Some(quoted) => quoted
+                        .strip_suffix('"')
+                        .ok_or_else(|| format!("line {}: unterminated string", number + 1))?
+                        .replace("\\\"", "\"")
+                        .replace("\\\\", "\\"),
+                    None => value.to_string(),
+                };
+                args.push(OsString::from(format!("--{}={}", key, value)));
+            }
         }
     }
+    Ok(args)
 }
 
-fn scale_oklab(oklab_in: Oklab, luma_out: f32) -> Oklab {
-    if oklab_in.l == 0.0 {
-        oklab_in
-    } else {
-        let gray_l = oklab_l_for_luma(luma_out);
-        let ratio = gray_l / oklab_in.l;
-        Oklab {
-            l: gray_l,
-            a: oklab_in.a * ratio,
-            b: oklab_in.b * ratio,
+// The line up to a # that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '#' if !in_string => return &line[..i],
+            '"' if !escaped => in_string = !in_string,
+            _ => {}
         }
+        escaped = c == '\\' && !escaped;
     }
+    line
 }
 
-fn clip(input: Vec3) -> Vec3 {
-    input.max(Vec3::ZERO).min(Vec3::ONE)
-}
-
-fn color_clip(input: Vec3) -> Vec3 {
-    clip(input)
-}
-
-fn darken_oklab(c_in: Oklab, brightness: f32) -> Vec3 {
-    let c_out = Oklab {
-        l: c_in.l * brightness,
-        a: c_in.a * brightness,
-        b: c_in.b * brightness,
+// The settings for a watched input: the command line's, overridden by
+// the nearest .hdrfix.toml in the input's folder or the folders above
+// it, up to the watched folder. The file is read each time, so edits
+// apply to the next capture without restarting the watcher.
+fn folder_settings(
+    input_path: &Path,
+    folder: &Path,
+    app: &App,
+    settings: &Settings,
+) -> Result<Settings> {
+    let config_path = match input_path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(folder))
+        .map(|dir| dir.join(FOLDER_CONFIG_NAME))
+        .find(|path| path.is_file())
+    {
+        Some(path) => path,
+        None => return Ok(settings.clone()),
     };
-    oklab_to_scrgb(c_out)
+    let invalid = |message: String| InvalidFolderConfig(config_path.display().to_string(), message);
+    let config_args = folder_config_args(&fs::read_to_string(&config_path)?).map_err(invalid)?;
+    let args = config_matches(app, env::args_os(), config_args).map_err(invalid)?;
+    self::settings(&args)
 }
 
-fn desat_oklab(c_in: Oklab, saturation: f32) -> Vec3 {
-    let c_out = Oklab {
-        l: c_in.l,
-        a: c_in.a * saturation,
-        b: c_in.b * saturation,
-    };
-    oklab_to_scrgb(c_out)
+// Later options replace the command line's, rather than being an error
+fn config_matches<'a, I>(
+    app: &App<'a, '_>,
+    command_line: I,
+    config_args: Vec<OsString>,
+) -> std::result::Result<ArgMatches<'a>, String>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    app.clone()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from_safe(command_line.into_iter().chain(config_args))
+        .map_err(|e| e.message.lines().next().unwrap_or_default().to_string())
 }
 
-const EPSILON: f32 = 0.001; // good enough for us for now
-
-fn close_enough(a: f32, b: f32) -> Ordering {
-    let delta = a - b;
-    if delta.abs() < EPSILON {
-        Ordering::Equal
-    } else if delta < 0.0 {
-        Ordering::Less
-    } else {
-        Ordering::Greater
-    }
+fn is_jxr(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("jxr"))
+        .unwrap_or(false)
 }
 
-fn binary_search<I, O, F, G>(input: I, min: f32, max: f32, func: F, comparator: G) -> O
-where
-    I: Copy + Clone,
-    O: Copy + Clone,
-    F: Fn(I, f32) -> O,
-    G: Fn(O) -> Ordering,
-{
-    let mid = (min + max) / 2.0;
-    let result = func(input, mid);
-    match close_enough(min, max) {
-        Ordering::Equal => result,
-        _ => match comparator(result) {
-            Ordering::Less => binary_search(input, mid, max, func, comparator),
-            Ordering::Greater => binary_search(input, min, mid, func, comparator),
-            Ordering::Equal => result,
-        },
+// All the .jxr files under a folder, including subfolders like the watcher.
+fn find_inputs(folder: &Path, inputs: &mut Vec<PathBuf>) -> Result<()> {
+    for dir_entry in folder.read_dir()? {
+        let path = dir_entry?.path();
+        if path.is_dir() {
+            find_inputs(&path, inputs)?;
+        } else if is_jxr(&path) {
+            inputs.push(path);
+        }
     }
+    Ok(())
 }
 
-fn color_darken_oklab(c_in: Vec3) -> Vec3 {
-    let max = c_in.max_element();
-    if max > 1.0 {
-        let c_in_oklab = scrgb_to_oklab(c_in);
-        let c_out = binary_search(c_in_oklab, 0.0, 1.0, darken_oklab, |rgb| {
-            close_enough(rgb.max_element(), 1.0)
+// For --watch-existing, convert the inputs already in the folder
+// that don't have an output file yet.
+fn convert_existing(
+    folder: &Path,
+    naming: &OutputNaming,
+    app: &App,
+    settings: &Settings,
+    cache: &mut WatchCache,
+) -> Result<()> {
+    let mut inputs = Vec::new();
+    find_inputs(folder, &mut inputs)?;
+    inputs.sort();
+    for input_path in inputs {
+        let result = folder_settings(&input_path, folder, app, settings).and_then(|settings| {
+            let output_path = input_path.with_file_name(naming.file_name(&input_path, &settings)?);
+            if output_path.exists() {
+                return Ok(());
+            }
+            let stamp = FileStamp::of(&input_path)?;
+            convert_with_retry(&input_path, &output_path, &settings)?;
+            cache.insert(&input_path, stamp)
         });
-        clip(c_out)
-    } else {
-        c_in
+        if let Err(e) = result {
+            eprintln!("Error: {}: {}", input_path.display(), e);
+        }
     }
+    Ok(())
 }
 
-fn color_desat_oklab(c_in: Vec3) -> Vec3 {
-    let max = c_in.max_element();
-    if max > 1.0 {
-        let c_in_oklab = scrgb_to_oklab(c_in);
-        let c_out = binary_search(c_in_oklab, 0.0, 1.0, desat_oklab, |rgb| {
-            close_enough(rgb.max_element(), 1.0)
+fn watch(
+    folder: &Path,
+    naming: &OutputNaming,
+    debounce: Duration,
+    existing: bool,
+    app: &App,
+    settings: &Settings,
+) -> Result<()> {
+    // Events come with absolute paths, which the cache keys are relative to.
+    let folder = &folder.canonicalize()?;
+    let (tx, rx) = channel::<DebouncedEvent>();
+    let mut watcher = RecommendedWatcher::new(tx, debounce)?;
+    watcher.watch(folder, RecursiveMode::Recursive)?;
+
+    // A file written in several bursts sends a Create followed by Writes;
+    // remember what each file was like when converted so it's only done once.
+    let mut cache = WatchCache::load(folder);
+    // Watch first, so nothing that arrives during the catch-up is missed.
+    if existing {
+        convert_existing(folder, naming, app, settings, &mut cache)?;
+    }
+    loop {
+        let input_path = match rx.recv()? {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => path,
+            // NoticeWrite comes right at the start of a write; the
+            // debounced Write follows once it has settled.
+            _ => continue,
+        };
+        if !is_jxr(&input_path) {
+            continue;
+        }
+        let result = wait_for_stable_size(&input_path).and_then(|_| {
+            let settings = folder_settings(&input_path, folder, app, settings)?;
+            let output_path = input_path.with_file_name(naming.file_name(&input_path, &settings)?);
+            let stamp = FileStamp::of(&input_path)?;
+            if cache.is_current(&input_path, &output_path, stamp) {
+                return Ok(());
+            }
+            convert_with_retry(&input_path, &output_path, &settings)?;
+            cache.insert(&input_path, stamp)
         });
-        clip(c_out)
-    } else {
-        c_in
+        // Never let one bad file stop the watcher.
+        if let Err(e) = result {
+            eprintln!("Error: {}: {}", input_path.display(), e);
+        }
     }
 }
 
-fn luma_rgb(val: Vec3) -> f32 {
-    val.x * 0.2126 + val.y * 0.7152 + val.z * 0.0722
-}
-
-fn scale_rgb(val: Vec3, luma_out: f32) -> Vec3 {
-    let luma_in = luma_rgb(val);
-    let scale = luma_out / luma_in;
-    val * scale
-}
-
-// https://64.github.io/tonemapping/#uncharted-2
-// Uncharted 2 / Hable Filmic
-fn uncharted2_tonemap_partial(x: f32) -> f32 {
-    const A: f32 = 0.15;
-    const B: f32 = 0.50;
-    const C: f32 = 0.10;
-    const D: f32 = 0.20;
-    const E: f32 = 0.02;
-    const F: f32 = 0.30;
-    ((x * (A * x + (C * B)) + (D * E)) / (x * (A * x + (B)) + (D * F))) - (E / F)
-}
-
-fn tonemap_uncharted2(v: Vec3, _options: &Options) -> Vec3 {
-    let exposure_bias: f32 = 2.0;
-    let luma = luma_rgb(v);
-    let curr = uncharted2_tonemap_partial(luma * exposure_bias);
-
-    let w = 11.2f32;
-    let white_scale = 1.0f32 / uncharted2_tonemap_partial(w);
-    let luma_out = curr * white_scale;
-
-    scale_rgb(v, luma_out)
-}
-
-fn tonemap_hable(val: Vec3, _options: &Options) -> Vec3 {
-    // stolen from ffmpeg's vf_tonemap
-
-    // desat
-    let luma = luma_rgb(val);
-    let desaturation: f32 = 2.0;
-    let epsilon: f32 = 1e-6;
-    let overbright = f32::max(luma - desaturation, epsilon) / f32::max(luma, epsilon);
-    let rgb_out = val * (1.0 - overbright) + luma * overbright;
-    let sig_orig = f32::max(rgb_out.max_element(), epsilon);
-
-    // hable/uncharted2
-    let exposure_bias: f32 = 2.0;
-    let luma = sig_orig;
-    let curr = uncharted2_tonemap_partial(luma * exposure_bias);
-    let w = 11.2f32;
-    let white_scale = 1.0f32 / uncharted2_tonemap_partial(w);
-    let sig = curr * white_scale;
-
-    rgb_out * (sig / sig_orig)
-}
-
-// can't use glam's Mat3 as a constant literal?
-type Matrix3x3 = [[f32; 3]; 3];
-
-// https://64.github.io/tonemapping/#aces
-// ACES (Academy Color Encoding System)
-const ACES_INPUT_MATRIX: Matrix3x3 = [
-    [0.59719, 0.35458, 0.04823],
-    [0.07600, 0.90834, 0.01566],
-    [0.02840, 0.13383, 0.83777],
-];
-
-const ACES_OUTPUT_MATRIX: Matrix3x3 = [
-    [1.60475, -0.53108, -0.07367],
-    [-0.10208, 1.10813, -0.00605],
-    [-0.00327, -0.07276, 1.07602],
-];
-
-#[allow(clippy::many_single_char_names)]
-fn aces_mul(m: &Matrix3x3, v: Vec3) -> Vec3 {
-    let x = m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2];
-    let y = m[1][0] * v[1] + m[1][1] * v[1] + m[1][2] * v[2];
-    let z = m[2][0] * v[1] + m[2][1] * v[1] + m[2][2] * v[2];
-    Vec3::new(x, y, z)
-}
-
-fn aces_rtt_and_odt_fit(v: Vec3) -> Vec3 {
-    let a = v * (v + Vec3::splat(0.0245786)) - Vec3::splat(0.000090537);
-    let b = v * (Vec3::splat(0.983729) * v + Vec3::splat(0.432951)) + Vec3::splat(0.238081);
-    a / b
-}
-
-fn tonemap_aces(c_in: Vec3, _options: &Options) -> Vec3 {
-    let v = c_in;
-    let v = aces_mul(&ACES_INPUT_MATRIX, v);
-    let v = aces_rtt_and_odt_fit(v);
-    aces_mul(&ACES_OUTPUT_MATRIX, v)
-}
-
-/*
-fn srgb_to_linear(val: Vec3) -> Vec3 {
-    Vec3::select(
-        val.cmple(Vec3::splat(0.04045)),
-        val / Vec3::splat(12.92),
-        ((val + Vec3::splat(0.055)) / Vec3::splat(1.055)).powf(2.4)
+// "N/M done, ~X min remaining", from the average time per file so far.
+// That's wall clock time, so files converted side by side with
+// --file-concurrency count for what they actually took. There's no
+// estimate until a file has finished.
+fn batch_progress(done: usize, total: usize, elapsed: Duration) -> String {
+    if done == 0 || done == total {
+        return format!("{}/{} done", done, total);
+    }
+    let remaining = elapsed.as_secs_f32() / done as f32 * (total - done) as f32;
+    format!(
+        "{}/{} done, ~{:.0} min remaining",
+        done,
+        total,
+        (remaining / 60.0).ceil()
     )
 }
-*/
-
-fn linear_to_srgb(val: Vec3) -> Vec3 {
-    // fixme make sure all the splats are efficient constants
-    let min = Vec3::splat(0.0031308);
-    let linear = val * Vec3::splat(12.92);
-    let gamma = (val * Vec3::splat(1.055)).powf(1.0 / 2.4) - Vec3::splat(0.055);
-    Vec3::select(val.cmple(min), linear, gamma)
-}
-
-const REC2100_MAX: f32 = 10000.0; // the 1.0 value for BT.2100 linear
-const SDR_WHITE: f32 = 80.0;
 
-fn exposure_scale(stops: f32) -> f32 {
-    2.0_f32.powf(stops)
+// Mean of levels measured by measure_levels(), which are all the same kind
+fn average_level(levels: &[Level]) -> Level {
+    let sum: f32 = levels
+        .iter()
+        .map(|level| match level {
+            Level::Scalar(val) | Level::Nits(val) => *val,
+            _ => 0.0,
+        })
+        .sum();
+    let mean = sum / levels.len() as f32;
+    match levels[0] {
+        Level::Scalar(_) => Level::Scalar(mean),
+        Level::Nits(_) => Level::Nits(mean),
+        level => level,
+    }
 }
 
-fn hdr_to_sdr_pixel(rgb_scrgb: Vec3, options: &Options) -> Vec3 {
-    let val = rgb_scrgb * options.scale;
-    let val = (options.tone_map)(val, options);
-    (options.color_map)(val)
+// Fix the auto exposure and white point for the whole batch for
+// --exposure-match, measured from the first input or averaged over all,
+// so the frames of a captured sequence don't flicker.
+fn match_exposure(inputs: &[PathBuf], mode: &str, settings: &Settings) -> Result<Settings> {
+    let measured = match mode {
+        "first" => &inputs[..inputs.len().min(1)],
+        _ => inputs,
+    };
+    if measured.is_empty() {
+        return Ok(settings.clone());
+    }
+    let mut exposures = Vec::new();
+    let mut hdr_maxes = Vec::new();
+    for path in measured {
+        eprintln!("Measuring exposure of {}", path.display());
+        let (exposure, hdr_max) = measure_levels(path, settings)?;
+        exposures.push(exposure);
+        hdr_maxes.push(hdr_max);
+    }
+    let matched = Settings {
+        auto_exposure: average_level(&exposures),
+        hdr_max: average_level(&hdr_maxes),
+        ..settings.clone()
+    };
+    eprintln!(
+        "Matched exposure for the batch: --auto-exposure={} --hdr-max={}",
+        tui::level_label(matched.auto_exposure),
+        tui::level_label(matched.hdr_max)
+    );
+    Ok(matched)
 }
 
-fn write_png(filename: &Path, data: &PixelBuffer) -> Result<()> {
-    use mtpng::encoder::{Encoder, Options};
-    use mtpng::ColorType;
-    use mtpng::{CompressionLevel, Header};
-
-    let writer = File::create(filename)?;
-
-    let mut options = Options::new();
-    options.set_compression_level(CompressionLevel::High)?;
-
-    let mut header = Header::new();
-    header.set_size(data.width as u32, data.height as u32)?;
-    header.set_color(ColorType::Truecolor, 8)?;
-
-    let mut encoder = Encoder::new(writer, &options);
-
-    encoder.write_header(&header)?;
-    encoder.write_image_rows(data.bytes())?;
-    encoder.finish()?;
+// Displays --suggest picks settings for, by their peak in nits
+const SUGGEST_TARGETS: &[(&str, f32)] = &[
+    ("100 nit sRGB SDR", 100.0),
+    ("400 nit HDR", 400.0),
+    ("600 nit HDR", 600.0),
+    ("1000 nit HDR", 1000.0),
+];
 
+// Print command lines for --suggest. The output's white is taken to be
+// shown at the display's peak, so the exposure keeps the content at its
+// original brightness, and only content brighter than the display can
+// show is compressed by the tone map.
+fn suggest(input_filename: &Path, settings: &Settings) -> Result<()> {
+    let (peak, average) = measure_nits(input_filename, settings)?;
+    println!("Peak {:.0} nits, average {:.1} nits", peak, average);
+    for &(label, display_peak) in SUGGEST_TARGETS {
+        let exposure = (SDR_WHITE / display_peak).log2();
+        let hdr_max = peak.max(display_peak);
+        println!(
+            "{}: hdrfix {} --exposure={:.2} --hdr-max={:.0}nits  (average at {:.1}% of white)",
+            label,
+            input_filename.display(),
+            exposure,
+            hdr_max,
+            average / display_peak * 100.0
+        );
+    }
     Ok(())
 }
 
-fn write_jpeg(filename: &Path, data: &PixelBuffer) -> Result<()> {
-    // @todo allow setting jpeg quality
-    // mozjpeg is much faster than image crate's encoder
-    std::panic::catch_unwind(|| {
-        use mozjpeg::{ColorSpace, Compress};
-        let mut c = Compress::new(ColorSpace::JCS_EXT_RGB);
-        c.set_size(data.width, data.height);
-        c.set_quality(95.0);
-        c.set_mem_dest(); // can't write direct to file?
-        c.start_compress();
-        if !c.write_scanlines(data.bytes()) {
-            panic!("error writing scanlines");
-        }
-        c.finish_compress();
-        let mut writer = File::create(filename).expect("error creating output file");
-        let data = c
-            .data_as_mut_slice()
-            .expect("error accessing JPEG output buffer");
-        writer.write_all(data).expect("error writing output file");
-    })
-    .map_err(|_| JpegWriteFailure)
+// Records which inputs of an --input-dir batch were converted, so a run
+// that was interrupted can pick up where it stopped. The JSON file holds
+// one object with a "completed" array of input paths.
+struct Manifest {
+    path: PathBuf,
+    completed: HashSet<String>,
 }
 
-struct Histogram {
-    luma_vals: Vec<f32>,
-}
+impl Manifest {
+    // A missing manifest is a fresh batch, but a damaged one is an error
+    // rather than starting over, in case it isn't ours.
+    fn load(path: &Path) -> Result<Self> {
+        let completed = match fs::read_to_string(path) {
+            Ok(contents) => parse_manifest(&contents).map_err(|message| {
+                InvalidManifest(path.display().to_string(), message.to_string())
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            completed,
+        })
+    }
 
-impl Histogram {
-    fn new(source: &PixelBuffer) -> Self {
-        // @todo maybe do a proper histogram with buckets
-        // instead of sorting every pixel value
-        let mut luma_vals = Vec::<f32>::new();
-        source
-            .pixels()
-            .map(luma_scrgb)
-            .collect_into_vec(&mut luma_vals);
-        luma_vals.par_sort_unstable_by(|a, b| match a.partial_cmp(b) {
-            Some(ordering) => ordering,
-            None => Ordering::Equal,
-        });
-        Self { luma_vals }
+    fn key(input_path: &Path) -> String {
+        input_path.to_string_lossy().into_owned()
     }
 
-    fn percentile(&self, target: f32) -> f32 {
-        let max_index = self.luma_vals.len() - 1;
-        let target_index = (max_index as f32 * target / 100.0) as usize;
-        self.luma_vals[target_index]
+    fn is_completed(&self, input_path: &Path) -> bool {
+        self.completed.contains(&Self::key(input_path))
     }
 
-    fn average_below_percentile(&self, percent: f32) -> f32 {
-        let max = self.percentile(percent);
-        let (sum, count) = self
-            .luma_vals
+    // Written to a temporary file and renamed over the manifest, so a crash
+    // partway through writing can't leave it damaged.
+    fn insert(&mut self, input_path: &Path) -> Result<()> {
+        self.completed.insert(Self::key(input_path));
+        let mut completed = self.completed.iter().collect::<Vec<_>>();
+        completed.sort();
+        let entries = completed
             .iter()
-            .fold((0.0f32, 0usize), |(sum, count), luma| {
-                if *luma > max {
-                    (sum, count)
-                } else {
-                    (sum + luma, count + 1)
-                }
-            });
-        sum / count as f32
+            .map(|path| format!("    {}", json_string(path)))
+            .collect::<Vec<_>>();
+        let contents = format!("{{\n  \"completed\": [\n{}\n  ]\n}}\n", entries.join(",\n"));
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
     }
 }
 
-fn scrgb_to_linear_srgb(c: Vec3) -> oklab::RGB<f32> {
-    oklab::RGB::new(c.x, c.y, c.z)
-}
-
-fn linear_srgb_to_scrgb(c: oklab::RGB<f32>) -> Vec3 {
-    Vec3::new(c.r, c.g, c.b)
-}
-
-fn scrgb_to_oklab(c: Vec3) -> Oklab {
-    linear_srgb_to_oklab(scrgb_to_linear_srgb(c))
-}
-
-fn oklab_to_scrgb(c: Oklab) -> Vec3 {
-    linear_srgb_to_scrgb(oklab_to_linear_srgb(c))
-}
-
-fn apply_levels(c_in: Vec3, level_min: f32, level_max: f32, gamma: f32) -> Vec3 {
-    let offset = level_min;
-    let scale = level_max - level_min;
-    let oklab_in = scrgb_to_oklab(c_in);
-    let luma_in = luma_oklab(oklab_in);
-    let luma_out = ((luma_in - offset) / scale).powf(gamma);
-    let oklab_out = scale_oklab(oklab_in, luma_out);
-    oklab_to_scrgb(oklab_out)
-}
-
-struct Lazy<T, F>
-where
-    F: (FnOnce() -> T),
-{
-    value: Option<T>,
-    func: Option<F>,
-}
-
-impl<T, F> Lazy<T, F>
-where
-    F: (FnOnce() -> T),
-{
-    fn new(func: F) -> Self {
-        Lazy {
-            value: None,
-            func: Some(func),
+fn json_string(source: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in source.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
 
-    fn force(&mut self) -> &T {
-        if self.value.is_none() {
-            let func = self.func.take().unwrap();
-            self.value = Some(func());
+// Just enough JSON for the manifest we write: the strings of the
+// "completed" array.
+fn parse_manifest(contents: &str) -> std::result::Result<HashSet<String>, &'static str> {
+    let start = contents
+        .find("\"completed\"")
+        .ok_or("no \"completed\" array")?;
+    let mut chars = contents[start + "\"completed\"".len()..].chars();
+    let mut completed = HashSet::new();
+    loop {
+        match chars.next() {
+            Some('[') | Some(',') | Some(':') => {}
+            Some(c) if c.is_whitespace() => {}
+            Some(']') => return Ok(completed),
+            Some('"') => {
+                let mut entry = String::new();
+                loop {
+                    match chars.next().ok_or("unterminated string")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("unterminated string")? {
+                            'n' => entry.push('\n'),
+                            't' => entry.push('\t'),
+                            'r' => entry.push('\r'),
+                            'u' => {
+                                let hex = chars.by_ref().take(4).collect::<String>();
+                                let code = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or("bad \\u escape")?;
+                                entry.push(code);
+                            }
+                            c => entry.push(c),
+                        },
+                        c => entry.push(c),
+                    }
+                }
+                completed.insert(entry);
+            }
+            _ => return Err("expected an array of strings for \"completed\""),
         }
-        self.value.as_ref().unwrap()
     }
 }
 
-impl<F> Lazy<Histogram, F>
-where
-    F: (FnOnce() -> Histogram),
-{
-    fn level(&mut self, level: Level) -> f32 {
-        match level {
-            Level::Scalar(val) => val,
-            Level::Percentile(val) => self.force().percentile(val),
+// Convert files with at most `concurrency` of them in flight, so only that
+// many images' buffers are in memory at once. Each conversion still spreads
+// its pixel work over the shared rayon pool. Stops handing out files after
+// the first error, which is returned once the others in flight finish.
+// Files over --warn-clip-above don't stop the batch, but are listed at the end.
+// With a manifest, inputs it lists are skipped and each one converted is added.
+fn convert_batch(
+    inputs: Vec<PathBuf>,
+    naming: &OutputNaming,
+    settings: &Settings,
+    concurrency: usize,
+    manifest: Option<Manifest>,
+) -> Result<()> {
+    let inputs = match &manifest {
+        Some(manifest) => {
+            let count = inputs.len();
+            let inputs = inputs
+                .into_iter()
+                .filter(|path| !manifest.is_completed(path))
+                .collect::<Vec<_>>();
+            if inputs.len() < count {
+                println!(
+                    "Skipping {} files already converted according to {}",
+                    count - inputs.len(),
+                    manifest.path.display()
+                );
+            }
+            inputs
+        }
+        None => inputs,
+    };
+    let manifest = manifest.map(Mutex::new);
+    let total = inputs.len();
+    let queue = Mutex::new(inputs.into_iter());
+    let first_error = Mutex::new(None);
+    let clipped = Mutex::new(Vec::new());
+    let started = Instant::now();
+    let done = Mutex::new(0);
+    thread::scope(|scope| {
+        for _ in 0..concurrency {
+            scope.spawn(|| loop {
+                if first_error.lock().unwrap().is_some() {
+                    return;
+                }
+                let path = match queue.lock().unwrap().next() {
+                    Some(path) => path,
+                    None => return,
+                };
+                let result = naming
+                    .file_name(&path, settings)
+                    .and_then(|output_file| hdrfix(&path, Path::new(&output_file), settings));
+                let result = match result {
+                    // Over --warn-clip-above, but the output was written
+                    Err(ClipThresholdExceeded(..)) => {
+                        clipped.lock().unwrap().push(path.clone());
+                        Ok(())
+                    }
+                    result => result,
+                };
+                let result = result.and_then(|()| match &manifest {
+                    Some(manifest) => manifest.lock().unwrap().insert(&path),
+                    None => Ok(()),
+                });
+                if let Err(e) = result {
+                    first_error.lock().unwrap().get_or_insert(e);
+                    return;
+                }
+                let mut done = done.lock().unwrap();
+                *done += 1;
+                println!("{}", batch_progress(*done, total, started.elapsed()));
+            });
         }
+    });
+    if let Some(e) = first_error.into_inner().unwrap() {
+        return Err(e);
     }
-}
-
-fn hdrfix(input_filename: &Path, output_filename: &Path, args: &ArgMatches) -> Result<()> {
-    if !args.is_present("overwrite") && output_filename.exists() {
-        println!(
-            "INFO: Skipping existing file '{}'",
-            output_filename.display()
-        );
+    let mut clipped = clipped.into_inner().unwrap();
+    if clipped.is_empty() {
         return Ok(());
     }
-
-    println!(
-        "{} -> {}",
-        input_filename.to_str().unwrap(),
-        output_filename.to_str().unwrap()
-    );
-
-    let source = time_func("read_input", || match input_filename.extension() {
-        Some(ext) if ext == "png" => read_png(input_filename),
-        Some(ext) if ext == "jxr" => read_jxr(input_filename),
-        _ => Err(InvalidInputFile),
-    })?;
-    let width = source.width as usize;
-    let height = source.height as usize;
-
-    let pre_gamma: f32 = args.value_of("pre-gamma").expect("pre-gamma arg").parse()?;
-    let mut pre_histogram = Lazy::new(|| Histogram::new(&source));
-    let pre_levels_min = pre_histogram.level(Level::with_str(
-        args.value_of("pre-levels-min").expect("pre-levels-min arg"),
-    )?);
-    let pre_levels_max = pre_histogram.level(Level::with_str(
-        args.value_of("pre-levels-max").expect("pre-levels-max arg"),
-    )?);
-    let source = {
-        let mut dest = PixelBuffer::new(width, height, PixelFormat::HDRFloat32);
-        dest.fill(
-            source
-                .pixels()
-                .map(|rgb| apply_levels(rgb, pre_levels_min, pre_levels_max, pre_gamma)),
-        );
-        dest
-    };
-
-    let mut input_histogram =
-        Lazy::new(|| time_func("input histogram", || Ok(Histogram::new(&source))).unwrap());
-
-    let exposure = args.value_of("exposure").unwrap().parse::<f32>()?;
-    let auto_exposure = Level::with_str(args.value_of("auto-exposure").unwrap())?;
-    let scale = exposure_scale(exposure) * 0.5
-        / match auto_exposure {
-            Level::Scalar(level) => level,
-            Level::Percentile(percent) => input_histogram.force().average_below_percentile(percent),
-        };
-
-    let hdr_max = match Level::with_str(args.value_of("hdr-max").unwrap())? {
-        // hdr_max input is in nits if scalar, so scale it to scrgb
-        Level::Scalar(nits) => nits / SDR_WHITE,
-
-        // If given a percentile for hdr_max, detect from input histogram.
-        Level::Percentile(val) => input_histogram.force().percentile(val),
-    } * scale;
-
-    let options = Options {
-        scale,
-        hdr_max,
-        saturation: args
-            .value_of("saturation")
-            .expect("saturation arg")
-            .parse()?,
-        tone_map: match args.value_of("tone-map").expect("tone-map arg") {
-            "linear" => tonemap_linear,
-            "reinhard" => tonemap_reinhard_oklab,
-            "reinhard-rgb" => tonemap_reinhard_rgb,
-            "aces" => tonemap_aces,
-            "uncharted2" => tonemap_uncharted2,
-            "hable" => tonemap_hable,
-            _ => unreachable!("bad tone-map option"),
-        },
-        color_map: match args.value_of("color-map").expect("color-map arg") {
-            "clip" => color_clip,
-            "darken" => color_darken_oklab,
-            "desaturate" => color_desat_oklab,
-            _ => unreachable!("bad color-map option"),
-        },
-    };
-
-    let mut tone_mapped = PixelBuffer::new(width, height, HDRFloat32);
-    time_func("hdr_to_sdr", || {
-        tone_mapped.fill(source.pixels().map(|rgb| hdr_to_sdr_pixel(rgb, &options)));
-        Ok(())
-    })?;
-
-    // apply histogram expansion and color gamut correction to output
-    let mut lazy_histogram =
-        Lazy::new(|| time_func("levels histogram", || Ok(Histogram::new(&tone_mapped))).unwrap());
-    let post_levels_min = lazy_histogram.level(Level::with_str(
-        args.value_of("post-levels-min")
-            .expect("post-levels-min arg"),
-    )?);
-    let post_levels_max = lazy_histogram.level(Level::with_str(
-        args.value_of("post-levels-max")
-            .expect("post-levels-max arg"),
-    )?);
-    let post_gamma: f32 = args
-        .value_of("post-gamma")
-        .expect("post-gamma arg")
-        .parse()?;
-
-    let mut dest = PixelBuffer::new(width, height, SDR8bit);
-    time_func("output mapping", || {
-        dest.fill(tone_mapped.pixels().map(|rgb| {
-            // We have to color map again
-            // in case the histogram pushed things back out of gamut.
-            clip((options.color_map)(apply_levels(
-                rgb,
-                post_levels_min,
-                post_levels_max,
-                post_gamma,
-            )))
-        }));
-        Ok(())
-    })?;
-
-    time_func("write output", || match output_filename.extension() {
-        Some(ext) if ext == "png" => write_png(output_filename, &dest),
-        Some(ext) if ext == "jpg" || ext == "jpeg" => write_jpeg(output_filename, &dest),
-        _ => Err(InvalidOutputFile),
-    })?;
-
-    Ok(())
+    clipped.sort();
+    eprintln!("Files over the clip threshold:");
+    for path in &clipped {
+        eprintln!("  {}", path.display());
+    }
+    Err(ClipThresholdFiles(clipped.len(), total))
 }
 
-fn run(args: &ArgMatches) -> Result<()> {
-    match args.value_of("watch") {
+fn run(args: &ArgMatches, app: &App) -> Result<()> {
+    if args.is_present("self-test") {
+        return self_test();
+    }
+    let mut settings = settings(args)?;
+    match args.value_of_os("watch") {
         Some(folder) => {
-            let (tx, rx) = channel::<DebouncedEvent>();
-            let mut watcher = RecommendedWatcher::new(tx, Duration::from_secs(2))?;
-            watcher.watch(folder, RecursiveMode::Recursive)?;
-
-            let suffix = args
-                .value_of("output-suffix")
-                .expect("Output suffix must be set");
-            loop {
-                let event = rx.recv()?;
-                if let DebouncedEvent::Create(input_path) = event {
-                    if input_path
-                        .extension()
-                        .map(|ext| ext == "jxr")
-                        .unwrap_or(false)
-                    {
-                        if let Some(file_stem) = input_path.file_stem() {
-                            let mut output_filename = file_stem.to_os_string();
-                            output_filename.push(suffix);
-                            let output_path = input_path.with_file_name(output_filename);
-                            if let Err(e) = hdrfix(&input_path, &output_path, args) {
-                                eprintln!("Error: {}", e);
-                            }
-                        }
-                    }
-                }
-            }
+            let debounce = args
+                .value_of("watch-debounce")
+                .expect("watch-debounce arg")
+                .parse()?;
+            watch(
+                Path::new(folder),
+                &OutputNaming::new(args),
+                Duration::from_secs_f32(debounce),
+                args.is_present("watch-existing"),
+                app,
+                &settings,
+            )
         }
-        None => match args.value_of("input-dir") {
+        None => match args.value_of_os("input-dir") {
             Some(dir) => {
                 let dir = Path::new(dir);
                 if !dir.is_dir() {
                     return Err(InvalidInputFile);
                 }
 
-                let suffix = args
-                    .value_of("output-suffix")
-                    .expect("Output suffix must be set");
+                let mut inputs = Vec::new();
                 for dir_entry in dir.read_dir().map_err(IoError)? {
                     let dir_entry = dir_entry.map_err(IoError)?;
                     let path = dir_entry.path();
-                    if path.is_file() {
-                        match path.extension() {
-                            Some(extension) if extension == "jxr" => {
-                                let mut output_file = path.file_stem().unwrap().to_os_string();
-                                output_file.push(suffix);
-                                hdrfix(&path, Path::new(&output_file), args)?;
-                            }
-                            _ => {}
-                        }
+                    if path.is_file() && is_jxr(&path) {
+                        inputs.push(path);
                     }
                 }
 
-                Ok(())
+                // Sorted so frames of a sequence go in order
+                inputs.sort();
+
+                if args.is_present("dry-run") {
+                    return dry_run(&inputs, settings.input_format);
+                }
+                if let Some(mode) = args.value_of("exposure-match") {
+                    settings = match_exposure(&inputs, mode, &settings)?;
+                }
+
+                let concurrency = args
+                    .value_of("file-concurrency")
+                    .expect("file-concurrency arg")
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&concurrency| concurrency > 0)
+                    .ok_or(InvalidFileConcurrency)?;
+                let manifest = match args.value_of_os("manifest") {
+                    Some(path) => Some(Manifest::load(Path::new(path))?),
+                    None => None,
+                };
+                convert_batch(
+                    inputs,
+                    &OutputNaming::new(args),
+                    &settings,
+                    concurrency,
+                    manifest,
+                )
             }
             None => {
                 let input_filename =
-                    Path::new(args.value_of("input").expect("input filename missing"));
+                    Path::new(args.value_of_os("input").expect("input filename missing"));
 
-                match args.value_of("output") {
-                    Some(output_filename) => {
-                        hdrfix(input_filename, Path::new(output_filename), args)
-                    }
+                if args.is_present("dry-run") {
+                    return dry_run(&[input_filename.to_path_buf()], settings.input_format);
+                }
+                if args.is_present("print-matrix") {
+                    return print_matrix(input_filename, &settings);
+                }
+                if args.is_present("suggest") {
+                    return suggest(input_filename, &settings);
+                }
+
+                let output_filename = match args.value_of_os("output") {
+                    Some(output_filename) => PathBuf::from(output_filename),
                     None => {
-                        let suffix = args
-                            .value_of("output-suffix")
-                            .expect("Output filename or suffix must be present");
-                        let mut output_filename = input_filename
-                            .file_stem()
-                            .expect("Invalid input file")
-                            .to_os_string();
-                        output_filename.push(suffix);
-                        hdrfix(input_filename, Path::new(&output_filename), args)
+                        PathBuf::from(OutputNaming::new(args).file_name(input_filename, &settings)?)
+                    }
+                };
+
+                if args.is_present("tui") {
+                    let width = args
+                        .value_of("tui-width")
+                        .expect("tui-width arg")
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&width| width > 0)
+                        .ok_or(InvalidTuiWidth)?;
+                    return tui::run(input_filename, &output_filename, width, &mut settings);
+                }
+
+                match args.value_of("benchmark") {
+                    Some(iterations) => {
+                        let iterations = iterations
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|&iterations| iterations > 0)
+                            .ok_or(InvalidIterations)?;
+                        benchmark(input_filename, &output_filename, &settings, iterations)
                     }
+                    None => hdrfix(input_filename, &output_filename, &settings),
                 }
             }
         },
@@ -980,51 +1036,217 @@ fn run(args: &ArgMatches) -> Result<()> {
 }
 
 fn main() {
-    let args = App::new("hdrfix converter for HDR screenshots")
+    let tone_map_help = format!(
+        "Method for mapping HDR into SDR domain: {}. Several may be chained with commas, like reinhard,aces, each one taking the output of the one before. lut:FILE uses a .cube 3D LUT from BT.2100 PQ to sRGB as the whole tone map instead.",
+        tone_map_names().join(", ")
+    );
+    let app = app(&tone_map_help);
+    let args = app.clone().get_matches();
+
+    match run(&args, &app) {
+        Ok(_) => eprintln!("Done."),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// The command line options, which a folder config adds to in watch mode
+fn app(tone_map_help: &str) -> App<'_, '_> {
+    App::new("hdrfix converter for HDR screenshots")
         .version(crate_version!())
         .author("Brion Vibber <brion@pobox.com>")
         .arg(Arg::with_name("input")
-            .help("Input filename, must be .jxr or .png as saved by NVIDIA capture overlay.")
+            .help("Input filename, must be .jxr or .png as saved by NVIDIA capture overlay. 16-bit BT.2100 PQ PNGs are also supported. Use - with --input-format to read the encoded image from standard input.")
             .index(1))
         .arg(Arg::with_name("output")
-            .help("Output filename, must be .png.")
+            .help("Output filename, must be .png, .jpg or .exr, or - to write the encoded image to standard output.")
             .index(2))
+        .arg(Arg::with_name("input-format")
+            .help("Decode the input as this format regardless of its file extension, for misnamed files or capture tools with nonstandard extensions. Required when the input is - for standard input.")
+            .long("input-format")
+            .possible_values(&["jxr", "png", "exr", "hdr", "dds"])
+            .takes_value(true))
+        .arg(Arg::with_name("raw")
+            .help("Read the input as headerless tightly packed little-endian float32 scRGB samples of this WIDTHxHEIGHT, RGB or RGBA as told by the file's length, with no decoder involved. For synthetic test data and raw frame dumps.")
+            .long("raw")
+            .conflicts_with("input-format")
+            .takes_value(true))
+        .arg(Arg::with_name("output-format")
+            .help("Encode the output as this format regardless of its file extension. Picks the format for - as the output, which is otherwise PNG.")
+            .long("output-format")
+            .possible_values(&["png", "jpg", "exr"])
+            .takes_value(true))
+        .arg(Arg::with_name("sanitize")
+            .help("How to fix NaN and infinite values in float input, which would otherwise corrupt the output: replace them with zero, clamp positive infinity to the brightest representable value (anything else still becomes zero), or leave them alone. A warning reports how many were fixed.")
+            .long("sanitize")
+            .possible_values(&["off", "zero", "clamp"])
+            .default_value("zero"))
+        .arg(Arg::with_name("background")
+            .help("Composite JPEG XR input over a solid background color using its alpha channel, given as linear scRGB \"r,g,b\" where 1.0 is SDR white. Implies --alpha flatten unless --alpha strip is given.")
+            .long("background")
+            .takes_value(true))
+        .arg(Arg::with_name("alpha")
+            .help("What to do with the alpha channel of JPEG XR input: keep it in RGBA PNG output if there's any transparency, strip it, or flatten over --background (black by default).")
+            .long("alpha")
+            .possible_values(&["keep", "strip", "flatten"])
+            .default_value("keep"))
+        .arg(Arg::with_name("trim-black")
+            .help("Crop off black letterbox bars around the input. Border rows and columns with nothing brighter than the threshold in nits are trimmed, by default 0.5; set another with --trim-black=N.")
+            .long("trim-black")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true))
+        .arg(Arg::with_name("aspect")
+            .help("Center-crop the input to this width:height aspect ratio before processing, like 16:9 from an ultrawide capture. Applied after --trim-black.")
+            .long("aspect")
+            .takes_value(true))
+        .arg(Arg::with_name("input-transfer")
+            .help("Override the transfer function of 8- and 16-bit PNG input, which is otherwise taken to be PQ. HLG is shown as on a 1000 nit display; sRGB white is 80 nits.")
+            .long("input-transfer")
+            .possible_values(&["pq", "hlg", "srgb"])
+            .takes_value(true))
+        .arg(Arg::with_name("gamma-input-detect")
+            .help("Guess the transfer function of 8- and 16-bit PNG input from its pixels, as PQ, HLG, or sRGB, and log the guess. --input-transfer takes precedence.")
+            .long("gamma-input-detect"))
+        .arg(Arg::with_name("working-space")
+            .help("Color space the tone map works in. rec2020 keeps saturated wide gamut colors apart through the tone curve, and they're only brought into the output gamut by the color map afterwards.")
+            .long("working-space")
+            .possible_values(&["srgb", "rec2020"])
+            .default_value("srgb"))
+        .arg(Arg::with_name("input-primaries")
+            .help("Override the color primaries of the input, for content that was saved in a different color space than its format implies. By default PNG input is taken as BT.2100 and JXR as scRGB (Rec.709 primaries).")
+            .long("input-primaries")
+            .possible_values(&["rec709", "rec2020", "rec2100", "p3"])
+            .takes_value(true))
+        .arg(Arg::with_name("input-scale")
+            .help("Multiply the decoded input values by this, before anything else, for captures stored at another scale than 1.0 for 80 nit SDR white. 2 doubles the values of a capture stored at half scale.")
+            .long("input-scale")
+            .default_value("1"))
+        .arg(Arg::with_name("auto-wb")
+            .help("Neutralize a color cast before tone mapping, estimating the light's color from the average of the image (grayworld) or from its brightest near-neutral colors (whitepatch), then adapting from it to D65 with the Bradford transform.")
+            .long("auto-wb")
+            .possible_values(&["off", "grayworld", "whitepatch"])
+            .default_value("off"))
         .arg(Arg::with_name("auto-exposure")
             .help("Input level or percentile of input data to average to re-expose to neutral 50% mid-tone on input. Default is 0.5, which passes input through unchanged.")
             .long("auto-exposure")
             .default_value("0.5"))
         .arg(Arg::with_name("exposure")
-            .help("Exposure adjustment in stops, applied after any auto exposure adjustment. May be positive or negative in stops; defaults to 0, which does not change the exposure.")
+            .help("Exposure adjustment in stops, applied after any auto exposure adjustment. May be positive or negative in stops; defaults to 0, which does not change the exposure. Three comma-separated values like 0.2,0,-0.3 adjust red, green and blue separately, as a quick fix for a color cast.")
             .long("exposure")
             .default_value("0"))
+        .arg(Arg::with_name("exposure-bracket")
+            .help("Write several outputs at a range of exposures, given as start:stop:step in stops (e.g. -2:2:1). The exposure is appended to each output filename, like out-2.0.png. Overrides --exposure.")
+            .long("exposure-bracket")
+            .takes_value(true))
+        .arg(Arg::with_name("tile-output")
+            .help("Split the output into a grid of separate files, given as rows,cols, for huge panoramas other tools can't open whole. Each tile's row and column are appended to the output filename, like out-r0-c1.png.")
+            .long("tile-output")
+            .conflicts_with("sweep")
+            .takes_value(true))
+        .arg(Arg::with_name("sweep")
+            .help("Write a grid of small previews instead of the plain result, running the conversion once for each of several values of a setting, like hdr-max=200,400,800,1600. Each tile is labeled with its value. The setting may be exposure, hdr-max (in nits), saturation, vibrance, shadows, highlights or grain.")
+            .long("sweep")
+            .conflicts_with_all(&["exposure-bracket", "split-compare", "false-color"])
+            .takes_value(true))
+        .arg(Arg::with_name("channel-mix")
+            .help("3x3 matrix applied to the linear input colors after exposure and before tone mapping, as nine comma-separated values row by row, e.g. \"1,0,0,0,1,0,0,0,1\" for no change. Each row gives the mix of input red, green and blue making up that output channel.")
+            .long("channel-mix")
+            .allow_hyphen_values(true)
+            .takes_value(true))
+        .arg(Arg::with_name("shadows")
+            .help("Exposure change in stops for the darkest parts of the image before tone mapping, fading out towards middle gray, like the shadows slider of a photo editor. Positive lifts shadows; 0 changes nothing.")
+            .long("shadows")
+            .allow_hyphen_values(true)
+            .default_value("0"))
+        .arg(Arg::with_name("highlights")
+            .help("Exposure change in stops for the brightest parts of the image before tone mapping, fading out towards middle gray. Negative pulls highlights down; 0 changes nothing.")
+            .long("highlights")
+            .allow_hyphen_values(true)
+            .default_value("0"))
         .arg(Arg::with_name("tone-map")
-            .help("Method for mapping HDR into SDR domain.")
+            .help(tone_map_help)
             .long("tone-map")
-            .possible_values(&["linear", "reinhard", "reinhard-rgb", "aces", "uncharted2", "hable"])
+            .takes_value(true)
             .default_value("hable"))
+        .arg(Arg::with_name("tone-map-param")
+            .help("Set a constant of the tone map as key=value, like white=1000 for the Reinhard maps (in nits, overriding hdr-max), exposure_bias for aces, uncharted2 and hable, linear_white for linear, uncharted2 and hable, desaturation for hable, or tiles for adaptive-tiles. May be given more than once.")
+            .long("tone-map-param")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
         .arg(Arg::with_name("hdr-max")
-            .help("Max HDR luminance level for Reinhard algorithm, in nits or a percentile to be calculated from input data. The default is 100%, which represents the highest input value.")
+            .help("Max HDR luminance level for Reinhard algorithms, in nits or a percentile to be calculated from input data. The default is 100%, which represents the highest input value after dropping outliers per hdr-max-clip. 'content' finds where the bright tail of the image's luma distribution begins, adapting to each image's highlights.")
             .long("hdr-max")
             .default_value("100%"))
+        .arg(Arg::with_name("hdr-max-clip")
+            .help("Percent of the brightest input values to ignore as outliers before picking a percentile hdr-max, so a single hot pixel can't skew the white point. Use 0 to consider every pixel.")
+            .long("hdr-max-clip")
+            .default_value("0.01%"))
+        .arg(Arg::with_name("roi")
+            .help("Region of interest as x,y,width,height in pixels. A percentile or content hdr-max is detected from just this part of the image, while the whole image is still tone mapped.")
+            .long("roi")
+            .takes_value(true))
         .arg(Arg::with_name("saturation")
-            .help("Coefficient for how to scale saturation in tone mapping. 1.0 will desaturate linearly to the compression ratio; smaller values will desaturate more aggressively.")
+            .help("Coefficient for how to scale saturation in tone mapping. 1.0 will desaturate linearly to the compression ratio; smaller values will desaturate more aggressively. 'auto' picks a value per image that keeps colors vivid without pushing many out of gamut.")
             .long("saturation")
             .default_value("1"))
+        .arg(Arg::with_name("vibrance")
+            .help("Saturation boost applied to the final image, with less boost for colors that are already saturated. 1.0 leaves colors unchanged, smaller values mute them.")
+            .long("vibrance")
+            .default_value("1"))
+        .arg(Arg::with_name("split-tone")
+            .help("Tint the shadows and highlights of the final image, given as shadow=#rrggbb,highlight=#rrggbb,balance=N. Each color's hue and relative chroma shift oklab a,b, weighted by lightness; balance is the lightness between 0 and 1 where both tints weigh the same, 0.5 by default.")
+            .long("split-tone")
+            .takes_value(true))
+        .arg(Arg::with_name("grain")
+            .help("Add monochrome film grain of this strength to the final image, stronger in the shadows like real film. Around 1 is clearly visible; 0 adds none.")
+            .long("grain")
+            .default_value("0"))
+        .arg(Arg::with_name("metering")
+            .help("Which part of the frame percentile and content levels of --auto-exposure and --hdr-max are measured from, like a camera's metering modes: the whole frame evenly, the whole frame weighted toward the middle, or just a small spot in the middle. --roi takes precedence for --hdr-max.")
+            .long("metering")
+            .possible_values(&["average", "center", "spot"])
+            .default_value("average"))
+        .arg(Arg::with_name("histogram-sample")
+            .help("Build the input histogram for percentile and content levels from this fraction of the pixels, like 0.1, picked evenly over the image at random with --seed. Much faster on huge images, at the cost of slightly less exact levels. By default every pixel is used.")
+            .long("histogram-sample")
+            .takes_value(true))
+        .arg(Arg::with_name("seed")
+            .help("Seed for the --grain pattern and the --histogram-sample pixels. The same seed gives the same result on every run.")
+            .long("seed")
+            .default_value("0"))
+        .arg(Arg::with_name("max-chroma")
+            .help("Clamp the oklab chroma of the final image to at most this value, keeping hue and lightness. The most saturated sRGB colors are around 0.32.")
+            .long("max-chroma")
+            .takes_value(true))
         .arg(Arg::with_name("color-map")
             .help("Method for mapping and fixing out of gamut colors.")
             .long("color-map")
-            .possible_values(&["clip", "darken", "desaturate", "desaturate-oklab"])
+            .possible_values(&color_map_names())
             .default_value("clip"))
+        .arg(Arg::with_name("lut-apply")
+            .help("3D .cube LUT file to apply to the final SDR colors before saving, e.g. for a creative grade.")
+            .long("lut-apply")
+            .takes_value(true))
+        .arg(Arg::with_name("grayscale")
+            .help("Render the output in grayscale from the tone mapped colors' oklab lightness, or from a weighted mix of linear red, green and blue given as --grayscale=r,g,b.")
+            .long("grayscale")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true))
         .arg(Arg::with_name("pre-gamma")
             .help("Gamma power applied on input.")
             .long("pre-gamma")
             .default_value("1.0"))
         .arg(Arg::with_name("pre-levels-min")
-            .help("Minimum input level to normalize to 0 when expanding input for processing. May be an absolute value in -infinity..infinity range or a percentile from 0% to 100%.")
+            .help("Minimum input level to normalize to 0 when expanding input for processing. May be an absolute value in -infinity..infinity range, a percentile from 0% to 100%, or a luminance in nits like 5nits.")
             .long("pre-levels-min")
             .default_value("0.0"))
         .arg(Arg::with_name("pre-levels-max")
-            .help("Maximum input level to normalize to 1 when expanding input for processing. May be an absolute value in -infinity..infinity range or a percentile from 0% to 100%.")
+            .help("Maximum input level to normalize to 1 when expanding input for processing. May be an absolute value in -infinity..infinity range, a percentile from 0% to 100%, or a luminance in nits like 5nits.")
             .long("pre-levels-max")
             .default_value("1.0"))
         .arg(Arg::with_name("post-gamma")
@@ -1032,36 +1254,433 @@ fn main() {
             .long("post-gamma")
             .default_value("1.0"))
         .arg(Arg::with_name("post-levels-min")
-            .help("Minimum output level to save when expanding final SDR output for saving. May be an absolute value in 0..1 range or a percentile from 0% to 100%.")
+            .help("Minimum output level to save when expanding final SDR output for saving. May be an absolute value in 0..1 range, a percentile from 0% to 100%, or a luminance in nits of the input like 5nits, which is mapped through the exposure and tone map.")
             .long("post-levels-min")
             .default_value("0.0"))
         .arg(Arg::with_name("post-levels-max")
-            .help("Maximum output level to save when expanding final SDR output for saving. May be an absolute value in 0..1 range or a percentile from 0% to 100%.")
+            .help("Maximum output level to save when expanding final SDR output for saving. May be an absolute value in 0..1 range, a percentile from 0% to 100%, or a luminance in nits of the input like 5nits, which is mapped through the exposure and tone map.")
             .long("post-levels-max")
             .default_value("1.0"))
+        .arg(Arg::with_name("auto-levels")
+            .help("Stretch the R, G and B channels of the tone-mapped image separately, clipping this percent of each channel's darkest and brightest values (e.g. 0.1%). Corrects color casts; applied before the post-levels.")
+            .long("auto-levels")
+            .takes_value(true))
+        .arg(Arg::with_name("auto-skip-tonemap")
+            .help("Skip the tone map, as if it were linear, for input that never goes past SDR white once exposed, so SDR content saved as HDR isn't darkened by the curve. Color mapping and levels still apply.")
+            .long("auto-skip-tonemap"))
+        .arg(Arg::with_name("denoise-chroma")
+            .help("Blur just the oklab chroma of the tone-mapped image by a gaussian of this radius in pixels, keeping the lightness untouched, to smooth out color blotches in noisy shadows without softening edges. 0 turns it off.")
+            .long("denoise-chroma")
+            .default_value("0"))
+        .arg(Arg::with_name("output-depth")
+            .help("Bits per channel for PNG output. 'auto' picks 16 when the image has smooth gradients that would show banding at 8 bits. JPEG output is always 8 bits.")
+            .long("output-depth")
+            .possible_values(&["8", "16", "auto"])
+            .default_value("8"))
+        .arg(Arg::with_name("quantize")
+            .help("How SDR output rounds to whole sample values: truncate (as versions before this option did), round to nearest, or ordered dither to hide banding.")
+            .long("quantize")
+            .possible_values(&["truncate", "round", "dither"])
+            .default_value("round"))
+        .arg(Arg::with_name("palette")
+            .help("Write an indexed PNG with this many colors, 2 to 256, for much smaller files of flat-color content like UI screenshots. Images with more colors get a palette picked in oklab and are dithered to it.")
+            .long("palette")
+            .takes_value(true))
+        .arg(Arg::with_name("output-transfer")
+            .help("Transfer function to encode SDR output with: srgb, bt1886 (the 2.4 gamma of BT.709 video) for stills going into video, or a plain 2.2 gamma.")
+            .long("output-transfer")
+            .possible_values(&["srgb", "bt1886", "gamma22"])
+            .default_value("srgb"))
+        .arg(Arg::with_name("exr-compression")
+            .help("Compression for .exr output, which keeps the HDR float data with only exposure and channel mixing applied.")
+            .long("exr-compression")
+            .possible_values(&["none", "zip", "piz"])
+            .default_value("zip"))
+        .arg(Arg::with_name("keep-hdr")
+            .help("Also write the decoded HDR input, untouched, to an .exr next to the output, named like out-hdr.exr for out.png. Uses --exr-compression.")
+            .long("keep-hdr"))
+        .arg(Arg::with_name("export-curve")
+            .help("Also write the luminance curve of the conversion to this CSV file: input nits against linear and encoded output from 0 to 1, sampled over a gray ramp with the exposure, tone map and levels the image gets.")
+            .long("export-curve")
+            .takes_value(true))
+        .arg(Arg::with_name("linearize-only")
+            .help("Debug the decoder: write the input exactly as decoded to linear scRGB, with no exposure, tone mapping, or levels, to .exr output.")
+            .long("linearize-only"))
+        .arg(Arg::with_name("inverse")
+            .help("Expand SDR input into HDR instead: an inverse Reinhard curve on luminance takes SDR white up to --hdr-max in nits, or 1000 nits by default, written as 16-bit BT.2100 PQ .png or linear scRGB .exr. PNG input is read as sRGB unless --input-transfer says otherwise; --exposure applies first.")
+            .long("inverse")
+            .conflicts_with_all(&["linearize-only", "low-memory", "tui", "sweep", "exposure-bracket", "split-compare", "false-color"]))
+        .arg(Arg::with_name("add-metadata")
+            .help("Add a key=value text field to PNG output, like 'Title=Sunrise'. May be given more than once. Text and EXIF metadata from PNG input is always kept.")
+            .long("add-metadata")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("dpi")
+            .help("Record this print resolution in dots per inch in a pHYs chunk of PNG output, so it prints at the intended size. Left out by default.")
+            .long("dpi")
+            .takes_value(true))
+        .arg(Arg::with_name("split-compare")
+            .help("Show a before and after comparison: the left half of the output is the input simply clipped to SDR at the same exposure, the right half the full conversion.")
+            .long("split-compare")
+            .takes_value(false))
+        .arg(Arg::with_name("show-oog")
+            .help("Tint pixels magenta where the tone-mapped color was out of the SDR gamut before the color map, to see what the color map has to fix.")
+            .long("show-oog")
+            .takes_value(false))
+        .arg(Arg::with_name("oog-mask")
+            .help("Also write a grayscale PNG to this file showing how far each pixel was out of the SDR gamut after tone mapping, before the color map: black is in gamut, and white is twice SDR white or more. Load it as a selection mask in other tools.")
+            .long("oog-mask")
+            .takes_value(true))
+        .arg(Arg::with_name("color-map-diff")
+            .help("Also write a grayscale PNG to this file showing how much the color map changed each pixel compared to plain clipping, as the oklab distance between the two amplified ten times: black is unchanged, and white is a distance of 0.1 or more.")
+            .long("color-map-diff")
+            .takes_value(true))
+        .arg(Arg::with_name("false-color")
+            .help("Instead of tone mapping, color each pixel by the luminance of the input in nits, from blue for the darkest to red for over 1000 nits. The legend of bands is printed.")
+            .long("false-color")
+            .takes_value(false))
+        .arg(Arg::with_name("ignore-hue-shift-warning")
+            .help("Don't measure and print the hue shift of per-channel tone maps like reinhard-rgb and aces.")
+            .long("ignore-hue-shift-warning"))
+        .arg(Arg::with_name("warn-clip-above")
+            .help("Warn and exit with an error code if more than this percentage of pixels were clipped: left out of the SDR gamut by the tone map, before the color map. The output is still written. With --input-dir, every file over the threshold is listed.")
+            .long("warn-clip-above")
+            .takes_value(true))
+        .arg(Arg::with_name("clip-report")
+            .help("Print how many pixels each of red, green and blue was clipped above SDR white or below black, before the color map. Clipping mostly in one channel points at a color cast or at input beyond that primary.")
+            .long("clip-report"))
+        .arg(Arg::with_name("best-effort")
+            .help("If a PNG or JPEG XR input is cut short or damaged partway through, convert the rows decoded before the damage with the rest of the image black, and print a warning, instead of failing.")
+            .long("best-effort"))
+        .arg(Arg::with_name("strict")
+            .help("Fail with an error on NaN or infinite input values, values beyond 10000 nits, 8- or 16-bit PNG tagged as sRGB or gamma encoded without --input-transfer, and auto white balance with nothing to go on, instead of warning and carrying on.")
+            .long("strict")
+            .conflicts_with("best-effort"))
+        .arg(Arg::with_name("low-memory")
+            .help("Decode, convert and save the image a strip of rows at a time, to keep memory use down on enormous images. Percentile levels, auto exposure, auto levels and exposure brackets need the whole image at once, so can't be used in this mode.")
+            .long("low-memory")
+            .takes_value(false))
+        .arg(Arg::with_name("gpu")
+            .help("Run the exposure, tone map and color map on the GPU. Needs hdrfix built with the gpu feature; falls back to the CPU with a warning if there's no GPU or the tone or color map is CPU-only.")
+            .long("gpu")
+            .takes_value(false))
         .arg(Arg::with_name("watch")
             .help("Watch a folder and convert any *.jxr files that appear into *-sdr.jpg versions. Provide a folder name.")
             .long("watch")
             .takes_value(true))
+        .arg(Arg::with_name("watch-debounce")
+            .help("Seconds to wait for file events to settle in watch mode before converting. Files are also only converted once their size stops changing.")
+            .long("watch-debounce")
+            .default_value("2"))
+        .arg(Arg::with_name("watch-existing")
+            .help("In watch mode, first convert any *.jxr files already in the folder that don't have an output file yet.")
+            .long("watch-existing")
+            .requires("watch"))
         .arg(Arg::with_name("output-suffix")
-            .help("Suffix for output files when watching or converting a directory. May also be used instead of an explicit output file name for single files. Includes the suffix for the filename including the file extension, must be either png or jpg. Defaults to '-sdr.jpg'.")
+            .help("Suffix for output files when watching or converting a directory. May also be used instead of an explicit output file name for single files. Includes the suffix for the filename including the file extension, must be png, jpg or exr. Defaults to '-sdr.jpg'.")
             .long("output-suffix")
             .short("s")
             .default_value("-sdr.jpg"))
+        .arg(Arg::with_name("output-template")
+            .help("Template for output file names when watching or converting a directory, or without an explicit output file name, used instead of output-suffix. Placeholders are {stem} and {ext} of the input file, {date} as YYYY-MM-DD and {tonemap}, like '{stem}_{tonemap}_sdr.png'.")
+            .long("output-template")
+            .takes_value(true))
         .arg(Arg::with_name("overwrite")
-            .help("If this option is enabled output files with the same name will be overwritten.")
+            .help("Overwrite output files that already exist. By default an existing output file is an error, to avoid accidentally losing data.")
             .long("overwrite")
+            .conflicts_with("skip-existing")
+            .takes_value(false))
+        .arg(Arg::with_name("skip-existing")
+            .help("Silently skip input files whose output file already exists, e.g. when re-running a batch.")
+            .long("skip-existing")
             .takes_value(false))
         .arg(Arg::with_name("input-dir")
-            .help("Input directory for jxr files to convert. The name for each output file is determined by the output-suffix argument. Whether existing output files are overwritten is defined by the overwrite option.")
+            .help("Input directory for jxr files to convert. The name for each output file is determined by the output-suffix argument. Whether existing output files are overwritten is defined by the overwrite and skip-existing options.")
             .long("input-dir")
             .alias("dir")
             .short("d")
             .takes_value(true))
-        .get_matches();
+        .arg(Arg::with_name("self-test")
+            .help("Check the color math of this build on known values and round trips (PQ, sRGB, oklab, primaries matrices and more), printing the largest error of each, without any input file. Exits with an error code if a check fails.")
+            .long("self-test"))
+        .arg(Arg::with_name("benchmark")
+            .help("Read the input once, then run the conversion the given number of times without writing output, and report min/median/mean/stddev timings for each stage.")
+            .long("benchmark")
+            .conflicts_with_all(&["watch", "input-dir", "dry-run"])
+            .takes_value(true))
+        .arg(Arg::with_name("tui")
+            .help("Read the input once and show a shrunken preview in the terminal, adjusting exposure, hdr-max and saturation with typed commands until the output is written with w. Needs a terminal with 24-bit color.")
+            .long("tui")
+            .conflicts_with_all(&["watch", "input-dir", "dry-run", "benchmark"]))
+        .arg(Arg::with_name("tui-width")
+            .help("Width in terminal columns of the --tui preview.")
+            .long("tui-width")
+            .default_value("80"))
+        .arg(Arg::with_name("resize-filter")
+            .help("Filter for shrinking the image for --tui and --sweep, in linear light: lanczos3 is sharpest, mitchell a little softer with less ringing at hard edges, bilinear softer still, and box a plain average of each block of pixels.")
+            .long("resize-filter")
+            .possible_values(&["box", "bilinear", "lanczos3", "mitchell"])
+            .default_value("lanczos3"))
+        .arg(Arg::with_name("print-matrix")
+            .help("Print the color matrix from the input's linear samples to the tone map's working space, taking in its primaries, --input-primaries, --channel-mix and --working-space, then the matrix back to the output primaries. Nothing is converted.")
+            .long("print-matrix")
+            .conflicts_with_all(&["watch", "input-dir", "dry-run", "benchmark", "tui"]))
+        .arg(Arg::with_name("suggest")
+            .help("Measure the input's peak and average nits and print suggested exposure and hdr-max settings for a 100 nit sRGB display and 400, 600 and 1000 nit HDR displays. Nothing is converted.")
+            .long("suggest")
+            .conflicts_with_all(&["watch", "input-dir", "dry-run", "benchmark", "tui", "print-matrix"]))
+        .arg(Arg::with_name("fast")
+            .help("Use a cheaper approximate oklab conversion for the --tui preview and --sweep tiles. Colors may be off by a fraction of a percent; the output --tui writes always uses the exact conversion, as do plain conversions.")
+            .long("fast"))
+        .arg(Arg::with_name("file-concurrency")
+            .help("How many files of an --input-dir to convert at once. Each conversion already uses all cores, so more mainly overlaps file reading and writing; every file in flight holds its full image buffers in memory.")
+            .long("file-concurrency")
+            .default_value("1"))
+        .arg(Arg::with_name("exposure-match")
+            .help("Use the same auto exposure and hdr-max for every file of an --input-dir, measured from the first file in name order or averaged over all of them, so frames of a captured sequence don't flicker.")
+            .long("exposure-match")
+            .possible_values(&["first", "average"])
+            .requires("input-dir")
+            .takes_value(true))
+        .arg(Arg::with_name("manifest")
+            .help("JSON file recording which files of an --input-dir were converted. Files it lists are skipped, so a batch that was interrupted can be run again to finish it. Created if missing.")
+            .long("manifest")
+            .requires("input-dir")
+            .takes_value(true))
+        .arg(Arg::with_name("dry-run")
+            .help("Check that the input files exist and can be decoded, without converting them. Reports a summary of any problems found.")
+            .long("dry-run")
+            .conflicts_with("watch")
+            .takes_value(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jxr_extension_ignores_case() {
+        assert!(is_jxr(Path::new("shot.jxr")));
+        assert!(is_jxr(Path::new("SHOT.JXR")));
+        assert!(is_jxr(Path::new("dir/shot.Jxr")));
+        assert!(!is_jxr(Path::new("shot.png")));
+        assert!(!is_jxr(Path::new("jxr")));
+    }
+
+    #[test]
+    fn find_inputs_ignores_case() {
+        let folder = env::temp_dir().join(format!("hdrfix-find-inputs-{}", process::id()));
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("a.jxr"), b"").unwrap();
+        fs::write(folder.join("sub").join("B.JXR"), b"").unwrap();
+        fs::write(folder.join("c.png"), b"").unwrap();
+
+        let mut inputs = Vec::new();
+        find_inputs(&folder, &mut inputs).unwrap();
+        inputs.sort();
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(
+            inputs,
+            vec![folder.join("a.jxr"), folder.join("sub").join("B.JXR")]
+        );
+    }
+
+    fn config_args(contents: &str) -> Vec<String> {
+        folder_config_args(contents)
+            .unwrap()
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn folder_config_comments() {
+        let contents = "# settings for this game\n\nsaturation = 1.2 # a bit more\n   # indented\n";
+        assert_eq!(config_args(contents), vec!["--saturation=1.2"]);
+        assert_eq!(strip_comment("key = 1 # note"), "key = 1 ");
+        assert_eq!(strip_comment("key = \"a # b\" # note"), "key = \"a # b\" ");
+        assert_eq!(
+            strip_comment("key = \"a \\\" # b\""),
+            "key = \"a \\\" # b\""
+        );
+    }
+
+    #[test]
+    fn folder_config_values() {
+        let contents = concat!(
+            "tone_map = \"reinhard\"\n",
+            "add-metadata = \"Comment=C:\\\\shots \\\"hdr\\\" # 1\"\n",
+            "hdr-max = 1000\n",
+            "keep_hdr = true\n",
+            "gpu = false\n",
+        );
+        assert_eq!(
+            config_args(contents),
+            vec![
+                "--tone-map=reinhard",
+                "--add-metadata=Comment=C:\\shots \"hdr\" # 1",
+                "--hdr-max=1000",
+                "--keep-hdr",
+            ]
+        );
+
+        // The folder's options replace the command line's
+        let help = String::new();
+        let command_line = ["hdrfix", "in.jxr", "--tone-map=hable", "--hdr-max=400"];
+        let args = config_matches(
+            &app(&help),
+            command_line.iter().map(OsString::from),
+            folder_config_args(contents).unwrap(),
+        )
+        .unwrap();
+        let overridden = settings(&args).unwrap();
+        assert_eq!(overridden.tone_map, "reinhard");
+        assert!(matches!(overridden.hdr_max, Level::Scalar(nits) if nits == 1000.0));
+        assert!(overridden.keep_hdr);
+        assert!(!overridden.gpu);
+        assert_eq!(
+            overridden.add_metadata,
+            vec![("Comment".to_string(), "C:\\shots \"hdr\" # 1".to_string())]
+        );
+    }
+
+    #[test]
+    fn folder_config_unknown_key() {
+        // Passed through for clap to reject, like an unknown option
+        let config_args = folder_config_args("not_an_option = 3").unwrap();
+        assert_eq!(config_args, vec!["--not-an-option=3"]);
+        let help = String::new();
+        let command_line = ["hdrfix", "in.jxr"].iter().map(OsString::from);
+        let error = config_matches(&app(&help), command_line, config_args).unwrap_err();
+        assert!(error.contains("--not-an-option"), "{}", error);
+    }
+
+    #[test]
+    fn folder_config_bad_lines() {
+        let error = |contents| folder_config_args(contents).unwrap_err();
+        assert_eq!(
+            error("saturation = 1\nsaturation"),
+            "line 2: expected key = value"
+        );
+        assert_eq!(error(" = 1"), "line 1: invalid key ''");
+        assert_eq!(error("bad key = 1"), "line 1: invalid key 'bad key'");
+        assert_eq!(error("tone.map = 1"), "line 1: invalid key 'tone.map'");
+        assert_eq!(error("output-dir = \"shots"), "line 1: unterminated string");
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("plain.jxr"), "\"plain.jxr\"");
+        assert_eq!(json_string("a \"b\".jxr"), "\"a \\\"b\\\".jxr\"");
+        assert_eq!(json_string("C:\\shots\\a.jxr"), "\"C:\\\\shots\\\\a.jxr\"");
+        assert_eq!(json_string("a\nb\t\u{1}"), "\"a\\u000ab\\u0009\\u0001\"");
+        assert_eq!(json_string("スクショ/é.jxr"), "\"スクショ/é.jxr\"");
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let paths = [
+            "plain.jxr",
+            "a \"b\".jxr",
+            "C:\\shots\\a.jxr",
+            "line\nbreak\t\r\u{7f}.jxr",
+            "スクショ/é.jxr",
+        ];
+        let entries = paths
+            .iter()
+            .map(|path| json_string(path))
+            .collect::<Vec<_>>();
+        let contents = format!("{{\"completed\": [{}]}}", entries.join(", "));
+        let expected = paths
+            .iter()
+            .map(|path| path.to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(parse_manifest(&contents), Ok(expected));
+        assert_eq!(parse_manifest("{\"completed\": []}"), Ok(HashSet::new()));
+
+        let path = env::temp_dir().join(format!("hdrfix-manifest-{}.json", process::id()));
+        let mut manifest = Manifest::load(&path).unwrap();
+        for input in &paths {
+            manifest.insert(Path::new(input)).unwrap();
+        }
+        let reloaded = Manifest::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for input in &paths {
+            assert!(reloaded.is_completed(Path::new(input)));
+        }
+        assert!(!reloaded.is_completed(Path::new("other.jxr")));
+    }
+
+    #[test]
+    fn manifest_damaged() {
+        assert_eq!(parse_manifest("{}"), Err("no \"completed\" array"));
+        assert_eq!(
+            parse_manifest("{\"completed\": [\"a.jxr"),
+            Err("unterminated string")
+        );
+        assert_eq!(
+            parse_manifest("{\"completed\": [\"\\uzz\"]}"),
+            Err("bad \\u escape")
+        );
+        assert_eq!(
+            parse_manifest("{\"completed\": [1]}"),
+            Err("expected an array of strings for \"completed\"")
+        );
+    }
+
+    #[test]
+    fn watch_cache_reload() {
+        let folder = env::temp_dir().join(format!("hdrfix-watch-cache-{}", process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let input = folder.join("sub").join("a.jxr");
+        let output = folder.join("a.png");
+        fs::write(&output, b"").unwrap();
+        let stamp = FileStamp {
+            modified: 1_600_000_000_000_000_000,
+            size: 1234,
+        };
+        let changed = FileStamp {
+            size: 1235,
+            ..stamp
+        };
+
+        let mut cache = WatchCache::load(&folder);
+        assert!(!cache.is_current(&input, &output, stamp));
+        cache.insert(&input, stamp).unwrap();
+        assert!(cache.is_current(&input, &output, stamp));
+
+        // Damaged lines are skipped, the rest still load
+        let mut contents = fs::read_to_string(folder.join(WATCH_CACHE_NAME)).unwrap();
+        contents.push_str("garbage\nx\t12\tb.jxr\n1\t2\n\n");
+        fs::write(folder.join(WATCH_CACHE_NAME), contents).unwrap();
+
+        let reloaded = WatchCache::load(&folder);
+        assert!(reloaded.is_current(&input, &output, stamp));
+        assert!(!reloaded.is_current(&input, &output, changed));
+        assert!(!reloaded.is_current(&folder.join("b.jxr"), &output, stamp));
+        assert_eq!(reloaded.entries.len(), 1);
+
+        fs::remove_file(&output).unwrap();
+        assert!(!reloaded.is_current(&input, &output, stamp));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn watch_cache_missing() {
+        let folder = env::temp_dir().join(format!("hdrfix-no-watch-cache-{}", process::id()));
+        assert!(WatchCache::load(&folder).entries.is_empty());
+    }
 
-    match run(&args) {
-        Ok(_) => println!("Done."),
-        Err(e) => eprintln!("Error: {}", e),
+    #[test]
+    fn batch_progress_estimate() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(batch_progress(0, 3, Duration::ZERO), "0/3 done");
+        assert_eq!(batch_progress(0, 3, minute), "0/3 done");
+        assert_eq!(batch_progress(1, 3, minute), "1/3 done, ~2 min remaining");
+        assert_eq!(batch_progress(2, 3, minute), "2/3 done, ~1 min remaining");
+        assert_eq!(batch_progress(3, 3, minute), "3/3 done");
+        assert_eq!(batch_progress(0, 0, Duration::ZERO), "0/0 done");
     }
 }
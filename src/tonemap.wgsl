@@ -0,0 +1,114 @@
+// Exposure, channel mixing, tone map and clip color map for --gpu.
+// Mirrors hdr_to_sdr_pixel() and the tone map functions in lib.rs,
+// so output matches the CPU path within float tolerance.
+
+struct Params {
+    channel_mix: mat3x3<f32>,
+    scale: f32,
+    hdr_max: f32,
+    saturation: f32,
+    tone_map: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> pixels_in: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read_write> pixels_out: array<vec4<f32>>;
+
+fn luma_rgb(v: vec3<f32>) -> f32 {
+    return dot(v, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn tonemap_reinhard_rgb(c_in: vec3<f32>) -> vec3<f32> {
+    let white2 = params.hdr_max * params.hdr_max;
+    return c_in * (1.0 + c_in / white2) / (1.0 + c_in);
+}
+
+fn tonemap_reinhard_luminance(c_in: vec3<f32>) -> vec3<f32> {
+    let white2 = params.hdr_max * params.hdr_max;
+    let luma_in = luma_rgb(c_in);
+    if (luma_in <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    let luma_out = luma_in * (1.0 + luma_in / white2) / (1.0 + luma_in);
+    return pow(max(c_in / luma_in, vec3<f32>(0.0)), vec3<f32>(params.saturation)) * luma_out;
+}
+
+fn uncharted2_tonemap_partial(x: f32) -> f32 {
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    return ((x * (a * x + (c * b)) + (d * e)) / (x * (a * x + b) + (d * f))) - (e / f);
+}
+
+fn tonemap_uncharted2(v: vec3<f32>) -> vec3<f32> {
+    let luma = luma_rgb(v);
+    let curr = uncharted2_tonemap_partial(luma * 2.0);
+    let white_scale = 1.0 / uncharted2_tonemap_partial(11.2);
+    return v * (curr * white_scale / luma);
+}
+
+fn tonemap_hable(v: vec3<f32>) -> vec3<f32> {
+    let luma = luma_rgb(v);
+    let epsilon = 1e-6;
+    let overbright = max(luma - 2.0, epsilon) / max(luma, epsilon);
+    let rgb_out = v * (1.0 - overbright) + luma * overbright;
+    let sig_orig = max(max(max(rgb_out.x, rgb_out.y), rgb_out.z), epsilon);
+    let white_scale = 1.0 / uncharted2_tonemap_partial(11.2);
+    let sig = uncharted2_tonemap_partial(sig_orig * 2.0) * white_scale;
+    return rgb_out * (sig / sig_orig);
+}
+
+// Same terms as aces_mul() in lib.rs, given the matrix rows
+fn aces_mul(m0: vec3<f32>, m1: vec3<f32>, m2: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(dot(m0, v), dot(m1, v.yyz), dot(m2, v.yyz));
+}
+
+fn tonemap_aces(c_in: vec3<f32>) -> vec3<f32> {
+    var v = aces_mul(
+        vec3<f32>(0.59719, 0.35458, 0.04823),
+        vec3<f32>(0.07600, 0.90834, 0.01566),
+        vec3<f32>(0.02840, 0.13383, 0.83777),
+        c_in,
+    );
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+    v = a / b;
+    return aces_mul(
+        vec3<f32>(1.60475, -0.53108, -0.07367),
+        vec3<f32>(-0.10208, 1.10813, -0.00605),
+        vec3<f32>(-0.00327, -0.07276, 1.07602),
+        v,
+    );
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if (i >= arrayLength(&pixels_in)) {
+        return;
+    }
+    var c = params.channel_mix * (pixels_in[i].rgb * params.scale);
+    // Numbered as in gpu::tone_map_index()
+    switch params.tone_map {
+        case 1u: {
+            c = tonemap_reinhard_rgb(c);
+        }
+        case 2u: {
+            c = tonemap_reinhard_luminance(c);
+        }
+        case 3u: {
+            c = tonemap_aces(c);
+        }
+        case 4u: {
+            c = tonemap_uncharted2(c);
+        }
+        case 5u: {
+            c = tonemap_hable(c);
+        }
+        default: {}
+    }
+    pixels_out[i] = vec4<f32>(clamp(c, vec3<f32>(0.0), vec3<f32>(1.0)), 0.0);
+}
@@ -0,0 +1,120 @@
+// Interactive preview for --tui: draws a shrunken tone mapped copy of the
+// input in the terminal and reads commands a line at a time to adjust the
+// settings, then writes the full size output once the user is happy.
+// Plain ANSI truecolor escapes keep this free of terminal library deps.
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use hdrfix::{Level, LocalError, Preview, Result, Settings};
+
+// Stops added or taken away by the + and - commands
+const EXPOSURE_STEP: f32 = 0.5;
+
+const HELP: &str = "Commands: + / - exposure by half a stop, e <stops> exposure, \
+h <level> hdr-max, s <factor> saturation, w write output and quit, q quit, \
+blank line redraws";
+
+// Two pixel rows per character cell: the upper half block takes the
+// top pixel as its foreground and the bottom pixel as its background.
+fn draw(pixels: &[[u8; 3]], width: usize, out: &mut impl Write) -> io::Result<()> {
+    write!(out, "\x1b[2J\x1b[H")?;
+    let rows: Vec<&[[u8; 3]]> = pixels.chunks(width).collect();
+    for pair in rows.chunks(2) {
+        for (x, top) in pair[0].iter().enumerate() {
+            let bottom = pair.get(1).map_or([0, 0, 0], |row| row[x]);
+            write!(
+                out,
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+            )?;
+        }
+        writeln!(out, "\x1b[0m")?;
+    }
+    Ok(())
+}
+
+// A level the way it's written on the command line
+pub fn level_label(level: Level) -> String {
+    match level {
+        Level::Scalar(val) => format!("{}", val),
+        Level::Percentile(val) => format!("{}%", val),
+        Level::Nits(val) => format!("{}nits", val),
+        Level::Content => String::from("content"),
+    }
+}
+
+fn status(settings: &Settings, clipped_percent: Option<f32>) -> String {
+    let mut status = format!(
+        "exposure {:+.1}  hdr-max {}  saturation {}",
+        settings.exposure,
+        level_label(settings.hdr_max),
+        settings.saturation
+    );
+    if let Some(clipped) = clipped_percent {
+        status.push_str(&format!("  clipped {:.2}%", clipped));
+    }
+    status
+}
+
+/// Run the preview loop on the input, writing the output if asked to.
+pub fn run(
+    input_filename: &Path,
+    output_filename: &Path,
+    width: usize,
+    settings: &mut Settings,
+) -> Result<()> {
+    let preview = Preview::new(input_filename, width, settings)?;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut message = String::from(HELP);
+    loop {
+        let (pixels, clipped_percent) = preview.render(settings)?;
+        let mut out = io::stdout();
+        draw(&pixels, preview.width(), &mut out)?;
+        writeln!(out, "{}", status(settings, clipped_percent))?;
+        writeln!(out, "{}", message)?;
+        write!(out, "> ")?;
+        out.flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let value = words.next();
+        message = String::from(HELP);
+        let result = match (command, value) {
+            ("", _) => Ok(()),
+            ("+", None) => {
+                settings.exposure += EXPOSURE_STEP;
+                Ok(())
+            }
+            ("-", None) => {
+                settings.exposure -= EXPOSURE_STEP;
+                Ok(())
+            }
+            ("e", Some(value)) => value
+                .parse()
+                .map(|stops| settings.exposure = stops)
+                .map_err(LocalError::from),
+            ("h", Some(value)) => {
+                Level::non_negative("hdr-max", value).map(|level| settings.hdr_max = level)
+            }
+            ("s", Some(value)) => value
+                .parse()
+                .map(|factor| settings.saturation = factor)
+                .map_err(LocalError::from),
+            ("w", None) => return preview.write(output_filename, settings),
+            ("q", None) => return Ok(()),
+            _ => {
+                message = format!("Unknown command \"{}\". {}", line.trim(), HELP);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            message = format!("{}. {}", e, HELP);
+        }
+    }
+}
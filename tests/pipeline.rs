@@ -0,0 +1,2933 @@
+// End to end tests running the whole conversion on tiny fixture images,
+// comparing the output against golden PNGs in tests/fixtures.
+//
+// If a change to the color math is intentional, regenerate the golden
+// images by running the tests with HDRFIX_BLESS=1 set, and check the
+// results over by eye before committing them.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use glam::{Mat3, Vec3};
+use hdrfix::LocalError;
+use hdrfix::{
+    color_map_names, color_matrix, convert, expand_output_template, hdrfix, measure_levels,
+    measure_nits, parse_aspect, parse_channel_mix, parse_exposure, parse_grayscale_mix,
+    parse_histogram_sample, parse_raw_size, parse_roi, parse_split_tone, parse_sweep,
+    parse_tile_grid, parse_tone_map_param, probe_input, probe_input_as, self_test,
+    supported_input_extensions, supported_output_extensions, tone_map_names, AlphaMode,
+    ExrCompression, Grayscale, InputFormat, Level, Lut3d, Metering, OutputDepth, OutputFormat,
+    OutputTransfer, OverwritePolicy, PixelBuffer, PixelFormat, Preview, Primaries, Quantize,
+    ResizeFilter, Sanitize, Settings, SplitTone, Transfer, WhiteBalance, FLOAT_PNG_FORMAT,
+    FLOAT_PNG_KEYWORD,
+};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+fn output(name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn read_rgb(filename: &Path) -> (u32, u32, Vec<u8>) {
+    let decoder = png::Decoder::new(File::open(filename).expect("open PNG"));
+    let mut reader = decoder.read_info().expect("read PNG header");
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).expect("read PNG data");
+    data.truncate(info.buffer_size());
+    (info.width, info.height, data)
+}
+
+fn png_bit_depth(filename: &Path) -> png::BitDepth {
+    let decoder = png::Decoder::new(File::open(filename).expect("open PNG"));
+    let reader = decoder.read_info().expect("read PNG header");
+    reader.info().bit_depth
+}
+
+fn png_color_type(filename: &Path) -> png::ColorType {
+    let decoder = png::Decoder::new(File::open(filename).expect("open PNG"));
+    let reader = decoder.read_info().expect("read PNG header");
+    reader.info().color_type
+}
+
+fn assert_matches_golden(output_filename: &Path, golden_name: &str) {
+    let golden_filename = fixture(golden_name);
+    if env::var_os("HDRFIX_BLESS").is_some() {
+        fs::copy(output_filename, &golden_filename).expect("bless golden image");
+    }
+
+    let (width, height, actual) = read_rgb(output_filename);
+    let (golden_width, golden_height, expected) = read_rgb(&golden_filename);
+    assert_eq!((width, height), (golden_width, golden_height));
+    // Zipping the bytes would hide a different channel count or depth
+    assert_eq!(
+        png_color_type(output_filename),
+        png_color_type(&golden_filename),
+        "{}: color type",
+        golden_name
+    );
+    assert_eq!(
+        png_bit_depth(output_filename),
+        png_bit_depth(&golden_filename),
+        "{}: bit depth",
+        golden_name
+    );
+    assert_eq!(actual.len(), expected.len(), "{}: length", golden_name);
+
+    // Allow off-by-one for float rounding differences between platforms.
+    for (i, (a, b)) in actual.iter().zip(expected.iter()).enumerate() {
+        assert!(
+            (*a as i32 - *b as i32).abs() <= 1,
+            "{}: byte {} is {}, expected {}",
+            golden_name,
+            i,
+            a,
+            b
+        );
+    }
+}
+
+// Pixels of a 4-pixel wide EXR, in rows
+fn read_exr_rgb(filename: &Path) -> Vec<[f32; 3]> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        filename,
+        |resolution, _| vec![[0.0; 3]; resolution.width() * resolution.height()],
+        |pixels, position, (r, g, b, _): (f32, f32, f32, f32)| {
+            pixels[position.y() * 4 + position.x()] = [r, g, b]
+        },
+    )
+    .unwrap();
+    image.layer_data.channel_data.pixels
+}
+
+// 4x4 scRGB float gradient with a few saturated and overbright colors
+fn synthetic_float() -> PixelBuffer {
+    let mut buffer = PixelBuffer::new(4, 4, PixelFormat::HDRFloat32);
+    for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+        let level = i as f32 / 2.0;
+        let rgba = match i % 4 {
+            0 => [level, level, level, 1.0],
+            1 => [level, level * 0.25, 0.0, 1.0],
+            2 => [0.0, level * 0.5, level, 1.0],
+            _ => [level * 0.1, level, level * 0.1, 1.0],
+        };
+        for (dest, val) in pixel.chunks_exact_mut(4).zip(rgba.iter()) {
+            dest.copy_from_slice(&val.to_ne_bytes());
+        }
+    }
+    buffer
+}
+
+// One row of scRGB float pixels with alpha
+fn float_buffer(pixels: &[[f32; 4]]) -> PixelBuffer {
+    let mut buffer = PixelBuffer::new(pixels.len(), 1, PixelFormat::HDRFloat32);
+    for (dest, rgba) in buffer.bytes_mut().chunks_exact_mut(16).zip(pixels) {
+        for (dest, val) in dest.chunks_exact_mut(4).zip(rgba.iter()) {
+            dest.copy_from_slice(&val.to_ne_bytes());
+        }
+    }
+    buffer
+}
+
+// 64x2 scRGB float ramp across a narrow range, which bands at 8 bits
+fn synthetic_gradient() -> PixelBuffer {
+    let mut buffer = PixelBuffer::new(64, 2, PixelFormat::HDRFloat32);
+    for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+        let level = 0.2 + (i % 64) as f32 * 0.0005;
+        for (dest, val) in pixel
+            .chunks_exact_mut(4)
+            .zip([level, level, level, 1.0].iter())
+        {
+            dest.copy_from_slice(&val.to_ne_bytes());
+        }
+    }
+    buffer
+}
+
+#[test]
+fn png_default_settings() {
+    let output_filename = output("pq-4x4-hable.png");
+    let settings = Settings::default();
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn png_reinhard_desaturate_levels() {
+    let output_filename = output("pq-4x4-reinhard.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        color_map: "desaturate".to_string(),
+        hdr_max: Level::Scalar(1000.0),
+        saturation: 0.5,
+        post_levels_max: Level::Percentile(90.0),
+        post_gamma: 1.2,
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-reinhard.png");
+}
+
+#[test]
+fn float_reinhard_rgb_auto_exposure() {
+    let output_filename = output("float-4x4-reinhard-rgb.png");
+    let settings = Settings {
+        tone_map: "reinhard-rgb".to_string(),
+        color_map: "darken".to_string(),
+        auto_exposure: Level::Percentile(50.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "float-4x4-reinhard-rgb.png");
+}
+
+#[test]
+fn float_aces_pre_levels() {
+    let output_filename = output("float-4x4-aces.png");
+    let settings = Settings {
+        tone_map: "aces".to_string(),
+        exposure: -1.0,
+        pre_levels_max: Level::Percentile(95.0),
+        pre_gamma: 0.8,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "float-4x4-aces.png");
+}
+
+#[test]
+fn exposure_bracket_writes_each_exposure() {
+    let output_filename = output("float-4x4-bracket.png");
+    let bracket_filenames = ["-1.0", "+0.0", "+1.0"]
+        .iter()
+        .map(|exposure| output(&format!("float-4x4-bracket{}.png", exposure)))
+        .collect::<Vec<_>>();
+    let settings = Settings {
+        exposure_bracket: Some(vec![-1.0, 0.0, 1.0]),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert!(!output_filename.exists());
+    for filename in &bracket_filenames {
+        assert!(filename.exists(), "missing {}", filename.display());
+    }
+}
+
+#[test]
+fn float_auto_levels() {
+    let output_filename = output("float-4x4-auto-levels.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        auto_levels: Some(5.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "float-4x4-auto-levels.png");
+}
+
+#[test]
+fn png_low_memory_matches_default() {
+    let output_filename = output("pq-4x4-hable-low-memory.png");
+    let settings = Settings {
+        low_memory: true,
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn extensionless_paths_are_invalid() {
+    let input = fixture("pq-4x4");
+    let output_filename = output("pq-4x4-no-extension");
+    let settings = Settings::default();
+    assert!(matches!(
+        probe_input(&input),
+        Err(LocalError::InvalidInputFile)
+    ));
+    assert!(matches!(
+        hdrfix(&input, &output_filename, &settings),
+        Err(LocalError::InvalidInputFile)
+    ));
+    assert!(matches!(
+        hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings),
+        Err(LocalError::InvalidOutputFile)
+    ));
+    // Standard input has no extension to go by either
+    assert!(matches!(
+        hdrfix(Path::new("-"), &output_filename, &settings),
+        Err(LocalError::StdinNeedsInputFormat)
+    ));
+}
+
+#[test]
+fn uppercase_extensions() {
+    let output_filename = output("pq-4x4-hable-upper.PNG");
+    hdrfix(
+        &fixture("pq-4x4.png"),
+        &output_filename,
+        &Settings::default(),
+    )
+    .unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn forced_output_format() {
+    let output_filename = output("pq-4x4-hable.out");
+    let settings = Settings {
+        output_format: Some(OutputFormat::Png),
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let input = dir.join(OsStr::from_bytes(b"pq-4x4-\xff.png"));
+    fs::copy(fixture("pq-4x4.png"), &input).unwrap();
+    let output_filename = dir.join(OsStr::from_bytes(b"pq-4x4-\xfe.png"));
+    let _ = fs::remove_file(&output_filename);
+
+    hdrfix(&input, &output_filename, &Settings::default()).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+
+    // A non-UTF-8 extension can't name a known format.
+    let bad_extension = input.with_extension(OsStr::from_bytes(b"p\xffg"));
+    assert!(matches!(
+        probe_input(&bad_extension),
+        Err(LocalError::InvalidInputFile)
+    ));
+}
+
+#[cfg(windows)]
+#[test]
+fn verbatim_long_paths() {
+    let input = fs::canonicalize(fixture("pq-4x4.png")).unwrap();
+    assert!(input.to_string_lossy().starts_with(r"\\?\"));
+    let output_filename = fs::canonicalize(env!("CARGO_TARGET_TMPDIR"))
+        .unwrap()
+        .join("pq-4x4-hable-verbatim.png");
+    let _ = fs::remove_file(&output_filename);
+
+    hdrfix(&input, &output_filename, &Settings::default()).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn existing_output_policy() {
+    let output_filename = output("pq-4x4-existing.png");
+    fs::write(&output_filename, b"not a png").unwrap();
+    let input = fixture("pq-4x4.png");
+
+    let settings = Settings::default();
+    assert!(matches!(
+        hdrfix(&input, &output_filename, &settings),
+        Err(LocalError::OutputExists(_))
+    ));
+
+    let settings = Settings {
+        overwrite: OverwritePolicy::Skip,
+        ..Settings::default()
+    };
+    hdrfix(&input, &output_filename, &settings).unwrap();
+    assert_eq!(fs::read(&output_filename).unwrap(), b"not a png");
+
+    let settings = Settings {
+        overwrite: OverwritePolicy::Overwrite,
+        ..Settings::default()
+    };
+    hdrfix(&input, &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn output_depth() {
+    let auto = Settings {
+        output_depth: OutputDepth::Auto,
+        ..Settings::default()
+    };
+
+    let output_filename = output("gradient-auto.png");
+    convert(synthetic_gradient(), &output_filename, &auto).unwrap();
+    assert_eq!(png_bit_depth(&output_filename), png::BitDepth::Sixteen);
+
+    let output_filename = output("float-4x4-auto-depth.png");
+    convert(synthetic_float(), &output_filename, &auto).unwrap();
+    assert_eq!(png_bit_depth(&output_filename), png::BitDepth::Eight);
+
+    let sixteen = Settings {
+        output_depth: OutputDepth::Sixteen,
+        ..Settings::default()
+    };
+    let output_filename = output("gradient-16.png");
+    convert(synthetic_gradient(), &output_filename, &sixteen).unwrap();
+    assert_eq!(png_bit_depth(&output_filename), png::BitDepth::Sixteen);
+
+    let output_filename = output("gradient-16.jpg");
+    assert!(matches!(
+        convert(synthetic_gradient(), &output_filename, &sixteen),
+        Err(LocalError::UnsupportedOutputDepth)
+    ));
+}
+
+// Palette and indices of an 8-bit indexed PNG
+fn read_indexed(filename: &Path) -> (Vec<u8>, Vec<u8>) {
+    let mut decoder = png::Decoder::new(File::open(filename).expect("open PNG"));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().expect("read PNG header");
+    assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+    let palette = reader.info().palette.as_ref().expect("PLTE").to_vec();
+    let mut indices = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut indices).expect("read PNG data");
+    (palette, indices)
+}
+
+#[test]
+fn indexed_palette() {
+    // Few enough colors are kept exactly
+    let output_filename = output("pq-4x4-palette.png");
+    let settings = Settings {
+        palette: Some(16),
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    let (palette, indices) = read_indexed(&output_filename);
+    let expanded: Vec<u8> = indices
+        .iter()
+        .flat_map(|&index| palette[index as usize * 3..index as usize * 3 + 3].to_vec())
+        .collect();
+    assert_eq!(expanded, read_rgb(&fixture("pq-4x4-hable.png")).2);
+
+    // A gradient squeezed into two colors dithers between them
+    let output_filename = output("gradient-palette.png");
+    let settings = Settings {
+        palette: Some(2),
+        ..Settings::default()
+    };
+    convert(synthetic_gradient(), &output_filename, &settings).unwrap();
+    let (palette, indices) = read_indexed(&output_filename);
+    assert_eq!(palette.len(), 6);
+    assert!(indices.contains(&0) && indices.contains(&1));
+
+    assert!(matches!(
+        convert(
+            synthetic_gradient(),
+            &output("gradient-palette.jpg"),
+            &settings
+        ),
+        Err(LocalError::PaletteNeedsPng)
+    ));
+}
+
+#[test]
+fn input_scale() {
+    let pixels = [
+        [0.25, 0.5, 1.0, 1.0],
+        [2.0, 1.0, 0.5, 1.0],
+        [4.0, 4.0, 4.0, 1.0],
+        [0.1, 0.2, 0.05, 1.0],
+    ];
+    let doubled: Vec<[f32; 4]> = pixels
+        .iter()
+        .map(|&[r, g, b, a]| [r * 2.0, g * 2.0, b * 2.0, a])
+        .collect();
+    let settings = Settings {
+        hdr_max: Level::Nits(1000.0),
+        ..Settings::default()
+    };
+    let expected_filename = output("float-doubled.png");
+    convert(float_buffer(&doubled), &expected_filename, &settings).unwrap();
+
+    let scaled_filename = output("float-input-scale.png");
+    let scaled = Settings {
+        input_scale: 2.0,
+        ..settings.clone()
+    };
+    convert(float_buffer(&pixels), &scaled_filename, &scaled).unwrap();
+    assert_eq!(read_rgb(&scaled_filename), read_rgb(&expected_filename));
+
+    // PQ input is scaled after decoding
+    let output_filename = output("pq-4x4-input-scale.png");
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &scaled).unwrap();
+    let unscaled_filename = output("pq-4x4-input-unscaled.png");
+    hdrfix(&fixture("pq-4x4.png"), &unscaled_filename, &settings).unwrap();
+    assert_ne!(read_rgb(&output_filename), read_rgb(&unscaled_filename));
+}
+
+#[test]
+fn auto_white_balance() {
+    // Grays of several levels under a warm light
+    let warm = Vec3::new(0.6, 0.5, 0.3);
+    let pixels: Vec<[f32; 4]> = [0.5, 1.0, 1.5, 2.0]
+        .iter()
+        .map(|&level| [warm.x * level, warm.y * level, warm.z * level, 1.0])
+        .collect();
+    for (mode, name) in [
+        (WhiteBalance::GrayWorld, "grayworld"),
+        (WhiteBalance::WhitePatch, "whitepatch"),
+    ] {
+        let output_filename = output(&format!("float-warm-{}.png", name));
+        let settings = Settings {
+            auto_wb: mode,
+            ..Settings::default()
+        };
+        convert(float_buffer(&pixels), &output_filename, &settings).unwrap();
+        let (_, _, data) = read_rgb(&output_filename);
+        for rgb in data.chunks_exact(3) {
+            let spread = rgb.iter().max().unwrap() - rgb.iter().min().unwrap();
+            assert!(spread <= 1, "{}: {:?} isn't neutral", name, rgb);
+        }
+    }
+}
+
+#[test]
+fn tile_output() {
+    assert_eq!(parse_tile_grid("2, 3").unwrap(), (2, 3));
+    assert!(matches!(
+        parse_tile_grid("2x3"),
+        Err(LocalError::InvalidTileGrid(_))
+    ));
+
+    let (_, _, full) = read_rgb(&fixture("pq-4x4-hable.png"));
+    let output_filename = output("pq-4x4-tiles.png");
+    let settings = Settings {
+        tile_output: Some((2, 3)),
+        ..Settings::default()
+    };
+    let tile_names: Vec<PathBuf> = (0..2)
+        .flat_map(|row| {
+            (0..3).map(move |col| output(&format!("pq-4x4-tiles-r{}-c{}.png", row, col)))
+        })
+        .collect();
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert!(!output_filename.exists());
+
+    // Columns of 1, 1 and 2 pixels, rows of 2
+    let col_bounds = [(0, 1), (1, 2), (2, 4)];
+    for (i, tile_name) in tile_names.iter().enumerate() {
+        let (row, col) = (i / 3, i % 3);
+        let (x, x_end) = col_bounds[col];
+        let (width, height, data) = read_rgb(tile_name);
+        assert_eq!((width, height), ((x_end - x) as u32, 2));
+        let expected: Vec<u8> = (row * 2..row * 2 + 2)
+            .flat_map(|y| full[(y * 4 + x) * 3..(y * 4 + x_end) * 3].to_vec())
+            .collect();
+        assert_eq!(data, expected);
+    }
+
+    let settings = Settings {
+        tile_output: Some((5, 1)),
+        ..Settings::default()
+    };
+    assert!(matches!(
+        hdrfix(
+            &fixture("pq-4x4.png"),
+            &output("pq-4x4-tiles-fine.png"),
+            &settings
+        ),
+        Err(LocalError::TileGridTooFine(5, 1, 4, 4))
+    ));
+}
+
+#[test]
+fn float_reinhard_luminance() {
+    let output_filename = output("float-4x4-reinhard-luminance.png");
+    let settings = Settings {
+        tone_map: "reinhard-luminance".to_string(),
+        hdr_max: Level::Scalar(1000.0),
+        saturation: 0.8,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "float-4x4-reinhard-luminance.png");
+}
+
+#[test]
+fn float_channel_mix() {
+    assert!(parse_channel_mix("1,0,0,0,1,0,0,0").is_err());
+
+    let output_filename = output("float-4x4-channel-mix.png");
+    let settings = Settings {
+        // swap red and blue, with a little green crosstalk into red
+        channel_mix: parse_channel_mix("0, 0.2, 1, 0, 1, 0, 1, 0, 0").unwrap(),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "float-4x4-channel-mix.png");
+}
+
+#[test]
+fn png_vibrance() {
+    let output_filename = output("pq-4x4-vibrance.png");
+    let settings = Settings {
+        vibrance: 1.5,
+        color_map: "desaturate".to_string(),
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-vibrance.png");
+}
+
+#[test]
+fn png_16bit_input() {
+    // Same PQ samples as pq-4x4.png, widened to 16 bits
+    let output_filename = output("pq-4x4-16bit-hable.png");
+    hdrfix(
+        &fixture("pq-4x4-16bit.png"),
+        &output_filename,
+        &Settings::default(),
+    )
+    .unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn hdr_max_ignores_hot_pixel() {
+    // 10x10 mid gray, optionally with one very bright pixel
+    let gray = |hot: bool| {
+        let mut buffer = PixelBuffer::new(10, 10, PixelFormat::HDRFloat32);
+        for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+            let level: f32 = if hot && i == 99 { 1000.0 } else { 2.0 };
+            for (dest, val) in pixel
+                .chunks_exact_mut(4)
+                .zip([level, level, level, 1.0].iter())
+            {
+                dest.copy_from_slice(&val.to_ne_bytes());
+            }
+        }
+        buffer
+    };
+    let settings = |clip| Settings {
+        tone_map: "reinhard".to_string(),
+        hdr_max_clip: clip,
+        ..Settings::default()
+    };
+
+    let reference = output("gray-reference.png");
+    convert(gray(false), &reference, &settings(0.0)).unwrap();
+    let clipped = output("gray-hot-clipped.png");
+    convert(gray(true), &clipped, &settings(1.0)).unwrap();
+    let unclipped = output("gray-hot-unclipped.png");
+    convert(gray(true), &unclipped, &settings(0.0)).unwrap();
+
+    let (_, _, reference) = read_rgb(&reference);
+    let (_, _, clipped) = read_rgb(&clipped);
+    let (_, _, unclipped) = read_rgb(&unclipped);
+    assert_eq!(clipped[0..3], reference[0..3]);
+    assert!(unclipped[0] < reference[0]);
+}
+
+#[test]
+fn exr_output_keeps_float_data() {
+    let output_filename = output("float-4x4.exr");
+    let settings = Settings {
+        exposure: 1.0,
+        exr_compression: ExrCompression::Piz,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        &output_filename,
+        |resolution, _| vec![[0.0; 3]; resolution.width() * resolution.height()],
+        |pixels, position, (r, g, b, _): (f32, f32, f32, f32)| {
+            pixels[position.y() * 4 + position.x()] = [r, g, b]
+        },
+    )
+    .unwrap();
+    let pixels = image.layer_data.channel_data.pixels;
+
+    // Exposure +1 doubles the linear values, with no tone mapping
+    assert_eq!(pixels.len(), 16);
+    for (i, expected) in [(4, [4.0, 4.0, 4.0]), (5, [5.0, 1.25, 0.0])].iter() {
+        for (actual, expected) in pixels[*i].iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 0.001, "pixel {}", i);
+        }
+    }
+}
+
+#[test]
+fn per_channel_exposure() {
+    assert_eq!(parse_exposure("-1.5").unwrap(), (-1.5, Vec3::ZERO));
+    assert_eq!(
+        parse_exposure("1, 0, -1").unwrap(),
+        (0.0, Vec3::new(1.0, 0.0, -1.0))
+    );
+    assert!(matches!(
+        parse_exposure("1,0"),
+        Err(LocalError::InvalidExposure(_))
+    ));
+
+    let output_filename = output("float-4x4-channel-exposure.exr");
+    let settings = Settings {
+        exposure: 1.0,
+        channel_exposure: Vec3::new(1.0, 0.0, -1.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    // On top of the uniform stop, red gets another and blue loses one
+    let pixels = read_exr_rgb(&output_filename);
+    for (actual, expected) in pixels[4].iter().zip([8.0, 4.0, 2.0].iter()) {
+        assert!((actual - expected).abs() < 0.001, "{:?}", pixels[4]);
+    }
+}
+
+const IDENTITY_CUBE: &str = "TITLE \"identity\"
+# corners of the RGB cube, red varying fastest
+LUT_3D_SIZE 2
+0 0 0
+1 0 0
+0 1 0
+1 1 0
+0 0 1
+1 0 1
+0 1 1
+1 1 1
+";
+
+#[test]
+fn lut_sampling() {
+    let lut = Lut3d::parse(IDENTITY_CUBE).unwrap();
+    let rgb = Vec3::new(0.25, 0.5, 0.9);
+    assert!((lut.sample(rgb) - rgb).abs().max_element() < 0.0001);
+    assert_eq!(lut.sample(Vec3::splat(2.0)), Vec3::ONE);
+
+    let invert = IDENTITY_CUBE
+        .lines()
+        .map(|line| line.replace('0', "x").replace('1', "0").replace('x', "1"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let lut = Lut3d::parse(&invert).unwrap();
+    assert!((lut.sample(rgb) - (Vec3::ONE - rgb)).abs().max_element() < 0.0001);
+
+    assert!(Lut3d::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+    assert!(Lut3d::parse("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+}
+
+#[test]
+fn png_identity_lut() {
+    let output_filename = output("pq-4x4-identity-lut.png");
+    let settings = Settings {
+        lut: Some(Lut3d::parse(IDENTITY_CUBE).unwrap()),
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn lut_tone_map() {
+    let tone_mapped = |cube: &str, name: &str| {
+        let cube_filename = output(&format!("{}.cube", name));
+        std::fs::write(&cube_filename, cube).unwrap();
+        let output_filename = output(&format!("float-4x4-lut-{}.png", name));
+        let settings = Settings {
+            tone_map: format!("lut:{}", cube_filename.display()),
+            ..Settings::default()
+        };
+        convert(synthetic_float(), &output_filename, &settings).unwrap();
+        read_rgb(&output_filename).2
+    };
+
+    // Every entry the same: the input no longer matters
+    let constant = IDENTITY_CUBE
+        .lines()
+        .map(|line| match line.chars().next() {
+            Some('0') | Some('1') => "0.5 0.25 0",
+            _ => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    for rgb in tone_mapped(&constant, "constant").chunks(3) {
+        assert_eq!(rgb, [127, 64, 0]);
+    }
+
+    // PQ code values shown as sRGB keep grays gray and in order
+    let identity = tone_mapped(IDENTITY_CUBE, "identity");
+    let grays = identity.chunks(3).step_by(4).collect::<Vec<_>>();
+    assert!(grays.iter().all(|rgb| rgb[0] == rgb[1] && rgb[1] == rgb[2]));
+    assert!(grays.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+
+    let settings = Settings {
+        tone_map: "lut:missing.cube".to_string(),
+        ..Settings::default()
+    };
+    assert!(convert(
+        synthetic_float(),
+        &output("float-4x4-lut-missing.png"),
+        &settings
+    )
+    .is_err());
+}
+
+#[test]
+fn raw_float_input() {
+    assert_eq!(parse_raw_size("4x2").unwrap(), (4, 2));
+    assert!(matches!(
+        parse_raw_size("4:2"),
+        Err(LocalError::InvalidRawSize(_))
+    ));
+
+    let pixels: [[f32; 4]; 4] = [
+        [0.0, 0.0, 0.0, 1.0],
+        [0.5, 0.25, 0.1, 1.0],
+        [2.0, 1.0, 0.5, 1.0],
+        [8.0, 6.0, 4.0, 1.0],
+    ];
+    // Packed RGB, leaving out the alpha
+    let raw = pixels
+        .iter()
+        .flat_map(|rgba| rgba[..3].iter().flat_map(|val| val.to_le_bytes()))
+        .collect::<Vec<u8>>();
+    let raw_filename = output("float-4x1.raw");
+    std::fs::write(&raw_filename, &raw).unwrap();
+
+    let expected = output("float-4x1-raw-expected.png");
+    convert(float_buffer(&pixels), &expected, &Settings::default()).unwrap();
+    let actual = output("float-4x1-raw.png");
+    let settings = Settings {
+        input_format: Some(InputFormat::Raw(4, 1)),
+        ..Settings::default()
+    };
+    hdrfix(&raw_filename, &actual, &settings).unwrap();
+    assert_eq!(read_rgb(&actual), read_rgb(&expected));
+
+    let settings = Settings {
+        input_format: Some(InputFormat::Raw(5, 1)),
+        ..Settings::default()
+    };
+    assert!(matches!(
+        hdrfix(&raw_filename, &output("float-5x1-raw.png"), &settings),
+        Err(LocalError::RawSizeMismatch(48, 60, 80))
+    ));
+}
+
+#[test]
+fn auto_skip_tone_map() {
+    let convert_with = |pixels: &[[f32; 4]], name: &str, tone_map: &str, skip: bool| {
+        let filename = output(&format!("float-3x1-skip-{}.png", name));
+        let settings = Settings {
+            tone_map: tone_map.to_string(),
+            auto_skip_tone_map: skip,
+            ..Settings::default()
+        };
+        convert(float_buffer(pixels), &filename, &settings).unwrap();
+        read_rgb(&filename)
+    };
+
+    // Within SDR white it comes out as the linear map would have it
+    let sdr = [
+        [0.1, 0.1, 0.1, 1.0],
+        [0.8, 0.4, 0.2, 1.0],
+        [1.0, 1.0, 1.0, 1.0],
+    ];
+    assert_eq!(
+        convert_with(&sdr, "sdr", "hable", true),
+        convert_with(&sdr, "sdr-linear", "linear", false)
+    );
+    assert_ne!(
+        convert_with(&sdr, "sdr-hable", "hable", false),
+        convert_with(&sdr, "sdr-linear-again", "linear", false)
+    );
+
+    // Brighter input is still tone mapped
+    let hdr = [
+        [0.1, 0.1, 0.1, 1.0],
+        [0.8, 0.4, 0.2, 1.0],
+        [4.0, 4.0, 4.0, 1.0],
+    ];
+    assert_eq!(
+        convert_with(&hdr, "hdr", "hable", true),
+        convert_with(&hdr, "hdr-hable", "hable", false)
+    );
+}
+
+#[test]
+fn adaptive_tiles() {
+    let tone_mapped = |source: PixelBuffer, name: &str, tone_map: &str, hdr_max: Level| {
+        let filename = output(&format!("float-{}-{}.png", name, tone_map));
+        let settings = Settings {
+            tone_map: tone_map.to_string(),
+            hdr_max,
+            ..Settings::default()
+        };
+        convert(source, &filename, &settings).unwrap();
+        read_rgb(&filename).2
+    };
+
+    // One white point for every tile is plain Reinhard
+    assert_eq!(
+        tone_mapped(
+            synthetic_float(),
+            "4x4",
+            "adaptive-tiles",
+            Level::Nits(400.0)
+        ),
+        tone_mapped(synthetic_float(), "4x4", "reinhard", Level::Nits(400.0))
+    );
+
+    // A dim room with a bright window on the right
+    let pixels = (0..64)
+        .map(|x| match x {
+            0..=31 => [0.5, 0.5, 0.5, 1.0],
+            _ => [20.0, 20.0, 20.0, 1.0],
+        })
+        .collect::<Vec<_>>();
+    let adaptive = tone_mapped(
+        float_buffer(&pixels),
+        "64x1",
+        "adaptive-tiles",
+        Level::Percentile(100.0),
+    );
+    let global = tone_mapped(
+        float_buffer(&pixels),
+        "64x1",
+        "reinhard",
+        Level::Percentile(100.0),
+    );
+    // The room is lifted, most of all far from the window
+    assert!(adaptive[0] > global[0]);
+    let room = adaptive
+        .chunks(3)
+        .take(32)
+        .map(|rgb| rgb[0])
+        .collect::<Vec<_>>();
+    assert!(room.windows(2).all(|pair| pair[0] >= pair[1]));
+    assert!(room[0] > room[31]);
+
+    // More tiles than fit the image are cut down to one per pixel
+    let tiny = [
+        [0.5, 0.5, 0.5, 1.0],
+        [2.0, 2.0, 2.0, 1.0],
+        [8.0, 8.0, 8.0, 1.0],
+    ];
+    tone_mapped(
+        float_buffer(&tiny),
+        "3x1",
+        "adaptive-tiles",
+        Level::Percentile(100.0),
+    );
+    let filename = output("float-64x1-adaptive-tiles-many.png");
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("tiles".to_string(), 1000.0);
+    let settings = Settings {
+        tone_map: "adaptive-tiles".to_string(),
+        tone_map_params,
+        ..Settings::default()
+    };
+    convert(float_buffer(&pixels), &filename, &settings).unwrap();
+
+    let settings = Settings {
+        tone_map: "adaptive-tiles,aces".to_string(),
+        ..Settings::default()
+    };
+    assert!(matches!(
+        convert(
+            synthetic_float(),
+            &output("float-4x4-adaptive-chain.png"),
+            &settings
+        ),
+        Err(LocalError::AdaptiveTilesChain(_))
+    ));
+}
+
+#[test]
+fn sanitize_non_finite_input() {
+    // Poison a few channels of the synthetic image
+    let poisoned = |values: [f32; 3]| {
+        let mut buffer = synthetic_float();
+        for (pixel, val) in buffer.bytes_mut().chunks_exact_mut(16).zip(values.iter()) {
+            pixel[0..4].copy_from_slice(&val.to_ne_bytes());
+        }
+        buffer
+    };
+    let settings = |sanitize| Settings {
+        sanitize,
+        auto_exposure: Level::Percentile(50.0),
+        ..Settings::default()
+    };
+
+    let reference = output("float-4x4-sanitize-reference.png");
+    convert(
+        poisoned([0.0, 0.0, 0.0]),
+        &reference,
+        &settings(Sanitize::Zero),
+    )
+    .unwrap();
+    let zeroed = output("float-4x4-sanitize-zero.png");
+    let bad = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY];
+    convert(poisoned(bad), &zeroed, &settings(Sanitize::Zero)).unwrap();
+    assert_eq!(read_rgb(&zeroed), read_rgb(&reference));
+
+    let clamped = output("float-4x4-sanitize-clamp.png");
+    convert(poisoned(bad), &clamped, &settings(Sanitize::Clamp)).unwrap();
+    let (_, _, clamped) = read_rgb(&clamped);
+    // +Inf red becomes the brightest value rather than black
+    assert!(clamped[3] > 200);
+}
+
+#[test]
+fn hue_shift_report_skips_nan() {
+    // Unsanitized NaN makes a NaN hue, which the report has to leave out
+    let pixels = float_buffer(&[
+        [f32::NAN, 0.2, 0.1, 1.0],
+        [4.0, 0.5, 0.1, 1.0],
+        [0.1, 2.0, 0.3, 1.0],
+    ]);
+    let settings = Settings {
+        tone_map: "reinhard-rgb".to_string(),
+        sanitize: Sanitize::Off,
+        hdr_max: Level::Scalar(1000.0),
+        ..Settings::default()
+    };
+    convert(pixels, &output("hue-shift-nan.png"), &settings).unwrap();
+}
+
+#[test]
+fn strict_rejects_bad_input() {
+    let strict = Settings {
+        strict: true,
+        ..Settings::default()
+    };
+    let nan = float_buffer(&[[0.5, f32::NAN, 0.5, 1.0], [0.5, 0.5, 0.5, 1.0]]);
+    let result = convert(nan.clone(), &output("strict-nan.png"), &strict);
+    assert!(matches!(result, Err(LocalError::StrictNonFinite(1))));
+    convert(nan, &output("lenient-nan.png"), &Settings::default()).unwrap();
+
+    // 20000 nits is twice what the HDR formats can hold
+    let bright = float_buffer(&[[250.0, 0.5, 0.5, 1.0], [0.5, 0.5, 0.5, 1.0]]);
+    let result = convert(bright, &output("strict-bright.png"), &strict);
+    assert!(matches!(result, Err(LocalError::StrictOutOfRange(1))));
+
+    convert(synthetic_float(), &output("strict-clean.png"), &strict).unwrap();
+}
+
+#[test]
+fn split_compare() {
+    let output_filename = output("float-4x4-split.png");
+    let settings = Settings {
+        split_compare: true,
+        tone_map: "aces".to_string(),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    let (_, _, split) = read_rgb(&output_filename);
+
+    let full_filename = output("float-4x4-split-full.png");
+    let settings = Settings {
+        tone_map: "aces".to_string(),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &full_filename, &settings).unwrap();
+    let (_, _, full) = read_rgb(&full_filename);
+
+    let naive_filename = output("float-4x4-split-naive.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &naive_filename, &settings).unwrap();
+    let (_, _, naive) = read_rgb(&naive_filename);
+
+    // 4 pixels of 3 bytes per row, left 2 pixels from the naive clip
+    for (row, ((split, full), naive)) in split
+        .chunks(12)
+        .zip(full.chunks(12))
+        .zip(naive.chunks(12))
+        .enumerate()
+    {
+        assert_eq!(split[..6], naive[..6], "row {}", row);
+        assert_eq!(split[6..], full[6..], "row {}", row);
+    }
+}
+
+#[test]
+fn levels_in_nits() {
+    assert!(matches!(Level::with_str("hdr-max", "5nits"), Ok(Level::Nits(nits)) if nits == 5.0));
+    assert!(matches!(Level::with_str("hdr-max", "5 nits"), Ok(Level::Nits(nits)) if nits == 5.0));
+
+    // 80 nits is scRGB 1.0
+    let scalar_filename = output("float-4x4-pre-levels-scalar.png");
+    let settings = Settings {
+        pre_levels_max: Level::Scalar(2.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &scalar_filename, &settings).unwrap();
+    let nits_filename = output("float-4x4-pre-levels-nits.png");
+    let settings = Settings {
+        pre_levels_max: Level::Nits(160.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &nits_filename, &settings).unwrap();
+    assert_eq!(read_rgb(&nits_filename), read_rgb(&scalar_filename));
+
+    // Content darker than the post-levels minimum ends up black
+    let post_filename = output("float-4x4-post-levels-nits.png");
+    let settings = Settings {
+        post_levels_min: Level::Nits(100.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &post_filename, &settings).unwrap();
+    let (_, _, post) = read_rgb(&post_filename);
+    // pixel 4 is gray at scRGB 2.0 = 160 nits, pixel 0 black
+    assert_eq!(post[0..3], [0, 0, 0]);
+    assert!(post[12] > 0);
+}
+
+#[test]
+fn grayscale_png_input() {
+    let (width, height, data) = read_rgb(&fixture("pq-4x4.png"));
+    let gray = data.chunks(3).map(|rgb| rgb[1]).collect::<Vec<u8>>();
+    let write_input = |name, color_type, data: &[u8]| {
+        let input = output(name);
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        input
+    };
+    let gray_input = write_input("pq-4x4-gray.png", png::ColorType::Grayscale, &gray);
+    let rgb = gray.iter().flat_map(|&val| [val; 3]).collect::<Vec<u8>>();
+    let rgb_input = write_input("pq-4x4-gray-rgb.png", png::ColorType::Rgb, &rgb);
+
+    let gray_output = output("pq-4x4-gray-out.png");
+    let rgb_output = output("pq-4x4-gray-rgb-out.png");
+    hdrfix(&gray_input, &gray_output, &Settings::default()).unwrap();
+    hdrfix(&rgb_input, &rgb_output, &Settings::default()).unwrap();
+    assert_eq!(read_rgb(&gray_output), read_rgb(&rgb_output));
+
+    // And a strip at a time
+    let low_memory = Settings {
+        low_memory: true,
+        auto_exposure: Level::Scalar(0.5),
+        hdr_max: Level::Scalar(1000.0),
+        ..Settings::default()
+    };
+    let gray_output = output("pq-4x4-gray-low-memory.png");
+    let rgb_output = output("pq-4x4-gray-rgb-low-memory.png");
+    hdrfix(&gray_input, &gray_output, &low_memory).unwrap();
+    hdrfix(&rgb_input, &rgb_output, &low_memory).unwrap();
+    assert_eq!(read_rgb(&gray_output), read_rgb(&rgb_output));
+}
+
+#[test]
+fn png_format_error_reports_format() {
+    let input = output("indexed-2x2.png");
+    {
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 2, 2);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(vec![0, 0, 0, 255, 255, 255]);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 1, 1, 0]).unwrap();
+    }
+    let error = probe_input(&input).unwrap_err();
+    assert!(matches!(error, LocalError::PNGFormatError { .. }));
+    let message = error.to_string();
+    assert!(message.contains("Indexed"), "{}", message);
+    assert!(message.contains("2x2"), "{}", message);
+}
+
+#[test]
+fn png_metadata_round_trip() {
+    let input = output("pq-4x4-metadata.png");
+    {
+        let (width, height, data) = read_rgb(&fixture("pq-4x4.png"));
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .add_text_chunk("Software".to_string(), "Game Bar".to_string())
+            .unwrap();
+        encoder
+            .add_itxt_chunk("Title".to_string(), "日の出".to_string())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_chunk(png::chunk::ChunkType(*b"eXIf"), b"MM\0\x2a\0\0\0\x08")
+            .unwrap();
+        writer.write_image_data(&data).unwrap();
+    }
+
+    for &low_memory in &[false, true] {
+        let output_filename = output("pq-4x4-metadata-sdr.png");
+        let settings = Settings {
+            add_metadata: vec![
+                ("Title".to_string(), "Sunrise".to_string()),
+                ("Comment".to_string(), "tone mapped".to_string()),
+            ],
+            dpi: Some(300.0),
+            low_memory,
+            ..Settings::default()
+        };
+        hdrfix(&input, &output_filename, &settings).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&output_filename).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        reader.finish().unwrap();
+        let info = reader.info();
+        let text = info
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [
+                ("Software", "Game Bar"),
+                ("Title", "Sunrise"),
+                ("Comment", "tone mapped")
+            ]
+        );
+        // 300 dpi is 11811 pixels per meter
+        let dims = info.pixel_dims.unwrap();
+        assert_eq!((dims.xppu, dims.yppu), (11811, 11811));
+        assert!(matches!(dims.unit, png::Unit::Meter));
+        // The png crate doesn't decode eXIf, so look for the raw chunk
+        let bytes = fs::read(&output_filename).unwrap();
+        let exif = b"\0\0\0\x08eXIfMM\0\x2a\0\0\0\x08";
+        assert!(bytes.windows(exif.len()).any(|chunk| chunk == exif));
+    }
+
+    assert!(hdrfix::parse_metadata_field("=value").is_err());
+    assert!(hdrfix::parse_metadata_field("no value").is_err());
+    assert!(matches!(
+        hdrfix::parse_dpi("0"),
+        Err(LocalError::InvalidDpi(_))
+    ));
+}
+
+#[test]
+fn show_out_of_gamut() {
+    let plain_filename = output("float-4x4-linear.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &plain_filename, &settings).unwrap();
+    let (_, _, plain) = read_rgb(&plain_filename);
+
+    let oog_filename = output("float-4x4-oog.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        show_oog: true,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &oog_filename, &settings).unwrap();
+    let (_, _, oog) = read_rgb(&oog_filename);
+
+    // Linear tone mapping at the default exposure leaves the input values
+    // as they were, so any channel over 1.0 is out of gamut.
+    let mut input = synthetic_float();
+    for (i, (plain, oog)) in plain.chunks(3).zip(oog.chunks(3)).enumerate() {
+        let rgb = input
+            .bytes_mut()
+            .chunks(4)
+            .skip(i * 4)
+            .take(3)
+            .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .fold(0.0, f32::max);
+        // Values right at 1.0 may land either side after the pre-levels
+        if rgb > 1.01 {
+            assert!(oog[1] < plain[1], "pixel {}: {:?}", i, oog);
+        } else if rgb < 0.99 {
+            assert_eq!(plain, oog, "pixel {}", i);
+        }
+    }
+}
+
+#[test]
+fn composite_over_background() {
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        background: Some(Vec3::splat(0.25)),
+        ..Settings::default()
+    };
+
+    let composited_filename = output("float-2x1-composited.png");
+    let transparent = float_buffer(&[[4.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.5, 0.5]]);
+    convert(transparent, &composited_filename, &settings).unwrap();
+
+    let expected_filename = output("float-2x1-composited-expected.png");
+    let opaque = float_buffer(&[[0.25, 0.25, 0.25, 1.0], [0.375, 0.375, 0.375, 1.0]]);
+    convert(opaque, &expected_filename, &settings).unwrap();
+
+    let (_, _, composited) = read_rgb(&composited_filename);
+    let (_, _, expected) = read_rgb(&expected_filename);
+    for (a, b) in composited.iter().zip(expected.iter()) {
+        assert!((*a as i32 - *b as i32).abs() <= 1, "{:?}", composited);
+    }
+
+    // Opaque input is unaffected
+    let opaque_filename = output("float-4x4-background.png");
+    convert(synthetic_float(), &opaque_filename, &settings).unwrap();
+    let plain_filename = output("float-4x4-no-background.png");
+    let settings = Settings {
+        background: None,
+        ..settings
+    };
+    convert(synthetic_float(), &plain_filename, &settings).unwrap();
+    assert_eq!(read_rgb(&opaque_filename), read_rgb(&plain_filename));
+}
+
+#[test]
+fn gpu_matches_cpu() {
+    // Without a GPU, or a build with the gpu feature, this checks the
+    // fallback; with one it checks the shader against the CPU path.
+    for tone_map in &["hable", "aces", "reinhard-rgb"] {
+        let cpu_filename = output("float-4x4-cpu.png");
+        let settings = Settings {
+            tone_map: tone_map.to_string(),
+            hdr_max: Level::Scalar(1000.0),
+            ..Settings::default()
+        };
+        convert(synthetic_float(), &cpu_filename, &settings).unwrap();
+
+        let gpu_filename = output("float-4x4-gpu.png");
+        let settings = Settings {
+            gpu: true,
+            ..settings
+        };
+        convert(synthetic_float(), &gpu_filename, &settings).unwrap();
+
+        let (_, _, cpu) = read_rgb(&cpu_filename);
+        let (_, _, gpu) = read_rgb(&gpu_filename);
+        for (i, (a, b)) in gpu.iter().zip(cpu.iter()).enumerate() {
+            assert!(
+                (*a as i32 - *b as i32).abs() <= 1,
+                "{}: byte {} is {}, expected {}",
+                tone_map,
+                i,
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn tone_map_params() {
+    // Reinhard's white param overrides hdr-max
+    let param_filename = output("float-4x4-reinhard-white-param.png");
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("white".to_string(), 400.0);
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        tone_map_params,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &param_filename, &settings).unwrap();
+
+    let hdr_max_filename = output("float-4x4-reinhard-hdr-max.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        hdr_max: Level::Scalar(400.0),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &hdr_max_filename, &settings).unwrap();
+    assert_eq!(read_rgb(&param_filename), read_rgb(&hdr_max_filename));
+
+    // Defaults given explicitly change nothing
+    let defaults_filename = output("float-4x4-hable-params.png");
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("exposure_bias".to_string(), 2.0);
+    tone_map_params.insert("linear_white".to_string(), 11.2);
+    let settings = Settings {
+        tone_map_params,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &defaults_filename, &settings).unwrap();
+    let plain_filename = output("float-4x4-hable.png");
+    convert(synthetic_float(), &plain_filename, &Settings::default()).unwrap();
+    assert_eq!(read_rgb(&defaults_filename), read_rgb(&plain_filename));
+
+    // Dividing by a linear white of 4 is two stops down, then a hard clip
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("linear_white".to_string(), 4.0);
+    let linear_white_filename = output("float-4x4-linear-white.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        tone_map_params,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &linear_white_filename, &settings).unwrap();
+    let exposure_filename = output("float-4x4-linear-exposure.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        exposure: -2.0,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &exposure_filename, &settings).unwrap();
+    assert_eq!(
+        read_rgb(&linear_white_filename),
+        read_rgb(&exposure_filename)
+    );
+
+    // A linear white of 0 would turn black into NaN, even in Settings
+    // built without the parser
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("linear_white".to_string(), 0.0);
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        tone_map_params,
+        ..Settings::default()
+    };
+    let result = convert(synthetic_float(), &output("linear-white-0.png"), &settings);
+    assert!(matches!(result, Err(LocalError::InvalidToneMapParam(_))));
+
+    let mut tone_map_params = HashMap::new();
+    tone_map_params.insert("white".to_string(), 400.0);
+    let settings = Settings {
+        tone_map: "aces".to_string(),
+        tone_map_params,
+        ..Settings::default()
+    };
+    let result = convert(synthetic_float(), &output("aces-white.png"), &settings);
+    assert!(matches!(result, Err(LocalError::UnknownToneMapParam(..))));
+
+    assert_eq!(
+        parse_tone_map_param("exposure_bias=1.5").unwrap(),
+        ("exposure_bias".to_string(), 1.5)
+    );
+    assert!(parse_tone_map_param("1.5").is_err());
+    assert_eq!(
+        parse_tone_map_param("exposure_bias=0").unwrap(),
+        ("exposure_bias".to_string(), 0.0)
+    );
+    for bad in &[
+        "white=0",
+        "white=-400",
+        "white=inf",
+        "linear_white=0",
+        "linear_white=NaN",
+        "tiles=0",
+        "tiles=-2",
+        "exposure_bias=-1",
+        "exposure_bias=inf",
+        "desaturation=-0.5",
+        "desaturation=NaN",
+    ] {
+        assert!(
+            matches!(
+                parse_tone_map_param(bad),
+                Err(LocalError::InvalidToneMapParam(_))
+            ),
+            "{}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn hdr_max_content_knee() {
+    // Most of the image at 0.5 with a few bright highlights
+    let mut buffer = PixelBuffer::new(64, 1, PixelFormat::HDRFloat32);
+    for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+        let level: f32 = if i % 16 == 0 { 40.0 } else { 0.5 };
+        for dest in pixel.chunks_exact_mut(4).take(3) {
+            dest.copy_from_slice(&level.to_ne_bytes());
+        }
+    }
+    let copy = |buffer: &mut PixelBuffer| {
+        let mut copy = PixelBuffer::new(64, 1, PixelFormat::HDRFloat32);
+        copy.bytes_mut().copy_from_slice(buffer.bytes_mut());
+        copy
+    };
+
+    assert!(matches!(
+        Level::with_str("hdr-max", "content"),
+        Ok(Level::Content)
+    ));
+    let content_filename = output("knee-content.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        hdr_max: Level::Content,
+        ..Settings::default()
+    };
+    convert(copy(&mut buffer), &content_filename, &settings).unwrap();
+
+    // The density falls off right above the bulk of the image at 0.5,
+    // so the knee is at the top of the next quarter-stop bucket.
+    let knee_filename = output("knee-nits.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        hdr_max: Level::Nits(2.0f32.powf(-0.25) * 80.0),
+        ..Settings::default()
+    };
+    convert(copy(&mut buffer), &knee_filename, &settings).unwrap();
+
+    let (_, _, content) = read_rgb(&content_filename);
+    let (_, _, knee) = read_rgb(&knee_filename);
+    for (a, b) in content.iter().zip(knee.iter()) {
+        assert!((*a as i32 - *b as i32).abs() <= 1, "{:?}", content);
+    }
+}
+
+#[test]
+fn trim_black_borders() {
+    // 6x4 with a black row above and below, and a black column on the left
+    let mut buffer = PixelBuffer::new(6, 4, PixelFormat::HDRFloat32);
+    for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+        let (x, y) = (i % 6, i / 6);
+        let level: f32 = if x == 0 || y == 0 || y == 3 {
+            0.001
+        } else {
+            0.5
+        };
+        for dest in pixel.chunks_exact_mut(4).take(3) {
+            dest.copy_from_slice(&level.to_ne_bytes());
+        }
+    }
+    let output_filename = output("float-trim-black.png");
+    let settings = Settings {
+        trim_black: Some(0.5),
+        ..Settings::default()
+    };
+    convert(buffer, &output_filename, &settings).unwrap();
+    let (width, height, _) = read_rgb(&output_filename);
+    assert_eq!((width, height), (5, 2));
+
+    // Nothing to trim
+    let output_filename = output("float-4x4-trim-black.png");
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    let (width, height, _) = read_rgb(&output_filename);
+    assert_eq!((width, height), (4, 4));
+}
+
+#[test]
+fn float_png_input() {
+    // Half float scRGB samples in a marked 16-bit PNG
+    let input = output("float-4x4-half.png");
+    {
+        let mut source = synthetic_float();
+        let samples = source
+            .bytes_mut()
+            .chunks_exact(16)
+            .flat_map(|pixel| pixel.chunks_exact(4).take(3))
+            .flat_map(|bytes| {
+                let val = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                half::f16::from_f32(val).to_be_bytes()
+            })
+            .collect::<Vec<u8>>();
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 4, 4);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        encoder
+            .add_text_chunk(FLOAT_PNG_KEYWORD.to_string(), FLOAT_PNG_FORMAT.to_string())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&samples).unwrap();
+    }
+
+    let png_filename = output("float-4x4-from-png.png");
+    hdrfix(&input, &png_filename, &Settings::default()).unwrap();
+    let direct_filename = output("float-4x4-direct.png");
+    convert(synthetic_float(), &direct_filename, &Settings::default()).unwrap();
+
+    let (_, _, from_png) = read_rgb(&png_filename);
+    let (_, _, direct) = read_rgb(&direct_filename);
+    for (a, b) in from_png.iter().zip(direct.iter()) {
+        assert!((*a as i32 - *b as i32).abs() <= 1, "{:?}", from_png);
+    }
+}
+
+#[test]
+fn output_template() {
+    let settings = Settings {
+        tone_map: "aces".to_string(),
+        ..Settings::default()
+    };
+    let input = Path::new("captures/shot.jxr");
+    assert_eq!(
+        expand_output_template("{stem}_{tonemap}_sdr.png", input, &settings).unwrap(),
+        "shot_aces_sdr.png"
+    );
+    assert_eq!(
+        expand_output_template("{stem}.{ext}.jpg", input, &settings).unwrap(),
+        "shot.jxr.jpg"
+    );
+    let dated = expand_output_template("{date}-{stem}.png", input, &settings).unwrap();
+    assert_eq!(dated.len(), "YYYY-MM-DD-shot.png".len());
+    assert!(matches!(
+        expand_output_template("{nope}.png", input, &settings),
+        Err(LocalError::InvalidOutputTemplate(_))
+    ));
+    assert!(expand_output_template("{stem.png", input, &settings).is_err());
+}
+
+#[test]
+fn input_transfer_detection() {
+    // PQ content is left as PQ
+    let pq_filename = output("pq-4x4-detect.png");
+    let detect = Settings {
+        detect_input_transfer: true,
+        ..Settings::default()
+    };
+    hdrfix(&fixture("pq-4x4.png"), &pq_filename, &detect).unwrap();
+    assert_matches_golden(&pq_filename, "pq-4x4-hable.png");
+
+    // A full range ramp with clipped highlights reads as sRGB
+    let input = output("srgb-8x8.png");
+    {
+        let samples = (0..64u32)
+            .flat_map(|i| {
+                let level = (i * 5).min(255) as u8;
+                vec![level, level, level]
+            })
+            .collect::<Vec<u8>>();
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 8, 8);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&samples).unwrap();
+    }
+    let detected_filename = output("srgb-8x8-detect.png");
+    hdrfix(&input, &detected_filename, &detect).unwrap();
+    let srgb_filename = output("srgb-8x8-srgb.png");
+    let srgb = Settings {
+        input_transfer: Some(Transfer::Srgb),
+        ..Settings::default()
+    };
+    hdrfix(&input, &srgb_filename, &srgb).unwrap();
+    let pq_filename = output("srgb-8x8-pq.png");
+    hdrfix(&input, &pq_filename, &Settings::default()).unwrap();
+
+    let (_, _, detected) = read_rgb(&detected_filename);
+    assert_eq!(detected, read_rgb(&srgb_filename).2);
+    assert_ne!(detected, read_rgb(&pq_filename).2);
+
+    assert!(matches!(Transfer::with_str("hlg"), Ok(Transfer::Hlg)));
+    assert!(matches!(
+        Transfer::with_str("gamma22"),
+        Err(LocalError::InvalidTransfer(_))
+    ));
+}
+
+#[test]
+fn keep_hdr_sidecar() {
+    let output_filename = output("float-4x4-keep.png");
+    let sidecar_filename = output("float-4x4-keep-hdr.exr");
+    let settings = Settings {
+        exposure: 1.0,
+        keep_hdr: true,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    assert!(output_filename.exists());
+
+    let pixels = read_exr_rgb(&sidecar_filename);
+
+    // The sidecar is the input as read, without the exposure adjustment
+    for (i, expected) in [(4, [2.0, 2.0, 2.0]), (5, [2.5, 0.625, 0.0])].iter() {
+        for (actual, expected) in pixels[*i].iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 0.001, "pixel {}", i);
+        }
+    }
+}
+
+#[test]
+fn max_chroma() {
+    let output_filename = output("float-4x4-max-chroma.png");
+    let settings = Settings {
+        max_chroma: Some(0.1),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    let default_filename = output("float-4x4-default.png");
+    convert(synthetic_float(), &default_filename, &Settings::default()).unwrap();
+
+    let chroma = |rgb: &[u8]| {
+        let lab = oklab::srgb_to_oklab(oklab::RGB::new(rgb[0], rgb[1], rgb[2]));
+        (lab.a * lab.a + lab.b * lab.b).sqrt()
+    };
+    let (_, _, clamped) = read_rgb(&output_filename);
+    let (_, _, default) = read_rgb(&default_filename);
+    let mut changed = 0;
+    for (a, b) in clamped.chunks_exact(3).zip(default.chunks_exact(3)) {
+        // Allow for 8-bit rounding
+        assert!(chroma(a) < 0.11, "{:?} has chroma {}", a, chroma(a));
+        if chroma(b) < 0.09 {
+            assert_eq!(a, b);
+        } else if a != b {
+            changed += 1;
+        }
+    }
+    assert!(changed > 0);
+}
+
+#[test]
+fn linearize_only() {
+    let settings = Settings {
+        linearize_only: true,
+        exposure: 2.0,
+        ..Settings::default()
+    };
+    let png_filename = output("pq-4x4-linear.png");
+    assert!(matches!(
+        hdrfix(&fixture("pq-4x4.png"), &png_filename, &settings),
+        Err(LocalError::LinearizeOnlyNeedsExr)
+    ));
+
+    // Same as the untouched --keep-hdr data, ignoring the exposure
+    let linear_filename = output("pq-4x4-linear.exr");
+    hdrfix(&fixture("pq-4x4.png"), &linear_filename, &settings).unwrap();
+    let sidecar_filename = output("pq-4x4-keep-hdr.exr");
+    let keep_hdr = Settings {
+        keep_hdr: true,
+        exposure: 2.0,
+        ..Settings::default()
+    };
+    hdrfix(
+        &fixture("pq-4x4.png"),
+        &output("pq-4x4-keep.png"),
+        &keep_hdr,
+    )
+    .unwrap();
+    assert_eq!(
+        read_exr_rgb(&linear_filename),
+        read_exr_rgb(&sidecar_filename)
+    );
+}
+
+#[test]
+fn quantize_modes() {
+    let quantized = |name: &str, quantize: Quantize| {
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            quantize,
+            ..Settings::default()
+        };
+        convert(synthetic_gradient(), &output_filename, &settings).unwrap();
+        read_rgb(&output_filename).2
+    };
+    let truncated = quantized("gradient-truncate.png", Quantize::Truncate);
+    let rounded = quantized("gradient-round.png", Quantize::Round);
+    let dithered = quantized("gradient-dither.png", Quantize::Dither);
+
+    // The ramp's mid-values fall on both sides of each half step
+    let diffs = truncated
+        .iter()
+        .zip(rounded.iter())
+        .map(|(t, r)| *r as i32 - *t as i32)
+        .collect::<Vec<i32>>();
+    assert!(diffs.iter().all(|diff| *diff == 0 || *diff == 1));
+    assert!(diffs.contains(&0) && diffs.contains(&1));
+    for (t, d) in truncated.iter().zip(dithered.iter()) {
+        assert!(*d == *t || *d == *t + 1);
+    }
+    assert_ne!(dithered, rounded);
+}
+
+#[test]
+fn supported_extensions() {
+    for ext in supported_input_extensions() {
+        // Gets as far as opening the file
+        let filename = output(&format!("missing.{}", ext));
+        assert!(matches!(
+            probe_input(&filename),
+            Err(LocalError::IoError(_))
+        ));
+    }
+    assert!(matches!(
+        probe_input(&output("missing.tiff")),
+        Err(LocalError::InvalidInputFile)
+    ));
+
+    for ext in supported_output_extensions() {
+        let filename = output(&format!("float-4x4-supported.{}", ext));
+        convert(synthetic_float(), &filename, &Settings::default()).unwrap();
+        assert!(filename.exists());
+    }
+    assert!(matches!(
+        convert(
+            synthetic_float(),
+            &output("float-4x4-supported.tiff"),
+            &Settings::default()
+        ),
+        Err(LocalError::InvalidOutputFile)
+    ));
+}
+
+#[test]
+fn auto_saturation() {
+    // Picks one of the candidates, so matches a fixed saturation run
+    let auto_filename = output("float-4x4-auto-saturation.png");
+    let settings = Settings {
+        tone_map: "reinhard".to_string(),
+        auto_saturation: true,
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &auto_filename, &settings).unwrap();
+    let (_, _, auto) = read_rgb(&auto_filename);
+
+    let matches = [0.5, 0.75, 1.0, 1.25, 1.5, 2.0].iter().any(|&saturation| {
+        let fixed_filename = output("float-4x4-fixed-saturation.png");
+        let settings = Settings {
+            tone_map: "reinhard".to_string(),
+            saturation,
+            ..Settings::default()
+        };
+        convert(synthetic_float(), &fixed_filename, &settings).unwrap();
+        read_rgb(&fixed_filename).2 == auto
+    });
+    assert!(matches);
+}
+
+#[test]
+fn output_transfer() {
+    let encoded = |name: &str, output_transfer: OutputTransfer| {
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            output_transfer,
+            ..Settings::default()
+        };
+        convert(synthetic_gradient(), &output_filename, &settings).unwrap();
+        read_rgb(&output_filename).2[0]
+    };
+    // The gradient starts at linear 0.2
+    assert_eq!(
+        encoded("gradient-bt1886.png", OutputTransfer::Bt1886),
+        (0.2_f32.powf(1.0 / 2.4) * 255.0).round() as u8
+    );
+    assert_eq!(
+        encoded("gradient-gamma22.png", OutputTransfer::Gamma22),
+        (0.2_f32.powf(1.0 / 2.2) * 255.0).round() as u8
+    );
+}
+
+#[test]
+fn alpha_modes() {
+    let transparent = || float_buffer(&[[4.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.5, 0.5]]);
+    let converted = |name: &str, alpha: AlphaMode| {
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            alpha,
+            ..Settings::default()
+        };
+        convert(transparent(), &output_filename, &settings).unwrap();
+        let decoder = png::Decoder::new(File::open(&output_filename).unwrap());
+        let color_type = decoder.read_info().unwrap().info().color_type;
+        (color_type, read_rgb(&output_filename).2)
+    };
+
+    let (color_type, kept) = converted("float-2x1-keep.png", AlphaMode::Keep);
+    assert_eq!(color_type, png::ColorType::Rgba);
+    assert_eq!((kept[3], kept[7]), (0, 128));
+    let (color_type, stripped) = converted("float-2x1-strip.png", AlphaMode::Strip);
+    assert_eq!(color_type, png::ColorType::Rgb);
+    assert_eq!(&kept[0..3], &stripped[0..3]);
+    assert_eq!(&kept[4..7], &stripped[3..6]);
+
+    // Flattening without a background composites over black
+    let (color_type, flattened) = converted("float-2x1-flatten.png", AlphaMode::Flatten);
+    assert_eq!(color_type, png::ColorType::Rgb);
+    let expected_filename = output("float-2x1-flatten-expected.png");
+    let opaque = float_buffer(&[[0.0, 0.0, 0.0, 1.0], [0.25, 0.25, 0.25, 1.0]]);
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        ..Settings::default()
+    };
+    convert(opaque, &expected_filename, &settings).unwrap();
+    assert_eq!(flattened, read_rgb(&expected_filename).2);
+}
+
+#[test]
+fn forced_input_format() {
+    // An EXR saved with a .png extension
+    let exr_filename = output("float-4x4-roundtrip.exr");
+    convert(synthetic_float(), &exr_filename, &Settings::default()).unwrap();
+    let misnamed = output("float-4x4-misnamed.png");
+    fs::copy(&exr_filename, &misnamed).unwrap();
+
+    let output_filename = output("float-4x4-from-misnamed.png");
+    assert!(hdrfix(&misnamed, &output_filename, &Settings::default()).is_err());
+    let settings = Settings {
+        input_format: Some(InputFormat::Exr),
+        ..Settings::default()
+    };
+    assert_eq!(
+        probe_input_as(&misnamed, settings.input_format).unwrap(),
+        (4, 4)
+    );
+    hdrfix(&misnamed, &output_filename, &settings).unwrap();
+
+    let direct_filename = output("float-4x4-direct-exr.png");
+    convert(synthetic_float(), &direct_filename, &Settings::default()).unwrap();
+    let (_, _, from_exr) = read_rgb(&output_filename);
+    let (_, _, direct) = read_rgb(&direct_filename);
+    for (a, b) in from_exr.iter().zip(direct.iter()) {
+        assert!((*a as i32 - *b as i32).abs() <= 1, "{:?}", from_exr);
+    }
+}
+
+#[test]
+fn false_color() {
+    // 0.05, 50, 500 and 5000 nits
+    let source = float_buffer(&[
+        [0.000625, 0.000625, 0.000625, 1.0],
+        [0.625, 0.625, 0.625, 1.0],
+        [6.25, 6.25, 6.25, 1.0],
+        [62.5, 62.5, 62.5, 1.0],
+    ]);
+    let output_filename = output("float-4x1-false-color.png");
+    let settings = Settings {
+        false_color: true,
+        ..Settings::default()
+    };
+    convert(source, &output_filename, &settings).unwrap();
+    let (_, _, data) = read_rgb(&output_filename);
+    let full = data[9];
+    assert!(full > 200);
+    assert_eq!(&data[0..3], &[0, 0, full]);
+    assert!(data[3] == data[4] && data[4] == data[5]);
+    assert_eq!((data[6], data[8]), (full, 0));
+    assert!(data[7] > 0 && data[7] < full);
+    assert_eq!(&data[9..12], &[full, 0, 0]);
+}
+
+#[test]
+fn tone_map_chain() {
+    let converted = |name: &str, tone_map: &str| {
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: tone_map.to_string(),
+            ..Settings::default()
+        };
+        convert(synthetic_float(), &output_filename, &settings).map(|_| read_rgb(&output_filename))
+    };
+
+    // linear passes its input through, so only the other operator counts
+    let hable = converted("float-4x4-chain-hable.png", "hable").unwrap();
+    let chained = converted("float-4x4-chain-linear-hable.png", "linear, hable").unwrap();
+    assert_eq!(hable, chained);
+    let twice = converted("float-4x4-chain-hable-hable.png", "hable,hable").unwrap();
+    assert_ne!(hable, twice);
+
+    let result = converted("float-4x4-chain-unknown.png", "hable,local");
+    assert!(matches!(result, Err(LocalError::UnknownToneMap(name)) if name == "local"));
+}
+
+#[test]
+fn warn_clip_above() {
+    let clipped = |name: &str, threshold: f32| {
+        let output_filename = output(name);
+        let _ = fs::remove_file(&output_filename);
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            warn_clip_above: Some(threshold),
+            ..Settings::default()
+        };
+        let result = convert(synthetic_float(), &output_filename, &settings);
+        assert!(output_filename.exists());
+        result
+    };
+
+    assert!(clipped("float-4x4-clip-under.png", 100.0).is_ok());
+    match clipped("float-4x4-clip-over.png", 0.0) {
+        Err(LocalError::ClipThresholdExceeded(percent, threshold)) => {
+            assert!(percent > 0.0 && percent <= 100.0);
+            assert_eq!(threshold, 0.0);
+        }
+        result => panic!("expected the clip threshold to fail, got {:?}", result),
+    }
+}
+
+#[test]
+fn roi_hdr_max() {
+    // A dim subject next to a bright light
+    let source = || {
+        float_buffer(&[
+            [0.25, 0.25, 0.25, 1.0],
+            [1.0, 1.0, 1.0, 1.0],
+            [40.0, 40.0, 40.0, 1.0],
+            [50.0, 50.0, 50.0, 1.0],
+        ])
+    };
+    let converted = |name: &str, hdr_max: Level, roi: Option<(usize, usize, usize, usize)>| {
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: "reinhard".to_string(),
+            hdr_max,
+            hdr_max_clip: 0.0,
+            roi,
+            ..Settings::default()
+        };
+        convert(source(), &output_filename, &settings).map(|_| read_rgb(&output_filename))
+    };
+
+    let whole = converted("float-4x1-roi-none.png", Level::Percentile(100.0), None).unwrap();
+    let subject = converted(
+        "float-4x1-roi-subject.png",
+        Level::Percentile(100.0),
+        Some((0, 0, 2, 1)),
+    )
+    .unwrap();
+    assert_ne!(whole, subject);
+    // The subject peaks at 1.0, 80 nits
+    let scalar = converted("float-4x1-roi-scalar.png", Level::Scalar(80.0), None).unwrap();
+    assert_eq!(subject, scalar);
+
+    // Cut down to fit, or outside the image entirely
+    let oversized = converted(
+        "float-4x1-roi-oversized.png",
+        Level::Percentile(100.0),
+        Some((2, 0, 100, 100)),
+    )
+    .unwrap();
+    assert_eq!(oversized, whole);
+    let result = converted(
+        "float-4x1-roi-outside.png",
+        Level::Percentile(100.0),
+        Some((4, 0, 1, 1)),
+    );
+    assert!(matches!(result, Err(LocalError::RoiOutsideImage(..))));
+
+    assert_eq!(parse_roi("10, 20,30,40").unwrap(), (10, 20, 30, 40));
+    assert!(parse_roi("10,20,0,40").is_err());
+    assert!(parse_roi("10,20,30").is_err());
+}
+
+#[test]
+fn aspect_crop() {
+    assert_eq!(parse_aspect("16:9").unwrap(), (16, 9));
+    assert!(matches!(
+        parse_aspect("16x9"),
+        Err(LocalError::InvalidAspect(_))
+    ));
+    assert!(matches!(
+        parse_aspect("16:0"),
+        Err(LocalError::InvalidAspect(_))
+    ));
+
+    let output_filename = output("float-4x4-aspect.png");
+    let settings = Settings {
+        aspect: Some((2, 1)),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    let (width, height, _) = read_rgb(&output_filename);
+    assert_eq!((width, height), (4, 2));
+
+    let settings = Settings {
+        aspect: Some((1, 4)),
+        ..Settings::default()
+    };
+    let output_filename = output("float-4x4-aspect-tall.png");
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    let (width, height, _) = read_rgb(&output_filename);
+    assert_eq!((width, height), (1, 4));
+}
+
+#[test]
+fn registered_operators() {
+    for tone_map in tone_map_names() {
+        for color_map in color_map_names() {
+            let settings = Settings {
+                tone_map: tone_map.to_string(),
+                color_map: color_map.to_string(),
+                ..Settings::default()
+            };
+            let output_filename = output(&format!("float-4x4-{}-{}.png", tone_map, color_map));
+            convert(synthetic_float(), &output_filename, &settings).unwrap();
+        }
+    }
+    let settings = Settings {
+        color_map: "desaturate-oklab".to_string(),
+        ..Settings::default()
+    };
+    let result = convert(
+        synthetic_float(),
+        &output("unknown-color-map.png"),
+        &settings,
+    );
+    assert!(matches!(result, Err(LocalError::UnknownColorMap(..))));
+}
+
+#[test]
+fn shadows_highlights() {
+    let zoned = |name: &str, shadows: f32, highlights: f32| {
+        // Dark, middle gray and bright
+        let source = float_buffer(&[
+            [0.01, 0.01, 0.01, 1.0],
+            [0.18, 0.18, 0.18, 1.0],
+            [0.9, 0.9, 0.9, 1.0],
+        ]);
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            shadows,
+            highlights,
+            ..Settings::default()
+        };
+        convert(source, &output_filename, &settings).unwrap();
+        let data = read_rgb(&output_filename).2;
+        (data[0], data[3], data[6])
+    };
+
+    let plain = zoned("float-3x1-zones-plain.png", 0.0, 0.0);
+    let lifted = zoned("float-3x1-zones-shadows.png", 1.0, 0.0);
+    assert!(lifted.0 > plain.0);
+    assert_eq!((lifted.1, lifted.2), (plain.1, plain.2));
+    let pulled = zoned("float-3x1-zones-highlights.png", 0.0, -1.0);
+    assert!(pulled.2 < plain.2);
+    assert_eq!((pulled.0, pulled.1), (plain.0, plain.1));
+}
+
+// A DDS file with a DX10 header for the given DXGI format
+fn dds_file(width: u32, height: u32, dxgi_format: u32, data: &[u8]) -> Vec<u8> {
+    let mut header = vec![0u8; 148];
+    header[0..4].copy_from_slice(b"DDS ");
+    header[4..8].copy_from_slice(&124u32.to_le_bytes());
+    header[12..16].copy_from_slice(&height.to_le_bytes());
+    header[16..20].copy_from_slice(&width.to_le_bytes());
+    header[76..80].copy_from_slice(&32u32.to_le_bytes());
+    // DDPF_FOURCC
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(b"DX10");
+    header[128..132].copy_from_slice(&dxgi_format.to_le_bytes());
+    header.extend_from_slice(data);
+    header
+}
+
+#[test]
+fn dds_input() {
+    let linearize = Settings {
+        linearize_only: true,
+        ..Settings::default()
+    };
+
+    // DXGI_FORMAT_R16G16B16A16_FLOAT
+    let texels = (0..16)
+        .flat_map(|i| {
+            let val = i as f32 * 0.5;
+            [val, val * 2.0, 1.0, 1.0]
+        })
+        .flat_map(|val| half::f16::from_f32(val).to_le_bytes())
+        .collect::<Vec<u8>>();
+    let half_filename = output("half-4x4.dds");
+    fs::write(&half_filename, dds_file(4, 4, 10, &texels)).unwrap();
+    assert_eq!(probe_input(&half_filename).unwrap(), (4, 4));
+    let half_linear = output("half-4x4-dds-linear.exr");
+    hdrfix(&half_filename, &half_linear, &linearize).unwrap();
+    let pixels = read_exr_rgb(&half_linear);
+    assert_eq!(pixels[0], [0.0, 0.0, 1.0]);
+    assert_eq!(pixels[5], [2.5, 5.0, 1.0]);
+
+    // DXGI_FORMAT_BC6H_UF16, one block in mode 11: mode bits 00011, then
+    // 10-bit endpoints rw gw bw rx gx bx, then a 3-bit index for the first
+    // texel and 4 bits for the others. Endpoint w is full red, x full green,
+    // and only the second texel uses x.
+    let block: u128 = 0b00011 | (1023 << 5) | (1023 << 45) | (0b1111 << 68);
+    let bc6h_filename = output("bc6h-4x4.dds");
+    fs::write(&bc6h_filename, dds_file(4, 4, 95, &block.to_le_bytes())).unwrap();
+    let bc6h_linear = output("bc6h-4x4-dds-linear.exr");
+    hdrfix(&bc6h_filename, &bc6h_linear, &linearize).unwrap();
+    let pixels = read_exr_rgb(&bc6h_linear);
+    // The largest half float, as the top of the unsigned range
+    assert_eq!(pixels[0], [65504.0, 0.0, 0.0]);
+    assert_eq!(pixels[1], [0.0, 65504.0, 0.0]);
+    assert_eq!(pixels[15], [65504.0, 0.0, 0.0]);
+
+    let truncated = output("truncated-4x4.dds");
+    fs::write(&truncated, dds_file(4, 4, 95, &[0; 8])).unwrap();
+    let result = hdrfix(
+        &truncated,
+        &output("truncated-4x4.png"),
+        &Settings::default(),
+    );
+    assert!(matches!(result, Err(LocalError::DDSError(..))));
+
+    // Dimensions whose data size overflows
+    for &(width, height, dxgi_format) in &[(u32::MAX, u32::MAX, 2), (u32::MAX, u32::MAX, 95)] {
+        let malformed = output("malformed.dds");
+        fs::write(&malformed, dds_file(width, height, dxgi_format, &[0; 16])).unwrap();
+        let result = hdrfix(&malformed, &output("malformed.png"), &Settings::default());
+        assert!(
+            matches!(result, Err(LocalError::DDSError(ref message)) if message.contains("too large"))
+        );
+    }
+}
+
+#[test]
+fn preview() {
+    let settings = Settings::default();
+    let full_size = Preview::new(&fixture("pq-4x4.png"), 80, &settings).unwrap();
+    assert_eq!((full_size.width(), full_size.height()), (4, 4));
+    let (pixels, _) = full_size.render(&settings).unwrap();
+    let (_, _, expected) = read_rgb(&fixture("pq-4x4-hable.png"));
+    assert_eq!(pixels.concat(), expected);
+
+    let shrunk = Preview::new(&fixture("pq-4x4.png"), 3, &settings).unwrap();
+    assert_eq!((shrunk.width(), shrunk.height()), (2, 2));
+    let output_filename = output("pq-4x4-hable-preview.png");
+    shrunk.write(&output_filename, &settings).unwrap();
+    assert_matches_golden(&output_filename, "pq-4x4-hable.png");
+}
+
+#[test]
+fn color_matrix_composition() {
+    let matrix = |settings: &Settings| color_matrix(&fixture("pq-4x4.png"), settings).unwrap();
+    // Both undo the read function's Rec.2020 to Rec.709 conversion,
+    // leaving the 10000 nit to 80 nit scale on the diagonal.
+    let near_scale = |matrix: Mat3| {
+        let cols = matrix.to_cols_array_2d();
+        for (i, col) in cols.iter().enumerate() {
+            for (j, val) in col.iter().enumerate() {
+                let expected = if i == j { 125.0 } else { 0.0 };
+                assert!((val - expected).abs() < 0.1, "{:?}", cols);
+            }
+        }
+    };
+
+    // BT.2100 white comes through as scRGB white at 10000 nits
+    let pq = matrix(&Settings::default());
+    let white = pq.mul_vec3(Vec3::ONE);
+    assert!((white - Vec3::splat(125.0)).abs().max_element() < 0.1);
+    near_scale(matrix(&Settings {
+        working_space: Primaries::Rec2020,
+        ..Settings::default()
+    }));
+    near_scale(matrix(&Settings {
+        input_primaries: Some(Primaries::Rec709),
+        ..Settings::default()
+    }));
+
+    assert!(matches!(
+        Primaries::with_str("rec2100"),
+        Ok(Primaries::Rec2020)
+    ));
+    assert!(matches!(
+        Primaries::with_str("aces"),
+        Err(LocalError::InvalidPrimaries(_))
+    ));
+}
+
+#[test]
+fn self_test_passes() {
+    self_test().unwrap();
+}
+
+#[test]
+fn inverse_tone_map() {
+    let input = fixture("pq-4x4-hable.png");
+    let luma = |rgb: &[f32; 3]| 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+    let inverse = |name: &str, nits: f32| {
+        let output_filename = output(name);
+        let settings = Settings {
+            inverse: true,
+            hdr_max: Level::Nits(nits),
+            ..Settings::default()
+        };
+        hdrfix(&input, &output_filename, &settings).unwrap();
+        read_exr_rgb(&output_filename)
+    };
+    // With the peak at SDR white the curve is a straight line
+    let sdr = inverse("pq-4x4-inverse-80.exr", 80.0);
+
+    let settings = Settings {
+        inverse: true,
+        hdr_max: Level::Nits(1000.0),
+        ..Settings::default()
+    };
+    let hdr = inverse("pq-4x4-inverse.exr", 1000.0);
+    // Brighter everywhere, more so toward white, but never past the peak
+    for (sdr, hdr) in sdr.iter().zip(&hdr) {
+        assert!(luma(hdr) >= luma(sdr) - 1e-6, "{:?} {:?}", sdr, hdr);
+        assert!(luma(hdr) <= 1000.0 / 80.0 + 1e-3);
+    }
+    let brightest = (0..16).max_by(|&a, &b| luma(&sdr[a]).total_cmp(&luma(&sdr[b])));
+    let brightest = brightest.unwrap();
+    assert!(luma(&hdr[brightest]) > 2.0 * luma(&sdr[brightest]));
+
+    // PQ PNG output reads back as the same light
+    let pq_filename = output("pq-4x4-inverse.png");
+    hdrfix(&input, &pq_filename, &settings).unwrap();
+    assert_eq!(png_bit_depth(&pq_filename), png::BitDepth::Sixteen);
+    let relinear_filename = output("pq-4x4-inverse-linear.exr");
+    let linearize = Settings {
+        linearize_only: true,
+        ..Settings::default()
+    };
+    hdrfix(&pq_filename, &relinear_filename, &linearize).unwrap();
+    for (hdr, pq) in hdr.iter().zip(&read_exr_rgb(&relinear_filename)) {
+        assert!((luma(hdr) - luma(pq)).abs() <= 0.01 * luma(hdr) + 1e-3);
+    }
+
+    let jpeg = hdrfix(&input, &output("pq-4x4-inverse.jpg"), &settings);
+    assert!(matches!(jpeg, Err(LocalError::InverseNeedsHdrOutput)));
+}
+
+#[test]
+fn fast_preview() {
+    let exact_settings = Settings {
+        tone_map: "reinhard".to_string(),
+        shadows: 1.0,
+        ..Settings::default()
+    };
+    let fast_settings = Settings {
+        fast: true,
+        ..exact_settings.clone()
+    };
+    let preview = Preview::new(&fixture("pq-4x4.png"), 80, &exact_settings).unwrap();
+    let (exact, _) = preview.render(&exact_settings).unwrap();
+    let (fast, _) = preview.render(&fast_settings).unwrap();
+    // Channels near zero in saturated colors are off the most
+    for (a, b) in exact.concat().iter().zip(fast.concat().iter()) {
+        assert!((*a as i32 - *b as i32).abs() <= 4, "{} vs {}", a, b);
+    }
+
+    // Only the preview is approximate, not the written output
+    let exact_filename = output("pq-4x4-reinhard-exact.png");
+    hdrfix(&fixture("pq-4x4.png"), &exact_filename, &exact_settings).unwrap();
+    let fast_filename = output("pq-4x4-reinhard-fast.png");
+    preview.write(&fast_filename, &fast_settings).unwrap();
+    assert_eq!(read_rgb(&exact_filename), read_rgb(&fast_filename));
+}
+
+#[test]
+fn grain() {
+    let converted = |name: &str, grain: f32, seed: u64, low_memory: bool| {
+        let output_filename = output(name);
+        let settings = Settings {
+            grain,
+            seed,
+            low_memory,
+            output_depth: OutputDepth::Eight,
+            ..Settings::default()
+        };
+        hdrfix(&fixture("pq-4x4.png"), &output_filename, &settings).unwrap();
+        read_rgb(&output_filename)
+    };
+
+    let plain = converted("pq-4x4-no-grain.png", 0.0, 1, false);
+    let grainy = converted("pq-4x4-grain.png", 2.0, 1, false);
+    assert_ne!(plain, grainy);
+    assert_eq!(grainy, converted("pq-4x4-grain-again.png", 2.0, 1, false));
+    assert_ne!(grainy, converted("pq-4x4-grain-seed.png", 2.0, 2, false));
+    // Strips pick up the same per-pixel noise as the whole image
+    assert_eq!(
+        grainy,
+        converted("pq-4x4-grain-low-memory.png", 2.0, 1, true)
+    );
+}
+
+#[test]
+fn level_syntax() {
+    assert!(
+        matches!(Level::with_str("hdr-max", " 50 % "), Ok(Level::Percentile(val)) if val == 50.0)
+    );
+    assert!(
+        matches!(Level::with_str("hdr-max", "100%"), Ok(Level::Percentile(val)) if val == 100.0)
+    );
+    assert!(matches!(Level::with_str("hdr-max", "0"), Ok(Level::Scalar(val)) if val == 0.0));
+    assert!(matches!(
+        Level::with_str("hdr-max", " content"),
+        Ok(Level::Content)
+    ));
+
+    for source in &["%50", "50%%", "", "nits", "fifty", "NaN", "inf", "1e40"] {
+        let result = Level::with_str("hdr-max", source);
+        assert!(
+            matches!(&result, Err(LocalError::InvalidLevel(_))),
+            "{:?} gave {:?}",
+            source,
+            result
+        );
+    }
+    for source in &["120%", "-5%"] {
+        let result = Level::with_str("post-levels-max", source);
+        assert!(
+            matches!(&result, Err(LocalError::LevelOutOfRange(_, level, _)) if level == source),
+            "{:?} gave {:?}",
+            source,
+            result
+        );
+    }
+    assert_eq!(
+        Level::with_str("post-levels-max", "120%")
+            .unwrap_err()
+            .to_string(),
+        "post-levels-max '120%' out of range, percentiles must be from 0 to 100%"
+    );
+    // Negative absolute levels only make sense for some options
+    assert!(
+        matches!(Level::with_str("pre-levels-min", "-1"), Ok(Level::Scalar(val)) if val == -1.0)
+    );
+    for source in &["120%", "-1", "-200nits"] {
+        let result = Level::non_negative("hdr-max", source);
+        assert!(
+            matches!(&result, Err(LocalError::LevelOutOfRange(_, level, _)) if level == source),
+            "{:?} gave {:?}",
+            source,
+            result
+        );
+    }
+}
+
+#[test]
+fn working_space() {
+    // Gray, then Rec.2020 green and red in scRGB, far outside sRGB
+    let converted = |name: &str, tone_map: &str, working_space: Primaries| {
+        let source = float_buffer(&[
+            [2.0, 2.0, 2.0, 1.0],
+            [-1.66, 5.59, -0.62, 1.0],
+            [8.3, -0.62, -0.09, 1.0],
+            [0.1, 0.1, 0.1, 1.0],
+        ]);
+        let output_filename = output(name);
+        let settings = Settings {
+            tone_map: tone_map.to_string(),
+            working_space,
+            ..Settings::default()
+        };
+        convert(source, &output_filename, &settings).unwrap();
+        read_rgb(&output_filename).2
+    };
+
+    let srgb = converted("float-4x1-working-srgb.png", "hable", Primaries::Rec709);
+    let rec2020 = converted("float-4x1-working-rec2020.png", "hable", Primaries::Rec2020);
+    assert_eq!(srgb[0..3], rec2020[0..3]);
+    assert_eq!(srgb[9..12], rec2020[9..12]);
+    assert_ne!(srgb[3..9], rec2020[3..9]);
+
+    // The oklab tone map has its own color handling
+    let oklab_srgb = converted("float-4x1-oklab-srgb.png", "reinhard", Primaries::Rec709);
+    let oklab_rec2020 = converted(
+        "float-4x1-oklab-rec2020.png",
+        "reinhard",
+        Primaries::Rec2020,
+    );
+    assert_eq!(oklab_srgb, oklab_rec2020);
+}
+
+#[test]
+fn sweep() {
+    assert_eq!(
+        parse_sweep("hdr-max=200, 400,800").unwrap(),
+        ("hdr-max".to_string(), vec![200.0, 400.0, 800.0])
+    );
+    for source in &["hdr-max", "tone-map=1,2", "saturation=1,,2", "exposure=nan"] {
+        assert!(matches!(
+            parse_sweep(source),
+            Err(LocalError::InvalidSweep(_))
+        ));
+    }
+    assert!(matches!(
+        parse_sweep("hdr-max=200,-400"),
+        Err(LocalError::LevelOutOfRange("hdr-max", ..))
+    ));
+    assert!(parse_sweep("exposure=-1,0,1").is_ok());
+
+    // Settings built without the parser are checked when converting
+    let swept = |param: &str, values: Vec<f32>| Settings {
+        sweep: Some((param.to_string(), values)),
+        ..Settings::default()
+    };
+    let result = convert(
+        synthetic_float(),
+        &output("float-4x4-sweep-bad.png"),
+        &swept("tone-map", vec![1.0]),
+    );
+    assert!(matches!(result, Err(LocalError::InvalidSweep(_))));
+    let result = convert(
+        synthetic_float(),
+        &output("float-4x4-sweep-bad.png"),
+        &swept("hdr-max", vec![-200.0]),
+    );
+    assert!(matches!(
+        result,
+        Err(LocalError::LevelOutOfRange("hdr-max", ..))
+    ));
+
+    let output_filename = output("float-4x4-sweep.png");
+    let settings = Settings {
+        sweep: Some(parse_sweep("saturation=0.5,1,2").unwrap()),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output_filename, &settings).unwrap();
+    // Two columns and two rows of 4x4 tiles with a 14 pixel label band above each
+    let (width, height, grid) = read_rgb(&output_filename);
+    assert_eq!((width, height), (8, 36));
+    let label_lit =
+        |tile_x: usize| (0..14 * 4).any(|i| grid[(i / 4 * 8 + tile_x + i % 4) * 3] > 200);
+    assert!(label_lit(0) && label_lit(4));
+    // The empty fourth tile stays black
+    assert!(grid[18 * 8 * 3..]
+        .chunks(8 * 3)
+        .all(|row| row[4 * 3..].iter().all(|&b| b == 0)));
+
+    // The second tile matches a plain conversion at that value
+    let plain_filename = output("float-4x4-sweep-plain.png");
+    convert(synthetic_float(), &plain_filename, &Settings::default()).unwrap();
+    let (_, _, plain) = read_rgb(&plain_filename);
+    for y in 0..4 {
+        for x in 0..4 {
+            for c in 0..3 {
+                let tile = grid[((14 + y) * 8 + 4 + x) * 3 + c] as i32;
+                assert!((tile - plain[(y * 4 + x) * 3 + c] as i32).abs() <= 1);
+            }
+        }
+    }
+}
+
+#[test]
+fn export_curve() {
+    let curve_filename = output("float-4x4-curve.csv");
+    let settings = Settings {
+        export_curve: Some(curve_filename.clone()),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &output("float-4x4-curve.png"), &settings).unwrap();
+
+    let csv = fs::read_to_string(&curve_filename).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("input_nits,output_linear,output_signal"));
+    let rows = lines
+        .map(|line| {
+            line.split(',')
+                .map(|val| val.parse::<f32>().unwrap())
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 256);
+    assert!((rows[0][0] - 0.001).abs() < 1e-6);
+    assert!((rows[255][0] - 10000.0).abs() < 1.0);
+    for pair in rows.windows(2) {
+        assert!(pair[1][0] > pair[0][0]);
+        assert!(pair[1][1] >= pair[0][1]);
+    }
+    assert!(rows[0][2] < 0.05 && rows[255][2] > 0.9);
+}
+
+#[test]
+fn best_effort() {
+    // A 16-bit PQ gradient, cut off partway through the image data
+    let input = output("pq-8x64-truncated.png");
+    {
+        let mut data = Vec::new();
+        for i in 0..8 * 64 * 3 {
+            data.extend_from_slice(&(20000 + (i * 7919 % 10000) as u16).to_be_bytes());
+        }
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 8, 64);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+    }
+    let full = fs::read(&input).unwrap();
+    fs::write(&input, &full[..full.len() / 2]).unwrap();
+
+    let output_filename = output("pq-8x64-truncated-out.png");
+    assert!(hdrfix(&input, &output_filename, &Settings::default()).is_err());
+
+    let settings = Settings {
+        best_effort: true,
+        ..Settings::default()
+    };
+    hdrfix(&input, &output_filename, &settings).unwrap();
+    let (width, height, data) = read_rgb(&output_filename);
+    assert_eq!((width, height), (8, 64));
+    let rows = data.chunks(8 * 3).collect::<Vec<_>>();
+    assert!(rows[0].iter().any(|&b| b > 0));
+    assert!(rows[63].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn resize_filter() {
+    // 16-bit PQ input, 8x8, with the given signal per column
+    let write_input = |name: &str, column: fn(usize) -> u16| {
+        let input = output(name);
+        let mut data = Vec::new();
+        for i in 0..8 * 8 {
+            for _ in 0..3 {
+                data.extend_from_slice(&column(i % 8).to_be_bytes());
+            }
+        }
+        let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 8, 8);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+        input
+    };
+    let previews = |input: &Path| {
+        ["box", "bilinear", "lanczos3", "mitchell"]
+            .iter()
+            .map(|filter| {
+                let settings = Settings {
+                    resize_filter: ResizeFilter::with_str(filter).unwrap(),
+                    hdr_max: Level::Nits(1000.0),
+                    ..Settings::default()
+                };
+                let preview = Preview::new(input, 4, &settings).unwrap();
+                assert_eq!((preview.width(), preview.height()), (4, 4));
+                preview.render(&settings).unwrap().0
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Weights are normalized, so a flat image comes out the same with any filter
+    let flat = previews(&write_input("pq-8x8-flat.png", |_| 30000));
+    assert!(flat.iter().all(|preview| *preview == flat[0]));
+
+    // Box keeps a hard edge between blocks; the others blur or ring
+    let edge = previews(&write_input("pq-8x8-edge.png", |x| {
+        if x < 4 {
+            40000
+        } else {
+            10000
+        }
+    }));
+    assert_eq!(edge[0][0], edge[0][1]);
+    assert_eq!(edge[0][2], edge[0][3]);
+    for preview in &edge[1..] {
+        assert_ne!(preview[1], edge[0][1]);
+    }
+    assert!(matches!(
+        ResizeFilter::with_str("nearest"),
+        Err(LocalError::InvalidResizeFilter(_))
+    ));
+}
+
+#[test]
+fn oog_mask() {
+    let source = float_buffer(&[
+        [0.5, 0.5, 0.5, 1.0],
+        [1.5, 1.5, 1.5, 1.0],
+        [3.0, 3.0, 3.0, 1.0],
+        [1.25, 0.0, 0.0, 1.0],
+    ]);
+    let mask_filename = output("float-4x1-oog-mask.png");
+    let settings = Settings {
+        tone_map: "linear".to_string(),
+        oog_mask: Some(mask_filename.clone()),
+        ..Settings::default()
+    };
+    convert(source, &output("float-4x1-oog.png"), &settings).unwrap();
+
+    let decoder = png::Decoder::new(File::open(&mask_filename).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    assert_eq!(reader.info().color_type, png::ColorType::Grayscale);
+    let mut data = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut data).unwrap();
+    assert_eq!(data, [0, 128, 255, 64]);
+}
+
+#[test]
+fn color_map_diff() {
+    let source = || {
+        float_buffer(&[
+            [0.5, 0.5, 0.5, 1.0],
+            [3.0, 3.0, 3.0, 1.0],
+            [1.5, 0.1, 0.1, 1.0],
+        ])
+    };
+    let diff = |color_map: &str| {
+        let diff_filename = output(&format!("float-3x1-color-map-diff-{}.png", color_map));
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            color_map: color_map.to_string(),
+            color_map_diff: Some(diff_filename.clone()),
+            ..Settings::default()
+        };
+        let output_filename = output(&format!("float-3x1-color-map-{}.png", color_map));
+        convert(source(), &output_filename, &settings).unwrap();
+        let decoder = png::Decoder::new(File::open(&diff_filename).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        data
+    };
+    assert_eq!(diff("clip"), [0, 0, 0]);
+    // Only the out of gamut red is changed; gray clips to white either way
+    let darken = diff("darken");
+    assert_eq!(darken[..2], [0, 0]);
+    assert!(darken[2] > 0);
+}
+
+#[test]
+fn grayscale_output() {
+    let color = output("float-4x4-color.png");
+    convert(synthetic_float(), &color, &Settings::default()).unwrap();
+    let (_, _, color) = read_rgb(&color);
+
+    let gray = output("float-4x4-grayscale.png");
+    let settings = Settings {
+        grayscale: Some(Grayscale::Lightness),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &gray, &settings).unwrap();
+    let (_, _, gray) = read_rgb(&gray);
+    for (gray, color) in gray.chunks(3).zip(color.chunks(3)) {
+        assert!(gray[0] == gray[1] && gray[1] == gray[2]);
+        // Colored pixels keep their lightness
+        assert_eq!(gray[0] == 0, color.iter().all(|&val| val == 0));
+    }
+
+    // All red weight gives the red channel
+    let red = output("float-4x4-grayscale-red.png");
+    let settings = Settings {
+        grayscale: Some(Grayscale::Mix(parse_grayscale_mix("2,0,0").unwrap())),
+        ..Settings::default()
+    };
+    convert(synthetic_float(), &red, &settings).unwrap();
+    let (_, _, red) = read_rgb(&red);
+    for (red, color) in red.chunks(3).zip(color.chunks(3)) {
+        assert_eq!(red, [color[0]; 3]);
+    }
+    assert!(parse_grayscale_mix("1,-1,0").is_err());
+    assert!(parse_grayscale_mix("1,1").is_err());
+}
+
+#[test]
+fn histogram_sample() {
+    let settings = |histogram_sample| Settings {
+        auto_exposure: Level::Percentile(50.0),
+        hdr_max: Level::Percentile(99.0),
+        histogram_sample,
+        ..Settings::default()
+    };
+    let full = output("gradient-histogram-full.png");
+    convert(synthetic_gradient(), &full, &settings(None)).unwrap();
+    let sampled = output("gradient-histogram-sampled.png");
+    convert(synthetic_gradient(), &sampled, &settings(Some(0.25))).unwrap();
+    let again = output("gradient-histogram-sampled-again.png");
+    convert(synthetic_gradient(), &again, &settings(Some(0.25))).unwrap();
+
+    let (_, _, full) = read_rgb(&full);
+    let (_, _, sampled) = read_rgb(&sampled);
+    // The same seed picks the same pixels
+    assert_eq!(read_rgb(&again).2, sampled);
+    // A quarter of a smooth ramp gives nearly the same levels
+    for (full, sampled) in full.iter().zip(&sampled) {
+        assert!((*full as i32 - *sampled as i32).abs() <= 2);
+    }
+    assert!(parse_histogram_sample("0").is_err());
+    assert!(parse_histogram_sample("1.5").is_err());
+}
+
+#[test]
+fn measured_levels_match() {
+    let input = fixture("pq-4x4.png");
+    let settings = Settings {
+        hdr_max: Level::Percentile(99.0),
+        ..Settings::default()
+    };
+    let (auto_exposure, hdr_max) = measure_levels(&input, &settings).unwrap();
+    assert!(matches!(auto_exposure, Level::Scalar(_)));
+    assert!(matches!(hdr_max, Level::Nits(_)));
+
+    let detected = output("pq-4x4-levels-detected.png");
+    hdrfix(&input, &detected, &settings).unwrap();
+    let fixed = output("pq-4x4-levels-fixed.png");
+    let fixed_settings = Settings {
+        auto_exposure,
+        hdr_max,
+        ..settings
+    };
+    hdrfix(&input, &fixed, &fixed_settings).unwrap();
+    for (detected, fixed) in read_rgb(&detected).2.iter().zip(&read_rgb(&fixed).2) {
+        assert!((*detected as i32 - *fixed as i32).abs() <= 1);
+    }
+}
+
+#[test]
+fn measured_nits() {
+    let input = fixture("pq-4x4.png");
+    let (peak, average) = measure_nits(&input, &Settings::default()).unwrap();
+    assert!(average > 0.0 && average <= peak);
+    // The same peak --hdr-max=100% finds
+    match measure_levels(&input, &Settings::default()).unwrap().1 {
+        Level::Nits(nits) => assert!((nits - peak).abs() < 0.01),
+        level => panic!("{:?}", level),
+    }
+
+    let settings = Settings {
+        low_memory: true,
+        ..Settings::default()
+    };
+    assert!(matches!(
+        measure_nits(&input, &settings),
+        Err(LocalError::LowMemoryUnsupported(_))
+    ));
+}
+
+#[test]
+fn split_tone() {
+    assert!(matches!(
+        parse_split_tone("shadow=#12345"),
+        Err(LocalError::InvalidSplitTone(_))
+    ));
+    assert!(parse_split_tone("balance=1").is_err());
+    assert_eq!(
+        parse_split_tone("balance=0.5").unwrap(),
+        SplitTone::default()
+    );
+
+    let grays = float_buffer(&[[0.05, 0.05, 0.05, 1.0], [2.0, 2.0, 2.0, 1.0]]);
+    let toned = |split_tone: SplitTone, name: &str| {
+        let filename = output(&format!("float-2x1-split-tone-{}.png", name));
+        let settings = Settings {
+            split_tone,
+            ..Settings::default()
+        };
+        convert(grays.clone(), &filename, &settings).unwrap();
+        let (_, _, rgb) = read_rgb(&filename);
+        rgb.chunks(3)
+            .map(|rgb| oklab::srgb_to_oklab(oklab::RGB::new(rgb[0], rgb[1], rgb[2])))
+            .collect::<Vec<_>>()
+    };
+    let plain = toned(SplitTone::default(), "none");
+    assert!(plain
+        .iter()
+        .all(|lab| lab.a.abs() < 0.01 && lab.b.abs() < 0.01));
+
+    // Blue shadows and orange highlights
+    let split_tone = parse_split_tone("shadow=#3060c0, highlight=#e0a040").unwrap();
+    let toned = toned(split_tone, "teal-orange");
+    assert!(toned[0].b < -0.01, "{:?}", toned[0]);
+    assert!(toned[1].b > 0.01, "{:?}", toned[1]);
+    for (toned, plain) in toned.iter().zip(&plain) {
+        assert!((toned.l - plain.l).abs() < 0.02);
+    }
+}
+
+#[test]
+fn denoise_chroma() {
+    // Alternating reddish and greenish pixels of about the same lightness
+    let noisy = || {
+        let mut pixels = Vec::new();
+        for i in 0..16 {
+            pixels.push(match i % 2 {
+                0 => [0.5, 0.3, 0.3, 1.0],
+                _ => [0.3, 0.45, 0.3, 1.0],
+            });
+        }
+        float_buffer(&pixels)
+    };
+    let spread = |denoise_chroma| {
+        let filename = output(&format!("float-16x1-denoise-{}.png", denoise_chroma));
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            denoise_chroma,
+            ..Settings::default()
+        };
+        convert(noisy(), &filename, &settings).unwrap();
+        let (_, _, data) = read_rgb(&filename);
+        // Red minus green swings between neighbors
+        data.chunks(3)
+            .map(|rgb| rgb[0] as i32 - rgb[1] as i32)
+            .collect::<Vec<i32>>()
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .max()
+            .unwrap()
+    };
+    let before = spread(0.0);
+    let after = spread(2.0);
+    assert!(after * 4 < before, "{} -> {}", before, after);
+}
+
+#[test]
+fn metering_modes() {
+    // A bright 7x7 subject in the middle of a dark 11x11 frame
+    let framed = || {
+        let mut buffer = PixelBuffer::new(11, 11, PixelFormat::HDRFloat32);
+        for (i, pixel) in buffer.bytes_mut().chunks_exact_mut(16).enumerate() {
+            let (x, y) = (i % 11, i / 11);
+            let level = match (2..9).contains(&x) && (2..9).contains(&y) {
+                true => 4.0f32,
+                false => 0.1,
+            };
+            for (dest, val) in pixel
+                .chunks_exact_mut(4)
+                .zip([level, level, level, 1.0].iter())
+            {
+                dest.copy_from_slice(&val.to_ne_bytes());
+            }
+        }
+        buffer
+    };
+    // Brightness of the dark corner after auto exposure
+    let corner = |metering| {
+        let filename = output(&format!("framed-11x11-{:?}.png", metering));
+        let settings = Settings {
+            auto_exposure: Level::Percentile(50.0),
+            metering,
+            ..Settings::default()
+        };
+        convert(framed(), &filename, &settings).unwrap();
+        read_rgb(&filename).2[0]
+    };
+    let average = corner(Metering::Average);
+    let center = corner(Metering::Center);
+    let spot = corner(Metering::Spot);
+    // The more the subject counts, the darker the surroundings come out
+    assert!(
+        average > center && center > spot,
+        "{} {} {}",
+        average,
+        center,
+        spot
+    );
+    assert!(Metering::with_str("matrix").is_err());
+}
+
+#[test]
+fn clip_hue_keeps_hue() {
+    let input = [1.5, 0.3, 0.1];
+    let hue = |lab: oklab::Oklab| lab.b.atan2(lab.a);
+    let input_hue = hue(oklab::linear_srgb_to_oklab(oklab::RGB::new(
+        input[0], input[1], input[2],
+    )));
+    let mapped = |color_map: &str| {
+        let filename = output(&format!("float-1x1-{}.png", color_map));
+        let settings = Settings {
+            tone_map: "linear".to_string(),
+            auto_exposure: Level::Scalar(0.5),
+            color_map: color_map.to_string(),
+            ..Settings::default()
+        };
+        convert(
+            float_buffer(&[[input[0], input[1], input[2], 1.0]]),
+            &filename,
+            &settings,
+        )
+        .unwrap();
+        let (_, _, rgb) = read_rgb(&filename);
+        hue(oklab::srgb_to_oklab(oklab::RGB::new(
+            rgb[0], rgb[1], rgb[2],
+        )))
+    };
+    let clip_hue = mapped("clip-hue");
+    // The same hue to within 8-bit rounding
+    assert!(
+        (clip_hue - input_hue).abs() < 0.02,
+        "{} vs {}",
+        clip_hue,
+        input_hue
+    );
+    let clipped_hue = mapped("clip");
+    assert!((clipped_hue - input_hue).abs() > 0.05);
+}